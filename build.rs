@@ -1,9 +1,19 @@
 fn main() {
+    // Regenerate the stubs when the vendored protos are updated
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=third_party/envoy/api/envoy/service/ext_proc");
+    println!("cargo:rerun-if-changed=third_party/envoy/api/envoy/extensions/filters/http/ext_proc");
+    // Consumers embedding only one side skip generating the other's stubs
+    let client = std::env::var_os("CARGO_FEATURE_CLIENT").is_some();
+    let server = std::env::var_os("CARGO_FEATURE_SERVER").is_some();
+    // For run_server's gRPC reflection
+    let out_dir = std::path::PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
     tonic_build::configure()
-        .build_client(true)
-        .build_server(true)
+        .build_client(client)
+        .build_server(server)
         .include_file("mod.rs")
         .out_dir("src/proto")
+        .file_descriptor_set_path(out_dir.join("ext_proc_descriptor.bin"))
         .compile_well_known_types(true)
         .compile(
             &["third_party/envoy/api/envoy/service/ext_proc/v3/external_processor.proto"],
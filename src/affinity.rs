@@ -0,0 +1,28 @@
+//! Pinning of runtime threads to CPU cores, to reduce scheduler noise in measurements
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use log::warn;
+use tokio::runtime::Builder;
+
+/// Pins the calling thread to `core`, returns false if the OS refused or the core doesn't exist
+pub fn pin_current_thread(core: usize) -> bool {
+    core_affinity::set_for_current(core_affinity::CoreId { id: core })
+}
+
+/// Pins each thread the runtime starts to the next of `cores`, round robin
+pub fn pin_runtime_threads(builder: &mut Builder, cores: Vec<usize>) {
+    if cores.is_empty() {
+        return;
+    }
+    let next = Arc::new(AtomicUsize::new(0));
+    builder.on_thread_start(move || {
+        let core = cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()];
+        if !pin_current_thread(core) {
+            warn!("Could not pin thread to core {}", core);
+        }
+    });
+}
@@ -0,0 +1,165 @@
+//! Comparison of a run with the same scenario of a previous run, read from the results
+//! `--json-only` prints, to catch regressions that comparing JSON by eye misses
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use crate::report::RunSummary;
+
+use super::workers::summary_from_json;
+use error::BaselineError;
+
+pub mod error {
+    use quick_error::quick_error;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum BaselineError {
+            OpenError(err: std::io::Error) {
+                display("Could not open baseline: {}", err)
+                from()
+            }
+            ParseError(err: serde_json::Error) {
+                display("Could not parse baseline: {}", err)
+                from()
+            }
+            NoRuns {
+                display("The baseline holds no complete run, expected `--json-only` results.")
+            }
+        }
+    );
+}
+
+/// How much worse than the baseline a run may get before it regressed
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    /// Largest throughput drop, in percent
+    pub max_throughput_drop: f64,
+    /// Largest increase of the mean and percentile latencies, in percent
+    pub max_latency_increase: f64,
+    /// Largest increase of the error count
+    pub max_error_increase: u64,
+}
+
+/// A metric of a run next to its baseline value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    pub metric: &'static str,
+    pub baseline: f64,
+    pub current: f64,
+    pub regressed: bool,
+}
+
+impl Delta {
+    /// Relative change in percent, None if the baseline is zero
+    pub fn change(&self) -> Option<f64> {
+        (self.baseline != 0.0).then(|| (self.current / self.baseline - 1.0) * 100.0)
+    }
+}
+
+/// The runs of a `--json-only` results file (`{"runs": [...]}`), or of a single run's results
+pub fn read_baseline<P: AsRef<Path>>(path: P) -> Result<Vec<RunSummary>, BaselineError> {
+    let results: serde_json::Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let runs: Vec<_> = match results.get("runs").and_then(|runs| runs.as_array()) {
+        Some(runs) => runs.iter().filter_map(summary_from_json).collect(),
+        None => summary_from_json(&results).into_iter().collect(),
+    };
+    if runs.is_empty() {
+        return Err(BaselineError::NoRuns);
+    }
+    Ok(runs)
+}
+
+/// The baseline run of the same scenario, or the one at the same position if none has its name
+pub fn matching_run<'a>(
+    baseline: &'a [RunSummary],
+    name: &str,
+    position: usize,
+) -> Option<&'a RunSummary> {
+    baseline
+        .iter()
+        .find(|run| run.name == name)
+        .or_else(|| baseline.get(position))
+}
+
+pub fn compare(
+    baseline: &RunSummary,
+    current: &RunSummary,
+    thresholds: &Thresholds,
+) -> Vec<Delta> {
+    let latency = |metric, baseline: f64, current: f64| Delta {
+        metric,
+        baseline,
+        current,
+        regressed: current > baseline * (1.0 + thresholds.max_latency_increase / 100.0),
+    };
+    let (before, after) = (&baseline.latency, &current.latency);
+    vec![
+        Delta {
+            metric: "throughput (req/s)",
+            baseline: baseline.rate(),
+            current: current.rate(),
+            regressed: current.rate()
+                < baseline.rate() * (1.0 - thresholds.max_throughput_drop / 100.0),
+        },
+        latency("mean latency (ms)", before.mean, after.mean),
+        latency("p50 latency (ms)", before.p50 as f64, after.p50 as f64),
+        latency("p90 latency (ms)", before.p90 as f64, after.p90 as f64),
+        latency("p99 latency (ms)", before.p99 as f64, after.p99 as f64),
+        latency("p99.9 latency (ms)", before.p999 as f64, after.p999 as f64),
+        Delta {
+            metric: "errors",
+            baseline: baseline.errors as f64,
+            current: current.errors as f64,
+            regressed: current.errors > baseline.errors + thresholds.max_error_increase,
+        },
+    ]
+}
+
+/// A markdown table of the deltas, regressions marked
+pub fn delta_table(deltas: &[Delta]) -> String {
+    let mut table = String::from(
+        "| Metric | Baseline | Current | Change | |\n\
+         |--------|----------|---------|--------|-|\n",
+    );
+    for delta in deltas {
+        let change = match delta.change() {
+            Some(change) => format!("{:+.1}%", change),
+            None => "-".to_string(),
+        };
+        table.push_str(&format!(
+            "| {} | {:.2} | {:.2} | {} | {} |\n",
+            delta.metric,
+            delta.baseline,
+            delta.current,
+            change,
+            if delta.regressed { "REGRESSION" } else { "" }
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::test_summary;
+
+    #[test]
+    fn regressions_beyond_thresholds_are_flagged() {
+        let baseline = test_summary();
+        let mut current = test_summary();
+        // 4% fewer transactions, a 22% slower p99, one more error
+        current.count = 960;
+        current.latency.p99 = 11;
+        current.errors += 1;
+        let thresholds = Thresholds {
+            max_throughput_drop: 5.0,
+            max_latency_increase: 10.0,
+            max_error_increase: 0,
+        };
+        let regressed: Vec<_> = compare(&baseline, &current, &thresholds)
+            .into_iter()
+            .filter(|delta| delta.regressed)
+            .map(|delta| delta.metric)
+            .collect();
+        assert_eq!(regressed, ["p99 latency (ms)", "errors"]);
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use hdrhistogram::Histogram;
+use log::{debug, error, info};
+use metered::{clear::Clear, ErrorCount, ResponseTime, Throughput};
+use tonic::transport::Channel;
+
+use super::{
+    error::BenchError,
+    http::HttpTarget,
+    metrics::{self, MetricsSink},
+    omission::{self, ExpectedInterval},
+    reconnect::{Retry, SharedConnection},
+    scenario::{Scenario, ScenarioBreakdown, ScenarioContext},
+    schedule::RateSchedule,
+    slowest::{SlowTransaction, SlowestTransactions},
+    targets::TargetBreakdown,
+};
+use crate::{
+    dummy::{
+        client::{
+            error::StreamHandleError, pool::ClientStreamPool, transaction::Transaction,
+            ClientStream,
+        },
+        DummyData,
+    },
+    error_category::ErrorBreakdown,
+    proto::envoy::service::ext_proc::v3::external_processor_client::ExternalProcessorClient,
+};
+
+/// The successful transactions left in a run of a fixed number of them. Benchers reserve one
+/// before each transaction and give it back if the transaction fails, so that exactly the number
+/// succeeds whatever the concurrency
+pub(super) struct TransactionBudget {
+    pub(super) total: u64,
+    remaining: AtomicU64,
+}
+
+impl TransactionBudget {
+    pub(super) fn new(total: u64) -> TransactionBudget {
+        TransactionBudget {
+            total,
+            remaining: AtomicU64::new(total),
+        }
+    }
+
+    fn reserve(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |remaining| {
+                remaining.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.remaining.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// How often each dynamic metadata key was emitted by the server
+#[derive(Default)]
+pub(super) struct MetadataSummary {
+    key_counts: Mutex<BTreeMap<String, u64>>,
+}
+
+impl MetadataSummary {
+    fn record(&self, transaction: &Transaction) {
+        if transaction.dynamic_metadata.fields.is_empty() {
+            return;
+        }
+        let mut key_counts = self.key_counts.lock().unwrap();
+        for key in transaction.dynamic_metadata.fields.keys() {
+            *key_counts.entry(key.clone()).or_default() += 1;
+        }
+    }
+
+    fn clear(&self) {
+        self.key_counts.lock().unwrap().clear();
+    }
+
+    pub(super) fn key_counts(&self) -> BTreeMap<String, u64> {
+        self.key_counts.lock().unwrap().clone()
+    }
+}
+
+/// What a bencher sends its transactions to
+pub(super) enum BencherTarget {
+    /// Simulates the ext_proc filter against the server
+    Processor {
+        client: ExternalProcessorClient<Channel>,
+        /// How many reconnections `client` is from
+        generation: u64,
+        stream: ClientStream,
+    },
+    /// Simulates the ext_proc filter over a stream checked out of a pool for each transaction
+    Pool {
+        pool: Arc<ClientStreamPool<ExternalProcessorClient<Channel>>>,
+        /// Data of the next transaction
+        data: Arc<DummyData>,
+        /// The last transaction, kept once its stream is returned
+        transaction: Transaction,
+    },
+    Http(HttpTarget),
+}
+
+impl BencherTarget {
+    /// The last transaction sent to a processor
+    fn last_transaction(&self) -> Option<&Transaction> {
+        match self {
+            BencherTarget::Processor { stream, .. } => Some(stream.transaction()),
+            BencherTarget::Pool { transaction, .. } => Some(transaction),
+            BencherTarget::Http(_) => None,
+        }
+    }
+}
+
+/// What [`StreamBencher::run_stream`] needs to retry a transaction
+pub(super) struct TransactionRetry<'a> {
+    retry: Option<&'a Retry>,
+    retries: &'a AtomicU64,
+    /// Re-established before retrying a transaction that lost it
+    connection: Option<&'a SharedConnection>,
+}
+
+pub(super) struct StreamBencher {
+    pub(super) metrics: Arc<StreamMetrics>,
+    pub(super) metadata: Arc<MetadataSummary>,
+
+    /// Position among the run's benchers
+    pub(super) index: usize,
+    pub(super) target: BencherTarget,
+    pub(super) scenario: Option<Box<dyn Scenario>>,
+    /// Transactions by the label of their scenario's data
+    pub(super) scenarios: Arc<ScenarioBreakdown>,
+    /// Open-loop schedule of transactions, run back to back when unset
+    pub(super) schedule: Option<Arc<RateSchedule>>,
+    /// Transactions run so far, including the warmup's
+    pub(super) transactions: u64,
+    /// Transactions running longer than this are aborted and their stream recycled
+    pub(super) watchdog: Option<Duration>,
+    pub(super) watchdog_timeouts: Arc<AtomicU64>,
+    /// Transactions running longer than this are cancelled and counted in `timeouts` only
+    pub(super) timeout: Option<Duration>,
+    pub(super) timeouts: Arc<AtomicU64>,
+    /// Retries transactions failing on a transient stream failure when set
+    pub(super) retry: Option<Retry>,
+    pub(super) retries: Arc<AtomicU64>,
+    pub(super) error_categories: Arc<ErrorBreakdown>,
+    pub(super) slowest: Arc<SlowestTransactions>,
+    /// Every transaction latency, in microseconds, when it is exported or corrected
+    pub(super) latencies: Option<Arc<Mutex<Histogram<u64>>>>,
+    /// Corrects `latencies` for coordinated omission when set
+    pub(super) expected_interval: Option<ExpectedInterval>,
+    /// Replaces the client when the server connection is lost
+    pub(super) connection: Option<Arc<SharedConnection>>,
+    /// Per-target metrics of the run and the index of this bencher's target, when the run has
+    /// several
+    pub(super) breakdown: Option<(Arc<TargetBreakdown>, usize)>,
+    /// Receives the measurements taken after the warmup
+    pub(super) metrics_sink: Option<Arc<dyn MetricsSink>>,
+    /// Whether the last transaction succeeded, telling scenarios whether it can be built upon
+    pub(super) last_succeeded: bool,
+    pub(super) print_errors: bool,
+    pub(super) print_immediate_responses: bool,
+
+    /// Open the stream before the warmup, then wait for the others at `ready_barrier`
+    pub(super) prestart_stream: bool,
+    pub(super) ready_barrier: Arc<tokio::sync::Barrier>,
+    pub(super) warmup_barrier: Arc<tokio::sync::Barrier>,
+    /// When the warmup ended, set by the first bencher done with it
+    pub(super) after_warmup: Arc<tokio::sync::OnceCell<Instant>>,
+    /// Stop once this many transactions succeeded, counted over every bencher after the warmup
+    pub(super) budget: Option<Arc<TransactionBudget>>,
+}
+
+impl StreamBencher {
+    pub(super) async fn bench_with_warmup(mut self, warmup: Duration, duration: Duration) {
+        if self.prestart_stream {
+            self.start_stream().await;
+            if self.ready_barrier.wait().await.is_leader() {
+                info!("Streams ready.");
+            }
+        }
+        let metrics_sink = self.metrics_sink.take();
+        self.bench(warmup, None).await;
+        self.metrics_sink = metrics_sink;
+        if let Some(ref mut interval) = self.expected_interval {
+            interval.freeze();
+        }
+        self.warmup_barrier.wait().await;
+        self.after_warmup
+            .get_or_init(|| async {
+                if !warmup.is_zero() {
+                    info!("Warmup done.");
+                }
+                self.metrics.clear();
+                self.metadata.clear();
+                self.watchdog_timeouts.store(0, Ordering::Relaxed);
+                self.timeouts.store(0, Ordering::Relaxed);
+                self.retries.store(0, Ordering::Relaxed);
+                self.error_categories.clear();
+                self.slowest.clear();
+                self.scenarios.clear();
+                if let Some(ref latencies) = self.latencies {
+                    latencies.lock().unwrap().reset();
+                }
+                if let Some(ref schedule) = self.schedule {
+                    schedule.restart();
+                }
+                if let Some(ref connection) = self.connection {
+                    connection.clear();
+                }
+                if let Some((ref breakdown, _)) = self.breakdown {
+                    breakdown.clear();
+                }
+                match self.target {
+                    BencherTarget::Processor { ref stream, .. } => stream.stats().clear(),
+                    BencherTarget::Pool { ref pool, .. } => pool.stats().clear(),
+                    BencherTarget::Http(_) => {}
+                }
+                match self.budget {
+                    Some(ref budget) => info!("Benchmarking for {} transactions", budget.total),
+                    None => info!("Benchmarking for {} seconds", duration.as_secs()),
+                }
+                Instant::now()
+            })
+            .await;
+        let budget = self.budget.clone();
+        self.bench(duration, budget).await;
+    }
+
+    /// Opens the stream of a processor target ahead of its first transaction
+    async fn start_stream(&mut self) {
+        let BencherTarget::Processor {
+            ref mut client,
+            ref mut stream,
+            ..
+        } = self.target
+        else {
+            return;
+        };
+        if let Err(e) = stream.start_stream(client).await {
+            if self.print_errors {
+                error!("while starting stream: {}", e);
+            }
+        }
+    }
+
+    /// Runs one attempt of the next transaction
+    async fn run_transaction(target: &mut BencherTarget) -> Result<(), BenchError> {
+        match target {
+            BencherTarget::Processor { client, stream, .. } => {
+                stream.start_stream(client).await?;
+                stream.handle_stream().await?;
+                stream.finish_stream();
+            }
+            BencherTarget::Pool {
+                pool,
+                data,
+                transaction,
+            } => {
+                // Interrupted transactions return their stream broken, the pool reopens it
+                let mut stream = pool.checkout().await?;
+                stream.set_data(data.clone());
+                let result = stream.handle_stream().await;
+                *transaction = stream.take_transaction();
+                result?;
+            }
+            BencherTarget::Http(http) => http.send().await?,
+        }
+        Ok(())
+    }
+
+    /// Sets the data of the next transaction from the scenario, false if it ended the run
+    async fn next_scenario_step(&mut self) -> bool {
+        let Some(ref mut scenario) = self.scenario else {
+            return true;
+        };
+        let last = self
+            .target
+            .last_transaction()
+            .filter(|_| self.last_succeeded);
+        let context = ScenarioContext {
+            stream: self.index,
+            transactions: self.transactions,
+            last,
+        };
+        let Some(data) = scenario.next_transaction(&context).await else {
+            return false;
+        };
+        match self.target {
+            BencherTarget::Processor { ref mut stream, .. } => stream.set_data(data),
+            BencherTarget::Pool {
+                data: ref mut next, ..
+            } => *next = data,
+            BencherTarget::Http(ref mut http) => http.set_data(data),
+        }
+        true
+    }
+
+    /// Runs transactions for `duration`, or until `budget` runs out
+    async fn bench(&mut self, duration: Duration, budget: Option<Arc<TransactionBudget>>) {
+        let start = Instant::now();
+        // Unbounded for runs of a number of transactions without a time limit
+        let end = start.checked_add(duration);
+        while start.elapsed() < duration {
+            let due = match self.schedule {
+                Some(ref schedule) => {
+                    let due = schedule.next_due();
+                    if matches!(end, Some(end) if due >= end) {
+                        break;
+                    }
+                    tokio::time::sleep_until(tokio::time::Instant::from_std(due)).await;
+                    schedule.record_start(due);
+                    Some(due)
+                }
+                None => None,
+            };
+            if !self.next_scenario_step().await {
+                break;
+            }
+            if matches!(budget, Some(ref budget) if !budget.reserve()) {
+                break;
+            }
+            let transaction_start = Instant::now();
+            let retry = TransactionRetry {
+                retry: self.retry.as_ref(),
+                retries: &self.retries,
+                connection: self.connection.as_deref(),
+            };
+            let Some(result) = StreamBencher::run_stream_within(
+                &self.metrics,
+                &mut self.target,
+                self.watchdog,
+                retry,
+                self.timeout,
+            )
+            .await
+            else {
+                if let Some(ref budget) = budget {
+                    budget.release();
+                }
+                self.transactions += 1;
+                self.last_succeeded = false;
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+                if let Some(ref sink) = self.metrics_sink {
+                    sink.counter(metrics::TIMEOUTS, 1);
+                }
+                // The stream may still get the responses of the cancelled transaction
+                if let BencherTarget::Processor { ref mut stream, .. } = self.target {
+                    stream.abort_stream();
+                }
+                if self.print_errors {
+                    error!("while running stream: transaction timed out");
+                }
+                continue;
+            };
+            let latency = transaction_start.elapsed();
+            if let (Some(budget), Err(_)) = (&budget, &result) {
+                budget.release();
+            }
+            if let (Some(schedule), Some(due)) = (&self.schedule, due) {
+                schedule.record_end(due);
+            }
+            if let Some(ref latencies) = self.latencies {
+                let mut latencies = latencies.lock().unwrap();
+                let interval = self.expected_interval.as_mut().and_then(|interval| {
+                    interval.observe(latency);
+                    interval.get()
+                });
+                match interval {
+                    Some(interval) => omission::record_corrected(&mut latencies, latency, interval),
+                    None => latencies.saturating_record(latency.as_micros() as u64),
+                }
+            }
+            if self.slowest.qualifies(latency) {
+                // Envoy's exchanges with the server aren't visible
+                let phases = match self.target.last_transaction() {
+                    Some(transaction) => transaction.phases.clone(),
+                    None => Vec::new(),
+                };
+                self.slowest.offer(SlowTransaction {
+                    latency_us: latency.as_micros() as u64,
+                    stream: self.index,
+                    transaction: self.transactions,
+                    error: result.as_ref().err().map(ToString::to_string),
+                    phases,
+                });
+            }
+            if let Some((ref breakdown, target)) = self.breakdown {
+                breakdown.record(target, latency, result.is_err());
+            }
+            if let Some(label) = self.scenario.as_ref().and_then(|scenario| scenario.label()) {
+                self.scenarios.record(label, latency, result.is_err());
+            }
+            self.transactions += 1;
+            self.last_succeeded = result.is_ok();
+            if let Some(ref sink) = self.metrics_sink {
+                sink.histogram(
+                    metrics::TRANSACTION_LATENCY_MS,
+                    latency.as_millis() as u64,
+                );
+                sink.counter(metrics::TRANSACTIONS, 1);
+                if result.is_err() {
+                    sink.counter(metrics::TRANSACTION_ERRORS, 1);
+                }
+            }
+            if let Err(ref e) = result {
+                self.error_categories.record(e);
+            }
+            if let Err(BenchError::WatchdogTimeout) = result {
+                self.watchdog_timeouts.fetch_add(1, Ordering::Relaxed);
+                if let Some(ref sink) = self.metrics_sink {
+                    sink.counter(metrics::WATCHDOG_TIMEOUTS, 1);
+                }
+                // The stream may still get the responses of the aborted transaction
+                if let BencherTarget::Processor { ref mut stream, .. } = self.target {
+                    stream.abort_stream();
+                }
+            }
+            if let (Err(BenchError::StreamError(StreamHandleError::MessageTimeout)), Some(sink)) =
+                (&result, &self.metrics_sink)
+            {
+                sink.counter(metrics::MESSAGE_TIMEOUTS, 1);
+            }
+            if let (Err(e), Some(connection)) = (&result, &self.connection) {
+                if e.is_transport_loss() {
+                    if let BencherTarget::Processor {
+                        ref mut client,
+                        ref mut generation,
+                        ref mut stream,
+                    } = self.target
+                    {
+                        stream.abort_stream();
+                        connection.reconnect(generation, client).await;
+                    }
+                }
+            }
+            match result {
+                Err(e) if self.print_errors => {
+                    error!("while running stream: {}", e);
+                }
+                Ok(_) => {
+                    let Some(transaction) = self.target.last_transaction() else {
+                        continue;
+                    };
+                    self.metadata.record(transaction);
+                    if let Some(ref immediate_response) = transaction.immediate_response {
+                        if self.print_immediate_responses {
+                            info!("Transaction denied with {}", immediate_response);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Runs a transaction, cancelled if it takes longer than `timeout`. The timeout is outside of
+    /// `run_stream`, so that cancelled transactions are left out of its latency, throughput and
+    /// error metrics. None if the transaction was cancelled
+    async fn run_stream_within(
+        metrics: &StreamMetrics,
+        target: &mut BencherTarget,
+        watchdog: Option<Duration>,
+        retry: TransactionRetry<'_>,
+        timeout: Option<Duration>,
+    ) -> Option<Result<(), BenchError>> {
+        let transaction = StreamBencher::run_stream(metrics, target, watchdog, retry);
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, transaction).await.ok(),
+            None => Some(transaction.await),
+        }
+    }
+}
+
+#[metered::metered(registry = StreamMetrics, registry_expr = metrics, visibility = pub)]
+impl StreamBencher {
+    #[measure([ResponseTime, Throughput, ErrorCount])]
+    async fn run_stream(
+        metrics: &StreamMetrics,
+        target: &mut BencherTarget,
+        watchdog: Option<Duration>,
+        retry: TransactionRetry<'_>,
+    ) -> Result<(), BenchError> {
+        // The watchdog bounds the transaction, retries included
+        let transaction = async {
+            let mut attempt = 0;
+            loop {
+                let result = StreamBencher::run_transaction(target).await;
+                let retry_policy = match (&result, retry.retry) {
+                    (Err(e), Some(policy)) if e.is_retryable() && attempt < policy.max_retries => {
+                        debug!("Retrying transaction after: {}", e);
+                        policy
+                    }
+                    _ => return result,
+                };
+                if let BencherTarget::Processor {
+                    ref mut client,
+                    ref mut generation,
+                    ref mut stream,
+                } = *target
+                {
+                    stream.abort_stream();
+                    let transport_lost = matches!(result, Err(ref e) if e.is_transport_loss());
+                    if let (true, Some(connection)) = (transport_lost, retry.connection) {
+                        connection.reconnect(generation, client).await;
+                    }
+                }
+                tokio::time::sleep(retry_policy.backoff.delay(attempt)).await;
+                retry.retries.fetch_add(1, Ordering::Relaxed);
+                attempt += 1;
+            }
+        };
+        match watchdog {
+            Some(threshold) => tokio::time::timeout(threshold, transaction)
+                .await
+                .map_err(|_| BenchError::WatchdogTimeout)?,
+            None => transaction.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Status;
+
+    #[test]
+    fn failed_transactions_give_their_reservation_back() {
+        let budget = TransactionBudget::new(2);
+        assert!(budget.reserve());
+        assert!(budget.reserve());
+        assert!(!budget.reserve());
+        // One of the two failed, another attempt may take its place
+        budget.release();
+        assert!(budget.reserve());
+        assert!(!budget.reserve());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn timed_out_transactions_are_left_out_of_the_metrics() {
+        use crate::dummy::{
+            client::Config,
+            in_memory::in_memory_client,
+            server::{behavior::BehaviorConfig, server_processing_mode, ExtProcService},
+        };
+
+        let behavior: BehaviorConfig =
+            serde_json::from_str(r#"{"latency": {"default": {"fixed": {"ms": 1000}}}}"#).unwrap();
+        let service = ExtProcService::new(server_processing_mode(false), behavior);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut target = BencherTarget::Processor {
+                client: in_memory_client(service).await.unwrap(),
+                generation: 0,
+                stream: ClientStream::new(Arc::new(DummyData::default()), Config::default()),
+            };
+            let metrics = StreamMetrics::default();
+            let retries = AtomicU64::new(0);
+            let retry = TransactionRetry {
+                retry: None,
+                retries: &retries,
+                connection: None,
+            };
+            let timeout = Some(Duration::from_millis(20));
+            let result =
+                StreamBencher::run_stream_within(&metrics, &mut target, None, retry, timeout).await;
+            assert!(result.is_none());
+            assert_eq!(metrics.run_stream.error_count.get(), 0);
+            assert_eq!(metrics.run_stream.response_time.histogram().len(), 0);
+            assert_eq!(metrics.run_stream.throughput.histogram().len(), 0);
+        });
+    }
+
+    #[test]
+    fn only_transient_stream_failures_are_retried() {
+        assert!(BenchError::StreamError(StreamHandleError::StreamClosed).is_retryable());
+        assert!(BenchError::from(Status::unavailable("restarting")).is_retryable());
+        assert!(!BenchError::from(Status::invalid_argument("bad request")).is_retryable());
+        assert!(!BenchError::StreamError(StreamHandleError::NotStarted).is_retryable());
+        assert!(!BenchError::WatchdogTimeout.is_retryable());
+    }
+}
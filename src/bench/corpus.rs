@@ -0,0 +1,294 @@
+//! Realistic request headers, synthesized from the header names and values of a corpus (a HAR
+//! dump or a JSON access log) without replaying its sensitive or identifying values
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+use super::scenario::{Scenario, ScenarioContext, ScenarioFactory};
+use crate::dummy::{har::Har, DummyData};
+
+use error::CorpusError;
+
+pub mod error {
+    use quick_error::quick_error;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum CorpusError {
+            OpenError(err: std::io::Error) {
+                display("Could not read header corpus: {}", err)
+                from()
+            }
+            ParseError(err: serde_json::Error) {
+                display("Could not parse header corpus: {}", err)
+                from()
+            }
+            Empty {
+                display("Header corpus has no requests.")
+            }
+        }
+    );
+}
+
+/// Values seen in fewer requests than this are never replayed, only their shape is
+pub const MIN_VERBATIM_COUNT: u64 = 5;
+
+/// Headers whose values are always anonymized, however common
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+    "x-forwarded-for",
+    "x-real-ip",
+];
+
+/// How often each header appears in the corpus' requests, and with which values
+pub struct HeaderCorpus {
+    headers: Vec<LearnedHeader>,
+}
+
+struct LearnedHeader {
+    name: String,
+    /// Share of requests carrying the header
+    presence: f64,
+    values: Vec<LearnedValue>,
+    weights: WeightedIndex<u64>,
+}
+
+enum LearnedValue {
+    /// Common enough to be sent as is
+    Verbatim(String),
+    /// Sent with its letters and digits redrawn, keeping its length and punctuation
+    Shape(String),
+}
+
+impl LearnedValue {
+    fn generate<R: Rng>(&self, rng: &mut R) -> String {
+        match self {
+            LearnedValue::Verbatim(value) => value.clone(),
+            LearnedValue::Shape(value) => value
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' => rng.gen_range('a'..='z'),
+                    'A'..='Z' => rng.gen_range('A'..='Z'),
+                    '0'..='9' => rng.gen_range('0'..='9'),
+                    _ => c,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl HeaderCorpus {
+    /// Learns from a `.har` file, or from a JSON access log with an object of request headers
+    /// per line
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<HeaderCorpus, CorpusError> {
+        let file = File::open(&path)?;
+        match path.as_ref().extension().and_then(|extension| extension.to_str()) {
+            Some("har") => HeaderCorpus::from_har(file),
+            _ => HeaderCorpus::from_json_lines(BufReader::new(file)),
+        }
+    }
+
+    pub fn from_har<R: Read>(reader: R) -> Result<HeaderCorpus, CorpusError> {
+        let har: Har = serde_json::from_reader(reader)?;
+        HeaderCorpus::learn(har.log.entries.into_iter().map(|entry| {
+            entry
+                .request
+                .headers
+                .into_iter()
+                .map(|header| (header.name, header.value))
+                .collect()
+        }))
+    }
+
+    pub fn from_json_lines<R: BufRead>(reader: R) -> Result<HeaderCorpus, CorpusError> {
+        let mut requests = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let headers: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&line)?;
+            requests.push(
+                headers
+                    .into_iter()
+                    .map(|(name, value)| match value {
+                        serde_json::Value::String(value) => (name, value),
+                        value => (name, value.to_string()),
+                    })
+                    .collect(),
+            );
+        }
+        HeaderCorpus::learn(requests)
+    }
+
+    /// Learns the header distributions of these requests' headers. Pseudo headers and
+    /// content-length, which describe the request rather than its client, are left out
+    pub fn learn<I>(requests: I) -> Result<HeaderCorpus, CorpusError>
+    where
+        I: IntoIterator<Item = Vec<(String, String)>>,
+    {
+        let mut count = 0u64;
+        let mut headers: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+        for request in requests {
+            count += 1;
+            for (name, value) in request {
+                let name = name.to_lowercase();
+                if name.starts_with(':') || name == "content-length" {
+                    continue;
+                }
+                *headers.entry(name).or_default().entry(value).or_default() += 1;
+            }
+        }
+        if count == 0 {
+            return Err(CorpusError::Empty);
+        }
+        let headers = headers
+            .into_iter()
+            .map(|(name, values)| {
+                let sensitive = SENSITIVE_HEADERS.contains(&name.as_str());
+                let occurrences: u64 = values.values().sum();
+                let (values, weights): (Vec<_>, Vec<_>) = values
+                    .into_iter()
+                    .map(|(value, seen)| {
+                        let value = if seen >= MIN_VERBATIM_COUNT && !sensitive {
+                            LearnedValue::Verbatim(value)
+                        } else {
+                            LearnedValue::Shape(value)
+                        };
+                        (value, seen)
+                    })
+                    .unzip();
+                LearnedHeader {
+                    name,
+                    presence: (occurrences as f64 / count as f64).min(1.0),
+                    values,
+                    weights: WeightedIndex::new(weights).expect("values were seen at least once"),
+                }
+            })
+            .collect();
+        Ok(HeaderCorpus { headers })
+    }
+
+    /// Headers of a synthesized request, each present as often as in the corpus
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .filter(|header| rng.gen_bool(header.presence))
+            .map(|header| {
+                let value = &header.values[header.weights.sample(rng)];
+                (header.name.clone(), value.generate(rng))
+            })
+            .collect()
+    }
+
+    /// Scenarios sending `base` with synthesized headers in place of its regular ones (pseudo
+    /// headers are kept). With a seed, each stream draws the same headers every run
+    pub fn scenarios(
+        self: Arc<Self>,
+        base: Arc<DummyData>,
+        seed: Option<u64>,
+    ) -> ScenarioFactory {
+        Arc::new(move |stream| {
+            let rng = match seed {
+                Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(stream as u64)),
+                None => StdRng::from_entropy(),
+            };
+            Box::new(CorpusScenario {
+                corpus: self.clone(),
+                base: base.clone(),
+                rng,
+            }) as Box<dyn Scenario>
+        })
+    }
+}
+
+struct CorpusScenario {
+    corpus: Arc<HeaderCorpus>,
+    base: Arc<DummyData>,
+    rng: StdRng,
+}
+
+#[tonic::async_trait]
+impl Scenario for CorpusScenario {
+    async fn next_transaction(
+        &mut self,
+        _context: &ScenarioContext<'_>,
+    ) -> Option<Arc<DummyData>> {
+        let base = &self.base;
+        let mut req_headers: Vec<(String, String)> = base
+            .req_headers
+            .iter()
+            .filter(|(name, _)| name.starts_with(':'))
+            .cloned()
+            .collect();
+        req_headers.extend(self.corpus.generate(&mut self.rng));
+        Some(Arc::new(DummyData {
+            req_headers,
+            req_attributes: base.req_attributes.clone(),
+            req_body: base.req_body.clone(),
+            req_trailers: base.req_trailers.clone(),
+            resp_status: base.resp_status,
+            resp_headers: base.resp_headers.clone(),
+            resp_body: base.resp_body.clone(),
+            resp_trailers: base.resp_trailers.clone(),
+            expect: base.expect.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rare_and_sensitive_values_are_anonymized() {
+        let request = |id: usize| {
+            vec![
+                (":path".to_string(), "/".to_string()),
+                ("Accept".to_string(), "text/html".to_string()),
+                ("authorization".to_string(), "Bearer abc123".to_string()),
+                ("x-request-id".to_string(), format!("req-{:04}", id)),
+            ]
+        };
+        let corpus = HeaderCorpus::learn((0..10).map(request)).unwrap();
+        let mut rng = StdRng::seed_from_u64(7);
+        let headers = corpus.generate(&mut rng);
+        let value = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+                .unwrap()
+        };
+        assert!(headers.iter().all(|(name, _)| !name.starts_with(':')));
+        assert_eq!(value("accept"), "text/html");
+        let authorization = value("authorization");
+        assert_ne!(authorization, "Bearer abc123");
+        assert_eq!(authorization.len(), "Bearer abc123".len());
+        // Unique values keep their shape only
+        let request_id = value("x-request-id");
+        assert_eq!(request_id.len(), "req-0000".len());
+        assert!(request_id[..3].chars().all(|c| c.is_ascii_lowercase()));
+        assert_eq!(&request_id[3..4], "-");
+        assert!(request_id[4..].chars().all(|c| c.is_ascii_digit()));
+
+        assert_eq!(
+            corpus.generate(&mut StdRng::seed_from_u64(7)),
+            headers,
+            "seeded generation is reproducible"
+        );
+        assert!(HeaderCorpus::learn(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use hyper::{client::HttpConnector, Body, Client, Method, Request, Uri};
+
+use super::error::BenchError;
+use crate::dummy::DummyData;
+
+/// Sends the dummy request to an Envoy listener configured with the ext_proc filter, so the
+/// whole proxy and processor path is measured
+pub(super) struct HttpTarget {
+    client: Client<HttpConnector>,
+    url: Uri,
+    data: Arc<DummyData>,
+}
+
+impl HttpTarget {
+    pub(super) fn new(client: Client<HttpConnector>, url: Uri, data: Arc<DummyData>) -> HttpTarget {
+        HttpTarget { client, url, data }
+    }
+
+    pub(super) fn set_data(&mut self, data: Arc<DummyData>) {
+        self.data = data;
+    }
+
+    pub(super) async fn send(&self) -> Result<(), BenchError> {
+        let response = self.client.request(self.request()).await?;
+        let status = response.status();
+        // Drain the body, the transaction isn't over until it is received
+        hyper::body::to_bytes(response.into_body()).await?;
+        if status.is_server_error() {
+            return Err(BenchError::HttpStatus(status.as_u16()));
+        }
+        Ok(())
+    }
+
+    /// `:method` and `:path` pseudo headers of the dummy data pick the method and path
+    fn request(&self) -> Request<Body> {
+        let mut method = if self.data.req_body.is_empty() {
+            Method::GET
+        } else {
+            Method::POST
+        };
+        let mut path = self.url.path().to_string();
+        let mut builder = Request::builder();
+        for (name, value) in &self.data.req_headers {
+            match name.to_lowercase().as_str() {
+                ":method" => method = Method::from_bytes(value.as_bytes()).unwrap_or(method),
+                ":path" => path = value.clone(),
+                name if name.starts_with(':') => {}
+                _ => builder = builder.header(name.as_str(), value.as_str()),
+            }
+        }
+        let uri = format!(
+            "{}://{}{}",
+            self.url.scheme_str().unwrap_or("http"),
+            self.url.authority().map(|a| a.as_str()).unwrap_or_default(),
+            path
+        );
+        builder
+            .method(method)
+            .uri(uri)
+            .body(Body::from(self.data.req_body.clone()))
+            .expect("dummy request headers must be valid HTTP headers")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pseudo_headers_pick_method_and_path() {
+        let data = DummyData {
+            req_headers: vec![
+                (":method".to_string(), "PUT".to_string()),
+                (":path".to_string(), "/upload?id=1".to_string()),
+                ("Content-Type".to_string(), "text/plain".to_string()),
+            ],
+            req_body: b"hello".to_vec(),
+            ..Default::default()
+        };
+        let target = HttpTarget::new(
+            Client::new(),
+            "http://127.0.0.1:10000/".parse().unwrap(),
+            Arc::new(data),
+        );
+        let request = target.request();
+        assert_eq!(request.method(), Method::PUT);
+        assert_eq!(request.uri(), "http://127.0.0.1:10000/upload?id=1");
+        assert_eq!(request.headers()["content-type"], "text/plain");
+        assert!(!request.headers().contains_key(":path"));
+    }
+}
@@ -0,0 +1,29 @@
+//! Telemetry of benchmark runs, for embedders redirecting it into their own systems
+
+/// Latency of each transaction, in milliseconds
+pub const TRANSACTION_LATENCY_MS: &str = "transaction_latency_ms";
+/// Finished transactions, including failed ones
+pub const TRANSACTIONS: &str = "transactions";
+/// Failed transactions
+pub const TRANSACTION_ERRORS: &str = "transaction_errors";
+/// Transactions aborted by the watchdog
+pub const WATCHDOG_TIMEOUTS: &str = "watchdog_timeouts";
+/// Transactions cancelled by the transaction timeout, left out of the other transaction metrics
+pub const TIMEOUTS: &str = "timeouts";
+/// Transactions failed by a message that wasn't answered within the message timeout
+pub const MESSAGE_TIMEOUTS: &str = "message_timeouts";
+/// Mean transactions per second since the run started, sampled every monitor interval
+pub const THROUGHPUT: &str = "throughput";
+/// Mean transaction latency in milliseconds since the run started, sampled every monitor interval
+pub const MEAN_LATENCY_MS: &str = "mean_latency_ms";
+
+/// Receives the measurements of a run as they are taken, in addition to the metered
+/// [`super::StreamMetrics`] the report is built from. Measurements of the warmup are not sent
+pub trait MetricsSink: Send + Sync {
+    /// Records a value of a distribution, e.g. a transaction latency
+    fn histogram(&self, name: &str, value: u64);
+    /// Adds to a monotonic count, e.g. of failed transactions
+    fn counter(&self, name: &str, increment: u64);
+    /// Sets the current value of a measure, e.g. the throughput
+    fn gauge(&self, name: &str, value: f64);
+}
@@ -0,0 +1,748 @@
+//! Benchmarking engine: runs concurrent client streams against a server, or HTTP requests through
+//! an Envoy using it, and aggregates their metrics, so other test suites can embed load tests
+//! (`bench_client` is a CLI over it)
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use hdrhistogram::Histogram;
+use hyper::Uri;
+use log::{error, info, warn};
+use tokio::sync::oneshot::{self, error::TryRecvError};
+use tonic::transport::Channel;
+
+use crate::{
+    affinity::pin_current_thread,
+    dummy::{
+        client::{pool::ClientStreamPool, stats::StreamStats, ClientStream, Config, Phase},
+        DummyData,
+    },
+    error_category::ErrorBreakdown,
+    proto::envoy::service::ext_proc::v3::external_processor_client::ExternalProcessorClient,
+    report::{
+        sink::{Sample, UdpSink},
+        LatencySummary, RunSummary,
+    },
+};
+use bencher::{BencherTarget, MetadataSummary, StreamBencher, TransactionBudget};
+use http::HttpTarget;
+use metrics::MetricsSink;
+use omission::ExpectedInterval;
+use prometheus::PrometheusExporter;
+use reconnect::SharedConnection;
+use scenario::{ScenarioBreakdown, ScenarioFactory, ScenarioSummary};
+use schedule::RateSchedule;
+use slowest::{SlowTransaction, SlowestTransactions};
+use targets::{weighted_slots, TargetBreakdown};
+
+pub use bencher::StreamMetrics;
+pub use reconnect::{Backoff, Reconnect, Retry};
+pub use targets::{TargetSummary, WeightedProcessor};
+
+pub mod baseline;
+mod bencher;
+pub mod corpus;
+mod http;
+pub mod metrics;
+pub mod omission;
+pub mod profile;
+pub mod prometheus;
+mod reconnect;
+pub mod scenario;
+pub mod schedule;
+pub mod slowest;
+#[cfg(feature = "server")]
+pub mod self_test;
+pub mod targets;
+pub mod workers;
+
+pub mod error {
+    use crate::dummy::client::error::StreamHandleError;
+    use crate::error_category::{Categorized, ErrorCategory};
+    use quick_error::quick_error;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum BenchError {
+            StreamError(err: StreamHandleError) {
+                display("{}", err)
+                from()
+                from(err: tonic::Status) -> (StreamHandleError::from(err))
+            }
+            HttpError(err: hyper::Error) {
+                display("HTTP request failed: {}", err)
+                from()
+            }
+            HttpStatus(status: u16) {
+                display("Envoy responded with {}.", status)
+            }
+            WatchdogTimeout {
+                display("Transaction aborted by the watchdog.")
+            }
+        }
+    );
+
+    impl Categorized for BenchError {
+        fn category(&self) -> ErrorCategory {
+            match self {
+                BenchError::StreamError(err) => err.category(),
+                BenchError::HttpError(_) => ErrorCategory::Transport,
+                // Envoy's local replies when the upstream is unreachable or late
+                BenchError::HttpStatus(502 | 503) => ErrorCategory::Transport,
+                BenchError::HttpStatus(504) => ErrorCategory::Timeout,
+                BenchError::HttpStatus(_) => ErrorCategory::Application,
+                BenchError::WatchdogTimeout => ErrorCategory::Timeout,
+            }
+        }
+
+        fn status(&self) -> Option<&tonic::Status> {
+            match self {
+                BenchError::StreamError(err) => err.status(),
+                _ => None,
+            }
+        }
+    }
+
+    impl BenchError {
+        /// Whether the transaction may succeed on a new stream: the stream closed under it or
+        /// ended with a retryable gRPC status. Failures of the transaction itself aren't
+        pub fn is_retryable(&self) -> bool {
+            match self {
+                BenchError::StreamError(
+                    StreamHandleError::StreamClosed | StreamHandleError::RequestSendError(_),
+                ) => true,
+                BenchError::StreamError(StreamHandleError::ResponseError(status)) => {
+                    ErrorCategory::of_status(status).is_retryable()
+                }
+                _ => false,
+            }
+        }
+
+        /// Whether the connection to the server was lost, rather than a transaction failing
+        pub fn is_transport_loss(&self) -> bool {
+            matches!(
+                self,
+                BenchError::StreamError(StreamHandleError::ResponseError(status))
+                    if status.code() == tonic::Code::Unavailable
+            )
+        }
+    }
+}
+
+/// Largest latency recorded in [`BenchReport::latencies`], an hour. Longer ones are recorded as
+/// this
+const MAX_LATENCY_MICROS: u64 = 3_600_000_000;
+
+/// Where transactions are sent to
+pub enum Target {
+    /// An External Processor server, with the client simulating the ext_proc filter
+    Processor(ExternalProcessorClient<Channel>),
+    /// Several External Processor servers, each handling a share of the streams proportional to
+    /// its weight
+    Processors(Vec<WeightedProcessor>),
+    /// An Envoy listener (e.g. `http://127.0.0.1:10000/`) with the ext_proc filter, receiving
+    /// actual HTTP requests
+    Envoy(Uri),
+}
+
+/// What to benchmark and for how long
+pub struct BenchPlan {
+    /// Scenario name, reported in the summary and tagged on live metrics
+    pub name: String,
+    pub data: Arc<DummyData>,
+    /// Picks the data of each transaction of each stream instead of `data`
+    pub scenario: Option<ScenarioFactory>,
+    /// Ignored when benchmarking through Envoy, which has its own configuration
+    pub stream_config: Config,
+    /// How many streams to handle concurrently
+    pub concurrency: usize,
+    /// Share this many streams between the `concurrency` benchers through a
+    /// [`ClientStreamPool`], instead of giving each its own, so transactions wait for a free
+    /// stream. Pooled streams are opened on first use and aren't reconnected, whatever
+    /// `prestart_streams` and `reconnect`. Ignored when benchmarking through Envoy
+    pub stream_pool_size: Option<usize>,
+    /// Metrics of the warmup are discarded
+    pub warmup: Duration,
+    pub duration: Duration,
+    /// Log errors from stream handlers
+    pub print_errors: bool,
+    /// Log immediate responses transactions were denied with
+    pub print_immediate_responses: bool,
+    /// Log throughput, latency and errors every `monitor_interval` during the run
+    pub print_progress: bool,
+    /// How often progress is logged and live metrics are pushed
+    pub monitor_interval: Duration,
+    /// Where to push live metrics during the run
+    pub sink: Option<UdpSink>,
+    /// Serves the metrics of the run to Prometheus scrapes
+    pub prometheus: Option<Arc<PrometheusExporter>>,
+    /// Receives every measurement of the run, to redirect the benchmark's telemetry elsewhere
+    pub metrics_sink: Option<Arc<dyn MetricsSink>>,
+    /// Run the metrics monitor on its own thread pinned to this core, away from the benchers
+    pub monitor_core: Option<usize>,
+    /// Abort transactions that take longer than this and recycle their stream, so a hung
+    /// server stream can't idle a bencher for the rest of the run
+    pub watchdog: Option<Duration>,
+    /// Cancel transactions that take longer than this and close their stream. Unlike the
+    /// watchdog's, these are counted in [`BenchReport::timeouts`] only, left out of the latency,
+    /// throughput and error metrics
+    pub timeout: Option<Duration>,
+    /// Open every stream, and with it its connection, before the warmup, waiting for all of them
+    /// to be ready, so measurements reflect steady state rather than setup. Ignored when
+    /// benchmarking through Envoy
+    pub prestart_streams: bool,
+    /// Re-establish the server connection with backoff when it is lost, instead of failing every
+    /// transaction until the run ends. Ignored when benchmarking through Envoy. Each of
+    /// [`Target::Processors`] is reconnected to its own endpoint, with this backoff
+    pub reconnect: Option<Reconnect>,
+    /// Retry transactions failing on a transient stream failure, on a new stream. Only the last
+    /// attempt's outcome is counted, its latency covering every attempt. Ignored when
+    /// benchmarking through Envoy
+    pub retry: Option<Retry>,
+    /// How many of the slowest transactions to report, with the timings of their messages
+    pub slowest_transactions: usize,
+    /// Start transactions at this rate, whether or not responses keep up (open loop), instead
+    /// of as fast as the streams can run them. `concurrency` is then the most transactions in
+    /// flight at once, those due while every stream is busy wait in a queue
+    pub target_rps: Option<f64>,
+    /// Record every transaction latency in a full resolution histogram, reported in
+    /// [`BenchReport::latencies`]
+    pub record_latencies: bool,
+    /// Correct the latencies of [`BenchReport::latencies`] for coordinated omission (see
+    /// [`omission`]), summarized in [`BenchReport::corrected_latency`]. Meant for closed loop
+    /// runs, open loop ones already measure latency from when transactions were due
+    pub correct_coordinated_omission: bool,
+    /// Stop once this many transactions succeeded after the warmup, rather than after
+    /// `duration`, which then only bounds the run. The reported duration is the measured one
+    pub transactions: Option<u64>,
+    /// Keep the monitor's samples, one per `monitor_interval`, in [`BenchReport::samples`]
+    pub record_samples: bool,
+}
+
+impl BenchPlan {
+    pub fn new(name: &str, data: Arc<DummyData>, stream_config: Config) -> BenchPlan {
+        BenchPlan {
+            name: name.to_string(),
+            data,
+            scenario: None,
+            stream_config,
+            concurrency: 100,
+            stream_pool_size: None,
+            warmup: Duration::from_secs(5),
+            duration: Duration::from_secs(30),
+            print_errors: false,
+            print_immediate_responses: false,
+            print_progress: true,
+            monitor_interval: Duration::from_secs(2),
+            sink: None,
+            prometheus: None,
+            metrics_sink: None,
+            monitor_core: None,
+            watchdog: None,
+            timeout: None,
+            prestart_streams: false,
+            reconnect: None,
+            retry: None,
+            slowest_transactions: 10,
+            target_rps: None,
+            record_latencies: false,
+            correct_coordinated_omission: false,
+            transactions: None,
+            record_samples: false,
+        }
+    }
+}
+
+/// Time the messages of a phase waited for their response, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaseLatency {
+    pub mean: f64,
+    pub p50: f64,
+    pub p99: f64,
+}
+
+/// Results of a benchmark run
+pub struct BenchReport {
+    pub summary: RunSummary,
+    /// Raw metrics, serializable to JSON
+    pub metrics: Arc<StreamMetrics>,
+    pub stream_stats: Arc<StreamStats>,
+    /// How many transactions each dynamic metadata key was emitted in
+    pub dynamic_metadata_keys: BTreeMap<String, u64>,
+    /// Errors by category, also counted as errors
+    pub error_categories: Arc<ErrorBreakdown>,
+    /// Transactions aborted by the watchdog, also counted as errors
+    pub watchdog_timeouts: u64,
+    /// Transactions cancelled by the plan's `timeout`, not counted in the metrics
+    pub timeouts: u64,
+    /// Transactions retried after a transient stream failure, counting every retry
+    pub retries: u64,
+    /// Times the server connection was re-established
+    pub reconnects: u64,
+    /// Time spent reconnecting, summed over reconnections
+    pub downtime: Duration,
+    /// The run's slowest transactions, slowest first
+    pub slowest_transactions: Vec<SlowTransaction>,
+    /// Queueing and latency measured from when transactions were due, when running open loop
+    pub schedule: Option<Arc<RateSchedule>>,
+    /// Every transaction latency of the run, in microseconds, if the plan asked to record or
+    /// correct them
+    pub latencies: Option<Histogram<u64>>,
+    /// Latency corrected for coordinated omission, if the plan asked for it
+    pub corrected_latency: Option<LatencySummary>,
+    /// Transactions and latency of each URL of [`Target::Processors`], empty for other targets
+    /// and when streams are pooled
+    pub targets: Vec<TargetSummary>,
+    /// Transactions and latency by scenario, for scenarios labeling their data (e.g.
+    /// [`scenario::WeightedMix`])
+    pub scenarios: Vec<ScenarioSummary>,
+    /// Throughput, latency and errors every monitor interval, warmup included, if the plan asked
+    /// to record them
+    pub samples: Vec<Sample>,
+}
+
+impl BenchReport {
+    /// Mean of the per-second throughput samples
+    pub fn mean_throughput(&self) -> f64 {
+        self.metrics.run_stream.throughput.histogram().mean()
+    }
+
+    /// Mean transaction latency split into the time the server reported processing it and the
+    /// rest (transport and queueing), in milliseconds. None unless the server reports its
+    /// processing time (see [`crate::dummy::PROCESSING_TIME_HEADER`])
+    pub fn latency_split(&self) -> Option<(f64, f64)> {
+        let server_times = self.stream_stats.server_times.0.histogram();
+        if server_times.len() == 0 {
+            return None;
+        }
+        let server = server_times.mean() / 1000.0;
+        Some((server, (self.summary.latency.mean - server).max(0.0)))
+    }
+
+    /// Mean, p50 and p99 of the time each phase's messages waited for their response, in
+    /// milliseconds, for the phases that had any
+    pub fn phase_latencies(&self) -> Vec<(Phase, PhaseLatency)> {
+        self.stream_stats
+            .phase_latencies
+            .iter()
+            .filter_map(|(phase, histogram)| {
+                let histogram = histogram.histogram();
+                (histogram.len() > 0).then(|| {
+                    let latency = PhaseLatency {
+                        mean: histogram.mean() / 1000.0,
+                        p50: histogram.quantile(0.5) as f64 / 1000.0,
+                        p99: histogram.quantile(0.99) as f64 / 1000.0,
+                    };
+                    (phase, latency)
+                })
+            })
+            .collect()
+    }
+
+    /// Everything the report holds, as a single JSON document
+    pub fn to_json(&self) -> serde_json::Value {
+        let summary = &self.summary;
+        let latency_split = self.latency_split().map(|(server, transport)| {
+            serde_json::json!({
+                "server": server,
+                "transport": transport,
+            })
+        });
+        serde_json::json!({
+            "name": summary.name,
+            "started_at": humantime::format_rfc3339_seconds(summary.started_at).to_string(),
+            "duration_secs": summary.duration.as_secs_f64(),
+            "concurrency": summary.concurrency,
+            "count": summary.count,
+            "errors": summary.errors,
+            "rate": summary.rate(),
+            "mean_throughput": self.mean_throughput(),
+            "latency_ms": latency_json(&summary.latency),
+            "latency_split_ms": latency_split,
+            "phase_latencies_ms": self
+                .phase_latencies()
+                .into_iter()
+                .map(|(phase, latency)| serde_json::json!({
+                    "phase": phase,
+                    "mean": latency.mean,
+                    "p50": latency.p50,
+                    "p99": latency.p99,
+                }))
+                .collect::<Vec<_>>(),
+            "metrics": self.metrics.as_ref(),
+            "stream_stats": self.stream_stats.as_ref(),
+            "dynamic_metadata_keys": self.dynamic_metadata_keys,
+            "error_categories": self.error_categories.as_ref(),
+            "timeouts": self.timeouts,
+            "watchdog_timeouts": self.watchdog_timeouts,
+            "retries": self.retries,
+            "reconnects": self.reconnects,
+            "downtime_secs": self.downtime.as_secs_f64(),
+            "slowest_transactions": self.slowest_transactions,
+            "corrected_latency_ms": self.corrected_latency.as_ref().map(latency_json),
+            "open_loop": self.schedule.as_deref(),
+            "targets": self.targets.iter().map(target_json).collect::<Vec<_>>(),
+            "scenarios": self.scenarios.iter().map(scenario_json).collect::<Vec<_>>(),
+            "samples": self.samples.iter().map(sample_json).collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn target_json(target: &TargetSummary) -> serde_json::Value {
+    serde_json::json!({
+        "url": target.url,
+        "weight": target.weight,
+        "count": target.count,
+        "errors": target.errors,
+        "latency_ms": latency_json(&target.latency),
+    })
+}
+
+fn scenario_json(scenario: &ScenarioSummary) -> serde_json::Value {
+    serde_json::json!({
+        "name": scenario.name,
+        "count": scenario.count,
+        "errors": scenario.errors,
+        "latency_ms": latency_json(&scenario.latency),
+    })
+}
+
+fn sample_json(sample: &Sample) -> serde_json::Value {
+    serde_json::json!({
+        "timestamp": humantime::format_rfc3339_millis(sample.timestamp).to_string(),
+        "rate": sample.rate,
+        "mean_latency_ms": sample.mean_latency,
+        "errors": sample.errors,
+    })
+}
+
+fn latency_json(latency: &LatencySummary) -> serde_json::Value {
+    serde_json::json!({
+        "mean": latency.mean,
+        "min": latency.min,
+        "max": latency.max,
+        "p50": latency.p50,
+        "p90": latency.p90,
+        "p95": latency.p95,
+        "p99": latency.p99,
+        "p999": latency.p999,
+    })
+}
+
+/// Runs the plan's streams for its warmup and duration and reports the metrics gathered after
+/// the warmup
+pub async fn run_benchmark(target: Target, plan: BenchPlan) -> BenchReport {
+    let metrics = Arc::new(StreamMetrics::default());
+    let metadata = Arc::new(MetadataSummary::default());
+    let stream_stats = Arc::new(StreamStats::default());
+    let ready_barrier = Arc::new(tokio::sync::Barrier::new(plan.concurrency));
+    let warmup_barrier = Arc::new(tokio::sync::Barrier::new(plan.concurrency));
+    let after_warmup = Arc::new(tokio::sync::OnceCell::new());
+    let budget = plan
+        .transactions
+        .map(|total| Arc::new(TransactionBudget::new(total)));
+    let watchdog_timeouts = Arc::new(AtomicU64::new(0));
+    let timeouts = Arc::new(AtomicU64::new(0));
+    let retries = Arc::new(AtomicU64::new(0));
+    let error_categories = Arc::new(ErrorBreakdown::default());
+    let slowest = Arc::new(SlowestTransactions::new(plan.slowest_transactions));
+    let scenarios = Arc::new(ScenarioBreakdown::default());
+    let schedule = plan.target_rps.map(|rps| Arc::new(RateSchedule::new(rps)));
+    let record_latencies = plan.record_latencies || plan.correct_coordinated_omission;
+    let latencies = record_latencies.then(|| {
+        let histogram = Histogram::new_with_max(MAX_LATENCY_MICROS, 3).unwrap();
+        Arc::new(Mutex::new(histogram))
+    });
+    let connect = |client: &ExternalProcessorClient<Channel>, reconnect: Option<Reconnect>| {
+        let connection = reconnect
+            .map(|reconnect| Arc::new(SharedConnection::new(reconnect, client.clone())));
+        (client.clone(), connection)
+    };
+    let (processors, weights): (Vec<_>, Vec<_>) = match target {
+        Target::Processor(ref client) => vec![(connect(client, plan.reconnect.clone()), 1)],
+        Target::Processors(ref targets) => targets
+            .iter()
+            .map(|target| {
+                let reconnect = plan.reconnect.as_ref().map(|reconnect| Reconnect {
+                    endpoint: target.endpoint.clone(),
+                    ..reconnect.clone()
+                });
+                (connect(&target.client, reconnect), target.weight)
+            })
+            .collect(),
+        Target::Envoy(_) => vec![],
+    }
+    .into_iter()
+    .unzip();
+    let slots = weighted_slots(weights);
+    assert!(
+        matches!(target, Target::Envoy(_)) || !slots.is_empty(),
+        "no processor target with a positive weight"
+    );
+    let pool = plan
+        .stream_pool_size
+        .filter(|_| !matches!(target, Target::Envoy(_)))
+        .map(|size| {
+            let transports = (0..size)
+                .map(|index| processors[slots[index % slots.len()]].0.clone())
+                .collect();
+            let config = plan.stream_config.clone();
+            let pool = ClientStreamPool::new(transports, plan.data.clone(), config);
+            Arc::new(pool.with_stats(stream_stats.clone()))
+        });
+    let breakdown = match target {
+        Target::Processors(ref targets) if pool.is_none() => {
+            Some(Arc::new(TargetBreakdown::new(targets)))
+        }
+        _ => None,
+    };
+
+    if let Some(ref exporter) = plan.prometheus {
+        exporter.set_run(&plan.name, metrics.clone());
+    }
+    let (stop_metrics, stop_receiver) = oneshot::channel();
+    let samples = plan.record_samples.then(Arc::<Mutex<Vec<Sample>>>::default);
+    if plan.warmup.saturating_add(plan.duration) > plan.monitor_interval
+        && (plan.print_progress
+            || plan.sink.is_some()
+            || plan.metrics_sink.is_some()
+            || samples.is_some())
+    {
+        let monitor = monitor_metrics(
+            metrics.clone(),
+            plan.monitor_interval,
+            stop_receiver,
+            plan.print_progress,
+            plan.sink,
+            plan.metrics_sink.clone(),
+            samples.clone(),
+        );
+        match plan.monitor_core {
+            Some(core) => spawn_pinned(core, monitor),
+            None => {
+                tokio::spawn(monitor);
+            }
+        }
+    }
+
+    let started_at = SystemTime::now() + plan.warmup;
+    let http_client = hyper::Client::new();
+    let mut join_set = tokio::task::JoinSet::new();
+    for index in 0..plan.concurrency {
+        let (target, connection) = match target {
+            Target::Envoy(ref url) => (
+                BencherTarget::Http(HttpTarget::new(
+                    http_client.clone(),
+                    url.clone(),
+                    plan.data.clone(),
+                )),
+                None,
+            ),
+            Target::Processor(_) | Target::Processors(_) if pool.is_some() => (
+                BencherTarget::Pool {
+                    pool: pool.clone().unwrap(),
+                    data: plan.data.clone(),
+                    transaction: Default::default(),
+                },
+                None,
+            ),
+            Target::Processor(_) | Target::Processors(_) => {
+                let (client, connection) = &processors[slots[index % slots.len()]];
+                (
+                    BencherTarget::Processor {
+                        client: client.clone(),
+                        generation: 0,
+                        stream: ClientStream::new(plan.data.clone(), plan.stream_config.clone())
+                            .with_stats(stream_stats.clone()),
+                    },
+                    connection.clone(),
+                )
+            }
+        };
+        let bencher = StreamBencher {
+            metrics: metrics.clone(),
+            metadata: metadata.clone(),
+            index,
+            target,
+            scenario: plan.scenario.as_ref().map(|factory| factory(index)),
+            scenarios: scenarios.clone(),
+            schedule: schedule.clone(),
+            transactions: 0,
+            last_succeeded: false,
+            watchdog: plan.watchdog,
+            watchdog_timeouts: watchdog_timeouts.clone(),
+            timeout: plan.timeout,
+            timeouts: timeouts.clone(),
+            retry: plan.retry.clone(),
+            retries: retries.clone(),
+            error_categories: error_categories.clone(),
+            slowest: slowest.clone(),
+            latencies: latencies.clone(),
+            expected_interval: plan
+                .correct_coordinated_omission
+                .then(ExpectedInterval::default),
+            connection,
+            breakdown: breakdown
+                .clone()
+                .map(|breakdown| (breakdown, slots[index % slots.len()])),
+            metrics_sink: plan.metrics_sink.clone(),
+            print_errors: plan.print_errors,
+            print_immediate_responses: plan.print_immediate_responses,
+            prestart_stream: plan.prestart_streams,
+            ready_barrier: ready_barrier.clone(),
+            warmup_barrier: warmup_barrier.clone(),
+            after_warmup: after_warmup.clone(),
+            budget: budget.clone(),
+        };
+        join_set.spawn(bencher.bench_with_warmup(plan.warmup, plan.duration));
+    }
+    while join_set.join_next().await.is_some() {}
+    _ = stop_metrics.send(());
+    info!("Benchmark finished.");
+    let duration = match (plan.transactions, after_warmup.get()) {
+        (Some(_), Some(warmup_ended)) => warmup_ended.elapsed(),
+        _ => plan.duration,
+    };
+
+    BenchReport {
+        summary: run_summary(plan.name, &metrics, started_at, duration, plan.concurrency),
+        metrics,
+        stream_stats,
+        dynamic_metadata_keys: metadata.key_counts(),
+        error_categories,
+        watchdog_timeouts: watchdog_timeouts.load(Ordering::Relaxed),
+        timeouts: timeouts.load(Ordering::Relaxed),
+        retries: retries.load(Ordering::Relaxed),
+        reconnects: connections(&processors).map(|c| c.reconnects()).sum(),
+        downtime: connections(&processors).map(|c| c.downtime()).sum(),
+        slowest_transactions: slowest.sorted(),
+        schedule,
+        corrected_latency: latencies
+            .as_ref()
+            .filter(|_| plan.correct_coordinated_omission)
+            .map(|latencies| omission::latency_summary(&latencies.lock().unwrap())),
+        latencies: latencies.map(|latencies| latencies.lock().unwrap().clone()),
+        scenarios: scenarios.summaries(),
+        targets: breakdown
+            .map(|breakdown| breakdown.summaries())
+            .unwrap_or_default(),
+        samples: samples
+            .map(|samples| std::mem::take(&mut *samples.lock().unwrap()))
+            .unwrap_or_default(),
+    }
+}
+
+fn connections(
+    processors: &[(ExternalProcessorClient<Channel>, Option<Arc<SharedConnection>>)],
+) -> impl Iterator<Item = &Arc<SharedConnection>> {
+    processors.iter().filter_map(|(_, connection)| connection.as_ref())
+}
+
+fn run_summary(
+    name: String,
+    metrics: &StreamMetrics,
+    started_at: SystemTime,
+    duration: Duration,
+    concurrency: usize,
+) -> RunSummary {
+    let response_time = metrics.run_stream.response_time.histogram();
+    RunSummary {
+        name,
+        started_at,
+        duration,
+        concurrency,
+        count: response_time.len(),
+        errors: metrics.run_stream.error_count.get(),
+        latency: LatencySummary {
+            mean: response_time.mean(),
+            min: response_time.min(),
+            max: response_time.max(),
+            p50: response_time.quantile(0.5),
+            p90: response_time.quantile(0.9),
+            p95: response_time.quantile(0.95),
+            p99: response_time.quantile(0.99),
+            p999: response_time.quantile(0.999),
+        },
+    }
+}
+
+/// Runs `future` on a new thread pinned to `core`, with its own single threaded runtime
+fn spawn_pinned<F: std::future::Future<Output = ()> + Send + 'static>(core: usize, future: F) {
+    std::thread::spawn(move || {
+        if !pin_current_thread(core) {
+            warn!("Could not pin monitor thread to core {}", core);
+        }
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(future);
+    });
+}
+
+async fn monitor_metrics(
+    metrics: Arc<StreamMetrics>,
+    interval: Duration,
+    mut stop: oneshot::Receiver<()>,
+    print_progress: bool,
+    sink: Option<UdpSink>,
+    metrics_sink: Option<Arc<dyn MetricsSink>>,
+    samples: Option<Arc<Mutex<Vec<Sample>>>>,
+) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        interval.tick().await;
+        match stop.try_recv() {
+            Ok(_) | Err(TryRecvError::Closed) => break,
+            Err(TryRecvError::Empty) => {}
+        }
+        if print_progress {
+            print_metrics(metrics.as_ref());
+        }
+        let sample = sample_metrics(metrics.as_ref());
+        if let Some(ref sink) = sink {
+            if let Err(e) = sink.send(&sample) {
+                error!("Could not send metrics sample: {}", e);
+            }
+        }
+        if let Some(ref metrics_sink) = metrics_sink {
+            metrics_sink.gauge(metrics::THROUGHPUT, sample.rate);
+            metrics_sink.gauge(metrics::MEAN_LATENCY_MS, sample.mean_latency);
+        }
+        if let Some(ref samples) = samples {
+            samples.lock().unwrap().push(sample);
+        }
+    }
+}
+
+fn sample_metrics(metrics: &StreamMetrics) -> Sample {
+    Sample {
+        timestamp: SystemTime::now(),
+        rate: metrics.run_stream.throughput.histogram().mean(),
+        mean_latency: metrics.run_stream.response_time.histogram().mean(),
+        errors: metrics.run_stream.error_count.get(),
+    }
+}
+
+fn print_metrics(metrics: &StreamMetrics) {
+    let err_count = metrics.run_stream.error_count.get();
+    let throughput = metrics.run_stream.throughput.histogram();
+    let response_time = metrics.run_stream.response_time.histogram();
+    info!(
+        "{:.2} req/s, {:.2}ms avg latency (p50 {}ms, p90 {}ms, p99 {}ms, p999 {}ms, max {}ms), \
+         {} errors",
+        throughput.mean(),
+        response_time.mean(),
+        response_time.quantile(0.5),
+        response_time.quantile(0.9),
+        response_time.quantile(0.99),
+        response_time.quantile(0.999),
+        response_time.max(),
+        err_count
+    );
+}
@@ -0,0 +1,89 @@
+//! Coordinated omission correction, à la wrk2: a stream stalled on a slow transaction doesn't
+//! start the transactions it would have in the meantime, so their latency goes unmeasured and the
+//! stall weighs as a single sample. Each bencher expects its transactions to start an interval
+//! apart, and a transaction slower than that is recorded along with those intended to start
+//! during it, each with the latency it would have seen from its intended start
+
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+
+use crate::report::LatencySummary;
+
+/// Expected time between the starts of a bencher's transactions: the mean latency of those run
+/// during the warmup, or of those run so far when there was none
+#[derive(Debug, Default)]
+pub struct ExpectedInterval {
+    total: Duration,
+    count: u32,
+    frozen: bool,
+}
+
+impl ExpectedInterval {
+    pub fn observe(&mut self, latency: Duration) {
+        if !self.frozen {
+            self.total += latency;
+            self.count += 1;
+        }
+    }
+
+    /// Stops learning the interval, once the warmup is over
+    pub fn freeze(&mut self) {
+        self.frozen = self.count > 0;
+    }
+
+    pub fn get(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.total / self.count)
+    }
+}
+
+/// Records `latency` in `histogram`, in microseconds, along with the latencies of the transactions
+/// intended to start every `interval` during it
+pub fn record_corrected(histogram: &mut Histogram<u64>, latency: Duration, interval: Duration) {
+    let latency_us = latency.as_micros() as u64;
+    let interval_us = interval.as_micros() as u64;
+    if histogram.record_correct(latency_us, interval_us).is_err() {
+        histogram.saturating_record(latency_us);
+    }
+}
+
+/// Summary of a histogram of microsecond latencies, in milliseconds
+pub fn latency_summary(histogram: &Histogram<u64>) -> LatencySummary {
+    let millis = |micros: u64| micros / 1000;
+    LatencySummary {
+        mean: histogram.mean() / 1000.0,
+        min: millis(histogram.min()),
+        max: millis(histogram.max()),
+        p50: millis(histogram.value_at_quantile(0.5)),
+        p90: millis(histogram.value_at_quantile(0.9)),
+        p95: millis(histogram.value_at_quantile(0.95)),
+        p99: millis(histogram.value_at_quantile(0.99)),
+        p999: millis(histogram.value_at_quantile(0.999)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalls_account_for_the_transactions_they_delayed() {
+        let mut interval = ExpectedInterval::default();
+        assert_eq!(interval.get(), None);
+        for latency in [8, 12] {
+            interval.observe(Duration::from_millis(latency));
+        }
+        interval.freeze();
+        interval.observe(Duration::from_secs(1));
+        let interval = interval.get().unwrap();
+        assert_eq!(interval, Duration::from_millis(10));
+
+        let mut histogram = Histogram::<u64>::new_with_max(3_600_000_000, 3).unwrap();
+        record_corrected(&mut histogram, Duration::from_millis(9), interval);
+        assert_eq!(histogram.len(), 1);
+        record_corrected(&mut histogram, Duration::from_millis(50), interval);
+        // The stall itself, then transactions intended to start 10, 20, 30 and 40ms into it
+        assert_eq!(histogram.len(), 6);
+        assert_eq!(latency_summary(&histogram).max, 50);
+    }
+}
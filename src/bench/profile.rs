@@ -0,0 +1,215 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::BenchReport;
+
+use error::ProfileError;
+
+pub mod error {
+    use quick_error::quick_error;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum ProfileError {
+            OpenError(err: std::io::Error) {
+                display("Could not open benchmark file: {}", err)
+                from()
+            }
+            ParseError(err: serde_json::Error) {
+                display("Could not parse benchmark file: {}", err)
+                from()
+            }
+            UnknownProfile(name: String, available: Vec<String>) {
+                display("No profile named '{}', available profiles: {}", name, available.join(", "))
+            }
+        }
+    );
+}
+
+/// A canonical benchmark definition file, holding named profiles (e.g. smoke, capacity, soak):
+/// ```json
+/// {
+///   "profiles": {
+///     "smoke": { "data_config": "bench/fixtures/simple.json", "duration": "10s" },
+///     "soak": {
+///       "data_config": "bench/fixtures/simple.json",
+///       "stream_concurrency": 50,
+///       "duration": "1h",
+///       "assertions": { "max_error_rate": 0.001, "max_p99_latency_ms": 20 }
+///     }
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct BenchmarkFile {
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl BenchmarkFile {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<BenchmarkFile, ProfileError> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile, ProfileError> {
+        self.profiles.get(name).ok_or_else(|| {
+            ProfileError::UnknownProfile(name.to_string(), self.profiles.keys().cloned().collect())
+        })
+    }
+}
+
+/// Settings of a benchmark. Unset settings keep bench_client's command line values, and command
+/// line options override the profile's settings
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct Profile {
+    /// Path to the data config (see [`crate::dummy::DummyDataConfig`])
+    #[serde(default)]
+    pub data_config: Option<PathBuf>,
+    /// URL to External Processor gRPC Service
+    #[serde(default)]
+    pub server_url: Option<String>,
+    /// How many streams to handle concurrently
+    #[serde(default)]
+    pub stream_concurrency: Option<usize>,
+    /// Warmup duration, e.g. "30s"
+    #[serde(default, with = "humantime_option")]
+    #[schemars(with = "Option<String>")]
+    pub warmup: Option<Duration>,
+    /// Benchmark duration, e.g. "5m"
+    #[serde(default, with = "humantime_option")]
+    #[schemars(with = "Option<String>")]
+    pub duration: Option<Duration>,
+    /// Reuse streams for more than one transaction
+    #[serde(default)]
+    pub reuse_streams: Option<bool>,
+    /// How many transactions should a stream handle before closing
+    #[serde(default)]
+    pub stream_max_handle: Option<usize>,
+    /// Results every run of the profile must meet
+    #[serde(default)]
+    pub assertions: Assertions,
+}
+
+/// Thresholds on the results of a run
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct Assertions {
+    /// Largest share of failed transactions, between 0 and 1
+    #[serde(default)]
+    pub max_error_rate: Option<f64>,
+    /// Smallest mean throughput, in transactions per second
+    #[serde(default)]
+    pub min_throughput: Option<f64>,
+    /// Largest mean transaction latency, in milliseconds
+    #[serde(default)]
+    pub max_mean_latency_ms: Option<f64>,
+    /// Largest p99 transaction latency, in milliseconds
+    #[serde(default)]
+    pub max_p99_latency_ms: Option<u64>,
+}
+
+impl Assertions {
+    /// Descriptions of the thresholds the report doesn't meet
+    pub fn check(&self, report: &BenchReport) -> Vec<String> {
+        let summary = &report.summary;
+        let mut failures = Vec::new();
+        if let Some(max) = self.max_error_rate {
+            let rate = if summary.count > 0 {
+                summary.errors as f64 / summary.count as f64
+            } else {
+                0.0
+            };
+            if rate > max {
+                failures.push(format!("error rate {:.4} is above {}", rate, max));
+            }
+        }
+        if let Some(min) = self.min_throughput {
+            let throughput = report.mean_throughput();
+            if throughput < min {
+                failures.push(format!("throughput {:.2} req/s is below {}", throughput, min));
+            }
+        }
+        if let Some(max) = self.max_mean_latency_ms {
+            if summary.latency.mean > max {
+                failures.push(format!(
+                    "mean latency {:.2}ms is above {}ms",
+                    summary.latency.mean, max
+                ));
+            }
+        }
+        if let Some(max) = self.max_p99_latency_ms {
+            if summary.latency.p99 > max {
+                failures.push(format!(
+                    "p99 latency {}ms is above {}ms",
+                    summary.latency.p99, max
+                ));
+            }
+        }
+        failures
+    }
+}
+
+/// Optional durations as humantime strings, e.g. "1h30m"
+mod humantime_option {
+    use std::time::Duration;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => {
+                serializer.serialize_str(&humantime::format_duration(*duration).to_string())
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(value) => humantime::parse_duration(&value)
+                .map(Some)
+                .map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_profiles_by_name() {
+        let file: BenchmarkFile = serde_json::from_str(
+            r#"{
+                "profiles": {
+                    "smoke": { "duration": "10s" },
+                    "soak": {
+                        "stream_concurrency": 50,
+                        "duration": "1h30m",
+                        "assertions": { "max_p99_latency_ms": 20 }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let soak = file.profile("soak").unwrap();
+        assert_eq!(soak.stream_concurrency, Some(50));
+        assert_eq!(soak.duration, Some(Duration::from_secs(5400)));
+        assert_eq!(soak.warmup, None);
+        assert_eq!(soak.assertions.max_p99_latency_ms, Some(20));
+        assert!(matches!(
+            file.profile("capacity"),
+            Err(ProfileError::UnknownProfile(_, available)) if available == ["smoke", "soak"]
+        ));
+    }
+}
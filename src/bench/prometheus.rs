@@ -0,0 +1,92 @@
+//! Prometheus scrape endpoint for the metrics of the run in progress, so soak tests can be
+//! graphed live (e.g. in Grafana) rather than by parsing progress lines. The metrics restart
+//! with each run and after its warmup, which Prometheus sees as counter resets
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use super::StreamMetrics;
+use crate::report::prometheus::{self, escape_label, TextWriter};
+
+const PREFIX: &str = "ext_proc_bench";
+const LATENCY_QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+/// Serves the [`StreamMetrics`] of the current run in the Prometheus text format
+#[derive(Default)]
+pub struct PrometheusExporter {
+    /// Scenario name and metrics of the current run
+    current: Mutex<Option<(String, Arc<StreamMetrics>)>>,
+}
+
+impl PrometheusExporter {
+    /// Serves the metrics of this run from now on
+    pub fn set_run(&self, name: &str, metrics: Arc<StreamMetrics>) {
+        *self.current.lock().unwrap() = Some((name.to_string(), metrics));
+    }
+
+    /// The metrics of the current run, empty before the first one
+    pub fn render(&self) -> String {
+        let current = self.current.lock().unwrap();
+        let Some((ref name, ref metrics)) = *current else {
+            return String::new();
+        };
+        let labels = format!("scenario=\"{}\"", escape_label(name));
+        let run = &metrics.run_stream;
+        let response_time = run.response_time.histogram();
+        let mut out = TextWriter::new(PREFIX);
+        out.header(
+            "transactions_total",
+            "counter",
+            "Finished transactions, including failed ones",
+        );
+        out.sample("transactions_total", &labels, response_time.len() as f64);
+        out.header("transaction_errors_total", "counter", "Failed transactions");
+        out.sample("transaction_errors_total", &labels, run.error_count.get() as f64);
+        out.header(
+            "throughput",
+            "gauge",
+            "Mean transactions per second since the run started",
+        );
+        out.sample("throughput", &labels, run.throughput.histogram().mean());
+        let latency = "transaction_latency_ms";
+        out.header(latency, "summary", "Transaction latency in milliseconds");
+        for quantile in LATENCY_QUANTILES {
+            let quantile_labels = format!("{},quantile=\"{}\"", labels, quantile);
+            out.sample(latency, &quantile_labels, response_time.quantile(quantile) as f64);
+        }
+        let count = response_time.len() as f64;
+        out.sample(&format!("{}_sum", latency), &labels, response_time.mean() * count);
+        out.sample(&format!("{}_count", latency), &labels, count);
+        out.finish()
+    }
+
+    /// Binds the endpoint, serving the metrics at `/metrics`. Must be called within a Tokio
+    /// runtime, which the returned server is then spawned on
+    pub fn serve(
+        self: Arc<Self>,
+        address: SocketAddr,
+    ) -> Result<impl Future<Output = ()>, hyper::Error> {
+        prometheus::serve(address, move || self.render())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_run_is_rendered() {
+        let exporter = PrometheusExporter::default();
+        assert_eq!(exporter.render(), "");
+        exporter.set_run("No Reuse", Arc::new(StreamMetrics::default()));
+        let text = exporter.render();
+        assert!(text.contains("# TYPE ext_proc_bench_transactions_total counter\n"));
+        assert!(text.contains("ext_proc_bench_transactions_total{scenario=\"No Reuse\"} 0\n"));
+        assert!(text.contains(
+            "ext_proc_bench_transaction_latency_ms{scenario=\"No Reuse\",quantile=\"0.99\"} 0\n"
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use tonic::transport::{Channel, Endpoint};
+
+use crate::{
+    proto::envoy::service::ext_proc::v3::external_processor_client::ExternalProcessorClient,
+    uds,
+};
+
+/// Exponential backoff with full jitter
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before reconnection or retry attempt number `attempt`, counted from 0
+    pub(super) fn delay(&self, attempt: u32) -> Duration {
+        Duration::from_secs_f64(self.ceiling(attempt).as_secs_f64() * rand::random::<f64>())
+    }
+
+    /// The largest delay before attempt number `attempt`
+    fn ceiling(&self, attempt: u32) -> Duration {
+        let ceiling = self.initial.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        Duration::from_secs_f64(ceiling.min(self.max.as_secs_f64()))
+    }
+}
+
+/// How to retry transactions that failed on a transient stream failure: the stream closing under
+/// them or a retryable gRPC status (see [`super::error::BenchError::is_retryable`])
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// Retries of a transaction before its failure is reported
+    pub max_retries: u32,
+    pub backoff: Backoff,
+}
+
+/// How to reconnect to the server when its connection is lost
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    pub endpoint: Endpoint,
+    /// Unix domain socket to connect to instead of the endpoint's address
+    pub uds: Option<PathBuf>,
+    pub backoff: Backoff,
+}
+
+/// The client shared by the benchers of a run. When the connection is lost, the first bencher to
+/// notice reconnects while the others wait for it
+pub(super) struct SharedConnection {
+    reconnect: Reconnect,
+    /// The current client and how many times it was replaced
+    current: tokio::sync::Mutex<(u64, ExternalProcessorClient<Channel>)>,
+    reconnects: AtomicU64,
+    downtime_micros: AtomicU64,
+}
+
+impl SharedConnection {
+    pub(super) fn new(
+        reconnect: Reconnect,
+        client: ExternalProcessorClient<Channel>,
+    ) -> SharedConnection {
+        SharedConnection {
+            reconnect,
+            current: tokio::sync::Mutex::new((0, client)),
+            reconnects: AtomicU64::new(0),
+            downtime_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Replaces `client`, whose connection was lost, with a connected one. `generation` is the
+    /// number of replacements `client` is from, so a connection is only re-established once.
+    pub(super) async fn reconnect(
+        &self,
+        generation: &mut u64,
+        client: &mut ExternalProcessorClient<Channel>,
+    ) {
+        let mut current = self.current.lock().await;
+        if current.0 == *generation {
+            let down_since = Instant::now();
+            let mut attempt = 0;
+            let channel = loop {
+                tokio::time::sleep(self.reconnect.backoff.delay(attempt)).await;
+                match uds::connect(&self.reconnect.endpoint, self.reconnect.uds.as_deref()).await {
+                    Ok(channel) => break channel,
+                    Err(e) => debug!("Reconnection attempt {} failed: {}", attempt + 1, e),
+                }
+                attempt = attempt.saturating_add(1);
+            };
+            *current = (current.0 + 1, ExternalProcessorClient::new(channel));
+            let downtime = down_since.elapsed();
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+            self.downtime_micros
+                .fetch_add(downtime.as_micros() as u64, Ordering::Relaxed);
+            info!("Reconnected after {:.2}s", downtime.as_secs_f64());
+        }
+        *generation = current.0;
+        *client = current.1.clone();
+    }
+
+    pub(super) fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn downtime(&self) -> Duration {
+        Duration::from_micros(self.downtime_micros.load(Ordering::Relaxed))
+    }
+
+    pub(super) fn clear(&self) {
+        self.reconnects.store(0, Ordering::Relaxed);
+        self.downtime_micros.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_to_max_with_jitter() {
+        let backoff = Backoff::default();
+        assert_eq!(backoff.ceiling(0), Duration::from_millis(100));
+        assert_eq!(backoff.ceiling(3), Duration::from_millis(800));
+        assert_eq!(backoff.ceiling(20), Duration::from_secs(10));
+        for attempt in 0..10 {
+            assert!(backoff.delay(attempt) <= backoff.ceiling(attempt));
+        }
+    }
+}
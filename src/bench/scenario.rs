@@ -0,0 +1,233 @@
+//! Programmatic scenarios, choosing the data of each transaction of a stream, for stateful flows
+//! config files can't express (login-then-request sequences, per-stream sessions, ...)
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hdrhistogram::Histogram;
+use rand::{
+    distributions::{Distribution, WeightedError, WeightedIndex},
+    rngs::StdRng,
+    SeedableRng,
+};
+
+use super::{omission::latency_summary, MAX_LATENCY_MICROS};
+use crate::{
+    dummy::{client::transaction::Transaction, DummyData, MixShape},
+    report::LatencySummary,
+};
+
+/// What a scenario knows of its stream when picking the next transaction
+pub struct ScenarioContext<'a> {
+    /// Index of the stream's bencher, from 0 to the plan's concurrency
+    pub stream: usize,
+    /// Transactions the stream ran so far, including the warmup's and failed ones
+    pub transactions: u64,
+    /// The stream's last transaction, after the server's mutations. None before the first one,
+    /// after a failure and when benchmarking through Envoy
+    pub last: Option<&'a Transaction>,
+}
+
+/// Picks the data of each transaction of a stream
+#[tonic::async_trait]
+pub trait Scenario: Send {
+    /// The data of the stream's next transaction, or None to end the stream's run early
+    async fn next_transaction(&mut self, context: &ScenarioContext<'_>) -> Option<Arc<DummyData>>;
+
+    /// Name of the data last returned, for the report to break transactions down by scenario
+    fn label(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Creates the scenario of each stream, given the stream's index
+pub type ScenarioFactory = Arc<dyn Fn(usize) -> Box<dyn Scenario> + Send + Sync>;
+
+/// Runs its steps in order, over and over
+pub struct Sequence {
+    steps: Vec<Arc<DummyData>>,
+    next: usize,
+}
+
+impl Sequence {
+    pub fn new(steps: Vec<Arc<DummyData>>) -> Sequence {
+        Sequence { steps, next: 0 }
+    }
+
+    /// Runs the same sequence on every stream
+    pub fn factory(steps: Vec<Arc<DummyData>>) -> ScenarioFactory {
+        Arc::new(move |_| Box::new(Sequence::new(steps.clone())) as Box<dyn Scenario>)
+    }
+}
+
+#[tonic::async_trait]
+impl Scenario for Sequence {
+    async fn next_transaction(
+        &mut self,
+        _context: &ScenarioContext<'_>,
+    ) -> Option<Arc<DummyData>> {
+        let step = self.steps.get(self.next)?.clone();
+        self.next = (self.next + 1) % self.steps.len();
+        Some(step)
+    }
+}
+
+/// Draws the data of each transaction from weighted shapes, like a mix of real traffic
+pub struct WeightedMix {
+    shapes: Arc<[MixShape]>,
+    weights: WeightedIndex<u32>,
+    rng: StdRng,
+    last: Option<usize>,
+}
+
+impl WeightedMix {
+    /// Creates the mix of each stream, failing if no shape has a positive weight
+    pub fn factory(shapes: Vec<MixShape>) -> Result<ScenarioFactory, WeightedError> {
+        let weights = WeightedIndex::new(shapes.iter().map(|shape| shape.weight))?;
+        let shapes: Arc<[MixShape]> = shapes.into();
+        Ok(Arc::new(move |_| {
+            Box::new(WeightedMix {
+                shapes: shapes.clone(),
+                weights: weights.clone(),
+                rng: StdRng::from_entropy(),
+                last: None,
+            }) as Box<dyn Scenario>
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Scenario for WeightedMix {
+    async fn next_transaction(
+        &mut self,
+        _context: &ScenarioContext<'_>,
+    ) -> Option<Arc<DummyData>> {
+        let index = self.weights.sample(&mut self.rng);
+        self.last = Some(index);
+        Some(self.shapes[index].data.clone())
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.last.map(|index| self.shapes[index].name.as_str())
+    }
+}
+
+/// Transactions of a scenario, after the warmup
+struct ScenarioMetrics {
+    errors: u64,
+    /// Latencies in microseconds
+    latencies: Histogram<u64>,
+}
+
+/// Transactions and latency by the [`Scenario::label`] of their data
+#[derive(Default)]
+pub(super) struct ScenarioBreakdown {
+    scenarios: Mutex<BTreeMap<String, ScenarioMetrics>>,
+}
+
+impl ScenarioBreakdown {
+    pub(super) fn record(&self, label: &str, latency: Duration, failed: bool) {
+        let mut scenarios = self.scenarios.lock().unwrap();
+        if !scenarios.contains_key(label) {
+            let metrics = ScenarioMetrics {
+                errors: 0,
+                latencies: Histogram::new_with_max(MAX_LATENCY_MICROS, 3).unwrap(),
+            };
+            scenarios.insert(label.to_string(), metrics);
+        }
+        let metrics = scenarios.get_mut(label).unwrap();
+        metrics.errors += failed as u64;
+        metrics
+            .latencies
+            .saturating_record(latency.as_micros() as u64);
+    }
+
+    pub(super) fn clear(&self) {
+        self.scenarios.lock().unwrap().clear();
+    }
+
+    /// Summaries ordered by name
+    pub(super) fn summaries(&self) -> Vec<ScenarioSummary> {
+        self.scenarios
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, metrics)| ScenarioSummary {
+                name: name.clone(),
+                count: metrics.latencies.len(),
+                errors: metrics.errors,
+                latency: latency_summary(&metrics.latencies),
+            })
+            .collect()
+    }
+}
+
+/// Results of the transactions of a scenario
+#[derive(Debug, Clone)]
+pub struct ScenarioSummary {
+    pub name: String,
+    /// Transactions, including failed ones
+    pub count: u64,
+    pub errors: u64,
+    pub latency: LatencySummary,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences_cycle_through_their_steps() {
+        let step = |path: &str| {
+            Arc::new(DummyData {
+                req_headers: vec![(":path".to_string(), path.to_string())],
+                ..Default::default()
+            })
+        };
+        let mut sequence = Sequence::new(vec![step("/login"), step("/orders")]);
+        let context = ScenarioContext {
+            stream: 0,
+            transactions: 0,
+            last: None,
+        };
+        let paths: Vec<String> = (0..3)
+            .map(|_| {
+                let data = futures::executor::block_on(sequence.next_transaction(&context));
+                data.unwrap().req_headers[0].1.clone()
+            })
+            .collect();
+        assert_eq!(paths, ["/login", "/orders", "/login"]);
+        let mut empty = Sequence::new(Vec::new());
+        assert!(futures::executor::block_on(empty.next_transaction(&context)).is_none());
+    }
+
+    #[test]
+    fn mixes_draw_by_weight_and_label_their_data() {
+        let shape = |name: &str, weight| MixShape {
+            name: name.to_string(),
+            weight,
+            data: Arc::new(DummyData::default()),
+        };
+        assert!(WeightedMix::factory(vec![shape("none", 0)]).is_err());
+        let shapes = vec![shape("small_get", 1), shape("upload", 0)];
+        let mut mix = WeightedMix::factory(shapes).unwrap()(0);
+        assert_eq!(mix.label(), None);
+        let context = ScenarioContext {
+            stream: 0,
+            transactions: 0,
+            last: None,
+        };
+        let breakdown = ScenarioBreakdown::default();
+        for _ in 0..3 {
+            futures::executor::block_on(mix.next_transaction(&context)).unwrap();
+            breakdown.record(mix.label().unwrap(), Duration::from_millis(2), false);
+        }
+        let summaries = breakdown.summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!((summaries[0].name.as_str(), summaries[0].count), ("small_get", 3));
+        assert_eq!(summaries[0].latency.p50, 2);
+    }
+}
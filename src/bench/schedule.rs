@@ -0,0 +1,89 @@
+//! Open-loop load: transactions are due at a fixed rate whatever the pace of responses, and the
+//! benchers are the pool running them. Transactions due while every bencher is busy queue up,
+//! and their latency counts from when they were due, so a slow server can't lower the load it is
+//! measured under
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use metered::{clear::Clear, hdr_histogram::AtomicHdrHistogram, metric::Histogram};
+use serde::Serialize;
+
+/// Largest recorded latency, an hour
+const MAX_RECORDED_MICROS: u64 = 3_600_000_000;
+
+#[derive(Debug, Serialize)]
+pub struct RateSchedule {
+    /// Transactions due per second
+    pub target_rps: f64,
+    #[serde(skip)]
+    origin: Mutex<Instant>,
+    #[serde(skip)]
+    next: AtomicU64,
+    /// How many transactions came due while each one waited for a free bencher
+    pub queue_depth: AtomicHdrHistogram,
+    /// Transaction latency from when they were due, queueing included, in microseconds
+    pub latency_us: AtomicHdrHistogram,
+}
+
+impl RateSchedule {
+    pub fn new(target_rps: f64) -> RateSchedule {
+        assert!(target_rps > 0.0, "the target rate must be positive");
+        RateSchedule {
+            target_rps,
+            origin: Mutex::new(Instant::now()),
+            next: AtomicU64::new(0),
+            queue_depth: AtomicHdrHistogram::with_bound(u32::MAX as u64),
+            latency_us: AtomicHdrHistogram::with_bound(MAX_RECORDED_MICROS),
+        }
+    }
+
+    /// Takes the next transaction, returning when it is due
+    pub fn next_due(&self) -> Instant {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed);
+        *self.origin.lock().unwrap() + Duration::from_secs_f64(slot as f64 / self.target_rps)
+    }
+
+    /// Records that the transaction due at `due` started now
+    pub fn record_start(&self, due: Instant) {
+        let waited = Instant::now().saturating_duration_since(due);
+        self.queue_depth.record((waited.as_secs_f64() * self.target_rps) as u64);
+    }
+
+    /// Records that the transaction due at `due` ended now
+    pub fn record_end(&self, due: Instant) {
+        self.latency_us.record(due.elapsed().as_micros() as u64);
+    }
+
+    /// Starts the schedule over from now, forgetting the measurements so far
+    pub fn restart(&self) {
+        *self.origin.lock().unwrap() = Instant::now();
+        self.next.store(0, Ordering::Relaxed);
+        self.queue_depth.clear();
+        self.latency_us.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transactions_are_due_at_the_target_rate() {
+        let schedule = RateSchedule::new(100.0);
+        let first = schedule.next_due();
+        let second = schedule.next_due();
+        let third = schedule.next_due();
+        assert_eq!(second - first, Duration::from_millis(10));
+        assert_eq!(third - first, Duration::from_millis(20));
+        schedule.record_start(first);
+        schedule.restart();
+        assert!(schedule.next_due() >= first);
+        assert_eq!(schedule.queue_depth.histogram().len(), 0);
+    }
+}
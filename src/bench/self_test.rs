@@ -0,0 +1,129 @@
+//! Short benchmark against an in-process server on an ephemeral port, to verify a build and its
+//! environment before pointing the client at a real server
+
+use std::{sync::Arc, time::Duration};
+
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+
+use super::{run_benchmark, BenchPlan, BenchReport, Target};
+use crate::{
+    dummy::{
+        client::Config,
+        server::{behavior::BehaviorConfig, server_processing_mode, ExtProcService},
+        DummyData,
+    },
+    proto::envoy::service::ext_proc::v3::{
+        external_processor_client::ExternalProcessorClient,
+        external_processor_server::ExternalProcessorServer,
+    },
+};
+
+use error::SelfTestError;
+
+pub mod error {
+    use quick_error::quick_error;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum SelfTestError {
+            BindError(err: std::io::Error) {
+                display("Could not start the in-process server: {}", err)
+                from()
+            }
+            ConnectError(err: tonic::transport::Error) {
+                display("Could not connect to the in-process server: {}", err)
+                from()
+            }
+            NoTransactions {
+                display("No transaction completed.")
+            }
+            TransactionErrors(errors: u64, count: u64) {
+                display("{} of {} transactions failed.", errors, count)
+            }
+        }
+    );
+}
+
+fn self_test_data() -> DummyData {
+    let headers = |headers: &[(&str, &str)]| -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+    DummyData {
+        req_headers: headers(&[
+            (":method", "POST"),
+            (":path", "/self-test"),
+            ("host", "localhost"),
+        ]),
+        req_body: b"self-test request".to_vec(),
+        resp_status: 200,
+        resp_headers: headers(&[(":status", "200"), ("content-type", "text/plain")]),
+        resp_body: b"self-test response".to_vec(),
+        ..Default::default()
+    }
+}
+
+/// Benchmarks reused streams against a strictly ordering server for `duration`, and fails unless
+/// transactions completed without errors or protocol violations
+pub async fn run_self_test(duration: Duration) -> Result<BenchReport, SelfTestError> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let service = ExtProcService::new(server_processing_mode(false), BehaviorConfig::default())
+        .with_strict_ordering(true);
+    let server = tokio::spawn(
+        Server::builder()
+            .add_service(ExternalProcessorServer::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let client = ExternalProcessorClient::connect(format!("http://{}", address)).await;
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            server.abort();
+            return Err(e.into());
+        }
+    };
+    let stream_config = Config {
+        reuse_stream: true,
+        fail_on_violation: true,
+        ..Default::default()
+    };
+    let plan = BenchPlan {
+        concurrency: 4,
+        warmup: Duration::ZERO,
+        duration,
+        print_progress: false,
+        ..BenchPlan::new("Self Test", Arc::new(self_test_data()), stream_config)
+    };
+    let report = run_benchmark(Target::Processor(client), plan).await;
+    server.abort();
+
+    let summary = &report.summary;
+    if summary.count == 0 {
+        return Err(SelfTestError::NoTransactions);
+    }
+    if summary.errors > 0 {
+        return Err(SelfTestError::TransactionErrors(summary.errors, summary.count));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test_passes() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let report = runtime
+            .block_on(run_self_test(Duration::from_millis(500)))
+            .unwrap();
+        assert!(report.summary.count > 0);
+    }
+}
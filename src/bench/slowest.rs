@@ -0,0 +1,133 @@
+//! The slowest transactions of a run, with the timings and sizes of their messages, so tail
+//! latency investigations start from concrete exchanges rather than a p99
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::{
+        atomic::{self, AtomicU64},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::dummy::client::transaction::PhaseTiming;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowTransaction {
+    pub latency_us: u64,
+    /// Index of the bencher that ran it
+    pub stream: usize,
+    /// Number of the transaction among its bencher's, warmup included
+    pub transaction: u64,
+    /// Why it failed, if it did
+    pub error: Option<String>,
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl PartialEq for SlowTransaction {
+    fn eq(&self, other: &Self) -> bool {
+        self.latency_us == other.latency_us
+    }
+}
+
+impl Eq for SlowTransaction {}
+
+impl PartialOrd for SlowTransaction {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SlowTransaction {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.latency_us.cmp(&other.latency_us)
+    }
+}
+
+/// Bounded reservoir of the slowest transactions, shared by the benchers of a run
+pub struct SlowestTransactions {
+    capacity: usize,
+    /// Latency transactions must exceed to be kept, that of the fastest kept one once full
+    threshold_us: AtomicU64,
+    /// Min-heap, the fastest kept transaction on top
+    kept: Mutex<BinaryHeap<Reverse<SlowTransaction>>>,
+}
+
+impl SlowestTransactions {
+    pub fn new(capacity: usize) -> SlowestTransactions {
+        SlowestTransactions {
+            capacity,
+            threshold_us: AtomicU64::new(0),
+            kept: Mutex::new(BinaryHeap::with_capacity(capacity + 1)),
+        }
+    }
+
+    /// Whether a transaction this slow would be kept, checked before building it
+    pub fn qualifies(&self, latency: Duration) -> bool {
+        self.capacity > 0
+            && latency.as_micros() as u64 > self.threshold_us.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn offer(&self, transaction: SlowTransaction) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut kept = self.kept.lock().unwrap();
+        kept.push(Reverse(transaction));
+        if kept.len() > self.capacity {
+            kept.pop();
+        }
+        if kept.len() == self.capacity {
+            if let Some(Reverse(fastest)) = kept.peek() {
+                self.threshold_us.store(fastest.latency_us, atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The kept transactions, slowest first
+    pub fn sorted(&self) -> Vec<SlowTransaction> {
+        let kept = self.kept.lock().unwrap();
+        let mut sorted: Vec<_> = kept.iter().map(|Reverse(t)| t.clone()).collect();
+        sorted.sort_by(|a, b| b.cmp(a));
+        sorted
+    }
+
+    pub fn clear(&self) {
+        self.kept.lock().unwrap().clear();
+        self.threshold_us.store(0, atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(latency_us: u64) -> SlowTransaction {
+        SlowTransaction {
+            latency_us,
+            stream: 0,
+            transaction: latency_us,
+            error: None,
+            phases: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_slowest() {
+        let slowest = SlowestTransactions::new(3);
+        for latency in [5, 50, 1, 20, 40, 10] {
+            if slowest.qualifies(Duration::from_micros(latency)) {
+                slowest.offer(transaction(latency));
+            }
+        }
+        let latencies: Vec<u64> = slowest.sorted().iter().map(|t| t.latency_us).collect();
+        assert_eq!(latencies, [50, 40, 20]);
+        assert!(!slowest.qualifies(Duration::from_micros(20)));
+        slowest.clear();
+        assert!(slowest.sorted().is_empty());
+        assert!(!SlowestTransactions::new(0).qualifies(Duration::MAX));
+    }
+}
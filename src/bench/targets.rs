@@ -0,0 +1,265 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use tonic::transport::{Channel, Endpoint};
+
+use super::{omission::latency_summary, MAX_LATENCY_MICROS};
+use crate::{
+    proto::envoy::service::ext_proc::v3::external_processor_client::ExternalProcessorClient,
+    report::LatencySummary,
+};
+
+use error::TargetsFileError;
+
+pub mod error {
+    use quick_error::quick_error;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum TargetsFileError {
+            OpenError(err: std::io::Error) {
+                display("Could not read targets file: {}", err)
+                from()
+            }
+            InvalidLine(line: usize, content: String) {
+                display("Invalid target on line {}: '{}'", line, content)
+            }
+            NoTargets {
+                display("Targets file lists no target.")
+            }
+        }
+    );
+}
+
+/// A server of a targets file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TargetSpec {
+    pub url: String,
+    /// Share of the streams sent to the server, relative to the other servers
+    pub weight: u32,
+}
+
+/// A server of [`super::Target::Processors`]
+#[derive(Clone)]
+pub struct WeightedProcessor {
+    /// Names the server in the per-target breakdown of [`super::BenchReport::targets`]
+    pub url: String,
+    pub client: ExternalProcessorClient<Channel>,
+    /// Where to reconnect to when the connection is lost
+    pub endpoint: Endpoint,
+    pub weight: u32,
+}
+
+/// Reads server URLs, one per line with an optional weight after whitespace (1 by default).
+/// Empty lines and lines starting with `#` are ignored, e.g.
+/// ```text
+/// # region a
+/// http://10.0.0.1:50051 3
+/// http://10.0.0.2:50051
+/// ```
+pub fn read_targets_file<P: AsRef<Path>>(path: P) -> Result<Vec<TargetSpec>, TargetsFileError> {
+    parse_targets(&fs::read_to_string(path)?)
+}
+
+pub fn parse_targets(contents: &str) -> Result<Vec<TargetSpec>, TargetsFileError> {
+    let mut targets = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || TargetsFileError::InvalidLine(index + 1, line.to_string());
+        let mut fields = line.split_whitespace();
+        let url = fields.next().ok_or_else(invalid)?;
+        let weight = match fields.next() {
+            Some(weight) => weight.parse().map_err(|_| invalid())?,
+            None => 1,
+        };
+        if weight == 0 || fields.next().is_some() {
+            return Err(invalid());
+        }
+        targets.push(TargetSpec {
+            url: url.to_string(),
+            weight,
+        });
+    }
+    if targets.is_empty() {
+        return Err(TargetsFileError::NoTargets);
+    }
+    Ok(targets)
+}
+
+/// A server given on the command line, as `<url>[=<weight>]`, e.g. `http://10.0.0.1:50051=3`
+pub fn parse_target(spec: &str) -> Result<TargetSpec, String> {
+    let (url, weight) = match spec.rsplit_once('=') {
+        Some((url, weight)) => {
+            let weight = weight
+                .parse()
+                .map_err(|e| format!("invalid weight '{}': {}", weight, e))?;
+            (url, weight)
+        }
+        None => (spec, 1),
+    };
+    if weight == 0 {
+        return Err("weights must be positive".to_string());
+    }
+    Ok(TargetSpec {
+        url: url.to_string(),
+        weight,
+    })
+}
+
+/// Transactions sent to one of several targets, after the warmup
+struct TargetMetrics {
+    url: String,
+    weight: u32,
+    errors: AtomicU64,
+    /// Latencies in microseconds
+    latencies: Mutex<Histogram<u64>>,
+}
+
+/// Transactions and latency of each of [`super::Target::Processors`], so servers of a run can
+/// be compared, e.g. two builds or the replicas of a deployment. Processors of the same URL,
+/// e.g. several connections to a server, are counted together
+pub(super) struct TargetBreakdown {
+    targets: Vec<TargetMetrics>,
+    /// Index in `targets` of each processor
+    processor_targets: Vec<usize>,
+}
+
+impl TargetBreakdown {
+    pub(super) fn new(processors: &[WeightedProcessor]) -> TargetBreakdown {
+        let mut targets: Vec<TargetMetrics> = Vec::new();
+        let mut processor_targets = Vec::with_capacity(processors.len());
+        for processor in processors {
+            match targets.iter().position(|target| target.url == processor.url) {
+                Some(index) => processor_targets.push(index),
+                None => {
+                    processor_targets.push(targets.len());
+                    targets.push(TargetMetrics {
+                        url: processor.url.clone(),
+                        weight: processor.weight,
+                        errors: AtomicU64::new(0),
+                        latencies: Mutex::new(
+                            Histogram::new_with_max(MAX_LATENCY_MICROS, 3).unwrap(),
+                        ),
+                    });
+                }
+            }
+        }
+        TargetBreakdown {
+            targets,
+            processor_targets,
+        }
+    }
+
+    /// Records a transaction sent to the processor at `index`
+    pub(super) fn record(&self, index: usize, latency: Duration, failed: bool) {
+        let target = &self.targets[self.processor_targets[index]];
+        if failed {
+            target.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let latency_us = latency.as_micros() as u64;
+        target.latencies.lock().unwrap().saturating_record(latency_us);
+    }
+
+    pub(super) fn clear(&self) {
+        for target in &self.targets {
+            target.errors.store(0, Ordering::Relaxed);
+            target.latencies.lock().unwrap().reset();
+        }
+    }
+
+    pub(super) fn summaries(&self) -> Vec<TargetSummary> {
+        self.targets
+            .iter()
+            .map(|target| {
+                let latencies = target.latencies.lock().unwrap();
+                TargetSummary {
+                    url: target.url.clone(),
+                    weight: target.weight,
+                    count: latencies.len(),
+                    errors: target.errors.load(Ordering::Relaxed),
+                    latency: latency_summary(&latencies),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Results of one of several targets of a run
+#[derive(Debug, Clone)]
+pub struct TargetSummary {
+    pub url: String,
+    pub weight: u32,
+    /// Transactions, including failed ones
+    pub count: u64,
+    pub errors: u64,
+    pub latency: LatencySummary,
+}
+
+/// Indices of the weighted targets, each repeated by its weight, for streams to cycle through
+pub(super) fn weighted_slots<I: IntoIterator<Item = u32>>(weights: I) -> Vec<usize> {
+    weights
+        .into_iter()
+        .enumerate()
+        .flat_map(|(index, weight)| std::iter::repeat(index).take(weight as usize))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_weighted_targets() {
+        let targets = parse_targets(
+            "# region a\nhttp://10.0.0.1:50051 3\n\n  http://10.0.0.2:50051  \n",
+        )
+        .unwrap();
+        assert_eq!(
+            targets,
+            vec![
+                TargetSpec {
+                    url: "http://10.0.0.1:50051".to_string(),
+                    weight: 3
+                },
+                TargetSpec {
+                    url: "http://10.0.0.2:50051".to_string(),
+                    weight: 1
+                },
+            ]
+        );
+        assert!(matches!(
+            parse_targets("http://a 0"),
+            Err(TargetsFileError::InvalidLine(1, _))
+        ));
+        assert!(matches!(
+            parse_targets("http://a 1\nhttp://b x"),
+            Err(TargetsFileError::InvalidLine(2, _))
+        ));
+        assert!(matches!(parse_targets("# none"), Err(TargetsFileError::NoTargets)));
+    }
+
+    #[test]
+    fn command_line_targets_have_optional_weights() {
+        let target = parse_target("http://10.0.0.1:50051=3").unwrap();
+        assert_eq!((target.url.as_str(), target.weight), ("http://10.0.0.1:50051", 3));
+        assert_eq!(parse_target("http://10.0.0.2:50051").unwrap().weight, 1);
+        assert!(parse_target("http://a=0").is_err());
+        assert!(parse_target("http://a=x").is_err());
+    }
+
+    #[test]
+    fn slots_follow_weights() {
+        assert_eq!(weighted_slots([3, 1]), vec![0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,255 @@
+//! Load generation over several processes, escaping the limits of a single one (fd caps,
+//! allocator contention). Workers are copies of the benchmarking binary: they get ready, wait for
+//! the coordinator to start them all at once and print their results as JSON, which the
+//! coordinator merges.
+//!
+//! The protocol runs over the workers' standard streams: a worker prints [`READY`] on stdout once
+//! connected, waits for [`START`] on stdin, then prints its `{"runs": [...]}` results (see
+//! [`super::BenchReport::to_json`]) and exits.
+
+use std::{
+    ffi::{OsStr, OsString},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    time::Duration,
+};
+
+use crate::report::{LatencySummary, RunSummary};
+
+use error::WorkerError;
+
+pub mod error {
+    use quick_error::quick_error;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum WorkerError {
+            IoError(err: std::io::Error) {
+                display("Could not talk to worker: {}", err)
+                from()
+            }
+            Failed(worker: usize, status: std::process::ExitStatus) {
+                display("Worker {} failed ({}).", worker, status)
+            }
+            Protocol(worker: usize, message: String) {
+                display("Worker {} sent unexpected output: {}", worker, message)
+            }
+        }
+    );
+}
+
+pub const READY: &str = "ready";
+pub const START: &str = "start";
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+}
+
+impl WorkerPool {
+    /// Spawns `count` copies of `program` with these arguments. Their logs go to our stderr
+    pub fn spawn<P: AsRef<Path>>(
+        program: P,
+        args: &[OsString],
+        count: usize,
+    ) -> Result<WorkerPool, WorkerError> {
+        let mut workers = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut child = Command::new(program.as_ref())
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+                unreachable!("the standard streams of workers are piped");
+            };
+            workers.push(Worker {
+                child,
+                stdin,
+                stdout: BufReader::new(stdout),
+            });
+        }
+        Ok(WorkerPool { workers })
+    }
+
+    /// Waits for every worker to be ready, then starts them all
+    pub fn start(&mut self) -> Result<(), WorkerError> {
+        for (index, worker) in self.workers.iter_mut().enumerate() {
+            let mut line = String::new();
+            worker.stdout.read_line(&mut line)?;
+            if line.trim() != READY {
+                return Err(match worker.child.try_wait()? {
+                    Some(status) => WorkerError::Failed(index, status),
+                    None => WorkerError::Protocol(index, line),
+                });
+            }
+        }
+        for worker in &mut self.workers {
+            writeln!(worker.stdin, "{}", START)?;
+            worker.stdin.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the workers to finish, returning the runs each of them reported
+    pub fn finish(self) -> Result<Vec<Vec<serde_json::Value>>, WorkerError> {
+        let mut results = Vec::with_capacity(self.workers.len());
+        for (index, mut worker) in self.workers.into_iter().enumerate() {
+            let mut output = String::new();
+            worker.stdout.read_to_string(&mut output)?;
+            let status = worker.child.wait()?;
+            if !status.success() {
+                return Err(WorkerError::Failed(index, status));
+            }
+            let runs = serde_json::from_str::<serde_json::Value>(&output)
+                .ok()
+                .and_then(|results| results.get("runs")?.as_array().cloned())
+                .ok_or_else(|| WorkerError::Protocol(index, output.clone()))?;
+            results.push(runs);
+        }
+        Ok(results)
+    }
+}
+
+/// Worker side of the protocol: reports being ready and waits to be started
+pub fn await_start() -> io::Result<()> {
+    let mut stdout = io::stdout();
+    writeln!(stdout, "{}", READY)?;
+    stdout.flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    if line.trim() != START {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected '{}', got '{}'", START, line.trim()),
+        ));
+    }
+    Ok(())
+}
+
+/// The command line arguments of workers: ours without the `stripped` options (and their values),
+/// which only concern the coordinator, followed by `extra`
+pub fn worker_args<I>(args: I, stripped: &[&str], extra: &[&str]) -> Vec<OsString>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args = args.into_iter();
+    let mut kept = Vec::new();
+    while let Some(arg) = args.next() {
+        let text = arg.to_string_lossy();
+        if stripped.contains(&text.as_ref()) {
+            args.next();
+            continue;
+        }
+        if stripped
+            .iter()
+            .any(|option| text.starts_with(&format!("{}=", option)))
+        {
+            continue;
+        }
+        kept.push(arg);
+    }
+    for option in extra {
+        if !kept.iter().any(|arg| arg == OsStr::new(option)) {
+            kept.push(OsString::from(option));
+        }
+    }
+    kept
+}
+
+/// The summary of a run in the results of a worker
+pub fn summary_from_json(run: &serde_json::Value) -> Option<RunSummary> {
+    let latency = run.get("latency_ms")?;
+    Some(RunSummary {
+        name: run.get("name")?.as_str()?.to_string(),
+        started_at: humantime::parse_rfc3339(run.get("started_at")?.as_str()?).ok()?,
+        duration: Duration::from_secs_f64(run.get("duration_secs")?.as_f64()?),
+        concurrency: run.get("concurrency")?.as_u64()? as usize,
+        count: run.get("count")?.as_u64()?,
+        errors: run.get("errors")?.as_u64()?,
+        latency: LatencySummary {
+            mean: latency.get("mean")?.as_f64()?,
+            min: latency.get("min")?.as_u64()?,
+            max: latency.get("max")?.as_u64()?,
+            p50: latency.get("p50")?.as_u64()?,
+            p90: latency.get("p90")?.as_u64()?,
+            p95: latency.get("p95")?.as_u64()?,
+            p99: latency.get("p99")?.as_u64()?,
+            p999: latency.get("p999")?.as_u64()?,
+        },
+    })
+}
+
+/// Combines the summaries of the same run on every worker. Counts and concurrency add up and
+/// the mean latency is exact, but percentiles are approximated by the count-weighted mean of the
+/// workers' percentiles
+pub fn merge_summaries(summaries: &[RunSummary]) -> Option<RunSummary> {
+    let first = summaries.first()?;
+    let count: u64 = summaries.iter().map(|summary| summary.count).sum();
+    let weighted = |value: fn(&LatencySummary) -> f64| {
+        if count == 0 {
+            return 0.0;
+        }
+        summaries
+            .iter()
+            .map(|summary| value(&summary.latency) * summary.count as f64)
+            .sum::<f64>()
+            / count as f64
+    };
+    Some(RunSummary {
+        name: first.name.clone(),
+        started_at: summaries.iter().map(|summary| summary.started_at).min()?,
+        duration: summaries.iter().map(|summary| summary.duration).max()?,
+        concurrency: summaries.iter().map(|summary| summary.concurrency).sum(),
+        count,
+        errors: summaries.iter().map(|summary| summary.errors).sum(),
+        latency: LatencySummary {
+            mean: weighted(|latency| latency.mean),
+            min: summaries.iter().map(|summary| summary.latency.min).min()?,
+            max: summaries.iter().map(|summary| summary.latency.max).max()?,
+            p50: weighted(|latency| latency.p50 as f64).round() as u64,
+            p90: weighted(|latency| latency.p90 as f64).round() as u64,
+            p95: weighted(|latency| latency.p95 as f64).round() as u64,
+            p99: weighted(|latency| latency.p99 as f64).round() as u64,
+            p999: weighted(|latency| latency.p999 as f64).round() as u64,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::test_summary;
+
+    #[test]
+    fn worker_summaries_are_merged() {
+        let mut fast = test_summary();
+        fast.count = 300;
+        fast.latency.p99 = 10;
+        let mut slow = test_summary();
+        slow.count = 100;
+        slow.errors = 4;
+        slow.latency.mean = 6.5;
+        slow.latency.max = 50;
+        slow.latency.p99 = 30;
+        let merged = merge_summaries(&[fast, slow]).unwrap();
+        assert_eq!((merged.count, merged.errors, merged.concurrency), (400, 4, 200));
+        assert_eq!(merged.latency.mean, 3.5);
+        assert_eq!((merged.latency.max, merged.latency.p99), (50, 15));
+        assert!(merge_summaries(&[]).is_none());
+    }
+
+    #[test]
+    fn coordinator_options_are_not_forwarded() {
+        let args = ["data.json", "--processes", "4", "--output=out.json", "-q"]
+            .into_iter()
+            .map(OsString::from);
+        let forwarded = worker_args(args, &["--processes", "--output"], &["--json-only"]);
+        assert_eq!(forwarded, ["data.json", "-q", "--json-only"]);
+    }
+}
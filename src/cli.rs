@@ -0,0 +1,126 @@
+//! Value parsers shared by the command line tools
+
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// Parses a humantime duration (e.g. `30s`, `5m`, `1h30m`), or a bare number of `unit`s, which
+/// options took before humantime values were accepted
+fn parse_duration(value: &str, unit: fn(u64) -> Duration) -> Result<Duration, String> {
+    if let Ok(count) = value.parse() {
+        return Ok(unit(count));
+    }
+    humantime::parse_duration(value).map_err(|e| format!("invalid duration '{}': {}", value, e))
+}
+
+/// A humantime duration, bare numbers being seconds
+pub fn parse_secs(value: &str) -> Result<Duration, String> {
+    parse_duration(value, Duration::from_secs)
+}
+
+/// A humantime duration, bare numbers being milliseconds
+pub fn parse_millis(value: &str) -> Result<Duration, String> {
+    parse_duration(value, Duration::from_millis)
+}
+
+/// A positive rate, e.g. transactions per second
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid rate '{}', expected a positive number", value)),
+    }
+}
+
+/// Placeholders [`OutputTemplate`]s may contain
+const OUTPUT_PLACEHOLDERS: [&str; 3] = ["scenario", "timestamp", "git_sha"];
+
+/// An output path with `{scenario}`, `{timestamp}` and `{git_sha}` placeholders, e.g.
+/// `results-{scenario}-{timestamp}.json`
+#[derive(Debug, Clone)]
+pub struct OutputTemplate(String);
+
+impl OutputTemplate {
+    pub fn has_placeholder(&self, name: &str) -> bool {
+        self.0.contains(&format!("{{{}}}", name))
+    }
+
+    /// Replaces the placeholders with `values`, given as `(name, value)` pairs
+    pub fn render(&self, values: &[(&str, &str)]) -> PathBuf {
+        let mut path = self.0.clone();
+        for (name, value) in values {
+            path = path.replace(&format!("{{{}}}", name), value);
+        }
+        PathBuf::from(path)
+    }
+}
+
+/// An output path template, rejecting unknown placeholders so typos don't end up in file names
+pub fn parse_output_template(value: &str) -> Result<OutputTemplate, String> {
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed placeholder in '{}'", value))?;
+        let name = &rest[start + 1..start + end];
+        if !OUTPUT_PLACEHOLDERS.contains(&name) {
+            return Err(format!(
+                "unknown placeholder '{{{}}}', expected one of {{{}}}",
+                name,
+                OUTPUT_PLACEHOLDERS.join("}, {")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(OutputTemplate(value.to_string()))
+}
+
+/// `time` as a file name friendly UTC timestamp, e.g. `20240131T235959Z`
+pub fn file_timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time)
+        .to_string()
+        .replace(['-', ':'], "")
+}
+
+/// `No Reuse` -> `no-reuse`
+pub fn file_slug(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_humantime_and_bare_numbers() {
+        assert_eq!(parse_secs("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_secs("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_millis("500"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_millis("2s"), Ok(Duration::from_secs(2)));
+        assert!(parse_secs("soon").is_err());
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+        assert!(parse_rate("0").is_err());
+    }
+
+    #[test]
+    fn renders_output_templates() {
+        let template = parse_output_template("results-{scenario}-{timestamp}.json").unwrap();
+        assert!(template.has_placeholder("scenario"));
+        assert!(!template.has_placeholder("git_sha"));
+        let timestamp = file_timestamp(SystemTime::UNIX_EPOCH);
+        assert_eq!(timestamp, "19700101T000000Z");
+        assert_eq!(
+            template.render(&[
+                ("scenario", &file_slug("3 Transaction Reuse")),
+                ("timestamp", &timestamp)
+            ]),
+            PathBuf::from("results-3-transaction-reuse-19700101T000000Z.json")
+        );
+        assert!(parse_output_template("results-{run}.json").is_err());
+        assert!(parse_output_template("results-{scenario.json").is_err());
+    }
+}
@@ -1,26 +1,63 @@
 use std::{
     fs::File,
-    sync::Arc,
-    time::{Duration, Instant},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
 };
 
-use clap::Parser;
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser};
 use ext_proc_playground::{
+    affinity::pin_runtime_threads,
+    bench::{
+        baseline::{compare, delta_table, matching_run, read_baseline, Thresholds},
+        corpus::HeaderCorpus,
+        profile::{Assertions, BenchmarkFile, Profile},
+        prometheus::PrometheusExporter,
+        run_benchmark,
+        scenario::{Sequence, WeightedMix},
+        targets::{parse_target, read_targets_file, TargetSpec},
+        workers::{await_start, merge_summaries, summary_from_json, worker_args, WorkerPool},
+        Backoff, BenchPlan, BenchReport, Reconnect, Retry, Target, WeightedProcessor,
+    },
+    cli::{
+        file_slug, file_timestamp, parse_millis, parse_output_template, parse_rate, parse_secs,
+        OutputTemplate,
+    },
     dummy::{
-        client::{error::StreamHandleError, ClientStream, Config},
-        DummyData, DummyDataConfig,
+        client::{
+            error::StreamHandleError,
+            replay::{replay, ReplayOptions},
+            transcript::to_grpcurl_json,
+            ClientStream, Config, Phase, StreamClosure, StreamKill,
+        },
+        recording::read_recording,
+        ConfigFormat, DummyData, DummyDataConfig, MixShape,
     },
+    error_category::ErrorCategory,
     proto::envoy::service::ext_proc::v3::external_processor_client::ExternalProcessorClient,
+    report::{
+        csv, hgrm,
+        sink::{Sample, SinkFormat, UdpSink},
+        OutputFormat, RunSummary,
+    },
+    schema::ConfigFile,
+    tls::{self, secure_endpoint, ClientTls},
+    uds,
 };
+#[cfg(feature = "server")]
+use ext_proc_playground::bench::self_test::run_self_test;
 
+use hdrhistogram::Histogram;
 use log::{error, info};
-use metered::{clear::Clear, ErrorCount, ResponseTime, Throughput};
-use tokio::sync::oneshot::{self, error::TryRecvError};
-use tonic::transport::Channel;
+use tonic::transport::Endpoint;
 
 #[derive(Parser, Debug)]
-
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[command(flatten)]
     bench_config: BenchConfig,
 
@@ -28,20 +65,262 @@ struct Args {
     #[arg(short, default_value_t = 2)]
     thread_count: usize,
 
-    /// Benchmark warmup duration
-    #[arg(short, default_value_t = 5)]
-    warmup: u64,
+    /// How many worker processes to spread the load over, each running the whole benchmark with
+    /// -s streams, to escape the limits of a single process (file descriptors, allocator
+    /// contention). Their results are merged into one report
+    #[arg(long, default_value_t = 1)]
+    processes: usize,
+
+    /// Run as one of the workers of --processes, started by the coordinator over stdin
+    #[arg(long, hide = true)]
+    worker: bool,
+
+    /// Cores to pin runtime threads to, round robin (e.g. 2,3)
+    #[arg(long, value_delimiter = ',')]
+    worker_cores: Vec<usize>,
+
+    /// Core to pin the metrics monitor thread to, isolating it from the stream handlers
+    #[arg(long)]
+    monitor_core: Option<usize>,
+
+    /// Benchmark warmup duration, e.g. 30s or 5m (bare numbers are seconds)
+    #[arg(short, default_value = "5s", value_parser = parse_secs)]
+    warmup: Duration,
+
+    /// Benchmark duration, e.g. 30s or 1h30m (bare numbers are seconds)
+    #[arg(short, default_value = "30s", value_parser = parse_secs)]
+    duration: Duration,
+
+    /// Stop after this many successful transactions over all streams, after the warmup, instead
+    /// of after the duration. -d then only bounds the run, which is unbounded if -d isn't given
+    #[arg(short = 'n', long, value_parser = clap::value_parser!(u64).range(1..))]
+    num_transactions: Option<u64>,
+
+    /// How often progress is logged and live metrics are pushed
+    #[arg(long, default_value = "2s", value_parser = parse_secs)]
+    monitor_interval: Duration,
+
+    /// Don't log throughput, latency and errors every few seconds during the run
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print a single JSON document with the results of every run to stdout when done, and only
+    /// log errors
+    #[arg(long)]
+    json_only: bool,
+
+    /// Load and validate the configuration, resolving body files and targets, print the effective
+    /// configuration and exit without connecting
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Path to benchmark data config file, JSON, YAML or TOML (see
+    /// [`ext_proc_playground::dummy::DummyDataConfig`])
+    #[arg(required_unless_present_any = ["profile", "har"])]
+    data_config_path: Option<String>,
+
+    /// Replay the entries of this HAR file (e.g. exported by a browser) in order on every
+    /// stream, instead of sending the data config's transaction
+    #[arg(long, conflicts_with_all = ["data_config_path", "header_corpus"])]
+    har: Option<PathBuf>,
+
+    /// Format of the data config, instead of the one of its extension (`.yaml` or `.yml`,
+    /// `.toml`, JSON otherwise)
+    #[arg(long, value_enum)]
+    config_format: Option<ConfigFormat>,
+
+    /// Benchmark definition file holding named profiles (see
+    /// [`ext_proc_playground::bench::profile::BenchmarkFile`])
+    #[arg(long, requires = "profile")]
+    bench_file: Option<PathBuf>,
+
+    /// Profile of --bench-file to run, e.g. smoke or soak. Options given on the command line
+    /// override the profile's settings, and the run fails if the profile's assertions don't hold
+    #[arg(long, requires = "bench_file")]
+    profile: Option<String>,
+
+    /// URLs of External Processor gRPC Services, as `<url>[=<weight>]`. Streams are spread over
+    /// several round-robin, each getting a share proportional to its weight, and the report
+    /// breaks transactions and latency down by server
+    #[arg(default_value = "http://[::1]:50051", value_parser = parse_target)]
+    server_urls: Vec<TargetSpec>,
+
+    /// File listing server URLs to spread streams over instead of the positional URLs, one per
+    /// line with an optional weight, e.g. `http://10.0.0.1:50051 3` (see
+    /// [`ext_proc_playground::bench::targets::read_targets_file`])
+    #[arg(long, conflicts_with = "envoy_url")]
+    targets_file: Option<PathBuf>,
+
+    /// Connect to the server over this unix domain socket instead of the URL's address, which
+    /// only names the `:authority` then
+    #[cfg(unix)]
+    #[arg(long, conflicts_with_all = ["targets_file", "envoy_url"])]
+    uds: Option<PathBuf>,
+
+    /// Send request headers synthesized from the headers of this corpus, a `.har` file or a JSON
+    /// access log with an object of request headers per line, instead of the data config's.
+    /// Rare and sensitive values are anonymized (see
+    /// [`ext_proc_playground::bench::corpus::HeaderCorpus`])
+    #[arg(long)]
+    header_corpus: Option<PathBuf>,
+
+    /// Seed of the headers synthesized from --header-corpus, so each run sends the same ones
+    #[arg(long, requires = "header_corpus")]
+    corpus_seed: Option<u64>,
+
+    /// Send actual HTTP requests to this Envoy listener, configured with the ext_proc filter,
+    /// instead of simulating the filter. Stream reuse options don't apply, Envoy manages streams
+    #[arg(long)]
+    envoy_url: Option<hyper::Uri>,
+
+    /// PEM CA certificate `https://` servers may also be verified against, e.g. a self-signed
+    /// test CA
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM certificate chain presented to servers requiring client certificates (mutual TLS)
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Name server certificates are verified against instead of the URL's host
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_domain: Option<String>,
+
+    /// Open this many connections to each server and spread streams over them round-robin,
+    /// rather than multiplexing every stream over a single HTTP/2 connection
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    connections: u32,
+
+    /// Reconnect to the server with jittered exponential backoff when the connection is lost
+    /// (e.g. server restarts), instead of failing every transaction for the rest of the run
+    #[arg(long, conflicts_with = "envoy_url")]
+    reconnect: bool,
+
+    /// Largest delay between reconnection attempts (bare numbers are milliseconds)
+    #[arg(long, visible_alias = "reconnect-max-backoff-ms", default_value = "10s", value_parser = parse_millis)]
+    reconnect_max_backoff: Duration,
+
+    /// Retry transactions failing on a transient stream failure (the stream closing, or a
+    /// retryable gRPC status such as UNAVAILABLE) up to this many times, on a new stream with
+    /// jittered exponential backoff. Only the last attempt counts as a success or an error
+    #[arg(long, default_value_t = 0, conflicts_with = "envoy_url")]
+    retries: u32,
+
+    /// Largest delay between retries of a transaction (bare numbers are milliseconds)
+    #[arg(long, visible_alias = "retry-max-backoff-ms", default_value = "1s", value_parser = parse_millis)]
+    retry_max_backoff: Duration,
+
+    /// Start this many transactions per second whatever the pace of responses (open loop),
+    /// instead of running each stream's transactions back to back. -s is then the most
+    /// transactions in flight, those due while every stream is busy are queued
+    #[arg(long, value_parser = parse_rate)]
+    target_rps: Option<f64>,
+
+    /// Correct latency for coordinated omission (à la wrk2): each stream expects its
+    /// transactions to start its mean warmup latency apart, and a stall also counts the
+    /// transactions intended to start during it. Reported next to the measured latency
+    #[arg(long, conflicts_with = "target_rps")]
+    correct_co: bool,
+
+    /// How many of the slowest transactions to keep, with the timings and sizes of their
+    /// messages, in the results written to --output
+    #[arg(long, default_value_t = 10)]
+    slowest_transactions: usize,
+
+    /// Format to write results to --output in
+    #[arg(long, value_enum, requires = "output")]
+    output_format: Option<OutputFormat>,
+
+    /// Path to write results to. May contain `{scenario}`, `{timestamp}` (start of the command,
+    /// UTC) and `{git_sha}` (of the working directory) placeholders, e.g.
+    /// `results-{scenario}-{timestamp}.json`. With --compare-reuse and no `{scenario}`, each run
+    /// is written to its own file, with the run number appended to the file name
+    #[arg(long, requires = "output_format", value_parser = parse_output_template)]
+    output: Option<OutputTemplate>,
+
+    /// Path to write every transaction latency to, as an HdrHistogram percentile distribution
+    /// (`.hgrm`, in milliseconds) for the HdrHistogram plotter. Takes the same placeholders as
+    /// --output
+    #[arg(long, value_parser = parse_output_template, conflicts_with = "processes")]
+    histogram_out: Option<OutputTemplate>,
+
+    /// Path to write the requests of a single transaction to, as grpcurl-replayable JSON lines,
+    /// before benchmarking
+    #[arg(long, conflicts_with = "envoy_url")]
+    export_transcript: Option<PathBuf>,
 
-    /// Benchmark duration
-    #[arg(short, default_value_t = 30)]
-    duration: u64,
+    /// Results of a previous run, as printed by --json-only, to compare every run with the run
+    /// of the same scenario. Regressions beyond the thresholds below fail the command
+    #[arg(long, conflicts_with = "processes")]
+    baseline: Option<PathBuf>,
 
-    /// Path to benchmark data json config file (see [`ext_proc_playground::dummy::DataConfig`])
-    data_config_path: String,
+    /// Largest throughput drop from the baseline, in percent
+    #[arg(long, default_value_t = 5.0, requires = "baseline")]
+    max_throughput_drop: f64,
 
-    /// URL to External Processor gRPC Service
-    #[arg(default_value = "http://[::1]:50051")]
-    server_url: String,
+    /// Largest increase of the mean and percentile latencies from the baseline, in percent
+    #[arg(long, default_value_t = 10.0, requires = "baseline")]
+    max_latency_increase: f64,
+
+    /// Largest increase of the error count from the baseline
+    #[arg(long, default_value_t = 0, requires = "baseline")]
+    max_error_increase: u64,
+
+    /// Serve the metrics of the run in progress to Prometheus scrapes on this port, at
+    /// `/metrics`. Not available with --processes, whose workers would compete for the port
+    #[arg(long, conflicts_with = "processes")]
+    metrics_port: Option<u16>,
+
+    /// Format of live metrics pushed to --metrics-sink-address during the run
+    #[arg(long, value_enum, requires = "metrics_sink_address")]
+    metrics_sink: Option<SinkFormat>,
+
+    /// UDP address (host:port) of an InfluxDB or StatsD endpoint
+    #[arg(long, requires = "metrics_sink")]
+    metrics_sink_address: Option<String>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print the JSON Schema of a config file, for editor autocompletion and validation
+    Schema {
+        #[arg(value_enum)]
+        config: ConfigFile,
+    },
+    /// Benchmark an in-process server for a few seconds to verify the build and environment
+    #[cfg(feature = "server")]
+    SelfTest {
+        /// Benchmark duration (bare numbers are seconds)
+        #[arg(short, default_value = "3s", value_parser = parse_secs)]
+        duration: Duration,
+    },
+    /// Replay streams recorded by run_server --record and compare the server's responses with
+    /// the recorded ones. Exits with an error on any difference
+    Replay {
+        /// Server to replay the streams against
+        server_url: String,
+
+        /// Recordings to replay, each on its own stream
+        #[arg(required = true)]
+        recordings: Vec<PathBuf>,
+
+        /// Leave header mutations of this header out of the comparison, for values that change
+        /// from run to run (repeatable)
+        #[arg(long = "ignore-header", value_name = "NAME")]
+        ignore_headers: Vec<String>,
+
+        /// How long to wait for each response (bare numbers are milliseconds)
+        #[arg(long, default_value = "5000", value_parser = parse_millis)]
+        timeout: Duration,
+    },
 }
 
 #[derive(clap::Args, Debug)]
@@ -50,6 +329,11 @@ struct BenchConfig {
     #[arg(short, default_value_t = 100)]
     stream_concurrency: usize,
 
+    /// Share this many streams between the concurrent transactions through a stream pool,
+    /// instead of giving each its own. Transactions then wait for a free stream
+    #[arg(long, conflicts_with = "reconnect")]
+    stream_pool_size: Option<usize>,
+
     /// Reuse streams for more than one transaction
     #[arg(long)]
     reuse_streams: bool,
@@ -61,195 +345,1125 @@ struct BenchConfig {
     /// Print errors from stream handlers
     #[arg(long)]
     print_errors: bool,
+
+    /// Print immediate responses transactions were denied with
+    #[arg(long)]
+    print_immediate_responses: bool,
+
+    /// Send requests in observability mode, without waiting for responses
+    #[arg(long)]
+    observability_mode: bool,
+
+    /// Send only this phase's messages in observability mode (repeatable), without waiting for
+    /// their responses, like deployments observing bodies while processing headers
+    #[arg(
+        long = "async-phase",
+        value_enum,
+        value_name = "PHASE",
+        conflicts_with = "observability_mode"
+    )]
+    async_phases: Vec<Phase>,
+
+    /// Abort transactions that take longer than this and recycle their stream (bare numbers are
+    /// milliseconds)
+    #[arg(long, visible_alias = "watchdog-ms", value_parser = parse_millis)]
+    watchdog: Option<Duration>,
+
+    /// Cancel transactions that take longer than this and close their stream, counting them as
+    /// timeouts instead of errors and leaving them out of the latency and throughput (bare
+    /// numbers are milliseconds)
+    #[arg(long = "timeout-ms", value_parser = parse_millis)]
+    timeout: Option<Duration>,
+
+    /// Open every stream before the warmup, and start once all of them are ready
+    #[arg(long)]
+    prestart_streams: bool,
+
+    /// Fail transactions on the first protocol violation of the server (responses of the wrong
+    /// type, duplicate responses, responses between transactions). They are counted either way
+    #[arg(long)]
+    fail_on_violation: bool,
+
+    /// Largest request or response in bytes, encoded. Transactions exceeding it fail
+    #[arg(long)]
+    max_message_size: Option<usize>,
+
+    /// Body bytes per second each stream may send, to emulate slow clients and origins
+    #[arg(long)]
+    max_body_rate: Option<u64>,
+
+    /// Send trailers from the start of each stream, instead of only once the server asks for
+    /// them with a mode override
+    #[arg(long)]
+    send_trailers: bool,
+
+    /// Send bodies in STREAMED mode, in chunks each awaiting its response like Envoy does,
+    /// instead of in one BUFFERED message
+    #[arg(long)]
+    streamed_bodies: bool,
+
+    /// Size in bytes of the body chunks sent in STREAMED mode (16384 when unset), also when the
+    /// server switches to it with a mode override
+    #[arg(long)]
+    body_chunk_size: Option<usize>,
+
+    /// Most body bytes sent in one message when the server switches to BUFFERED_PARTIAL with a
+    /// mode override (1 MiB when unset). The rest of longer bodies is sent in chunks
+    #[arg(long)]
+    partial_buffer_limit: Option<usize>,
+
+    /// Pipeline the chunks of STREAMED bodies, sending them without waiting for their responses
+    /// and receiving those concurrently
+    #[arg(long, requires = "streamed_bodies")]
+    pipelined_bodies: bool,
+
+    /// Kill reused streams once they handled this many transactions, to measure the cost of
+    /// recovering from lost streams
+    #[arg(long, requires = "reuse_streams")]
+    kill_streams_after: Option<usize>,
+
+    /// Kill reused streams open for longer than this, e.g. 30s (bare numbers are seconds)
+    #[arg(long, requires = "reuse_streams", value_parser = parse_secs)]
+    kill_streams_after_time: Option<Duration>,
+
+    /// How killed streams are closed: half-closed and ended by the server, or reset
+    #[arg(long, value_enum, default_value = "end")]
+    kill_closure: StreamClosure,
+
+    /// How long to wait for each response before abandoning the stream (bare numbers are
+    /// milliseconds)
+    #[arg(long, visible_alias = "message-timeout-ms", value_parser = parse_millis)]
+    message_timeout: Option<Duration>,
+
+    /// Run the same workload once per stream reuse strategy (no reuse, infinite reuse and every
+    /// --compare-max-handle value) and print a comparison table
+    #[arg(long)]
+    compare_reuse: bool,
+
+    /// Transaction caps to add as reuse strategies when running with --compare-reuse
+    #[arg(long, value_delimiter = ',')]
+    compare_max_handle: Vec<usize>,
+}
+
+impl BenchConfig {
+    fn stream_kill(&self) -> Option<StreamKill> {
+        if self.kill_streams_after.is_none() && self.kill_streams_after_time.is_none() {
+            return None;
+        }
+        Some(StreamKill {
+            after_transactions: self.kill_streams_after,
+            after: self.kill_streams_after_time,
+            closure: self.kill_closure,
+        })
+    }
+
+    /// Stream configurations to benchmark, in the order they should run
+    fn stream_configs(&self) -> Vec<Config> {
+        let base = Config {
+            observability_mode: self.observability_mode,
+            async_phases: self.async_phases.clone(),
+            message_timeout: self.message_timeout,
+            fail_on_violation: self.fail_on_violation,
+            max_message_size: self.max_message_size,
+            max_body_rate: self.max_body_rate,
+            send_trailers: self.send_trailers,
+            streamed_bodies: self.streamed_bodies,
+            body_chunk_size: self.body_chunk_size,
+            partial_buffer_limit: self.partial_buffer_limit,
+            pipelined_bodies: self.pipelined_bodies,
+            kill: self.stream_kill(),
+            ..Default::default()
+        };
+        if !self.compare_reuse {
+            return vec![Config {
+                reuse_stream: self.reuse_streams,
+                max_handled: self.stream_max_handle,
+                ..base
+            }];
+        }
+
+        let mut configs = vec![
+            Config {
+                reuse_stream: false,
+                max_handled: None,
+                ..base.clone()
+            },
+            Config {
+                reuse_stream: true,
+                max_handled: None,
+                ..base.clone()
+            },
+        ];
+        configs.extend(self.compare_max_handle.iter().map(|max| Config {
+            reuse_stream: true,
+            max_handled: Some(*max),
+            ..base.clone()
+        }));
+        configs
+    }
 }
 
 fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = match Args::from_arg_matches(&matches) {
+        Ok(args) => args,
+        Err(e) => e.exit(),
+    };
     env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(if args.json_only {
+            log::LevelFilter::Error
+        } else {
+            log::LevelFilter::Info
+        })
         .init();
-    let args: Args = Args::parse();
-    info!("Args:\n{:?}", args);
-
-    let dummy_data = {
-        let config_file = File::open(args.data_config_path);
-        if let Err(e) = config_file {
-            error!("Could not open config file: {}", e);
+    match args.command {
+        Some(Command::Schema { config }) => {
+            match serde_json::to_string_pretty(&config.schema()) {
+                Ok(schema) => println!("{}", schema),
+                Err(e) => error!("Could not serialize schema: {}", e),
+            }
             return;
         }
-        let config = serde_json::from_reader::<File, DummyDataConfig>(config_file.unwrap());
-        if let Err(e) = config {
-            error!("Could not parse config file: {}", e);
+        #[cfg(feature = "server")]
+        Some(Command::SelfTest { duration }) => {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            match runtime.block_on(run_self_test(duration)) {
+                Ok(report) => {
+                    let summary = &report.summary;
+                    info!(
+                        "Self test passed: {} transactions, {:.2} req/s, {:.2}ms avg latency",
+                        summary.count,
+                        summary.rate(),
+                        summary.latency.mean
+                    );
+                }
+                Err(e) => {
+                    error!("Self test failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
             return;
         }
-        let dummy_data = DummyData::try_from(config.unwrap());
-        if let Err(e) = dummy_data {
-            error!("Could not initialize dummy data: {}", e);
+        Some(Command::Replay {
+            ref server_url,
+            ref recordings,
+            ref ignore_headers,
+            timeout,
+        }) => {
+            let options = ReplayOptions {
+                ignore_headers: ignore_headers.clone(),
+                timeout,
+            };
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            if !runtime.block_on(replay_recordings(server_url, recordings, &options)) {
+                std::process::exit(1);
+            }
             return;
         }
-        Arc::new(dummy_data.unwrap())
+        None => {}
+    }
+    if args.num_transactions.is_some()
+        && matches.value_source("duration") != Some(ValueSource::CommandLine)
+    {
+        args.duration = Duration::MAX;
+    }
+    let mut assertions = Assertions::default();
+    if let (Some(path), Some(name)) = (args.bench_file.clone(), args.profile.clone()) {
+        let profile = match BenchmarkFile::from_file(&path) {
+            Ok(file) => file.profile(&name).cloned(),
+            Err(e) => Err(e),
+        };
+        match profile {
+            Ok(profile) => {
+                assertions = profile.assertions.clone();
+                apply_profile(&mut args, profile, &matches);
+            }
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                return;
+            }
+        }
+    }
+    info!("Args:\n{:?}", args);
+
+    let replay = match args.har {
+        Some(ref path) => match DummyData::from_har(path) {
+            Ok(entries) => Some(entries.into_iter().map(Arc::new).collect::<Vec<_>>()),
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let (dummy_data, data_config, mix_configs) = match replay {
+        // The first entry stands for the others where a single transaction is sent
+        Some(ref entries) => {
+            let har = args.har.as_ref().unwrap().display().to_string();
+            let config_json = serde_json::json!({ "har": har, "entries": entries.len() });
+            (entries[0].clone(), config_json, Vec::new())
+        }
+        None => {
+            let Some(ref data_config_path) = args.data_config_path else {
+                error!("No data config given on the command line or in the profile");
+                return;
+            };
+            let config = DummyDataConfig::from_file(data_config_path, args.config_format);
+            if let Err(e) = config {
+                error!("{}", e);
+                return;
+            }
+            let mut config = config.unwrap();
+            // Kept for --dry-run, the dummy data takes the config over
+            let config_json = serde_json::to_value(&config).unwrap_or_default();
+            let mix_configs = std::mem::take(&mut config.mix);
+            let dummy_data = DummyData::try_from(config);
+            if let Err(e) = dummy_data {
+                error!("Could not initialize dummy data: {}", e);
+                return;
+            }
+            (Arc::new(dummy_data.unwrap()), config_json, mix_configs)
+        }
+    };
+    let replay = replay.map(Sequence::factory);
+    let mix = if mix_configs.is_empty() {
+        None
+    } else {
+        let shapes: Result<Vec<_>, _> = mix_configs.into_iter().map(MixShape::try_from).collect();
+        let mix = match shapes {
+            Ok(shapes) => WeightedMix::factory(shapes).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match mix {
+            Ok(mix) => Some(mix),
+            Err(e) => {
+                error!("Invalid traffic mix: {}", e);
+                return;
+            }
+        }
+    };
+    let corpus = match args.header_corpus {
+        Some(ref path) => match HeaderCorpus::from_file(path) {
+            Ok(corpus) => Some(Arc::new(corpus)),
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => None,
+    };
+    if mix.is_some() && corpus.is_some() {
+        error!("--header-corpus doesn't apply to data configs with a traffic mix");
+        return;
+    }
+    let targets = match args.targets_file {
+        Some(ref path) => match read_targets_file(path) {
+            Ok(targets) => targets,
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => args.server_urls.clone(),
     };
+    if args.dry_run {
+        print_dry_run(&args, &data_config, &dummy_data, &targets);
+        return;
+    }
+    if args.processes > 1 && !args.worker {
+        run_workers(&args);
+        return;
+    }
 
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(args.thread_count)
-        .enable_all()
-        .build()
-        .unwrap();
-    let client = {
-        let client = runtime.block_on(async move {
-            ExternalProcessorClient::connect(args.server_url.clone()).await
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.worker_threads(args.thread_count).enable_all();
+    pin_runtime_threads(&mut runtime, args.worker_cores.clone());
+    let runtime = runtime.build().unwrap();
+    // Through Envoy, only Envoy talks to the servers
+    let mut processors = Vec::new();
+    #[cfg(unix)]
+    let uds_path = args.uds.clone();
+    #[cfg(not(unix))]
+    let uds_path: Option<PathBuf> = None;
+    if args.envoy_url.is_none() {
+        #[allow(unused_mut)]
+        let mut client_tls = ClientTls::default();
+        #[cfg(feature = "tls")]
+        {
+            client_tls.ca = args.tls_ca.clone();
+            client_tls.identity = args.tls_cert.clone().zip(args.tls_key.clone());
+            client_tls.domain = args.tls_domain.clone();
+        }
+        for target in targets {
+            let endpoint = match Endpoint::from_shared(target.url.clone()) {
+                Ok(endpoint) => endpoint,
+                Err(e) => {
+                    error!("Invalid server URL {}: {}", target.url, e);
+                    return;
+                }
+            };
+            if endpoint.uri().scheme_str() == Some("https") && !tls::ENABLED {
+                error!("{} needs TLS, build with the tls feature", target.url);
+                return;
+            }
+            let endpoint = match secure_endpoint(endpoint, &client_tls) {
+                Ok(endpoint) => endpoint,
+                Err(e) => {
+                    error!("Could not configure TLS for {}: {}", target.url, e);
+                    return;
+                }
+            };
+            // Each channel is its own HTTP/2 connection, with its own flow control windows
+            for _ in 0..args.connections {
+                let channel = runtime.block_on(uds::connect(&endpoint, uds_path.as_deref()));
+                if let Err(e) = channel {
+                    error!("Could not connect to server {}: {}", target.url, e);
+                    return;
+                }
+                processors.push(WeightedProcessor {
+                    url: target.url.clone(),
+                    client: ExternalProcessorClient::new(channel.unwrap()),
+                    endpoint: endpoint.clone(),
+                    weight: target.weight,
+                });
+            }
+        }
+    }
+    let reconnect = match processors.first() {
+        Some(processor) if args.reconnect => Some(Reconnect {
+            endpoint: processor.endpoint.clone(),
+            uds: uds_path,
+            backoff: Backoff {
+                max: args.reconnect_max_backoff,
+                ..Default::default()
+            },
+        }),
+        _ => None,
+    };
+    let retry = (args.retries > 0).then(|| Retry {
+        max_retries: args.retries,
+        backoff: Backoff {
+            initial: Duration::from_millis(10),
+            max: args.retry_max_backoff,
+            ..Default::default()
+        },
+    });
+    let metrics_sink = match (args.metrics_sink, &args.metrics_sink_address) {
+        (Some(format), Some(address)) => match UdpSink::connect(format, address) {
+            Ok(sink) => Some(sink),
+            Err(e) => {
+                error!("Could not connect to metrics sink: {}", e);
+                return;
+            }
+        },
+        _ => None,
+    };
+    let prometheus = match args.metrics_port {
+        Some(port) => {
+            let exporter = Arc::new(PrometheusExporter::default());
+            let _runtime = runtime.enter();
+            match exporter.clone().serve(([0, 0, 0, 0], port).into()) {
+                Ok(server) => {
+                    runtime.spawn(server);
+                    info!("Serving Prometheus metrics on port {}", port);
+                    Some(exporter)
+                }
+                Err(e) => {
+                    error!("Could not serve Prometheus metrics on port {}: {}", port, e);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    if let (Some(path), Some(processor)) = (&args.export_transcript, processors.first()) {
+        let config = Config {
+            capture_transcript: true,
+            ..args.bench_config.stream_configs().remove(0)
+        };
+        let mut stream = ClientStream::new(dummy_data.clone(), config);
+        let mut transcript_client = processor.client.clone();
+        let result = runtime.block_on(async {
+            stream.start_stream(&mut transcript_client).await?;
+            stream.handle_stream().await?;
+            stream.finish_stream();
+            Ok::<_, StreamHandleError>(())
         });
-        if let Err(e) = client {
-            error!("Could not connect to server: {}", e);
-            return;
+        if let Err(e) = result {
+            // The requests sent before the failure are still worth replaying
+            error!("Transcript transaction failed: {}", e);
         }
-        client.unwrap()
-    };
-    let metrics = Arc::new(StreamMetrics::default());
-
-    let mut benchers = Vec::with_capacity(args.bench_config.stream_concurrency);
-    let warmup_barrier = Arc::new(tokio::sync::Barrier::new(
-        args.bench_config.stream_concurrency,
-    ));
-    let after_barrier = Arc::new(tokio::sync::OnceCell::new());
-    for _ in 0..args.bench_config.stream_concurrency {
-        benchers.push(StreamBencher {
-            metrics: metrics.clone(),
-            client: client.clone(),
-            stream: ClientStream::new(
-                dummy_data.clone(),
-                Config {
-                    reuse_stream: args.bench_config.reuse_streams,
-                    max_handled: args.bench_config.stream_max_handle,
-                },
-            ),
+        match std::fs::write(path, to_grpcurl_json(&stream.transaction().transcript)) {
+            Ok(_) => info!("Transcript written to {}", path.display()),
+            Err(e) => error!("Could not write transcript {}: {}", path.display(), e),
+        }
+    }
+
+    let timestamp = file_timestamp(SystemTime::now());
+    let git_sha = [&args.output, &args.histogram_out]
+        .into_iter()
+        .flatten()
+        .any(|template| template.has_placeholder("git_sha"))
+        .then(current_git_sha)
+        .unwrap_or_default();
+    let baseline = match args.baseline {
+        Some(ref path) => match read_baseline(path) {
+            Ok(baseline) => baseline,
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                return;
+            }
+        },
+        None => Vec::new(),
+    };
+    let thresholds = Thresholds {
+        max_throughput_drop: args.max_throughput_drop,
+        max_latency_increase: args.max_latency_increase,
+        max_error_increase: args.max_error_increase,
+    };
+    let mut comparison = Vec::new();
+    let mut assertion_failures = Vec::new();
+    if args.worker {
+        if let Err(e) = await_start() {
+            error!("Could not start worker: {}", e);
+            std::process::exit(1);
+        }
+    }
+    for (run, stream_config) in args.bench_config.stream_configs().into_iter().enumerate() {
+        let label = reuse_label(&stream_config);
+        if args.bench_config.compare_reuse {
+            if run > 0 {
+                // Let the server settle streams of the previous run before starting the next one
+                runtime.block_on(tokio::time::sleep(Duration::from_secs(1)));
+            }
+            info!("Running '{}'", label);
+        }
+
+        let sink = match metrics_sink {
+            Some(ref sink) => match sink.with_tag("scenario", &label) {
+                Ok(sink) => Some(sink),
+                Err(e) => {
+                    error!("Could not create metrics sink: {}", e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let plan = BenchPlan {
+            concurrency: args.bench_config.stream_concurrency,
+            stream_pool_size: args.bench_config.stream_pool_size,
+            warmup: args.warmup,
+            duration: args.duration,
+            monitor_interval: args.monitor_interval,
             print_errors: args.bench_config.print_errors,
-            warmup_barrier: warmup_barrier.clone(),
-            after_warmup: after_barrier.clone(),
-        })
+            print_immediate_responses: args.bench_config.print_immediate_responses,
+            print_progress: !(args.quiet || args.json_only),
+            sink,
+            prometheus: prometheus.clone(),
+            monitor_core: args.monitor_core,
+            watchdog: args.bench_config.watchdog,
+            timeout: args.bench_config.timeout,
+            prestart_streams: args.bench_config.prestart_streams,
+            reconnect: reconnect.clone(),
+            retry: retry.clone(),
+            slowest_transactions: args.slowest_transactions,
+            target_rps: args.target_rps,
+            record_latencies: args.histogram_out.is_some(),
+            correct_coordinated_omission: args.correct_co,
+            transactions: args.num_transactions,
+            record_samples: matches!(
+                args.output_format,
+                Some(OutputFormat::Json | OutputFormat::Csv)
+            ),
+            scenario: mix.clone().or_else(|| replay.clone()).or_else(|| {
+                corpus
+                    .clone()
+                    .map(|corpus| corpus.scenarios(dummy_data.clone(), args.corpus_seed))
+            }),
+            ..BenchPlan::new(&label, dummy_data.clone(), stream_config)
+        };
+        let target = match args.envoy_url {
+            Some(ref url) => Target::Envoy(url.clone()),
+            None if processors.len() == 1 => Target::Processor(processors[0].client.clone()),
+            None => Target::Processors(processors.clone()),
+        };
+        let report = runtime.block_on(run_benchmark(target, plan));
+        if !args.json_only {
+            print_report(&report);
+        }
+
+        let render = |template: &OutputTemplate| {
+            let path = template.render(&[
+                ("scenario", &file_slug(&label)),
+                ("timestamp", &timestamp),
+                ("git_sha", &git_sha),
+            ]);
+            if args.bench_config.compare_reuse && !template.has_placeholder("scenario") {
+                numbered_path(&path, run)
+            } else {
+                path
+            }
+        };
+        if let (Some(format), Some(output)) = (args.output_format, &args.output) {
+            let path = render(output);
+            match format {
+                OutputFormat::Json => write_output(&path, &report.to_json()),
+                OutputFormat::Csv => write_csv(&path, &report.summary, &report.samples),
+                _ => {
+                    let mut results = format.to_json(&report.summary).unwrap_or_default();
+                    if let Some(results) = results.as_object_mut() {
+                        results.insert(
+                            "slowest_transactions".to_string(),
+                            serde_json::json!(report.slowest_transactions),
+                        );
+                    }
+                    write_output(&path, &results);
+                }
+            }
+        }
+        if let (Some(template), Some(latencies)) = (&args.histogram_out, &report.latencies) {
+            write_histogram(&render(template), latencies);
+        }
+        assertion_failures.extend(
+            assertions
+                .check(&report)
+                .into_iter()
+                .map(|failure| format!("{}: {}", label, failure)),
+        );
+        if let Some(before) = matching_run(&baseline, &label, run) {
+            let deltas = compare(before, &report.summary, &thresholds);
+            if !args.json_only {
+                info!("Compared with baseline '{}':\n{}", before.name, delta_table(&deltas));
+            }
+            assertion_failures.extend(deltas.iter().filter(|delta| delta.regressed).map(|delta| {
+                format!(
+                    "{}: {} regressed from {:.2} to {:.2}",
+                    label, delta.metric, delta.baseline, delta.current
+                )
+            }));
+        }
+        comparison.push(report);
     }
 
-    runtime.block_on(perform_benchmark(
-        Duration::from_secs(args.warmup),
-        Duration::from_secs(args.duration),
-        benchers,
-        metrics,
-    ));
+    if args.json_only {
+        let runs: Vec<_> = comparison.iter().map(BenchReport::to_json).collect();
+        println!("{}", serde_json::json!({ "runs": runs }));
+    } else if args.bench_config.compare_reuse {
+        print_comparison(&comparison);
+    }
+    if !assertion_failures.is_empty() {
+        error!("Assertions failed:\n  {}", assertion_failures.join("\n  "));
+        std::process::exit(1);
+    }
 }
 
-async fn perform_benchmark(
-    warmup: Duration,
-    duration: Duration,
-    benchers: Vec<StreamBencher>,
-    metrics: Arc<StreamMetrics>,
-) {
-    const MONITOR_INTERVAL: Duration = Duration::from_secs(2);
-    let (stop_metrics, stop_receiver) = tokio::sync::oneshot::channel();
-
-    if warmup.saturating_add(duration) > MONITOR_INTERVAL {
-        tokio::spawn(monitor_metrics(
-            metrics.clone(),
-            MONITOR_INTERVAL,
-            stop_receiver,
-        ));
+/// Runs the benchmark in --processes workers and reports their merged results
+fn run_workers(args: &Args) {
+    let program = match std::env::current_exe() {
+        Ok(program) => program,
+        Err(e) => {
+            error!("Could not find the bench_client executable: {}", e);
+            return;
+        }
+    };
+    let worker_args = worker_args(
+        std::env::args_os().skip(1),
+        &["--processes", "--output", "--output-format"],
+        &["--worker", "--json-only"],
+    );
+    let results = WorkerPool::spawn(program, &worker_args, args.processes).and_then(|mut pool| {
+        pool.start()?;
+        info!("{} workers started.", args.processes);
+        pool.finish()
+    });
+    let results = match results {
+        Ok(results) => results,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let run_count = results.iter().map(Vec::len).min().unwrap_or_default();
+    let mut merged = Vec::with_capacity(run_count);
+    for run in 0..run_count {
+        let summaries: Option<Vec<_>> = results
+            .iter()
+            .map(|runs| summary_from_json(&runs[run]))
+            .collect();
+        match summaries.as_deref().and_then(merge_summaries) {
+            Some(summary) => merged.push(summary),
+            None => {
+                error!("Workers reported incomplete results for run {}", run + 1);
+                std::process::exit(1);
+            }
+        }
     }
 
-    let mut join_set = tokio::task::JoinSet::new();
-    for bencher in benchers {
-        join_set.spawn(bencher.bench_with_warmup(warmup, duration));
+    let timestamp = file_timestamp(SystemTime::now());
+    let git_sha = match args.output {
+        Some(ref output) if output.has_placeholder("git_sha") => current_git_sha(),
+        _ => String::new(),
+    };
+    for (run, summary) in merged.iter().enumerate() {
+        if !args.json_only {
+            info!(
+                "Merged results of '{}' over {} workers: {} transactions, {} errors, {:.2} req/s, \
+                 {:.2}ms avg latency, {}ms p99 (approximate)",
+                summary.name,
+                args.processes,
+                summary.count,
+                summary.errors,
+                summary.rate(),
+                summary.latency.mean,
+                summary.latency.p99
+            );
+        }
+        if let (Some(format), Some(output)) = (args.output_format, &args.output) {
+            let path = output.render(&[
+                ("scenario", &file_slug(&summary.name)),
+                ("timestamp", &timestamp),
+                ("git_sha", &git_sha),
+            ]);
+            let path = if args.bench_config.compare_reuse && !output.has_placeholder("scenario") {
+                numbered_path(&path, run)
+            } else {
+                path
+            };
+            match format.to_json(summary) {
+                Some(results) => write_output(&path, &results),
+                None => write_csv(&path, summary, &[]),
+            }
+        }
+    }
+    if args.json_only {
+        let runs: Vec<_> = results
+            .iter()
+            .enumerate()
+            .map(|(worker, runs)| serde_json::json!({ "worker": worker, "runs": runs }))
+            .collect();
+        let merged: Vec<_> = merged
+            .iter()
+            .map(|summary| {
+                serde_json::json!({
+                    "name": summary.name,
+                    "concurrency": summary.concurrency,
+                    "count": summary.count,
+                    "errors": summary.errors,
+                    "rate": summary.rate(),
+                    "latency_ms": {
+                        "mean": summary.latency.mean,
+                        "min": summary.latency.min,
+                        "max": summary.latency.max,
+                        "p50": summary.latency.p50,
+                        "p90": summary.latency.p90,
+                        "p95": summary.latency.p95,
+                        "p99": summary.latency.p99,
+                        "p999": summary.latency.p999,
+                    },
+                })
+            })
+            .collect();
+        println!("{}", serde_json::json!({ "runs": merged, "workers": runs }));
     }
-    while join_set.join_next().await.is_some() {}
-    _ = stop_metrics.send(());
+}
 
-    info!("Benchmark finished.");
-    let serialized = serde_json::to_string_pretty(metrics.as_ref());
-    if let Err(e) = serialized {
-        error!("Could not serialize final results: {}", e);
-        return;
+/// Overrides the arguments with the profile's settings, except those given on the command line
+fn apply_profile(args: &mut Args, profile: Profile, matches: &ArgMatches) {
+    let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+    if let Some(path) = profile.data_config.filter(|_| unset("data_config_path")) {
+        args.data_config_path = Some(path.to_string_lossy().into_owned());
+    }
+    if let Some(url) = profile.server_url.filter(|_| unset("server_urls")) {
+        args.server_urls = vec![TargetSpec { url, weight: 1 }];
+    }
+    if let Some(concurrency) = profile
+        .stream_concurrency
+        .filter(|_| unset("stream_concurrency"))
+    {
+        args.bench_config.stream_concurrency = concurrency;
+    }
+    if let Some(warmup) = profile.warmup.filter(|_| unset("warmup")) {
+        args.warmup = warmup;
+    }
+    if let Some(duration) = profile.duration.filter(|_| unset("duration")) {
+        args.duration = duration;
+    }
+    if let Some(reuse) = profile.reuse_streams.filter(|_| unset("reuse_streams")) {
+        args.bench_config.reuse_streams = reuse;
+    }
+    if let Some(max) = profile.stream_max_handle.filter(|_| unset("stream_max_handle")) {
+        args.bench_config.stream_max_handle = Some(max);
     }
-    info!("Final Results:\n{}", serialized.unwrap());
 }
 
-async fn monitor_metrics(
-    metrics: Arc<StreamMetrics>,
-    interval: Duration,
-    mut stop: oneshot::Receiver<()>,
+fn print_dry_run(
+    args: &Args,
+    data_config: &serde_json::Value,
+    data: &DummyData,
+    targets: &[TargetSpec],
 ) {
-    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
-    loop {
-        interval.tick().await;
-        match stop.try_recv() {
-            Ok(_) | Err(TryRecvError::Closed) => break,
-            Err(TryRecvError::Empty) => {}
+    let runs: Vec<String> = args
+        .bench_config
+        .stream_configs()
+        .iter()
+        .map(reuse_label)
+        .collect();
+    let target = match args.envoy_url {
+        Some(ref url) => serde_json::json!({ "envoy_url": url.to_string() }),
+        None => serde_json::json!({ "servers": targets }),
+    };
+    let effective = serde_json::json!({
+        "data_config": data_config,
+        "request_body_bytes": data.req_body.len(),
+        "response_body_bytes": data.resp_body.len(),
+        "target": target,
+        "runs": runs,
+        "stream_concurrency": args.bench_config.stream_concurrency,
+        "warmup_secs": args.warmup.as_secs_f64(),
+        "duration_secs": (args.duration != Duration::MAX).then(|| args.duration.as_secs_f64()),
+        "transactions": args.num_transactions,
+    });
+    match serde_json::to_string_pretty(&effective) {
+        Ok(effective) => println!("{}", effective),
+        Err(e) => error!("Could not serialize configuration: {}", e),
+    }
+    info!("Configuration is valid.");
+}
+
+fn reuse_label(config: &Config) -> String {
+    match (config.reuse_stream, config.max_handled) {
+        (false, _) => "No Reuse".to_string(),
+        (true, None) => "Infinite Reuse".to_string(),
+        (true, Some(max)) => format!("{} Transaction Reuse", max),
+    }
+}
+
+/// Replays every recording on its own stream, returning whether all of them matched
+async fn replay_recordings(
+    server_url: &str,
+    recordings: &[PathBuf],
+    options: &ReplayOptions,
+) -> bool {
+    let endpoint = match Endpoint::from_shared(server_url.to_string()) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Invalid server URL {}: {}", server_url, e);
+            return false;
+        }
+    };
+    let endpoint = match secure_endpoint(endpoint, &ClientTls::default()) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Could not configure TLS for {}: {}", server_url, e);
+            return false;
+        }
+    };
+    let mut client = match endpoint.connect().await {
+        Ok(channel) => ExternalProcessorClient::new(channel),
+        Err(e) => {
+            error!("Could not connect to server {}: {}", server_url, e);
+            return false;
+        }
+    };
+    let mut matched = true;
+    for path in recordings {
+        let recording = match read_recording(path) {
+            Ok(recording) => recording,
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                matched = false;
+                continue;
+            }
+        };
+        let report = match replay(&mut client, &recording, options).await {
+            Ok(report) => report,
+            Err(e) => {
+                error!("{}: stream failed: {}", path.display(), e);
+                matched = false;
+                continue;
+            }
+        };
+        if report.matched() {
+            info!(
+                "{}: {} requests, {} responses, all matching",
+                path.display(),
+                report.requests,
+                report.responses
+            );
+            continue;
+        }
+        matched = false;
+        error!(
+            "{}: {} of {} responses differ",
+            path.display(),
+            report.mismatches.len(),
+            report.responses.max(report.mismatches.len())
+        );
+        for mismatch in &report.mismatches {
+            println!("{} response #{}:", path.display(), mismatch.index);
+            for line in &mismatch.diff {
+                println!("  {}", line);
+            }
         }
-        print_metrics(metrics.as_ref());
     }
+    matched
 }
 
-fn print_metrics(metrics: &StreamMetrics) {
-    let err_count = metrics.run_stream.error_count.get();
-    let throughput = metrics.run_stream.throughput.histogram();
-    let response_time = metrics.run_stream.response_time.histogram();
+fn print_report(report: &BenchReport) {
+    let latency = &report.summary.latency;
     info!(
-        "{:.2} req/s, {:.2}ms avg latency, {} errors",
-        throughput.mean(),
-        response_time.mean(),
-        err_count
+        "Latency: {:.2}ms avg, p50 {}ms, p90 {}ms, p99 {}ms, p999 {}ms, max {}ms",
+        latency.mean, latency.p50, latency.p90, latency.p99, latency.p999, latency.max
     );
+    match serde_json::to_string_pretty(report.metrics.as_ref()) {
+        Ok(metrics) => info!("Final Results:\n{}", metrics),
+        Err(e) => error!("Could not serialize final results: {}", e),
+    }
+    match serde_json::to_string_pretty(report.stream_stats.as_ref()) {
+        Ok(stats) => info!("Stream Stats:\n{}", stats),
+        Err(e) => error!("Could not serialize stream stats: {}", e),
+    }
+    let sizes = &report.stream_stats.message_sizes;
+    let (requests, responses) = (sizes.requests.histogram(), sizes.responses.histogram());
+    if requests.len() > 0 {
+        info!(
+            "Message sizes: requests {:.0}B avg ({}B p99), responses {:.0}B avg ({}B p99)",
+            requests.mean(),
+            requests.quantile(0.99),
+            responses.mean(),
+            responses.quantile(0.99)
+        );
+    }
+    let mode_override_transactions = report
+        .stream_stats
+        .mode_overrides
+        .transactions
+        .load(Ordering::Relaxed);
+    if mode_override_transactions > 0 {
+        info!(
+            "Mode overrides: received in {} of {} transactions (see mode_overrides in the stream stats)",
+            mode_override_transactions, report.summary.count
+        );
+    }
+    if let Some(ref schedule) = report.schedule {
+        let (queue_depth, latency) = (
+            schedule.queue_depth.histogram(),
+            schedule.latency_us.histogram(),
+        );
+        info!(
+            "Open loop at {} req/s: queue depth {:.1} avg ({} max), latency from due time \
+             {:.2}ms avg, p50 {:.2}ms, p99 {:.2}ms, p999 {:.2}ms",
+            schedule.target_rps,
+            queue_depth.mean(),
+            queue_depth.max(),
+            latency.mean() / 1000.0,
+            latency.quantile(0.5) as f64 / 1000.0,
+            latency.quantile(0.99) as f64 / 1000.0,
+            latency.quantile(0.999) as f64 / 1000.0
+        );
+    }
+    if let Some(ref latency) = report.corrected_latency {
+        info!(
+            "Latency corrected for coordinated omission: {:.2}ms avg, p50 {}ms, p90 {}ms, \
+             p99 {}ms, p999 {}ms, max {}ms",
+            latency.mean, latency.p50, latency.p90, latency.p99, latency.p999, latency.max
+        );
+    }
+    let kills = &report.stream_stats.stream_kills;
+    let recoveries = kills.recovery_micros.histogram();
+    if recoveries.len() > 0 {
+        info!(
+            "Stream recovery: {} streams killed, first transactions on replacement streams took \
+             {:.3}ms avg ({:.3}ms p99)",
+            kills.killed.load(Ordering::Relaxed),
+            recoveries.mean() / 1000.0,
+            recoveries.quantile(0.99) as f64 / 1000.0
+        );
+    }
+    if let Some((server, transport)) = report.latency_split() {
+        info!(
+            "Mean latency split: {:.3}ms in the server, {:.3}ms in transport and queueing",
+            server, transport
+        );
+    }
+    for (phase, latency) in report.phase_latencies() {
+        info!(
+            "Waiting for {:?} responses: {:.3}ms avg, p50 {:.3}ms, p99 {:.3}ms",
+            phase, latency.mean, latency.p50, latency.p99
+        );
+    }
+    let backpressure_events = report
+        .stream_stats
+        .backpressure_events
+        .load(Ordering::Relaxed);
+    if backpressure_events > 0 {
+        info!(
+            "Send backpressure: {} requests blocked for {:.2}s in total",
+            backpressure_events,
+            report.stream_stats.backpressure_time().as_secs_f64()
+        );
+    }
+    let error_categories: Vec<String> = ErrorCategory::ALL
+        .iter()
+        .map(|&category| (category, report.error_categories.get(category)))
+        .filter(|&(_, count)| count > 0)
+        .map(|(category, count)| format!("{} {}", category, count))
+        .collect();
+    if !error_categories.is_empty() {
+        info!("Errors by category: {}", error_categories.join(", "));
+    }
+    if report.watchdog_timeouts > 0 {
+        info!("Watchdog timeouts: {}", report.watchdog_timeouts);
+    }
+    if report.timeouts > 0 {
+        info!("Timeouts: {}", report.timeouts);
+    }
+    if report.retries > 0 {
+        info!("Retries after transient stream failures: {}", report.retries);
+    }
+    if report.reconnects > 0 {
+        info!(
+            "Reconnected {} times, {:.2}s of downtime",
+            report.reconnects,
+            report.downtime.as_secs_f64()
+        );
+    }
+    let duration = report.summary.duration.as_secs_f64();
+    for target in report.targets.iter().filter(|_| report.targets.len() > 1) {
+        let rate = if duration > 0.0 { target.count as f64 / duration } else { 0.0 };
+        info!(
+            "{} (weight {}): {} transactions, {:.2} req/s, {} errors, {:.2}ms avg latency \
+             (p50 {}ms, p99 {}ms)",
+            target.url,
+            target.weight,
+            target.count,
+            rate,
+            target.errors,
+            target.latency.mean,
+            target.latency.p50,
+            target.latency.p99
+        );
+    }
+    for scenario in &report.scenarios {
+        let share = scenario.count as f64 / report.summary.count.max(1) as f64 * 100.0;
+        info!(
+            "{}: {} transactions ({:.1}%), {} errors, {:.2}ms avg latency (p50 {}ms, p99 {}ms)",
+            scenario.name,
+            scenario.count,
+            share,
+            scenario.errors,
+            scenario.latency.mean,
+            scenario.latency.p50,
+            scenario.latency.p99
+        );
+    }
+    if !report.dynamic_metadata_keys.is_empty() {
+        let summary: Vec<String> = report
+            .dynamic_metadata_keys
+            .iter()
+            .map(|(key, count)| format!("  {}: {} transactions", key, count))
+            .collect();
+        info!("Dynamic metadata keys:\n{}", summary.join("\n"));
+    }
 }
 
-struct StreamBencher {
-    metrics: Arc<StreamMetrics>,
+/// Short hash of the checked out commit, for output file names
+fn current_git_sha() -> String {
+    let output = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => {
+            info!("Not in a git repository, using 'unknown' for {{git_sha}}");
+            "unknown".to_string()
+        }
+    }
+}
 
-    client: ExternalProcessorClient<Channel>,
-    stream: ClientStream,
-    print_errors: bool,
+/// `results.json` -> `results.2.json`
+fn numbered_path(path: &Path, run: usize) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", run + 1));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
 
-    warmup_barrier: Arc<tokio::sync::Barrier>,
-    after_warmup: Arc<tokio::sync::OnceCell<()>>,
+fn write_output(path: &Path, results: &serde_json::Value) {
+    let file = File::create(path);
+    if let Err(e) = file {
+        error!("Could not create output file {}: {}", path.display(), e);
+        return;
+    }
+    if let Err(e) = serde_json::to_writer_pretty(file.unwrap(), results) {
+        error!("Could not write output file {}: {}", path.display(), e);
+        return;
+    }
+    info!("Results written to {}", path.display());
 }
 
-impl StreamBencher {
-    async fn bench_with_warmup(mut self, warmup: Duration, duration: Duration) {
-        self.bench(warmup).await;
-        self.warmup_barrier.wait().await;
-        self.after_warmup
-            .get_or_init(|| async {
-                if !warmup.is_zero() {
-                    info!("Warmup done.");
-                }
-                self.metrics.clear();
-                info!("Benchmarking for {} seconds", duration.as_secs());
-            })
-            .await;
-        self.bench(duration).await;
-    }
-
-    async fn bench(&mut self, duration: Duration) {
-        let start = Instant::now();
-        while start.elapsed() < duration {
-            let result =
-                StreamBencher::run_stream(&self.metrics, &mut self.stream, &mut self.client).await;
-            match result {
-                Err(e) if self.print_errors => {
-                    error!("while running stream: {}", e);
-                }
-                _ => {}
-            }
+/// Writes the summary to `path`, and the samples, if any, next to it in `<stem>-samples.csv`
+fn write_csv(path: &Path, summary: &RunSummary, samples: &[Sample]) {
+    let mut outputs = vec![(path.to_path_buf(), csv::summary_to_csv(summary))];
+    if !samples.is_empty() {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let samples_path = path.with_file_name(format!("{}-samples.csv", stem));
+        outputs.push((samples_path, csv::samples_to_csv(samples)));
+    }
+    for (path, contents) in outputs {
+        match std::fs::write(&path, contents) {
+            Ok(_) => info!("Results written to {}", path.display()),
+            Err(e) => error!("Could not write output file {}: {}", path.display(), e),
         }
     }
 }
 
-#[metered::metered(registry = StreamMetrics, registry_expr = metrics)]
-impl StreamBencher {
-    #[measure([ResponseTime, Throughput, ErrorCount])]
-    async fn run_stream(
-        metrics: &StreamMetrics,
-        stream: &mut ClientStream,
-        client: &mut ExternalProcessorClient<Channel>,
-    ) -> Result<(), StreamHandleError> {
-        stream.start_stream(client).await?;
-        stream.handle_stream().await?;
-        stream.finish_stream();
-        Ok(())
+fn write_histogram(path: &Path, latencies: &Histogram<u64>) {
+    let file = File::create(path);
+    if let Err(e) = file {
+        error!("Could not create histogram file {}: {}", path.display(), e);
+        return;
+    }
+    let mut writer = BufWriter::new(file.unwrap());
+    if let Err(e) = hgrm::write_percentiles(latencies, 1000.0, &mut writer).and(writer.flush()) {
+        error!("Could not write histogram file {}: {}", path.display(), e);
+        return;
+    }
+    info!("Latency histogram written to {}", path.display());
+}
+
+/// Prints a markdown table comparing the runs, relative to the first run
+fn print_comparison(runs: &[BenchReport]) {
+    let mut table = String::from(
+        "| Scenario | RPS | Change | Mean Latency (ms) | p99 Latency (ms) | Errors |\n\
+         |----------|-----|--------|-------------------|------------------|--------|\n",
+    );
+    let baseline_rps = runs
+        .first()
+        .map(BenchReport::mean_throughput)
+        .unwrap_or_default();
+    for report in runs {
+        let summary = &report.summary;
+        let change = if baseline_rps > 0.0 {
+            format!("{:+.1}%", (report.mean_throughput() / baseline_rps - 1.0) * 100.0)
+        } else {
+            "-".to_string()
+        };
+        table.push_str(&format!(
+            "| {} | {:.0} | {} | {:.2} | {} | {} |\n",
+            summary.name,
+            report.mean_throughput(),
+            change,
+            summary.latency.mean,
+            summary.latency.p99,
+            summary.errors
+        ));
     }
-}
\ No newline at end of file
+    info!("Reuse comparison:\n{}", table);
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use clap::Parser;
+use ext_proc_playground::{
+    cli::parse_millis,
+    conformance::{run_conformance, Check, Outcome},
+    proto::envoy::service::ext_proc::v3::external_processor_client::ExternalProcessorClient,
+    tls::{secure_endpoint, ClientTls},
+};
+use log::error;
+use tonic::transport::Endpoint;
+
+/// Runs scripted streams against an ext_proc server and reports which parts of the protocol it
+/// handles as Envoy expects
+#[derive(Parser, Debug)]
+struct Args {
+    /// Server to check
+    #[arg(default_value = "http://[::1]:50051")]
+    server_url: String,
+
+    /// Run only these checks (repeatable), all of them by default
+    #[arg(long = "check", value_enum, value_name = "CHECK")]
+    checks: Vec<Check>,
+
+    /// How long to wait for each response (bare numbers are milliseconds)
+    #[arg(long, default_value = "1000", value_parser = parse_millis)]
+    timeout: Duration,
+}
+
+fn main() {
+    let args = Args::parse();
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let checks = if args.checks.is_empty() {
+        Check::ALL.to_vec()
+    } else {
+        args.checks.clone()
+    };
+    let endpoint = match Endpoint::from_shared(args.server_url.clone()) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Invalid server URL {}: {}", args.server_url, e);
+            std::process::exit(2);
+        }
+    };
+    let endpoint = match secure_endpoint(endpoint, &ClientTls::default()) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Could not configure TLS for {}: {}", args.server_url, e);
+            std::process::exit(2);
+        }
+    };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let results = runtime.block_on(async {
+        let channel = endpoint.connect().await?;
+        let mut client = ExternalProcessorClient::new(channel);
+        Ok::<_, tonic::transport::Error>(run_conformance(&mut client, &checks, args.timeout).await)
+    });
+    let results = match results {
+        Ok(results) => results,
+        Err(e) => {
+            error!("Could not connect to server {}: {}", args.server_url, e);
+            std::process::exit(2);
+        }
+    };
+
+    for result in &results {
+        let duration = result.duration.as_secs_f64() * 1000.0;
+        if result.detail.is_empty() {
+            println!("{:<4} {:<20} {:>8.1}ms", result.outcome, result.check.name(), duration);
+        } else {
+            println!(
+                "{:<4} {:<20} {:>8.1}ms  {}",
+                result.outcome,
+                result.check.name(),
+                duration,
+                result.detail
+            );
+        }
+    }
+    let count = |outcome| results.iter().filter(|r| r.outcome == outcome).count();
+    let failed = count(Outcome::Failed);
+    println!(
+        "{} passed, {} failed, {} not exercised",
+        count(Outcome::Passed),
+        failed,
+        count(Outcome::NotExercised)
+    );
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
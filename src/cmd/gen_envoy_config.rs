@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use clap::Parser;
+use ext_proc_playground::{
+    cli::parse_millis, dummy::server::mode::ProcessingModeArgs, filter_config::FilterConfig,
+};
+
+/// Prints the Envoy ext_proc filter and cluster YAML matching run_server and bench_client settings
+#[derive(Parser, Debug)]
+struct Args {
+    /// Address of the External Processor gRPC server, as seen from Envoy
+    #[arg(long, default_value = "127.0.0.1")]
+    server_address: String,
+
+    /// Port of the External Processor gRPC server
+    #[arg(short, default_value_t = 50051)]
+    port: u16,
+
+    // Same as run_server's processing mode flags
+    #[command(flatten)]
+    processing_mode: ProcessingModeArgs,
+
+    /// Same as bench_client's --observability-mode
+    #[arg(long)]
+    observability_mode: bool,
+
+    /// Same as bench_client's --message-timeout
+    #[arg(long, visible_alias = "message-timeout-ms", default_value = "200ms", value_parser = parse_millis)]
+    message_timeout: Duration,
+
+    /// Envoy's max_message_timeout, bounding the message timeout extensions servers can ask for
+    /// (bare numbers are milliseconds)
+    #[arg(long, visible_alias = "max-message-timeout-ms", value_parser = parse_millis)]
+    max_message_timeout: Option<Duration>,
+
+    /// Let requests through when the server fails or times out
+    #[arg(long)]
+    failure_mode_allow: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    let config = FilterConfig {
+        server_address: args.server_address,
+        server_port: args.port,
+        processing_mode: args.processing_mode.processing_mode(),
+        message_timeout: args.message_timeout,
+        max_message_timeout: args.max_message_timeout,
+        failure_mode_allow: args.failure_mode_allow,
+        async_mode: args.observability_mode,
+        ..Default::default()
+    };
+    println!("# HttpConnectionManager http_filters, before envoy.filters.http.router");
+    print!("{}", config.filter_yaml());
+    println!();
+    println!("# static_resources clusters");
+    print!("{}", config.cluster_yaml());
+}
@@ -1,15 +1,31 @@
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
 use clap::Parser;
 use ext_proc_playground::{
-    dummy::server::ExtProcService,
-    proto::envoy::{
-        extensions::filters::http::ext_proc::v3::{
-            processing_mode::{BodySendMode, HeaderSendMode},
-            ProcessingMode,
-        },
-        service::ext_proc::v3::external_processor_server::ExternalProcessorServer,
+    affinity::pin_runtime_threads,
+    cli::{file_slug, parse_secs},
+    dummy::server::{
+        behavior::{BehaviorConfig, SharedBehavior},
+        metrics::{self, MetricsInterceptor, ServerMetrics},
+        mode::ProcessingModeArgs,
+        recording::RecordingInterceptor,
+        reflection::reflection_service,
+        shutdown_requested, ExtProcService,
     },
+    proto::envoy::service::ext_proc::v3::external_processor_server::ExternalProcessorServer,
+    report::prometheus,
 };
-use log::{error, info};
+use futures::future::{self, Either};
+use log::{error, info, warn};
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
+use tokio::sync::watch;
 use tonic::transport::Server;
 
 #[derive(Parser, Debug)]
@@ -18,9 +34,149 @@ struct Args {
     #[arg(short, default_value_t = 2)]
     thread_count: usize,
 
+    /// Cores to pin runtime threads to, round robin (e.g. 2,3)
+    #[arg(long, value_delimiter = ',')]
+    worker_cores: Vec<usize>,
+
     // Port to listen to
     #[arg(short, default_value_t = 50051)]
     port: u16,
+
+    /// Listen on this unix domain socket instead of the port, like an ext_proc sidecar
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "port")]
+    uds: Option<PathBuf>,
+
+    /// Path to server behavior json config file (see [`ext_proc_playground::dummy::server::behavior::BehaviorConfig`])
+    #[arg(long)]
+    behavior_config: Option<String>,
+
+    #[command(flatten)]
+    processing_mode: ProcessingModeArgs,
+
+    /// Validate the order of incoming messages against the processing mode and end streams that
+    /// violate it with a descriptive status
+    #[arg(long, visible_alias = "strict")]
+    strict_ordering: bool,
+
+    /// Reload behavior configs whenever their file changes (they are also reloaded on SIGHUP).
+    /// Open streams are kept, their next messages are handled with the new behavior
+    #[arg(long)]
+    watch_behavior_config: bool,
+
+    /// Close streams that receive no message for this long (e.g. 30s, bare numbers are seconds),
+    /// with an OK status, so idle reused streams don't hold server resources forever
+    #[arg(long, visible_alias = "idle-timeout-secs", value_parser = parse_secs)]
+    idle_timeout: Option<Duration>,
+
+    /// On SIGINT or SIGTERM, how long to let open streams finish their transactions after new
+    /// connections are refused, before exiting anyway (bare numbers are seconds). A second
+    /// signal exits immediately
+    #[arg(long, default_value = "30s", value_parser = parse_secs)]
+    drain_timeout: Duration,
+
+    /// Seed of the faults injected by behavior configs, so a flaky server profile fails the same
+    /// way every run
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Largest request or response in bytes, encoded. Streams exceeding it are ended with a
+    /// RESOURCE_EXHAUSTED status
+    #[arg(long)]
+    max_message_size: Option<usize>,
+
+    /// Report how long each message took to answer in a response header mutation, so clients
+    /// can tell server time from transport time
+    #[arg(long)]
+    report_processing_time: bool,
+
+    /// Hold the responses to STREAMED body chunks until the body ends, then send them all, like a
+    /// processor needing whole bodies. For clients with --pipelined-bodies
+    #[arg(long)]
+    hold_chunk_responses: bool,
+
+    /// Record the messages of every stream in this directory, a length-delimited protobuf file
+    /// per stream (see [`ext_proc_playground::dummy::server::recording`]), under a
+    /// subdirectory per instance
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Don't serve gRPC reflection next to the ext_proc service
+    #[arg(long)]
+    no_reflection: bool,
+
+    /// Serve server metrics (requests by message type, open streams, processing time, stream
+    /// duration) of every instance to Prometheus scrapes on this port, at `/metrics`
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// PEM certificate chain to serve TLS with, on every instance
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA certificate clients must present a certificate signed by (mutual TLS)
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    /// Additional instance with its own behavior, as `<port or unix:path>[=<behavior config>]`,
+    /// e.g. `50052=bench/fixtures/deny_behavior.json`. May be repeated
+    #[arg(long = "instance", value_parser = parse_instance)]
+    instances: Vec<InstanceSpec>,
+}
+
+/// Where an instance listens
+#[derive(Debug, Clone)]
+enum Listen {
+    Port(u16),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Display for Listen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listen::Port(port) => write!(f, "[::1]:{}", port),
+            #[cfg(unix)]
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An additional ExtProcService instance
+#[derive(Debug, Clone)]
+struct InstanceSpec {
+    listen: Listen,
+    behavior_config: Option<PathBuf>,
+}
+
+/// `<port or unix:path>[=<behavior config path>]`
+fn parse_instance(spec: &str) -> Result<InstanceSpec, String> {
+    let (listen, behavior_config) = match spec.split_once('=') {
+        Some((listen, path)) => (listen, Some(PathBuf::from(path))),
+        None => (spec, None),
+    };
+    let listen = match listen.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => Listen::Unix(PathBuf::from(path)),
+        #[cfg(not(unix))]
+        Some(_) => return Err("unix domain sockets are not supported".to_string()),
+        None => Listen::Port(
+            listen
+                .parse()
+                .map_err(|e| format!("invalid port '{}': {}", listen, e))?,
+        ),
+    };
+    Ok(InstanceSpec {
+        listen,
+        behavior_config,
+    })
 }
 
 fn main() {
@@ -29,31 +185,246 @@ fn main() {
         .init();
     let args = Args::parse();
 
-    let mut processing_mode = ProcessingMode::default();
-    processing_mode.set_request_header_mode(HeaderSendMode::Send);
-    processing_mode.set_response_header_mode(HeaderSendMode::Send);
-    processing_mode.set_request_body_mode(BodySendMode::Buffered);
-    processing_mode.set_response_body_mode(BodySendMode::Buffered);
-    processing_mode.set_request_trailer_mode(HeaderSendMode::Skip);
-    processing_mode.set_response_trailer_mode(HeaderSendMode::Skip);
-
-    let service = ExtProcService::new(processing_mode);
-
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(args.thread_count)
-        .enable_all()
-        .build()
-        .unwrap();
+    #[allow(unused_mut)]
+    let mut listen = Listen::Port(args.port);
+    #[cfg(unix)]
+    if let Some(ref path) = args.uds {
+        listen = Listen::Unix(path.clone());
+    }
+    let mut instances = vec![InstanceSpec {
+        listen,
+        behavior_config: args.behavior_config.as_ref().map(PathBuf::from),
+    }];
+    instances.extend(args.instances.iter().cloned());
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.worker_threads(args.thread_count).enable_all();
+    pin_runtime_threads(&mut runtime, args.worker_cores.clone());
+    let runtime = runtime.build().unwrap();
+
+    #[allow(unused_mut)]
+    let mut server = Server::builder();
+    #[cfg(feature = "tls")]
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        let client_ca = args.tls_ca.as_ref();
+        let tls_config = match ext_proc_playground::tls::server_tls_config(cert, key, client_ca) {
+            Ok(tls_config) => tls_config,
+            Err(e) => {
+                error!("Could not load TLS certificates: {}", e);
+                return;
+            }
+        };
+        server = match server.tls_config(tls_config) {
+            Ok(server) => server,
+            Err(e) => {
+                error!("Invalid TLS configuration: {}", e);
+                return;
+            }
+        };
+    }
+
+    let (shutdown, shutdown_receiver) = watch::channel(false);
+    let mut servers = Vec::with_capacity(instances.len());
+    let mut server_metrics = Vec::with_capacity(instances.len());
+    for instance in instances {
+        let behavior = match instance.behavior_config {
+            Some(ref path) => {
+                let config = BehaviorConfig::from_file(path);
+                if let Err(e) = config {
+                    error!("{}: {}", path.display(), e);
+                    return;
+                }
+                config.unwrap()
+            }
+            None => BehaviorConfig::default(),
+        };
+        let processing_mode = args.processing_mode.processing_mode();
+        let service = ExtProcService::new(processing_mode, behavior)
+            .with_strict_ordering(args.strict_ordering)
+            .with_idle_timeout(args.idle_timeout)
+            .with_seed(args.seed)
+            .with_max_message_size(args.max_message_size)
+            .with_processing_time(args.report_processing_time)
+            .with_held_chunk_responses(args.hold_chunk_responses)
+            .with_shutdown(shutdown_receiver.clone());
+        let service = match args.record {
+            Some(ref dir) => {
+                let dir = dir.join(file_slug(&instance.listen.to_string()));
+                match RecordingInterceptor::new(&dir) {
+                    Ok(recording) => service.with_interceptor(recording),
+                    Err(e) => {
+                        error!("Could not record in {}: {}", dir.display(), e);
+                        return;
+                    }
+                }
+            }
+            None => service,
+        };
+        let service = match args.metrics_port {
+            Some(_) => {
+                let metrics = Arc::new(ServerMetrics::default());
+                server_metrics.push((instance.listen.to_string(), metrics.clone()));
+                service.with_interceptor(MetricsInterceptor::new(metrics))
+            }
+            None => service,
+        };
+
+        if let Some(path) = instance.behavior_config {
+            #[cfg(unix)]
+            runtime.spawn(reload_on_hangup(path.clone(), service.behavior()));
+            if args.watch_behavior_config {
+                runtime.spawn(watch_behavior(path, service.behavior()));
+            }
+        }
+        let reflection = !args.no_reflection;
+        let served = serve(
+            server.clone(),
+            instance.listen,
+            service,
+            reflection,
+            shutdown_receiver.clone(),
+        );
+        servers.push(runtime.spawn(served));
+    }
+    if let Some(port) = args.metrics_port {
+        let _runtime = runtime.enter();
+        let render = move || metrics::render(&server_metrics);
+        match prometheus::serve(([0, 0, 0, 0], port).into(), render) {
+            Ok(endpoint) => {
+                runtime.spawn(endpoint);
+                info!("Serving Prometheus metrics on port {}", port);
+            }
+            Err(e) => {
+                error!("Could not serve Prometheus metrics on port {}: {}", port, e);
+                return;
+            }
+        }
+    }
+    let drain_timeout = args.drain_timeout;
     runtime.block_on(async move {
-        let address = format!("[::1]:{}", args.port);
-        info!("Serving {}", address);
-        let server = ExternalProcessorServer::new(service);
-        if let Err(e) = Server::builder()
-            .add_service(server)
-            .serve(format!("[::1]:{}", args.port).parse().unwrap())
-            .await
+        let served = future::join_all(servers);
+        let served = match future::select(served, Box::pin(termination_signal())).await {
+            // Every instance failed
+            Either::Left(_) => return,
+            Either::Right((_, served)) => served,
+        };
+        info!("Shutting down, draining open streams for up to {:?}", drain_timeout);
+        _ = shutdown.send(true);
+        let deadline = Box::pin(tokio::time::sleep(drain_timeout));
+        match future::select(served, future::select(deadline, Box::pin(termination_signal()))).await
         {
-            error!("error serving gRPC: {}", e);
+            Either::Left(_) => info!("All streams drained"),
+            Either::Right(_) => warn!("Exiting with streams still open"),
         }
     });
 }
+
+/// Resolves on SIGINT (Ctrl-C), or SIGTERM on unix
+async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                let interrupt = Box::pin(tokio::signal::ctrl_c());
+                future::select(interrupt, Box::pin(terminate.recv())).await;
+                return;
+            }
+            Err(e) => error!("Could not listen to SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Could not listen to SIGINT: {}", e);
+        future::pending::<()>().await;
+    }
+}
+
+/// Serves `service` until `shutdown` turns true, then refuses new connections and returns once
+/// the open ones are closed
+async fn serve(
+    mut server: Server,
+    listen: Listen,
+    service: ExtProcService,
+    reflection: bool,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    info!("Serving {}", listen);
+    let reflection = match reflection.then(reflection_service) {
+        Some(Err(e)) => {
+            error!("Could not serve gRPC reflection on {}: {}", listen, e);
+            None
+        }
+        reflection => reflection.and_then(Result::ok),
+    };
+    let router = server
+        .add_service(ExternalProcessorServer::new(service))
+        .add_optional_service(reflection);
+    let shutdown = async move { shutdown_requested(&mut shutdown).await };
+    let result = match listen {
+        Listen::Port(port) => {
+            router
+                .serve_with_shutdown(format!("[::1]:{}", port).parse().unwrap(), shutdown)
+                .await
+        }
+        #[cfg(unix)]
+        Listen::Unix(ref path) => {
+            // A socket left behind by a previous run would fail the bind
+            _ = fs::remove_file(path);
+            match tokio::net::UnixListener::bind(path) {
+                Ok(listener) => {
+                    router
+                        .serve_with_incoming_shutdown(UnixListenerStream::new(listener), shutdown)
+                        .await
+                }
+                Err(e) => {
+                    error!("Could not bind {}: {}", listen, e);
+                    return;
+                }
+            }
+        }
+    };
+    if let Err(e) = result {
+        error!("error serving gRPC on {}: {}", listen, e);
+    }
+}
+
+fn reload_behavior(path: &Path, behavior: &SharedBehavior) {
+    match BehaviorConfig::from_file(path) {
+        Ok(config) => {
+            behavior.store(config);
+            info!("Reloaded behavior config {}", path.display());
+        }
+        // Keep the current behavior until the file is fixed
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Reloads the behavior config when its modification time changes
+async fn watch_behavior(path: PathBuf, behavior: SharedBehavior) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let current = modified(&path);
+        if current != last_modified {
+            last_modified = current;
+            reload_behavior(&path, &behavior);
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(path: PathBuf, behavior: SharedBehavior) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Could not listen to SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        reload_behavior(&path, &behavior);
+    }
+}
@@ -0,0 +1,329 @@
+//! Conformance checks of ext_proc servers: scripted streams exercising one part of the protocol
+//! each, failing when the server answers a message with the wrong response, answers it twice,
+//! answers after the end of a transaction, errors or doesn't answer in time
+
+use std::{
+    fmt::{Display, Formatter},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    dummy::{
+        client::{transaction::Transaction, transport::Transport, ClientStream, Config},
+        DummyData,
+    },
+    proto::envoy::{
+        config::core::v3::{HeaderMap, HeaderValue},
+        service::ext_proc::v3::{processing_request::Request, HttpHeaders, ProcessingRequest},
+    },
+};
+
+/// How long to keep listening after a transaction for responses that shouldn't come, at most
+const SETTLE_TIME: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Check {
+    /// Request and response headers, without bodies
+    HeadersOnly,
+    /// Bodies sent whole, in BUFFERED mode
+    BufferedBody,
+    /// Bodies sent in small chunks, in STREAMED mode
+    StreamedBody,
+    /// Request and response trailers
+    Trailers,
+    /// The server's mode_overrides, if it sends any, are followed for the rest of the transaction
+    ModeOverride,
+    /// An ImmediateResponse, if the server sends one, ends the transaction
+    ImmediateResponse,
+    /// Streams reset or half-closed in the middle of a transaction don't disturb the server
+    MidStreamClose,
+}
+
+impl Check {
+    pub const ALL: [Check; 7] = [
+        Check::HeadersOnly,
+        Check::BufferedBody,
+        Check::StreamedBody,
+        Check::Trailers,
+        Check::ModeOverride,
+        Check::ImmediateResponse,
+        Check::MidStreamClose,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::HeadersOnly => "headers-only",
+            Check::BufferedBody => "buffered-body",
+            Check::StreamedBody => "streamed-body",
+            Check::Trailers => "trailers",
+            Check::ModeOverride => "mode-override",
+            Check::ImmediateResponse => "immediate-response",
+            Check::MidStreamClose => "mid-stream-close",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    /// The server never did what the check is about (e.g. it sent no mode_override), which
+    /// isn't a failure
+    NotExercised,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Outcome::Passed => "PASS",
+            Outcome::Failed => "FAIL",
+            Outcome::NotExercised => "N/A",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub check: Check,
+    pub outcome: Outcome,
+    /// Why the check failed or wasn't exercised, or what the server did
+    pub detail: String,
+    pub duration: Duration,
+}
+
+/// Runs `checks` in order, each on new streams of `transport`. `timeout` bounds the wait for
+/// every response
+pub async fn run_conformance<T: Transport>(
+    transport: &mut T,
+    checks: &[Check],
+    timeout: Duration,
+) -> Vec<CheckResult> {
+    let mut results = Vec::with_capacity(checks.len());
+    for &check in checks {
+        let started = Instant::now();
+        let (outcome, detail) = match run_check(transport, check, timeout).await {
+            Ok((outcome, detail)) => (outcome, detail),
+            Err(detail) => (Outcome::Failed, detail),
+        };
+        results.push(CheckResult {
+            check,
+            outcome,
+            detail,
+            duration: started.elapsed(),
+        });
+    }
+    results
+}
+
+async fn run_check<T: Transport>(
+    transport: &mut T,
+    check: Check,
+    timeout: Duration,
+) -> Result<(Outcome, String), String> {
+    let passed = |detail: &str| Ok((Outcome::Passed, detail.to_string()));
+    match check {
+        Check::HeadersOnly => {
+            run_transaction(transport, headers_data(), Config::default(), timeout).await?;
+            passed("")
+        }
+        Check::BufferedBody => {
+            run_transaction(transport, body_data(), Config::default(), timeout).await?;
+            passed("")
+        }
+        Check::StreamedBody => {
+            let config = Config {
+                streamed_bodies: true,
+                body_chunk_size: Some(4),
+                ..Default::default()
+            };
+            run_transaction(transport, body_data(), config, timeout).await?;
+            passed("")
+        }
+        Check::Trailers => {
+            let config = Config {
+                send_trailers: true,
+                ..Default::default()
+            };
+            run_transaction(transport, trailers_data(), config, timeout).await?;
+            passed("")
+        }
+        Check::ModeOverride => {
+            let transaction =
+                run_transaction(transport, trailers_data(), Config::default(), timeout).await?;
+            match transaction.mode_overrides {
+                0 => Ok((
+                    Outcome::NotExercised,
+                    "The server sent no mode_override".to_string(),
+                )),
+                overrides => passed(&format!("{} mode_overrides followed", overrides)),
+            }
+        }
+        Check::ImmediateResponse => {
+            let transaction =
+                run_transaction(transport, body_data(), Config::default(), timeout).await?;
+            match transaction.immediate_response {
+                Some(immediate_response) => passed(&format!(
+                    "Denied with status {}",
+                    immediate_response.status
+                )),
+                None => Ok((
+                    Outcome::NotExercised,
+                    "The server sent no ImmediateResponse".to_string(),
+                )),
+            }
+        }
+        Check::MidStreamClose => {
+            close_mid_stream(transport, timeout).await?;
+            run_transaction(transport, headers_data(), Config::default(), timeout)
+                .await
+                .map_err(|e| format!("No new stream served after the closed ones: {}", e))?;
+            passed("")
+        }
+    }
+}
+
+/// Runs a transaction of `data` on its own stream, then checks that nothing else arrives
+async fn run_transaction<T: Transport>(
+    transport: &mut T,
+    data: DummyData,
+    config: Config,
+    timeout: Duration,
+) -> Result<Transaction, String> {
+    let config = Config {
+        message_timeout: Some(timeout),
+        fail_on_violation: true,
+        ..config
+    };
+    let mut stream = ClientStream::new(Arc::new(data), config);
+    stream
+        .start_stream(transport)
+        .await
+        .map_err(|status| format!("Could not open a stream: {}", status))?;
+    stream.handle_stream().await.map_err(|e| e.to_string())?;
+    tokio::time::sleep(SETTLE_TIME.min(timeout)).await;
+    stream.drain().map_err(|e| e.to_string())?;
+    let transaction = stream.take_transaction();
+    stream.finish_stream();
+    Ok(transaction)
+}
+
+/// Resets a stream right after its request headers, then half-closes another one, which the
+/// server must end in time
+async fn close_mid_stream<T: Transport>(
+    transport: &mut T,
+    timeout: Duration,
+) -> Result<(), String> {
+    for reset in [true, false] {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        let mut responses = transport
+            .open(ReceiverStream::new(receiver))
+            .await
+            .map_err(|status| format!("Could not open a stream: {}", status))?;
+        sender
+            .send(headers_request())
+            .await
+            .map_err(|_| "The stream closed before its first request".to_string())?;
+        if reset {
+            continue;
+        }
+        drop(sender);
+        // The server may answer the headers, or end the stream with an error status
+        let end = async { while let Ok(Some(_)) = responses.message().await {} };
+        if tokio::time::timeout(timeout, end).await.is_err() {
+            return Err("A half-closed stream wasn't ended by the server".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn headers(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn headers_data() -> DummyData {
+    DummyData {
+        req_headers: headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":authority", "conformance.test"),
+            (":path", "/"),
+        ]),
+        resp_status: 200,
+        resp_headers: headers(&[(":status", "200")]),
+        ..Default::default()
+    }
+}
+
+fn body_data() -> DummyData {
+    let mut data = headers_data();
+    data.req_headers[0].1 = "POST".to_string();
+    data.req_body = b"conformance request body".to_vec();
+    data.resp_body = b"conformance response body".to_vec();
+    data
+}
+
+fn trailers_data() -> DummyData {
+    DummyData {
+        req_trailers: headers(&[("x-request-trailer", "1")]),
+        resp_trailers: headers(&[("grpc-status", "0")]),
+        ..body_data()
+    }
+}
+
+fn headers_request() -> ProcessingRequest {
+    let headers = headers_data()
+        .req_headers
+        .into_iter()
+        .map(|(key, value)| HeaderValue { key, value })
+        .collect();
+    ProcessingRequest {
+        async_mode: false,
+        request: Some(Request::RequestHeaders(HttpHeaders {
+            headers: Some(HeaderMap { headers }),
+            ..Default::default()
+        })),
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::{
+        dummy::server::{behavior::BehaviorConfig, server_processing_mode, ExtProcService},
+        proto::envoy::service::ext_proc::v3::{
+            external_processor_client::ExternalProcessorClient,
+            external_processor_server::ExternalProcessorServer,
+        },
+    };
+
+    #[test]
+    fn the_dummy_server_conforms() {
+        let service = ExtProcService::new(server_processing_mode(false), BehaviorConfig::default());
+        let mut client = ExternalProcessorClient::new(ExternalProcessorServer::new(service));
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let results = runtime.block_on(run_conformance(
+            &mut client,
+            &Check::ALL,
+            Duration::from_secs(5),
+        ));
+        assert_eq!(results.len(), Check::ALL.len());
+        for result in results {
+            assert_ne!(
+                result.outcome,
+                Outcome::Failed,
+                "{}: {}",
+                result.check.name(),
+                result.detail
+            );
+        }
+    }
+}
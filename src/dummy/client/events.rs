@@ -0,0 +1,108 @@
+//! Lifecycle of the transactions of a [`super::ClientStream`], for tooling rendering or asserting
+//! on them (see [`super::ClientStream::events`])
+
+use super::{transaction::ImmediateResponseReport, Phase};
+use crate::proto::envoy::{
+    extensions::filters::http::ext_proc::v3::ProcessingMode,
+    service::ext_proc::v3::processing_response::Response,
+};
+
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    TransactionStarted {
+        /// Transactions the stream handled before this one, 0 on a new stream
+        index: usize,
+    },
+    PhaseSent {
+        phase: Phase,
+    },
+    /// The response to the message of `phase`, carrying the server's mutations
+    ResponseReceived {
+        phase: Phase,
+        mutations: Response,
+    },
+    OverrideApplied(Override),
+    TransactionFinished {
+        outcome: TransactionOutcome,
+    },
+}
+
+/// Server requests changing how the rest of the stream is handled
+#[derive(Debug, Clone, PartialEq)]
+pub enum Override {
+    /// mode_override set the processing mode of the next messages
+    ProcessingMode(ProcessingMode),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionOutcome {
+    /// Every message was answered and the mutations checked out
+    Completed,
+    /// The server answered the HTTP transaction locally
+    Denied(ImmediateResponseReport),
+    /// The transaction failed, with the error's description
+    Failed(String),
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::dummy::{
+        client::{ClientStream, Config},
+        in_memory::in_memory_client,
+        server::{behavior::BehaviorConfig, server_processing_mode, ExtProcService},
+        DummyData,
+    };
+
+    #[test]
+    fn transaction_lifecycle_is_reported() {
+        let service = ExtProcService::new(server_processing_mode(false), BehaviorConfig::default());
+        let data = DummyData {
+            req_headers: vec![(":method".to_string(), "GET".to_string())],
+            resp_status: 200,
+            resp_headers: vec![(":status".to_string(), "200".to_string())],
+            ..Default::default()
+        };
+        let mut stream = ClientStream::new(Arc::new(data), Config::default());
+        let mut events = stream.events().into_inner();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut client = in_memory_client(service).await.unwrap();
+            stream.start_stream(&mut client).await.unwrap();
+            stream.handle_stream().await.unwrap();
+        });
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert!(matches!(
+            received.first(),
+            Some(StreamEvent::TransactionStarted { index: 0 })
+        ));
+        assert!(matches!(
+            received.get(1),
+            Some(StreamEvent::PhaseSent {
+                phase: Phase::RequestHeaders
+            })
+        ));
+        assert!(received.iter().any(|event| matches!(
+            event,
+            StreamEvent::ResponseReceived {
+                phase: Phase::ResponseHeaders,
+                mutations: Response::ResponseHeaders(_)
+            }
+        )));
+        assert!(matches!(
+            received.last(),
+            Some(StreamEvent::TransactionFinished {
+                outcome: TransactionOutcome::Completed
+            })
+        ));
+    }
+}
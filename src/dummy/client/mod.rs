@@ -1,7 +1,28 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    ops::Range,
+    sync::Arc,
+    time::Duration,
+};
 
-use tokio::sync::mpsc::Sender;
-use tonic::{transport::Channel, Status, Streaming};
+use futures::{
+    future::{self, Either},
+    FutureExt,
+};
+use log::debug;
+use metered::{hdr_histogram::AtomicHdrHistogram, metric::Histogram};
+use prost::Message;
+use serde::Serialize;
+use tokio::{
+    sync::mpsc::{
+        error::{SendError, TrySendError},
+        Sender, UnboundedSender,
+    },
+    time::Instant,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{Status, Streaming};
 
 use crate::proto::envoy::{
     config::core::v3::{HeaderMap, HeaderValue},
@@ -10,15 +31,33 @@ use crate::proto::envoy::{
         ProcessingMode,
     },
     service::ext_proc::v3::{
-        external_processor_client::ExternalProcessorClient, processing_request::Request, HttpBody,
-        HttpHeaders, ProcessingRequest, ProcessingResponse,
+        processing_request::Request, processing_response::Response, HttpBody, HttpHeaders,
+        HttpTrailers, ProcessingRequest, ProcessingResponse,
     },
 };
+use crate::proto::google::protobuf::Struct;
 
 use super::DummyData;
 use error::StreamHandleError;
+use events::{Override, StreamEvent, TransactionOutcome};
+use stats::StreamStats;
+use throttle::TokenBucket;
+use transaction::{PhaseTiming, Transaction};
+use transport::Transport;
+
+pub mod events;
+pub mod mutation;
+pub mod pool;
+pub mod replay;
+pub mod stats;
+mod throttle;
+pub mod transaction;
+pub mod transcript;
+pub mod transport;
 
 pub mod error {
+    use super::ProtocolViolation;
+    use crate::error_category::{Categorized, ErrorCategory};
     use crate::proto::envoy::service::ext_proc::v3::ProcessingRequest;
     use quick_error::quick_error;
     use tokio::sync::mpsc::error::SendError;
@@ -35,25 +74,84 @@ pub mod error {
             StreamClosed {
                 display("Stream closed unexpectedly.")
             }
+            HeaderNotRemoved(name: String) {
+                display("Header '{}' is still present after the server removed it.", name)
+            }
+            MissingDynamicMetadata(key: String) {
+                display("Expected dynamic metadata key '{}' was not emitted.", key)
+            }
+            AttributeNotEchoed(name: String) {
+                display("Attribute '{}' was not echoed back unchanged.", name)
+            }
+            MessageTimeout {
+                display("Timed out waiting for a response.")
+            }
+            ProtocolViolation(violation: ProtocolViolation) {
+                display("Protocol violation: {}.", violation)
+            }
+            MessageTooLarge(size: usize, max: usize) {
+                display("Message of {} bytes exceeds the {} bytes limit.", size, max)
+            }
+            NotStarted {
+                display("Stream used before start_stream or after it was finished.")
+            }
         }
     );
+
+    impl Categorized for StreamHandleError {
+        fn category(&self) -> ErrorCategory {
+            match self {
+                // The request channel only closes with the stream
+                StreamHandleError::RequestSendError(_) | StreamHandleError::StreamClosed => {
+                    ErrorCategory::Transport
+                }
+                StreamHandleError::ResponseError(status) => status.category(),
+                StreamHandleError::MessageTimeout => ErrorCategory::Timeout,
+                StreamHandleError::ProtocolViolation(_) | StreamHandleError::MessageTooLarge(..) => {
+                    ErrorCategory::Protocol
+                }
+                StreamHandleError::HeaderNotRemoved(_)
+                | StreamHandleError::MissingDynamicMetadata(_)
+                | StreamHandleError::AttributeNotEchoed(_)
+                | StreamHandleError::NotStarted => ErrorCategory::Application,
+            }
+        }
+
+        fn status(&self) -> Option<&Status> {
+            match self {
+                StreamHandleError::ResponseError(status) => Some(status),
+                _ => None,
+            }
+        }
+    }
 }
 
 /// A stream handler for ExternalProcessorClient that sends dummy data to the service.
-/// In a real world scenario this would be an RPC Stream Handler that can be pooled.
+/// Streams can be pooled and shared by several users with [`pool::ClientStreamPool`].
 pub struct ClientStream {
     data: Arc<DummyData>,
     config: Config,
 
-    request_sender: Option<Sender<ProcessingRequest>>,
-    response_receiver: Option<Streaming<ProcessingResponse>>,
+    handle: StreamHandle,
 
     state: StreamState,
+    transaction: Transaction,
+    stats: Arc<StreamStats>,
+    events: Option<UnboundedSender<StreamEvent>>,
+    /// Enforces `config.max_body_rate` over the stream's life
+    throttle: Option<TokenBucket>,
+    /// Whether the last stream was killed (see [`Config::kill`])
+    killed: bool,
+    /// When the replacement of a killed or lost stream started, until its first transaction
+    recovery_started: Option<Instant>,
 }
 
 struct StreamState {
     processing_mode: ProcessingMode,
     handle_count: usize,
+    /// The message waiting for a response
+    awaiting: Option<Phase>,
+    opened_at: Instant,
 }
 
 impl Default for StreamState {
@@ -68,21 +166,238 @@ impl Default for StreamState {
                 response_trailer_mode: HeaderSendMode::Skip.into(),
             },
             handle_count: 0,
+            awaiting: None,
+            opened_at: Instant::now(),
         }
     }
 }
 
 /// Reuse stream configuration
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Config {
     /// Prevent stream from being closed on calls to finish_stream (streams may still be closed if max_handled is reached)
     pub reuse_stream: bool,
     /// Implemented as hardcap, but this can also be implemented as a softcap
     /// (chance to close stream using fastrnd until a hardcap, to prevent stream creation spikes)
     pub max_handled: Option<usize>,
+    /// Send requests with `async_mode` set, like Envoy's observability mode.
+    /// The server must not respond, so the stream never waits for responses (and never receives mode overrides)
+    pub observability_mode: bool,
+    /// Send only these phases' messages with `async_mode` set, not waiting for their responses,
+    /// and the others as usual. Every phase is when `observability_mode` is set
+    pub async_phases: Vec<Phase>,
+    /// How long to wait for the response to each message before abandoning the stream (Envoy's message_timeout)
+    pub message_timeout: Option<Duration>,
+    /// Keep the requests sent during each transaction (see [`Transaction::transcript`])
+    pub capture_transcript: bool,
+    /// Fail the transaction on the first protocol violation of the server, instead of counting
+    /// it and ignoring the offending response
+    pub fail_on_violation: bool,
+    /// Largest encoded request or response in bytes, like gRPC's max send and receive message sizes
+    pub max_message_size: Option<usize>,
+    /// Body bytes per second each stream may send, to emulate slow clients and origins. Body
+    /// messages are held back until the whole message fits the rate
+    pub max_body_rate: Option<u64>,
+    /// Start streams sending trailers (SEND trailer modes) instead of skipping them until the
+    /// server asks for them with a mode override, like Envoy configured to send trailers
+    pub send_trailers: bool,
+    /// Start streams in STREAMED body mode instead of BUFFERED: bodies are sent in chunks of
+    /// `body_chunk_size` bytes, each awaiting its response before the next is sent, as Envoy does
+    pub streamed_bodies: bool,
+    /// Size of the body chunks sent in STREAMED mode, whether set by `streamed_bodies` or by a
+    /// mode override. [`DEFAULT_BODY_CHUNK_SIZE`] when unset
+    pub body_chunk_size: Option<usize>,
+    /// Most body bytes sent in one message in BUFFERED_PARTIAL mode, which servers can only
+    /// switch to with a mode override. Longer bodies are sent as a first message of this many
+    /// bytes, then the rest in chunks of `body_chunk_size`. [`DEFAULT_PARTIAL_BUFFER_LIMIT`] when
+    /// unset
+    pub partial_buffer_limit: Option<usize>,
+    /// Pipeline the chunks of STREAMED bodies: send them all without waiting for their responses,
+    /// which are received while the rest is sent. This only emulates pipelining on top of
+    /// STREAMED mode, where each chunk is still answered by one response; Envoy itself awaits
+    /// each chunk's response in that mode
+    pub pipelined_bodies: bool,
+    /// Kill reused streams after a number of transactions or a time, to measure the cost of
+    /// recovering from the loss of a stream (see [`stats::StreamKillStats`])
+    pub kill: Option<StreamKill>,
+}
+
+/// Size of the body chunks sent in STREAMED mode, unless configured
+pub const DEFAULT_BODY_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Buffer limit in BUFFERED_PARTIAL mode unless configured, Envoy's default per-connection buffer
+/// limit
+pub const DEFAULT_PARTIAL_BUFFER_LIMIT: usize = 1024 * 1024;
+
+/// When and how reused streams are killed. Streams are killed between transactions
+#[derive(Clone, Debug)]
+pub struct StreamKill {
+    /// Kill streams once they handled this many transactions
+    pub after_transactions: Option<usize>,
+    /// Kill streams open for longer than this
+    pub after: Option<Duration>,
+    pub closure: StreamClosure,
+}
+
+/// How a client closes a stream it kills
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StreamClosure {
+    /// Half-close the stream and let the server end it, like a client done with it
+    End,
+    /// Drop the stream, resetting it with RST_STREAM (CANCEL) like a crashing client
+    Reset,
+}
+
+impl Config {
+    /// Whether `phase`'s messages are sent with `async_mode` set, without awaiting a response
+    pub fn is_async(&self, phase: Phase) -> bool {
+        self.observability_mode || self.async_phases.contains(&phase)
+    }
+}
+
+/// A message the client may send during a transaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    RequestHeaders,
+    RequestBody,
+    RequestTrailers,
+    ResponseHeaders,
+    ResponseBody,
+    ResponseTrailers,
+}
+
+/// Ways a server can break the ext_proc protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    /// The response doesn't answer the kind of message that was sent
+    UnexpectedResponseType,
+    /// Another response to a message that was already answered
+    DuplicateResponse,
+    /// A response received after the transaction's last message was answered
+    ResponseAfterEndOfStream,
+}
+
+impl Display for ProtocolViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            ProtocolViolation::UnexpectedResponseType => "response of the wrong type",
+            ProtocolViolation::DuplicateResponse => "multiple responses to one message",
+            ProtocolViolation::ResponseAfterEndOfStream => "response after the end of the stream",
+        };
+        f.write_str(description)
+    }
+}
+
+impl Phase {
+    /// Whether `response` may answer this phase's message. An ImmediateResponse answers any message
+    fn answered_by(&self, response: &Response) -> bool {
+        matches!(
+            (self, response),
+            (_, Response::ImmediateResponse(_))
+                | (Phase::RequestHeaders, Response::RequestHeaders(_))
+                | (Phase::RequestBody, Response::RequestBody(_))
+                | (Phase::RequestTrailers, Response::RequestTrailers(_))
+                | (Phase::ResponseHeaders, Response::ResponseHeaders(_))
+                | (Phase::ResponseBody, Response::ResponseBody(_))
+                | (Phase::ResponseTrailers, Response::ResponseTrailers(_))
+        )
+    }
+
+    fn is_request(&self) -> bool {
+        matches!(
+            self,
+            Phase::RequestHeaders | Phase::RequestBody | Phase::RequestTrailers
+        )
+    }
+
+    /// All phases, in the order they are sent
+    const ALL: [Phase; 6] = [
+        Phase::RequestHeaders,
+        Phase::RequestBody,
+        Phase::RequestTrailers,
+        Phase::ResponseHeaders,
+        Phase::ResponseBody,
+        Phase::ResponseTrailers,
+    ];
 }
 
 impl StreamState {
+    fn new(config: &Config) -> StreamState {
+        let mut state = StreamState::default();
+        if config.streamed_bodies {
+            state.processing_mode.set_request_body_mode(BodySendMode::Streamed);
+            state.processing_mode.set_response_body_mode(BodySendMode::Streamed);
+        }
+        if config.send_trailers {
+            state.set_request_trailer_mode(HeaderSendMode::Send);
+            state.set_response_trailer_mode(HeaderSendMode::Send);
+        }
+        state
+    }
+
+    /// The body mode of `phase`, None for phases without a body
+    fn body_mode(&self, phase: Phase) -> Option<BodySendMode> {
+        match phase {
+            Phase::RequestBody => Some(self.processing_mode.request_body_mode()),
+            Phase::ResponseBody => Some(self.processing_mode.response_body_mode()),
+            _ => None,
+        }
+    }
+
+    /// The byte ranges of the body messages sent for `phase`'s body of `len` bytes: the whole
+    /// body, or chunks of it in STREAMED mode. In BUFFERED_PARTIAL mode, bodies longer than
+    /// `buffer_limit` are sent as a first message of `buffer_limit` bytes, the rest in chunks. A
+    /// single None for phases without a body
+    fn body_chunks(
+        &self,
+        phase: Phase,
+        len: usize,
+        chunk_size: usize,
+        buffer_limit: usize,
+    ) -> Vec<Option<Range<usize>>> {
+        let Some(mode) = self.body_mode(phase) else {
+            return vec![None];
+        };
+        let buffered = match mode {
+            BodySendMode::Streamed => 0,
+            BodySendMode::BufferedPartial if len > buffer_limit => buffer_limit.max(1),
+            _ => return vec![Some(0..len)],
+        };
+        let chunk_size = chunk_size.max(1);
+        let first = (buffered > 0).then_some(Some(0..buffered));
+        first
+            .into_iter()
+            .chain(
+                (buffered..len)
+                    .step_by(chunk_size)
+                    .map(|start| Some(start..(start + chunk_size).min(len))),
+            )
+            .collect()
+    }
+
+    /// Whether the current processing mode sends a message for `phase`
+    fn should_send(&self, phase: Phase, data: &DummyData) -> bool {
+        let mode = &self.processing_mode;
+        match phase {
+            Phase::RequestHeaders => mode.request_header_mode() != HeaderSendMode::Skip,
+            Phase::RequestBody => {
+                mode.request_body_mode() != BodySendMode::None && !data.req_body.is_empty()
+            }
+            Phase::RequestTrailers => {
+                mode.request_trailer_mode() == HeaderSendMode::Send && !data.req_trailers.is_empty()
+            }
+            Phase::ResponseHeaders => mode.response_header_mode() != HeaderSendMode::Skip,
+            Phase::ResponseBody => {
+                mode.response_body_mode() != BodySendMode::None && !data.resp_body.is_empty()
+            }
+            Phase::ResponseTrailers => {
+                mode.response_trailer_mode() == HeaderSendMode::Send
+                    && !data.resp_trailers.is_empty()
+            }
+        }
+    }
+
     fn set_request_header_mode(&mut self, mode: HeaderSendMode) {
         if let HeaderSendMode::Default = mode as HeaderSendMode {
             self.processing_mode
@@ -117,25 +432,52 @@ impl StreamState {
     }
 }
 
-trait StreamHandleRef<T> {
-    fn as_expected_ref(&self) -> &T;
+/// The client's end of the current stream. Requests can only be sent and responses awaited on
+/// an open stream, using one otherwise is a [`StreamHandleError::NotStarted`] error
+#[derive(Default)]
+enum StreamHandle {
+    /// Before start_stream, or after the stream was closed
+    #[default]
+    Closed,
+    Open {
+        requests: Sender<ProcessingRequest>,
+        responses: Streaming<ProcessingResponse>,
+    },
+    /// Half-closed by the client, until the server ends it (see [`StreamClosure::End`])
+    HalfClosed(Streaming<ProcessingResponse>),
 }
 
-impl StreamHandleRef<Sender<ProcessingRequest>> for Option<Sender<ProcessingRequest>> {
-    fn as_expected_ref(&self) -> &Sender<ProcessingRequest> {
-        self.as_ref()
-            .expect("Must be used after start_stream but before finish_stream")
+impl StreamHandle {
+    fn requests(&self) -> Result<&Sender<ProcessingRequest>, StreamHandleError> {
+        match self {
+            StreamHandle::Open { requests, .. } => Ok(requests),
+            _ => Err(StreamHandleError::NotStarted),
+        }
     }
-}
 
-trait StreamHandleMutRef<T> {
-    fn as_expected_mut(&mut self) -> &mut T;
-}
+    fn responses(&mut self) -> Result<&mut Streaming<ProcessingResponse>, StreamHandleError> {
+        match self {
+            StreamHandle::Open { responses, .. } => Ok(responses),
+            _ => Err(StreamHandleError::NotStarted),
+        }
+    }
+
+    /// The responses still to be read, also those of a half-closed stream
+    fn remaining_responses(&mut self) -> Option<&mut Streaming<ProcessingResponse>> {
+        match self {
+            StreamHandle::Open { responses, .. } | StreamHandle::HalfClosed(responses) => {
+                Some(responses)
+            }
+            StreamHandle::Closed => None,
+        }
+    }
 
-impl StreamHandleMutRef<Streaming<ProcessingResponse>> for Option<Streaming<ProcessingResponse>> {
-    fn as_expected_mut(&mut self) -> &mut Streaming<ProcessingResponse> {
-        self.as_mut()
-            .expect("Must be used after start_stream but before finish_stream")
+    /// Stops sending requests, leaving the server to end the stream
+    fn half_close(&mut self) {
+        *self = match std::mem::take(self) {
+            StreamHandle::Open { responses, .. } => StreamHandle::HalfClosed(responses),
+            handle => handle,
+        };
     }
 }
 
@@ -143,58 +485,321 @@ impl ClientStream {
     pub fn new(data: Arc<DummyData>, config: Config) -> ClientStream {
         ClientStream {
             data,
+            throttle: config.max_body_rate.map(TokenBucket::new),
+            state: StreamState::new(&config),
             config,
-            request_sender: None,
-            response_receiver: None,
-            state: Default::default(),
+            handle: StreamHandle::Closed,
+            transaction: Default::default(),
+            stats: Default::default(),
+            events: None,
+            killed: false,
+            recovery_started: None,
         }
     }
 
-    pub async fn start_stream(
-        &mut self,
-        client: &mut ExternalProcessorClient<Channel>,
-    ) -> Result<(), Status> {
-        if matches!(self.request_sender, Some(ref sender) if !sender.is_closed())
-            && self.response_receiver.is_some()
-        {
+    /// Counts protocol events into `stats` instead of the stream's own counters
+    pub fn with_stats(mut self, stats: Arc<StreamStats>) -> ClientStream {
+        self.stats = stats;
+        self
+    }
+
+    /// Sends `data` in the next transactions, e.g. to change requests between transactions of a
+    /// reused stream
+    pub fn set_data(&mut self, data: Arc<DummyData>) {
+        self.data = data;
+    }
+
+    pub fn stats(&self) -> &Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// The last handled transaction
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    /// Takes the last handled transaction, e.g. to keep it once a pooled stream is returned
+    pub fn take_transaction(&mut self) -> Transaction {
+        std::mem::take(&mut self.transaction)
+    }
+
+    /// The lifecycle events of the transactions handled from now on. Events are buffered until
+    /// read, and a new subscription replaces the previous one
+    pub fn events(&mut self) -> UnboundedReceiverStream<StreamEvent> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.events = Some(sender);
+        UnboundedReceiverStream::new(receiver)
+    }
+
+    /// Sends an event to the subscriber, if any, building it only then
+    fn emit(&self, event: impl FnOnce() -> StreamEvent) {
+        if let Some(ref events) = self.events {
+            // A dropped subscriber just misses the events
+            _ = events.send(event());
+        }
+    }
+
+    /// Opens a stream over `transport`, unless the current one can be reused
+    pub async fn start_stream<T: Transport>(&mut self, transport: &mut T) -> Result<(), Status> {
+        if matches!(self.handle, StreamHandle::Open { ref requests, .. } if !requests.is_closed()) {
             return Ok(());
         }
 
+        let started = Instant::now();
+        // A stream still open here was closed by the server
+        let replacing = self.killed || matches!(self.handle, StreamHandle::Open { .. });
+        self.await_server_end().await;
         let (tx, rx) = tokio::sync::mpsc::channel(4);
-        let response = client
-            .process(tokio_stream::wrappers::ReceiverStream::new(rx))
+        let responses = transport
+            .open(tokio_stream::wrappers::ReceiverStream::new(rx))
             .await?;
 
-        self.request_sender = Some(tx);
-        self.response_receiver = Some(response.into_inner());
-        self.state = Default::default();
+        self.handle = StreamHandle::Open {
+            requests: tx,
+            responses,
+        };
+        self.state = StreamState::new(&self.config);
+        self.killed = false;
+        self.recovery_started = replacing.then_some(started);
         Ok(())
     }
 
-    pub fn sender_as_ref(
-        request_sender: &Option<Sender<ProcessingRequest>>,
-    ) -> &Sender<ProcessingRequest> {
-        request_sender
-            .as_ref()
-            .expect("Must be used after start_stream but before finish_stream")
+    /// Waits for the server to end a stream the client half-closed, within the message timeout
+    async fn await_server_end(&mut self) {
+        let StreamHandle::HalfClosed(mut responses) = std::mem::take(&mut self.handle) else {
+            return;
+        };
+        let end = async { while let Ok(Some(_)) = responses.message().await {} };
+        match self.config.message_timeout {
+            Some(timeout) => {
+                _ = tokio::time::timeout(timeout, end).await;
+            }
+            None => end.await,
+        }
     }
 
-    pub fn receiver_as_ref(
-        response_receiver: &mut Option<Streaming<ProcessingResponse>>,
-    ) -> &mut Streaming<ProcessingResponse> {
-        response_receiver
-            .as_mut()
-            .expect("Must be used after start_stream but before finish_stream")
+    pub async fn handle_stream(&mut self) -> Result<(), StreamHandleError> {
+        self.transaction = Transaction::new(&self.data);
+        self.emit(|| StreamEvent::TransactionStarted {
+            index: self.state.handle_count,
+        });
+        let result = self.run_transaction().await;
+        if let Some(started) = self.recovery_started.take() {
+            let recovery = started.elapsed().as_micros() as u64;
+            self.stats.stream_kills.recovery_micros.record(recovery);
+        }
+        self.emit(|| StreamEvent::TransactionFinished {
+            outcome: match (&result, &self.transaction.immediate_response) {
+                (Err(e), _) => TransactionOutcome::Failed(e.to_string()),
+                (Ok(_), Some(immediate_response)) => {
+                    TransactionOutcome::Denied(immediate_response.clone())
+                }
+                (Ok(_), None) => TransactionOutcome::Completed,
+            },
+        });
+        result
     }
 
-    pub async fn handle_stream(&mut self) -> Result<(), StreamHandleError> {
-        async fn send_headers<F: FnOnce(HttpHeaders) -> Request>(
-            into_request: F,
-            sender: &Sender<ProcessingRequest>,
-            headers: &[(String, String)],
-            end_of_stream: bool,
-        ) -> Result<(), StreamHandleError> {
-            let headers_map = HeaderMap {
+    async fn run_transaction(&mut self) -> Result<(), StreamHandleError> {
+        // Nothing may arrive between transactions of a reused stream
+        self.drain()?;
+
+        let started = Instant::now();
+        let micros_since_start = || started.elapsed().as_micros() as u64;
+
+        let chunk_size = self.config.body_chunk_size.unwrap_or(DEFAULT_BODY_CHUNK_SIZE);
+        let buffer_limit = self
+            .config
+            .partial_buffer_limit
+            .unwrap_or(DEFAULT_PARTIAL_BUFFER_LIMIT);
+        'phases: for phase in Phase::ALL {
+            let replaced = if phase.is_request() {
+                self.transaction.request_replaced
+            } else {
+                self.transaction.response_replaced
+            };
+            if replaced || !self.state.should_send(phase, &self.data) {
+                continue;
+            }
+            let body_len = match phase {
+                Phase::RequestBody => self.data.req_body.len(),
+                Phase::ResponseBody => self.data.resp_body.len(),
+                _ => 0,
+            };
+            let chunks = self.state.body_chunks(phase, body_len, chunk_size, buffer_limit);
+            if self.config.pipelined_bodies
+                && !self.config.is_async(phase)
+                && self.state.body_mode(phase) == Some(BodySendMode::Streamed)
+            {
+                self.run_pipelined_body(phase, chunks, started).await?;
+                if self.transaction.immediate_response.is_some() {
+                    break 'phases;
+                }
+                continue;
+            }
+            for chunk in chunks {
+                let request = self.phase_request(phase, chunk.clone());
+                if self.config.capture_transcript {
+                    self.transaction.transcript.push(request.clone());
+                }
+                self.throttle(&request).await;
+                let request_bytes = request.encoded_len();
+                self.send_request(request).await?;
+                self.transaction.phases.push(PhaseTiming {
+                    phase,
+                    sent_at_us: micros_since_start(),
+                    answered_at_us: None,
+                    request_bytes,
+                    response_bytes: 0,
+                });
+                self.emit(|| StreamEvent::PhaseSent { phase });
+                self.state.awaiting = Some(phase);
+                self.transaction.body_chunk = chunk;
+                self.await_response(phase).await?;
+                self.state.awaiting = None;
+                if !self.config.is_async(phase) {
+                    if let Some(timing) = self.transaction.phases.last_mut() {
+                        timing.answered_at_us = Some(micros_since_start());
+                    }
+                }
+                self.check_unsolicited(ProtocolViolation::DuplicateResponse)?;
+                if self.transaction.immediate_response.is_some() {
+                    // The HTTP transaction was answered locally, the remaining phases never happen
+                    break 'phases;
+                }
+            }
+        }
+        self.state.handle_count += 1;
+        if self.transaction.mode_overrides > 0 {
+            StreamStats::increment(&self.stats.mode_overrides.transactions);
+        }
+        if !self.transaction.server_time.is_zero() {
+            self.stats
+                .server_times
+                .0
+                .record(self.transaction.server_time.as_micros() as u64);
+        }
+        self.stats.phase_latencies.record(&self.transaction.phases);
+        if !self.config.observability_mode {
+            self.transaction.verify(&self.data)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the `chunks` of `phase`'s body without waiting for their responses, which are
+    /// received concurrently. Sending stops once the transaction is answered with an
+    /// ImmediateResponse
+    async fn run_pipelined_body(
+        &mut self,
+        phase: Phase,
+        chunks: Vec<Option<Range<usize>>>,
+        started: Instant,
+    ) -> Result<(), StreamHandleError> {
+        let micros_since_start = || started.elapsed().as_micros() as u64;
+        let mut requests = Vec::with_capacity(chunks.len());
+        let mut request_bytes = Vec::with_capacity(chunks.len());
+        for chunk in &chunks {
+            let request = self.phase_request(phase, chunk.clone());
+            self.check_message_size(&self.stats.message_sizes.requests, request.encoded_len())?;
+            if self.config.capture_transcript {
+                self.transaction.transcript.push(request.clone());
+            }
+            request_bytes.push(request.encoded_len());
+            let wait = self.reserve_bandwidth(&request);
+            requests.push((request, (!wait.is_zero()).then(|| Instant::now() + wait)));
+        }
+        let sender = self.handle.requests()?.clone();
+        let stats = self.stats.clone();
+        let mut sent_at_us = Vec::with_capacity(chunks.len());
+        let send = async {
+            for (request, send_at) in requests {
+                if let Some(send_at) = send_at {
+                    tokio::time::sleep_until(send_at).await;
+                }
+                send_with_backpressure(&sender, &stats, request).await?;
+                sent_at_us.push(micros_since_start());
+            }
+            Ok::<_, StreamHandleError>(())
+        };
+        for _ in &chunks {
+            self.emit(|| StreamEvent::PhaseSent { phase });
+        }
+        self.state.awaiting = Some(phase);
+        let first = self.transaction.phases.len();
+        let receive = async {
+            for (chunk, request_bytes) in chunks.into_iter().zip(request_bytes) {
+                self.transaction.phases.push(PhaseTiming {
+                    phase,
+                    sent_at_us: 0,
+                    answered_at_us: None,
+                    request_bytes,
+                    response_bytes: 0,
+                });
+                self.transaction.body_chunk = chunk;
+                self.process_single_response().await?;
+                if let Some(timing) = self.transaction.phases.last_mut() {
+                    timing.answered_at_us = Some(micros_since_start());
+                }
+                if self.transaction.immediate_response.is_some() {
+                    break;
+                }
+            }
+            Ok::<_, StreamHandleError>(())
+        };
+        {
+            futures::pin_mut!(send, receive);
+            match future::select(send, receive).await {
+                Either::Left((sent, receive)) => {
+                    sent?;
+                    receive.await?;
+                }
+                // The rest of the body is not sent after an ImmediateResponse
+                Either::Right((received, _unsent)) => received?,
+            }
+        }
+        let answered = self.transaction.phases.len() - first;
+        if sent_at_us.len() > answered {
+            // Chunks sent after the one answered with an ImmediateResponse may still be answered,
+            // and their responses would be taken for the next transaction's
+            self.abort_stream();
+        }
+        for (timing, sent_at_us) in self.transaction.phases[first..].iter_mut().zip(sent_at_us) {
+            timing.sent_at_us = sent_at_us;
+        }
+        self.state.awaiting = None;
+        self.check_unsolicited(ProtocolViolation::DuplicateResponse)
+    }
+
+    /// Waits until the body of `request`, if any, fits the stream's bandwidth cap
+    async fn throttle(&mut self, request: &ProcessingRequest) {
+        let wait = self.reserve_bandwidth(request);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes the body of `request`, if any, from the stream's bandwidth cap, returning how long
+    /// to wait before sending it
+    fn reserve_bandwidth(&mut self, request: &ProcessingRequest) -> Duration {
+        let (Some(bucket), Some(Request::RequestBody(body) | Request::ResponseBody(body))) =
+            (self.throttle.as_mut(), &request.request)
+        else {
+            return Duration::ZERO;
+        };
+        bucket.reserve(body.body.len(), Instant::now())
+    }
+
+    /// Sends a request on the stream (see [`send_with_backpressure`])
+    async fn send_request(&self, request: ProcessingRequest) -> Result<(), StreamHandleError> {
+        self.check_message_size(&self.stats.message_sizes.requests, request.encoded_len())?;
+        let sender = self.handle.requests()?;
+        send_with_backpressure(sender, &self.stats, request).await
+    }
+
+    /// The message of `phase`, carrying the `chunk` of its body for body phases
+    fn phase_request(&self, phase: Phase, chunk: Option<Range<usize>>) -> ProcessingRequest {
+        fn header_map(headers: &[(String, String)]) -> HeaderMap {
+            HeaderMap {
                 headers: headers
                     .iter()
                     .map(|header| HeaderValue {
@@ -202,98 +807,112 @@ impl ClientStream {
                         value: header.1.clone(),
                     })
                     .collect(),
-            };
-            Ok(sender
-                .send(ProcessingRequest {
-                    async_mode: false,
-                    request: Some(into_request(HttpHeaders {
-                        headers: Some(headers_map),
-                        attributes: Default::default(),
-                        end_of_stream,
-                    })),
-                })
-                .await?)
+            }
         }
-        async fn send_body<F: FnOnce(HttpBody) -> Request>(
-            into_request: F,
-            sender: &Sender<ProcessingRequest>,
-            body: &[u8],
+        fn http_headers(
+            headers: &[(String, String)],
+            attributes: &HashMap<String, Struct>,
             end_of_stream: bool,
-        ) -> Result<(), StreamHandleError> {
-            Ok(sender
-                .send(ProcessingRequest {
-                    async_mode: false,
-                    request: Some(into_request(HttpBody {
-                        body: Vec::from(body),
-                        end_of_stream,
-                    })),
-                })
-                .await?)
+        ) -> HttpHeaders {
+            HttpHeaders {
+                headers: Some(header_map(headers)),
+                attributes: attributes.clone(),
+                end_of_stream,
+            }
         }
-        async fn send_empty(_sender: &Sender<ProcessingRequest>) -> Result<(), StreamHandleError> {
-            Ok(())
+        fn http_trailers(trailers: &[(String, String)]) -> HttpTrailers {
+            HttpTrailers {
+                trailers: Some(header_map(trailers)),
+            }
         }
 
-        /*let response_receiver = self.response_receiver
-        .as_mut()
-        .expect("Must be used after start_stream but before finish_stream");*/
-
-        if self.state.processing_mode.request_header_mode() != HeaderSendMode::Skip {
-            send_headers(
-                |headers| Request::RequestHeaders(headers),
-                self.request_sender.as_expected_ref(),
-                &self.data.req_headers,
-                self.data.req_body.is_empty(),
-            )
-            .await?;
-            self.process_single_response().await?;
-        }
-        if self.state.processing_mode.request_body_mode() != BodySendMode::None
-            && !self.data.req_body.is_empty()
-        {
-            send_body(
-                |body| Request::RequestBody(body),
-                self.request_sender.as_expected_ref(),
-                &self.data.req_body,
-                true,
-            )
-            .await?;
-            self.process_single_response().await?;
-        }
-        if self.state.processing_mode.response_header_mode() != HeaderSendMode::Skip {
-            send_headers(
-                |headers| Request::ResponseHeaders(headers),
-                self.request_sender.as_expected_ref(),
-                &self.data.resp_headers,
-                self.data.resp_body.is_empty(),
-            )
-            .await?;
-            self.process_single_response().await?;
+        let data = &self.data;
+        let request = match phase {
+            Phase::RequestHeaders => Request::RequestHeaders(http_headers(
+                &data.req_headers,
+                &data.req_attributes,
+                data.req_body.is_empty() && data.req_trailers.is_empty(),
+            )),
+            Phase::RequestBody => {
+                let chunk = chunk.unwrap_or(0..data.req_body.len());
+                Request::RequestBody(HttpBody {
+                    end_of_stream: chunk.end == data.req_body.len() && data.req_trailers.is_empty(),
+                    body: data.req_body[chunk].to_vec(),
+                })
+            }
+            Phase::RequestTrailers => Request::RequestTrailers(http_trailers(&data.req_trailers)),
+            Phase::ResponseHeaders => Request::ResponseHeaders(http_headers(
+                &data.resp_headers,
+                &data.resp_attributes,
+                data.resp_body.is_empty() && data.resp_trailers.is_empty(),
+            )),
+            Phase::ResponseBody => {
+                let chunk = chunk.unwrap_or(0..data.resp_body.len());
+                Request::ResponseBody(HttpBody {
+                    end_of_stream: chunk.end == data.resp_body.len()
+                        && data.resp_trailers.is_empty(),
+                    body: data.resp_body[chunk].to_vec(),
+                })
+            }
+            Phase::ResponseTrailers => {
+                Request::ResponseTrailers(http_trailers(&data.resp_trailers))
+            }
+        };
+        ProcessingRequest {
+            async_mode: self.config.is_async(phase),
+            request: Some(request),
         }
-        if self.state.processing_mode.response_body_mode() != BodySendMode::None
-            && !self.data.resp_body.is_empty()
-        {
-            send_body(
-                |body| Request::ResponseBody(body),
-                self.request_sender.as_expected_ref(),
-                &self.data.req_body,
-                true,
-            )
-            .await?;
-            self.process_single_response().await?;
+    }
+
+    /// Waits for the response to the last sent request, `phase`'s, unless it was sent async
+    async fn await_response(&mut self, phase: Phase) -> Result<(), StreamHandleError> {
+        if self.config.is_async(phase) {
+            return Ok(());
         }
-        self.state.handle_count += 1;
-        Ok(())
+        self.process_single_response().await
     }
 
     pub async fn process_single_response(&mut self) -> Result<(), StreamHandleError> {
-        let response = self.response_receiver.as_expected_mut().message().await?;
-        if let None = response {
+        let receiver = self.handle.responses()?;
+        let response = match self.config.message_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, receiver.message()).await {
+                Ok(response) => response?,
+                Err(_) => {
+                    // A late response would be taken as the response to the next message
+                    self.abort_stream();
+                    StreamStats::increment(&self.stats.message_timeouts);
+                    return Err(StreamHandleError::MessageTimeout);
+                }
+            },
+            None => receiver.message().await?,
+        };
+        let Some(response) = response else {
             return Err(StreamHandleError::StreamClosed);
+        };
+        self.check_message_size(&self.stats.message_sizes.responses, response.encoded_len())?;
+        if let (Some(_), Some(timing)) = (self.state.awaiting, self.transaction.phases.last_mut()) {
+            timing.response_bytes += response.encoded_len();
+        }
+
+        if let (Some(phase), Some(ref inner)) = (self.state.awaiting, &response.response) {
+            if !phase.answered_by(inner) {
+                self.violation(ProtocolViolation::UnexpectedResponseType)?;
+                return Ok(());
+            }
+        }
+
+        if let (Some(phase), Some(ref inner)) = (self.state.awaiting, &response.response) {
+            self.emit(|| StreamEvent::ResponseReceived {
+                phase,
+                mutations: inner.clone(),
+            });
         }
 
-        let response = response.unwrap();
         if let Some(mode_overrides) = response.mode_override {
+            let before = self.state.processing_mode.clone();
+            self.emit(|| {
+                StreamEvent::OverrideApplied(Override::ProcessingMode(mode_overrides.clone()))
+            });
             self.state
                 .set_request_header_mode(mode_overrides.request_header_mode());
             self.state
@@ -308,17 +927,464 @@ impl ClientStream {
                 .set_request_trailer_mode(mode_overrides.request_trailer_mode());
             self.state
                 .set_response_trailer_mode(mode_overrides.response_trailer_mode());
+            self.stats.mode_overrides.record(
+                self.state.awaiting,
+                &before,
+                &self.state.processing_mode,
+            );
+            self.transaction.mode_overrides += 1;
+        }
+
+        if let Some(metadata) = response.dynamic_metadata {
+            self.stats.record_dynamic_metadata(metadata.encoded_len());
+            self.transaction.merge_dynamic_metadata(metadata);
+        }
+        if let Some(ref response) = response.response {
+            self.transaction.apply_response(response, &self.data)?;
+            if let Some(ref immediate_response) = self.transaction.immediate_response {
+                debug!("Transaction ended with {}", immediate_response);
+            }
         }
 
         Ok(())
     }
 
+    /// Records the encoded size of a message, failing if it exceeds max_message_size
+    fn check_message_size(
+        &self,
+        sizes: &AtomicHdrHistogram,
+        size: usize,
+    ) -> Result<(), StreamHandleError> {
+        sizes.record(size as u64);
+        match self.config.max_message_size {
+            Some(max) if size > max => Err(StreamHandleError::MessageTooLarge(size, max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a violation, failing if configured to
+    fn violation(&self, violation: ProtocolViolation) -> Result<(), StreamHandleError> {
+        self.stats.record_violation(violation);
+        if self.config.fail_on_violation {
+            return Err(StreamHandleError::ProtocolViolation(violation));
+        }
+        Ok(())
+    }
+
+    /// Counts responses that were already received while no message awaits one
+    fn check_unsolicited(&mut self, violation: ProtocolViolation) -> Result<(), StreamHandleError> {
+        if self.config.observability_mode {
+            return Ok(());
+        }
+        loop {
+            let Some(receiver) = self.handle.remaining_responses() else {
+                return Ok(());
+            };
+            let next = receiver.message().now_or_never();
+            match next {
+                Some(Ok(Some(_))) => self.violation(violation)?,
+                Some(Err(status)) => return Err(status.into()),
+                // Closed streams and responses yet to come are left to the next read
+                Some(Ok(None)) | None => return Ok(()),
+            }
+        }
+    }
+
+    /// Consumes the responses already received while no message awaits one, so they can't be
+    /// taken for responses of the next transaction, and returns how many there were. They are
+    /// counted as orphaned, and as protocol violations unless they may belong to an interrupted
+    /// transaction. Responses still in flight are not waited for, streams of interrupted
+    /// transactions are safer closed with [`ClientStream::abort_stream`].
+    pub fn drain(&mut self) -> Result<u64, StreamHandleError> {
+        let interrupted = self.state.awaiting.take().is_some();
+        if self.config.observability_mode {
+            return Ok(0);
+        }
+        let mut drained = 0;
+        loop {
+            let Some(receiver) = self.handle.remaining_responses() else {
+                break;
+            };
+            match receiver.message().now_or_never() {
+                Some(Ok(Some(_))) => {
+                    drained += 1;
+                    StreamStats::increment(&self.stats.orphaned_responses);
+                    if !interrupted {
+                        self.violation(ProtocolViolation::ResponseAfterEndOfStream)?;
+                    }
+                }
+                Some(Err(status)) => return Err(status.into()),
+                // Closed streams and responses yet to come are left to the next read
+                Some(Ok(None)) | None => break,
+            }
+        }
+        Ok(drained)
+    }
+
+    /// Closes the stream regardless of reuse, e.g. after abandoning a transaction whose
+    /// responses may still arrive. The next transaction starts a new stream
+    pub fn abort_stream(&mut self) {
+        self.handle = StreamHandle::Closed;
+    }
+
+    /// How the stream is to be killed after its last transaction, if it is
+    fn kill_due(&self) -> Option<StreamClosure> {
+        let kill = self.config.kill.as_ref().filter(|_| self.config.reuse_stream)?;
+        let state = &self.state;
+        let handled = matches!(kill.after_transactions, Some(max) if state.handle_count >= max);
+        let expired = matches!(kill.after, Some(after) if state.opened_at.elapsed() >= after);
+        (handled || expired).then_some(kill.closure)
+    }
+
     pub fn finish_stream(&mut self) {
+        if let Some(closure) = self.kill_due() {
+            StreamStats::increment(&self.stats.stream_kills.killed);
+            self.killed = true;
+            match closure {
+                StreamClosure::End => self.handle.half_close(),
+                StreamClosure::Reset => self.handle = StreamHandle::Closed,
+            }
+            return;
+        }
         if !self.config.reuse_stream
             || matches!(self.config.max_handled, Some(ref max) if self.state.handle_count >= *max)
         {
-            self.request_sender = None;
-            self.response_receiver = None;
+            self.handle = StreamHandle::Closed;
+        }
+    }
+}
+
+/// Sends a request, measuring how long it waits for room when the channel is full, which happens
+/// when the HTTP/2 flow-control window doesn't let requests out
+async fn send_with_backpressure(
+    sender: &Sender<ProcessingRequest>,
+    stats: &StreamStats,
+    request: ProcessingRequest,
+) -> Result<(), StreamHandleError> {
+    let request = match sender.try_send(request) {
+        Ok(()) => return Ok(()),
+        Err(TrySendError::Closed(request)) => return Err(SendError(request).into()),
+        Err(TrySendError::Full(request)) => request,
+    };
+    let blocked_since = Instant::now();
+    let result = sender.send(request).await;
+    stats.record_backpressure(blocked_since.elapsed());
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::codec::{Codec, ProstCodec};
+
+    use super::*;
+    use crate::proto::envoy::service::ext_proc::v3::{BodyResponse, HeadersResponse};
+
+    #[test]
+    fn responses_must_answer_the_sent_phase() {
+        let headers = Response::RequestHeaders(HeadersResponse::default());
+        assert!(Phase::RequestHeaders.answered_by(&headers));
+        assert!(!Phase::ResponseHeaders.answered_by(&headers));
+        assert!(!Phase::RequestBody.answered_by(&headers));
+        let body = Response::RequestBody(BodyResponse::default());
+        assert!(!Phase::RequestHeaders.answered_by(&body));
+        let immediate = Response::ImmediateResponse(Default::default());
+        assert!(Phase::ALL.iter().all(|phase| phase.answered_by(&immediate)));
+    }
+
+    #[test]
+    fn violations_are_counted_and_optionally_fatal() {
+        use std::sync::atomic::Ordering::Relaxed;
+        let stream = ClientStream::new(Arc::new(DummyData::default()), Config::default());
+        assert!(stream.violation(ProtocolViolation::DuplicateResponse).is_ok());
+        let strict = ClientStream::new(
+            Arc::new(DummyData::default()),
+            Config {
+                fail_on_violation: true,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            strict.violation(ProtocolViolation::UnexpectedResponseType),
+            Err(StreamHandleError::ProtocolViolation(ProtocolViolation::UnexpectedResponseType))
+        ));
+        assert_eq!(stream.stats().duplicate_responses.load(Relaxed), 1);
+        assert_eq!(strict.stats().unexpected_response_types.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn message_sizes_are_recorded_and_capped() {
+        let stream = ClientStream::new(
+            Arc::new(DummyData::default()),
+            Config {
+                max_message_size: Some(100),
+                ..Default::default()
+            },
+        );
+        let requests = &stream.stats().message_sizes.requests;
+        assert!(stream.check_message_size(requests, 100).is_ok());
+        assert!(matches!(
+            stream.check_message_size(requests, 101),
+            Err(StreamHandleError::MessageTooLarge(101, 100))
+        ));
+        assert_eq!(requests.histogram().len(), 2);
+    }
+
+    /// Responses of a stream the server ended right away
+    fn empty_responses() -> Streaming<ProcessingResponse> {
+        let mut codec = ProstCodec::<ProcessingRequest, ProcessingResponse>::default();
+        Streaming::new_request(codec.decoder(), hyper::Body::empty(), None)
+    }
+
+    #[test]
+    fn reused_streams_are_killed_after_their_transactions() {
+        let kill = |closure| StreamKill {
+            after_transactions: Some(2),
+            after: None,
+            closure,
+        };
+        for closure in [StreamClosure::End, StreamClosure::Reset] {
+            let config = Config {
+                reuse_stream: true,
+                kill: Some(kill(closure)),
+                ..Default::default()
+            };
+            let mut stream = ClientStream::new(Arc::new(DummyData::default()), config);
+            let (requests, _receiver) = tokio::sync::mpsc::channel(1);
+            stream.handle = StreamHandle::Open {
+                requests,
+                responses: empty_responses(),
+            };
+            stream.state.handle_count = 1;
+            stream.finish_stream();
+            assert!(stream.handle.requests().is_ok() && !stream.killed);
+            stream.state.handle_count = 2;
+            stream.finish_stream();
+            assert!(stream.handle.requests().is_err() && stream.killed);
+            // Killed streams are only read until the server ends them when half-closed
+            let half_closed = matches!(stream.handle, StreamHandle::HalfClosed(_));
+            assert_eq!(half_closed, closure == StreamClosure::End);
+            let killed = &stream.stats().stream_kills.killed;
+            assert_eq!(killed.load(std::sync::atomic::Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn unstarted_streams_fail_instead_of_panicking() {
+        let mut stream = ClientStream::new(Arc::new(data_with_bodies()), Config::default());
+        assert!(matches!(
+            futures::executor::block_on(stream.handle_stream()),
+            Err(StreamHandleError::NotStarted)
+        ));
+        stream.finish_stream();
+        assert!(matches!(
+            futures::executor::block_on(stream.process_single_response()),
+            Err(StreamHandleError::NotStarted)
+        ));
+    }
+
+    #[test]
+    fn message_timeouts_abandon_the_stream_and_are_counted() {
+        let config = Config {
+            message_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let mut stream = ClientStream::new(Arc::new(DummyData::default()), config);
+        let (requests, _receiver) = tokio::sync::mpsc::channel(1);
+        // A hung server: the response body never ends
+        let (_hung, body) = hyper::Body::channel();
+        let mut codec = ProstCodec::<ProcessingRequest, ProcessingResponse>::default();
+        stream.handle = StreamHandle::Open {
+            requests,
+            responses: Streaming::new_request(codec.decoder(), body, None),
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        assert!(matches!(
+            runtime.block_on(stream.process_single_response()),
+            Err(StreamHandleError::MessageTimeout)
+        ));
+        assert!(stream.handle.requests().is_err());
+        let timeouts = &stream.stats().message_timeouts;
+        assert_eq!(timeouts.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drain_forgets_interrupted_transaction() {
+        let mut stream = ClientStream::new(Arc::new(DummyData::default()), Config::default());
+        stream.state.awaiting = Some(Phase::RequestBody);
+        assert!(matches!(stream.drain(), Ok(0)));
+        assert_eq!(stream.state.awaiting, None);
+    }
+
+    fn data_with_bodies() -> DummyData {
+        DummyData {
+            req_headers: vec![("Host".to_string(), "example.com".to_string())],
+            req_body: b"request".to_vec(),
+            resp_status: 200,
+            resp_headers: vec![("Server".to_string(), "dummy".to_string())],
+            resp_body: b"response".to_vec(),
+            resp_trailers: vec![("grpc-status".to_string(), "0".to_string())],
+            ..Default::default()
         }
     }
+
+    const PHASES: [Phase; 4] = [
+        Phase::RequestHeaders,
+        Phase::RequestBody,
+        Phase::ResponseHeaders,
+        Phase::ResponseBody,
+    ];
+
+    #[test]
+    fn trailers_are_sent_only_in_send_mode() {
+        let mut state = StreamState::default();
+        let data = data_with_bodies();
+        assert!(!state.should_send(Phase::ResponseTrailers, &data));
+
+        state.set_request_trailer_mode(HeaderSendMode::Send);
+        state.set_response_trailer_mode(HeaderSendMode::Send);
+        // There are no request trailers to send
+        assert!(!state.should_send(Phase::RequestTrailers, &data));
+        assert!(state.should_send(Phase::ResponseTrailers, &data));
+
+        // A mode override back to DEFAULT skips trailers again
+        state.set_response_trailer_mode(HeaderSendMode::Default);
+        assert!(!state.should_send(Phase::ResponseTrailers, &data));
+
+        let config = Config {
+            send_trailers: true,
+            ..Default::default()
+        };
+        assert!(StreamState::new(&config).should_send(Phase::ResponseTrailers, &data));
+    }
+
+    #[test]
+    fn default_mode_sends_every_phase() {
+        let state = StreamState::default();
+        let data = data_with_bodies();
+        assert!(PHASES.iter().all(|phase| state.should_send(*phase, &data)));
+    }
+
+    #[test]
+    fn skipped_phases_are_not_sent() {
+        let mut state = StreamState::default();
+        state.set_request_header_mode(HeaderSendMode::Skip);
+        state
+            .processing_mode
+            .set_response_body_mode(BodySendMode::None);
+        let data = data_with_bodies();
+
+        let sent: Vec<Phase> = PHASES
+            .into_iter()
+            .filter(|phase| state.should_send(*phase, &data))
+            .collect();
+        assert_eq!(sent, vec![Phase::RequestBody, Phase::ResponseHeaders]);
+    }
+
+    #[test]
+    fn empty_bodies_are_not_sent() {
+        let state = StreamState::default();
+        let mut data = data_with_bodies();
+        data.req_body.clear();
+        data.resp_body.clear();
+        assert!(!state.should_send(Phase::RequestBody, &data));
+        assert!(!state.should_send(Phase::ResponseBody, &data));
+    }
+
+    #[test]
+    fn body_messages_carry_their_own_body() {
+        let stream = ClientStream::new(Arc::new(data_with_bodies()), Config::default());
+        match stream.phase_request(Phase::RequestBody, None).request {
+            Some(Request::RequestBody(body)) => assert_eq!(body.body, b"request"),
+            other => panic!("expected a request body, got {:?}", other),
+        }
+        match stream.phase_request(Phase::ResponseBody, None).request {
+            Some(Request::ResponseBody(body)) => assert_eq!(body.body, b"response"),
+            other => panic!("expected a response body, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn streamed_bodies_are_sent_in_chunks() {
+        let config = Config {
+            streamed_bodies: true,
+            body_chunk_size: Some(3),
+            ..Default::default()
+        };
+        let stream = ClientStream::new(Arc::new(data_with_bodies()), config);
+        let chunks = stream.state.body_chunks(Phase::RequestBody, 7, 3, 3);
+        assert_eq!(chunks, [Some(0..3), Some(3..6), Some(6..7)]);
+        let bodies: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| match stream.phase_request(Phase::RequestBody, chunk).request {
+                Some(Request::RequestBody(body)) => (body.body, body.end_of_stream),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            bodies,
+            [
+                (b"req".to_vec(), false),
+                (b"ues".to_vec(), false),
+                (b"t".to_vec(), true)
+            ]
+        );
+        assert_eq!(
+            StreamState::default().body_chunks(Phase::RequestBody, 7, 3, 3),
+            [Some(0..7)]
+        );
+        assert_eq!(stream.state.body_chunks(Phase::RequestHeaders, 0, 3, 3), [None]);
+    }
+
+    #[test]
+    fn buffered_partial_bodies_stream_past_the_buffer_limit() {
+        let mut state = StreamState::default();
+        state
+            .processing_mode
+            .set_request_body_mode(BodySendMode::BufferedPartial);
+        assert_eq!(state.body_chunks(Phase::RequestBody, 7, 3, 8), [Some(0..7)]);
+        assert_eq!(
+            state.body_chunks(Phase::RequestBody, 7, 2, 4),
+            [Some(0..4), Some(4..6), Some(6..7)]
+        );
+        // The response body is still BUFFERED
+        assert_eq!(state.body_chunks(Phase::ResponseBody, 7, 2, 4), [Some(0..7)]);
+    }
+
+    #[test]
+    fn selected_phases_are_sent_async() {
+        let stream = ClientStream::new(
+            Arc::new(data_with_bodies()),
+            Config {
+                async_phases: vec![Phase::RequestBody, Phase::ResponseBody],
+                ..Default::default()
+            },
+        );
+        let body = Some(0..stream.data.req_body.len());
+        assert!(stream.phase_request(Phase::RequestBody, body).async_mode);
+        assert!(!stream.phase_request(Phase::RequestHeaders, None).async_mode);
+        assert!(!stream.phase_request(Phase::ResponseHeaders, None).async_mode);
+    }
+
+    #[test]
+    fn observability_mode_keeps_configured_processing_mode() {
+        // Without responses there are no mode overrides, so the initial processing mode decides
+        // which phases are sent for the whole lifetime of the stream
+        let stream = ClientStream::new(
+            Arc::new(data_with_bodies()),
+            Config {
+                observability_mode: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            stream.state.processing_mode,
+            StreamState::default().processing_mode
+        );
+        assert!(PHASES
+            .iter()
+            .all(|phase| stream.state.should_send(*phase, &stream.data)));
+    }
 }
@@ -0,0 +1,209 @@
+use std::ops::Range;
+
+use crate::proto::envoy::service::ext_proc::v3::{
+    body_mutation::Mutation, BodyMutation, HeaderMutation,
+};
+
+/// Applies a header mutation received from the server to a header list, the way Envoy would:
+/// removals first, then additions. Header names are matched case-insensitively. Options append
+/// to existing values only when `append` is true; unset, it means false and replaces them. The
+/// server resolves the other append actions into these two before sending its mutation.
+pub fn apply_header_mutation(headers: &mut Vec<(String, String)>, mutation: &HeaderMutation) {
+    for name in &mutation.remove_headers {
+        remove_header(headers, name);
+    }
+    for option in &mutation.set_headers {
+        let Some(header) = option.header.as_ref() else {
+            continue;
+        };
+        let append = matches!(option.append, Some(ref append) if append.value);
+        if !append {
+            remove_header(headers, &header.key);
+        }
+        headers.push((header.key.to_lowercase(), header.value.clone()));
+    }
+}
+
+/// Headers the mutation removes that are still present in `headers` after applying it.
+/// Headers removed and set again by the same mutation are expected to be present.
+pub fn removed_headers_present<'a>(
+    headers: &[(String, String)],
+    mutation: &'a HeaderMutation,
+) -> Vec<&'a str> {
+    mutation
+        .remove_headers
+        .iter()
+        .filter(|name| {
+            !mutation.set_headers.iter().any(|option| {
+                matches!(option.header, Some(ref header) if header.key.eq_ignore_ascii_case(name))
+            })
+        })
+        .filter(|name| headers.iter().any(|(key, _)| key.eq_ignore_ascii_case(name)))
+        .map(|name| name.as_str())
+        .collect()
+}
+
+/// Applies the server's response to one chunk of `body` to the effective body.
+///
+/// Body responses may replace or clear the chunk they respond to, so a body sent in several chunks
+/// is rebuilt chunk by chunk. `effective` stays None while the body is unchanged, and once a chunk
+/// is mutated holds the body up to and including `chunk`.
+pub fn apply_body_mutation(
+    effective: &mut Option<Vec<u8>>,
+    body: &[u8],
+    chunk: Range<usize>,
+    mutation: Option<&BodyMutation>,
+) {
+    let replacement = match mutation.and_then(|mutation| mutation.mutation.as_ref()) {
+        Some(Mutation::Body(replacement)) => Some(replacement.as_slice()),
+        Some(Mutation::ClearBody(true)) => Some(&[][..]),
+        _ => None,
+    };
+    if effective.is_none() && replacement.is_none() {
+        return;
+    }
+    let effective = effective.get_or_insert_with(|| body[..chunk.start].to_vec());
+    effective.extend_from_slice(replacement.unwrap_or(&body[chunk]));
+}
+
+fn remove_header(headers: &mut Vec<(String, String)>, name: &str) {
+    headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{
+        envoy::config::core::v3::{HeaderValue, HeaderValueOption},
+        google::protobuf::BoolValue,
+    };
+
+    fn option(key: &str, value: &str, append: Option<bool>) -> HeaderValueOption {
+        HeaderValueOption {
+            header: Some(HeaderValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            append: append.map(|value| BoolValue { value }),
+        }
+    }
+
+    fn apply(existing: &[(&str, &str)], option: HeaderValueOption) -> Vec<(String, String)> {
+        let mut applied = headers(existing);
+        apply_header_mutation(
+            &mut applied,
+            &HeaderMutation {
+                set_headers: vec![option],
+                remove_headers: vec![],
+            },
+        );
+        applied
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn replaces_by_default() {
+        let set = || option("X-Checksum", "b", None);
+        assert_eq!(
+            apply(&[("x-checksum", "a"), ("X-Checksum", "c")], set()),
+            headers(&[("x-checksum", "b")])
+        );
+        assert_eq!(apply(&[], set()), headers(&[("x-checksum", "b")]));
+    }
+
+    #[test]
+    fn appends_with_append() {
+        let set = || option("X-Checksum", "b", Some(true));
+        assert_eq!(
+            apply(&[("x-checksum", "a")], set()),
+            headers(&[("x-checksum", "a"), ("x-checksum", "b")])
+        );
+        assert_eq!(apply(&[], set()), headers(&[("x-checksum", "b")]));
+    }
+
+    #[test]
+    fn replaces_without_append() {
+        let mut trailers = headers(&[("x-checksum", "a"), ("grpc-status", "0")]);
+        apply_header_mutation(
+            &mut trailers,
+            &HeaderMutation {
+                set_headers: vec![option("x-checksum", "b", Some(false))],
+                remove_headers: vec!["Grpc-Status".to_string()],
+            },
+        );
+        assert_eq!(trailers, headers(&[("x-checksum", "b")]));
+    }
+
+    #[test]
+    fn removes_before_setting() {
+        let mutation = HeaderMutation {
+            set_headers: vec![option("x-tag", "new", None)],
+            remove_headers: vec!["x-tag".to_string(), "x-debug".to_string()],
+        };
+        let mut applied = headers(&[("x-tag", "old"), ("X-Debug", "1"), ("host", "h")]);
+        apply_header_mutation(&mut applied, &mutation);
+        assert_eq!(applied, headers(&[("host", "h"), ("x-tag", "new")]));
+        assert!(removed_headers_present(&applied, &mutation).is_empty());
+    }
+
+    #[test]
+    fn reports_headers_still_present() {
+        let mutation = HeaderMutation {
+            set_headers: vec![],
+            remove_headers: vec!["x-debug".to_string(), "x-absent".to_string()],
+        };
+        let not_applied = headers(&[("X-Debug", "1")]);
+        assert_eq!(removed_headers_present(&not_applied, &mutation), vec!["x-debug"]);
+    }
+
+    fn replace(replacement: &[u8]) -> BodyMutation {
+        BodyMutation {
+            mutation: Some(Mutation::Body(replacement.to_vec())),
+        }
+    }
+
+    #[test]
+    fn unmutated_body_is_not_copied() {
+        let mut effective = None;
+        apply_body_mutation(&mut effective, b"body", 0..4, None);
+        apply_body_mutation(
+            &mut effective,
+            b"body",
+            0..4,
+            Some(&BodyMutation {
+                mutation: Some(Mutation::ClearBody(false)),
+            }),
+        );
+        assert_eq!(effective, None);
+    }
+
+    #[test]
+    fn replaces_and_clears_whole_body() {
+        let mut effective = None;
+        apply_body_mutation(&mut effective, b"body", 0..4, Some(&replace(b"new body")));
+        assert_eq!(effective.as_deref(), Some(&b"new body"[..]));
+
+        let mut effective = None;
+        let clear = BodyMutation {
+            mutation: Some(Mutation::ClearBody(true)),
+        };
+        apply_body_mutation(&mut effective, b"body", 0..4, Some(&clear));
+        assert_eq!(effective.as_deref(), Some(&b""[..]));
+    }
+
+    #[test]
+    fn rebuilds_chunked_replacement() {
+        let body = b"aaabbbccc";
+        let mut effective = None;
+        apply_body_mutation(&mut effective, body, 0..3, None);
+        apply_body_mutation(&mut effective, body, 3..6, Some(&replace(b"XY")));
+        apply_body_mutation(&mut effective, body, 6..9, None);
+        assert_eq!(effective.as_deref(), Some(&b"aaaXYccc"[..]));
+    }
+}
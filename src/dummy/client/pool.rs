@@ -0,0 +1,179 @@
+//! A pool of [`ClientStream`]s shared by concurrent tasks, like the streams an ext_proc filter
+//! keeps open to its server, so fewer streams than in-flight transactions can be modeled
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tonic::Status;
+
+use super::{stats::StreamStats, transport::Transport, ClientStream, Config};
+use crate::dummy::DummyData;
+
+/// Streams and the transport each is opened over
+struct PoolSlot<T> {
+    stream: ClientStream,
+    transport: T,
+}
+
+/// Owns one stream over each of its transports and hands them out one transaction at a time.
+/// The pool applies the reuse policy of its [`Config`] (`reuse_stream`, `max_handled`, `kill`)
+/// when streams are returned, and reopens those closed since their last use, or broken by an
+/// interrupted transaction, when they are checked out again
+pub struct ClientStreamPool<T> {
+    /// Idle streams, the most recently returned last
+    idle: Mutex<Vec<PoolSlot<T>>>,
+    /// A permit per idle stream
+    available: Semaphore,
+    stats: Arc<StreamStats>,
+}
+
+impl<T: Transport> ClientStreamPool<T> {
+    /// A stream sending `data` over each of `transports`, which may be clients of the same
+    /// channel. Streams are opened when first checked out
+    pub fn new(transports: Vec<T>, data: Arc<DummyData>, config: Config) -> ClientStreamPool<T> {
+        let stats = Arc::new(StreamStats::default());
+        let idle: Vec<_> = transports
+            .into_iter()
+            .map(|transport| PoolSlot {
+                stream: ClientStream::new(data.clone(), config.clone()).with_stats(stats.clone()),
+                transport,
+            })
+            .collect();
+        ClientStreamPool {
+            available: Semaphore::new(idle.len()),
+            idle: Mutex::new(idle),
+            stats,
+        }
+    }
+
+    /// Record the stats of the pool's streams in `stats`, e.g. to share them with other pools
+    pub fn with_stats(mut self, stats: Arc<StreamStats>) -> ClientStreamPool<T> {
+        for slot in self.idle.get_mut().unwrap().iter_mut() {
+            slot.stream.stats = stats.clone();
+        }
+        self.stats = stats;
+        self
+    }
+
+    /// Stats of the pool's streams, shared between them
+    pub fn stats(&self) -> &Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// Streams not checked out
+    pub fn idle(&self) -> usize {
+        self.available.available_permits()
+    }
+
+    /// Waits for an idle stream, the most recently used one first so the others may be closed by
+    /// the server's idle timeout, and opens it if it isn't open. A stream failing to open is
+    /// returned to the pool
+    pub async fn checkout(&self) -> Result<PooledStream<'_, T>, Status> {
+        let permit = self
+            .available
+            .acquire()
+            .await
+            .expect("the pool never closes its semaphore");
+        let slot = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("each permit stands for an idle stream");
+        let mut pooled = PooledStream {
+            pool: self,
+            slot: Some(slot),
+            _permit: permit,
+        };
+        let slot = pooled.slot.as_mut().unwrap();
+        slot.stream.start_stream(&mut slot.transport).await?;
+        Ok(pooled)
+    }
+}
+
+/// A stream checked out of a [`ClientStreamPool`], returned to it when dropped. Transactions
+/// interrupted by the drop (e.g. by a timeout) leave the stream to be reopened, since their
+/// responses may still arrive on it
+pub struct PooledStream<'a, T> {
+    pool: &'a ClientStreamPool<T>,
+    slot: Option<PoolSlot<T>>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T> Deref for PooledStream<'_, T> {
+    type Target = ClientStream;
+
+    fn deref(&self) -> &ClientStream {
+        &self.slot.as_ref().unwrap().stream
+    }
+}
+
+impl<T> DerefMut for PooledStream<'_, T> {
+    fn deref_mut(&mut self) -> &mut ClientStream {
+        &mut self.slot.as_mut().unwrap().stream
+    }
+}
+
+impl<T> Drop for PooledStream<'_, T> {
+    fn drop(&mut self) {
+        let Some(mut slot) = self.slot.take() else {
+            return;
+        };
+        if slot.stream.state.awaiting.is_some() {
+            slot.stream.abort_stream();
+        } else {
+            slot.stream.finish_stream();
+        }
+        // Returned before the permit is released, so the next checkout finds it
+        self.pool.idle.lock().unwrap().push(slot);
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+    use crate::{
+        dummy::server::{behavior::BehaviorConfig, server_processing_mode, ExtProcService},
+        proto::envoy::service::ext_proc::v3::{
+            external_processor_client::ExternalProcessorClient,
+            external_processor_server::ExternalProcessorServer,
+        },
+    };
+
+    #[test]
+    fn streams_are_shared_and_reused_by_policy() {
+        let service = ExtProcService::new(server_processing_mode(false), BehaviorConfig::default());
+        let client = ExternalProcessorClient::new(ExternalProcessorServer::new(service));
+        let data = DummyData {
+            req_headers: vec![(":method".to_string(), "GET".to_string())],
+            resp_status: 200,
+            resp_headers: vec![(":status".to_string(), "200".to_string())],
+            ..Default::default()
+        };
+        let config = Config {
+            reuse_stream: true,
+            max_handled: Some(2),
+            ..Default::default()
+        };
+        let pool = ClientStreamPool::new(vec![client], Arc::new(data), config);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            for handled_before in [0, 1, 0] {
+                let mut stream = pool.checkout().await.unwrap();
+                assert_eq!(stream.state.handle_count, handled_before);
+                // The only stream is checked out
+                assert!(pool.checkout().now_or_never().is_none());
+                stream.handle_stream().await.unwrap();
+            }
+        });
+        assert_eq!(pool.idle(), 1);
+    }
+}
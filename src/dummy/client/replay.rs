@@ -0,0 +1,255 @@
+//! Replays of recorded streams (see [`crate::dummy::recording`]): the recorded requests are sent
+//! verbatim, in order, and every response of the server is compared with the recorded one
+
+use std::time::Duration;
+
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+use super::transport::Transport;
+use crate::{
+    dummy::recording::RecordedMessage,
+    proto::envoy::{
+        config::core::v3::HeaderMutation,
+        service::ext_proc::v3::{processing_response::Response, ProcessingResponse},
+    },
+};
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Header mutations of these headers are left out of the comparison, for values that change
+    /// from one run to the next like timestamps or processing times. Case insensitive
+    pub ignore_headers: Vec<String>,
+    /// How long to wait for each response before reporting it missing
+    pub timeout: Duration,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            ignore_headers: Vec::new(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A response of the server that differs from the recorded one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    /// Position of the response in the stream, from 0
+    pub index: usize,
+    /// None if the server sent a response the recording doesn't have
+    pub expected: Option<ProcessingResponse>,
+    /// None if the server didn't send the response in time
+    pub actual: Option<ProcessingResponse>,
+    /// The differing fields, as `-` (expected) and `+` (actual) lines
+    pub diff: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayReport {
+    pub requests: usize,
+    pub responses: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    pub fn matched(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Replays one recorded stream. A missing response ends the replay, as the responses after it
+/// could not be told apart
+pub async fn replay<T: Transport>(
+    transport: &mut T,
+    recording: &[RecordedMessage],
+    options: &ReplayOptions,
+) -> Result<ReplayReport, Status> {
+    let (sender, receiver) = tokio::sync::mpsc::channel(recording.len().max(1));
+    let mut responses = transport.open(ReceiverStream::new(receiver)).await?;
+    let mut report = ReplayReport::default();
+    for message in recording {
+        if let Some(ref request) = message.request {
+            if sender.send(request.clone()).await.is_err() {
+                break;
+            }
+            report.requests += 1;
+        }
+        let Some(ref expected) = message.response else {
+            continue;
+        };
+        let index = report.responses;
+        let actual = match tokio::time::timeout(options.timeout, responses.message()).await {
+            Ok(response) => response?,
+            Err(_) => None,
+        };
+        let missing = actual.is_none();
+        if actual.is_some() {
+            report.responses += 1;
+        }
+        if let Some(mismatch) = compare(index, Some(expected), actual.as_ref(), options) {
+            report.mismatches.push(mismatch);
+        }
+        if missing {
+            return Ok(report);
+        }
+    }
+    drop(sender);
+    // Anything past the recorded responses is unexpected
+    while let Ok(Some(actual)) = tokio::time::timeout(options.timeout, responses.message())
+        .await
+        .unwrap_or(Ok(None))
+    {
+        let mismatch = compare(report.responses, None, Some(&actual), options);
+        report.responses += 1;
+        report.mismatches.extend(mismatch);
+    }
+    Ok(report)
+}
+
+fn compare(
+    index: usize,
+    expected: Option<&ProcessingResponse>,
+    actual: Option<&ProcessingResponse>,
+    options: &ReplayOptions,
+) -> Option<Mismatch> {
+    let normalize = |response: Option<&ProcessingResponse>| {
+        response.cloned().map(|mut response| {
+            remove_ignored_headers(&mut response, &options.ignore_headers);
+            response
+        })
+    };
+    let (expected, actual) = (normalize(expected), normalize(actual));
+    if expected == actual {
+        return None;
+    }
+    let diff = diff_lines(&format!("{:#?}", expected), &format!("{:#?}", actual));
+    Some(Mismatch {
+        index,
+        expected,
+        actual,
+        diff,
+    })
+}
+
+fn remove_ignored_headers(response: &mut ProcessingResponse, ignore_headers: &[String]) {
+    if ignore_headers.is_empty() {
+        return;
+    }
+    let mutation: Option<&mut HeaderMutation> = match response.response {
+        Some(Response::RequestHeaders(ref mut headers))
+        | Some(Response::ResponseHeaders(ref mut headers)) => headers
+            .response
+            .as_mut()
+            .and_then(|common| common.header_mutation.as_mut()),
+        Some(Response::RequestBody(ref mut body)) | Some(Response::ResponseBody(ref mut body)) => {
+            body.response
+                .as_mut()
+                .and_then(|common| common.header_mutation.as_mut())
+        }
+        Some(Response::RequestTrailers(ref mut trailers))
+        | Some(Response::ResponseTrailers(ref mut trailers)) => trailers.header_mutation.as_mut(),
+        Some(Response::ImmediateResponse(ref mut immediate_response)) => {
+            immediate_response.headers.as_mut()
+        }
+        None => None,
+    };
+    let Some(mutation) = mutation else {
+        return;
+    };
+    let ignored = |key: &str| ignore_headers.iter().any(|name| name.eq_ignore_ascii_case(key));
+    mutation.set_headers.retain(|option| match option.header {
+        Some(ref header) => !ignored(&header.key),
+        None => true,
+    });
+    mutation.remove_headers.retain(|key| !ignored(key));
+}
+
+/// The lines only one side has, from a longest common subsequence of the two
+fn diff_lines(expected: &str, actual: &str) -> Vec<String> {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    // common[i][j] is the length of the LCS of expected[i..] and actual[j..]
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            diff.push(format!("-{}", expected[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", actual[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::{
+        dummy::server::{behavior::BehaviorConfig, server_processing_mode, ExtProcService},
+        proto::envoy::service::ext_proc::v3::{
+            external_processor_client::ExternalProcessorClient,
+            external_processor_server::ExternalProcessorServer, processing_request::Request,
+            ProcessingRequest,
+        },
+    };
+
+    #[test]
+    fn responses_are_compared_with_the_recording() {
+        let service = ExtProcService::new(server_processing_mode(false), BehaviorConfig::default());
+        let mut client = ExternalProcessorClient::new(ExternalProcessorServer::new(service));
+        let request = ProcessingRequest {
+            async_mode: false,
+            request: Some(Request::RequestHeaders(Default::default())),
+        };
+        let mut recording = vec![
+            RecordedMessage {
+                elapsed_us: 0,
+                request: Some(request),
+                response: None,
+            },
+            RecordedMessage {
+                elapsed_us: 10,
+                request: None,
+                response: Some(ProcessingResponse::default()),
+            },
+        ];
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let options = ReplayOptions::default();
+        let report = runtime
+            .block_on(replay(&mut client, &recording, &options))
+            .unwrap();
+        assert_eq!((report.requests, report.responses), (1, 1));
+        assert_eq!(report.mismatches.len(), 1);
+        let mismatch = &report.mismatches[0];
+        assert!(mismatch.diff.iter().any(|line| line.starts_with('-')));
+        assert!(mismatch.diff.iter().any(|line| line.contains("RequestHeaders")));
+
+        // What the server answers is what it answers next time
+        recording[1].response = mismatch.actual.clone();
+        let report = runtime
+            .block_on(replay(&mut client, &recording, &options))
+            .unwrap();
+        assert!(report.matched());
+    }
+}
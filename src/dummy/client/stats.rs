@@ -0,0 +1,356 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use metered::{clear::Clear, hdr_histogram::AtomicHdrHistogram, metric::Histogram};
+use serde::Serialize;
+
+use super::{transaction::PhaseTiming, Phase, ProtocolViolation};
+use crate::proto::envoy::extensions::filters::http::ext_proc::v3::ProcessingMode;
+
+/// Counters of protocol events, shared by every stream created with the same stats
+#[derive(Default, Debug, Serialize)]
+pub struct StreamStats {
+    /// Messages that weren't answered within the message timeout, each abandoning its stream
+    pub message_timeouts: AtomicU64,
+    /// Responses that don't answer the message that was sent
+    pub unexpected_response_types: AtomicU64,
+    /// Extra responses to an already answered message
+    pub duplicate_responses: AtomicU64,
+    /// Responses received between transactions
+    pub responses_after_end_of_stream: AtomicU64,
+    /// Responses consumed by [`super::ClientStream::drain`] before reusing a stream
+    pub orphaned_responses: AtomicU64,
+    /// Requests that waited for room in the request channel, because the server or the
+    /// HTTP/2 flow-control window doesn't take them in as fast as they are sent
+    pub backpressure_events: AtomicU64,
+    /// Time spent waiting for room in the request channel, in microseconds
+    pub backpressure_micros: AtomicU64,
+    /// Encoded sizes of requests and responses
+    pub message_sizes: MessageSizes,
+    /// Encoded size of the dynamic_metadata of every response, in bytes
+    pub dynamic_metadata_bytes: AtomicU64,
+    /// Processing time servers reported for each transaction, when they report it
+    pub server_times: ServerTimes,
+    /// How long each phase's messages waited for their response
+    pub phase_latencies: PhaseLatencies,
+    /// How servers use mode_override
+    pub mode_overrides: ModeOverrideStats,
+    /// Streams killed on purpose and the cost of replacing them
+    pub stream_kills: StreamKillStats,
+}
+
+/// Histograms of encoded message sizes, in bytes
+#[derive(Debug, Serialize)]
+pub struct MessageSizes {
+    pub requests: AtomicHdrHistogram,
+    pub responses: AtomicHdrHistogram,
+}
+
+/// Largest recorded size, messages are far smaller in practice
+const MAX_RECORDED_SIZE: u64 = 1 << 30;
+
+impl Default for MessageSizes {
+    fn default() -> Self {
+        MessageSizes {
+            requests: AtomicHdrHistogram::with_bound(MAX_RECORDED_SIZE),
+            responses: AtomicHdrHistogram::with_bound(MAX_RECORDED_SIZE),
+        }
+    }
+}
+
+/// Usage of mode_override, which sets the processing mode of the next messages of a transaction
+#[derive(Default, Debug, Serialize)]
+pub struct ModeOverrideStats {
+    /// Transactions that received at least one mode_override
+    pub transactions: AtomicU64,
+    /// mode_overrides received with the response to each phase's message
+    pub after_phase: PhaseCounts,
+    /// mode_overrides that changed each field of the processing mode. Servers may send the
+    /// current mode again, changing nothing
+    pub changed_fields: ModeFieldCounts,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct PhaseCounts {
+    pub request_headers: AtomicU64,
+    pub request_body: AtomicU64,
+    pub request_trailers: AtomicU64,
+    pub response_headers: AtomicU64,
+    pub response_body: AtomicU64,
+    pub response_trailers: AtomicU64,
+}
+
+impl PhaseCounts {
+    fn count(&self, phase: Phase) -> &AtomicU64 {
+        match phase {
+            Phase::RequestHeaders => &self.request_headers,
+            Phase::RequestBody => &self.request_body,
+            Phase::RequestTrailers => &self.request_trailers,
+            Phase::ResponseHeaders => &self.response_headers,
+            Phase::ResponseBody => &self.response_body,
+            Phase::ResponseTrailers => &self.response_trailers,
+        }
+    }
+
+    fn clear(&self) {
+        for phase in Phase::ALL {
+            self.count(phase).store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct ModeFieldCounts {
+    pub request_header_mode: AtomicU64,
+    pub response_header_mode: AtomicU64,
+    pub request_body_mode: AtomicU64,
+    pub response_body_mode: AtomicU64,
+    pub request_trailer_mode: AtomicU64,
+    pub response_trailer_mode: AtomicU64,
+}
+
+impl ModeFieldCounts {
+    fn record(&self, old: &ProcessingMode, new: &ProcessingMode) {
+        let fields = [
+            (old.request_header_mode, new.request_header_mode, &self.request_header_mode),
+            (old.response_header_mode, new.response_header_mode, &self.response_header_mode),
+            (old.request_body_mode, new.request_body_mode, &self.request_body_mode),
+            (old.response_body_mode, new.response_body_mode, &self.response_body_mode),
+            (old.request_trailer_mode, new.request_trailer_mode, &self.request_trailer_mode),
+            (old.response_trailer_mode, new.response_trailer_mode, &self.response_trailer_mode),
+        ];
+        for (old, new, count) in fields {
+            if old != new {
+                StreamStats::increment(count);
+            }
+        }
+    }
+
+    fn clear(&self) {
+        for count in [
+            &self.request_header_mode,
+            &self.response_header_mode,
+            &self.request_body_mode,
+            &self.response_body_mode,
+            &self.request_trailer_mode,
+            &self.response_trailer_mode,
+        ] {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl ModeOverrideStats {
+    /// Records a mode_override received in response to `phase`'s message, which changed the
+    /// stream's processing mode from `before` to `after`
+    pub(super) fn record(
+        &self,
+        phase: Option<Phase>,
+        before: &ProcessingMode,
+        after: &ProcessingMode,
+    ) {
+        if let Some(phase) = phase {
+            StreamStats::increment(self.after_phase.count(phase));
+        }
+        self.changed_fields.record(before, after);
+    }
+
+    fn clear(&self) {
+        self.transactions.store(0, Ordering::Relaxed);
+        self.after_phase.clear();
+        self.changed_fields.clear();
+    }
+}
+
+/// Histogram of server processing times, in microseconds
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct ServerTimes(pub AtomicHdrHistogram);
+
+/// Largest recorded server time, an hour
+const MAX_RECORDED_MICROS: u64 = 3_600_000_000;
+
+impl Default for ServerTimes {
+    fn default() -> Self {
+        ServerTimes(AtomicHdrHistogram::with_bound(MAX_RECORDED_MICROS))
+    }
+}
+
+/// Histograms of the time from sending a message to receiving its response, in microseconds, per
+/// phase. Every chunk of a body is a message of its own
+#[derive(Debug, Serialize)]
+pub struct PhaseLatencies {
+    pub request_headers: AtomicHdrHistogram,
+    pub request_body: AtomicHdrHistogram,
+    pub request_trailers: AtomicHdrHistogram,
+    pub response_headers: AtomicHdrHistogram,
+    pub response_body: AtomicHdrHistogram,
+    pub response_trailers: AtomicHdrHistogram,
+}
+
+impl Default for PhaseLatencies {
+    fn default() -> Self {
+        let histogram = || AtomicHdrHistogram::with_bound(MAX_RECORDED_MICROS);
+        PhaseLatencies {
+            request_headers: histogram(),
+            request_body: histogram(),
+            request_trailers: histogram(),
+            response_headers: histogram(),
+            response_body: histogram(),
+            response_trailers: histogram(),
+        }
+    }
+}
+
+impl PhaseLatencies {
+    pub fn histogram(&self, phase: Phase) -> &AtomicHdrHistogram {
+        match phase {
+            Phase::RequestHeaders => &self.request_headers,
+            Phase::RequestBody => &self.request_body,
+            Phase::RequestTrailers => &self.request_trailers,
+            Phase::ResponseHeaders => &self.response_headers,
+            Phase::ResponseBody => &self.response_body,
+            Phase::ResponseTrailers => &self.response_trailers,
+        }
+    }
+
+    /// The histogram of every phase, in the order a transaction goes through them
+    pub fn iter(&self) -> impl Iterator<Item = (Phase, &AtomicHdrHistogram)> {
+        Phase::ALL.into_iter().map(|phase| (phase, self.histogram(phase)))
+    }
+
+    /// Records the phases of a transaction that were answered
+    pub(super) fn record(&self, phases: &[PhaseTiming]) {
+        for timing in phases {
+            if let Some(answered_at_us) = timing.answered_at_us {
+                self.histogram(timing.phase)
+                    .record(answered_at_us.saturating_sub(timing.sent_at_us));
+            }
+        }
+    }
+
+    fn clear(&self) {
+        for phase in Phase::ALL {
+            self.histogram(phase).clear();
+        }
+    }
+}
+
+/// Streams killed by the client (see [`super::Config::kill`]), and how long the first transaction
+/// on the stream replacing a killed or server-closed stream took, opening the stream included
+#[derive(Debug, Serialize)]
+pub struct StreamKillStats {
+    pub killed: AtomicU64,
+    /// In microseconds
+    pub recovery_micros: AtomicHdrHistogram,
+}
+
+impl Default for StreamKillStats {
+    fn default() -> Self {
+        StreamKillStats {
+            killed: AtomicU64::new(0),
+            recovery_micros: AtomicHdrHistogram::with_bound(MAX_RECORDED_MICROS),
+        }
+    }
+}
+
+impl StreamStats {
+    pub(super) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_violation(&self, violation: ProtocolViolation) {
+        StreamStats::increment(match violation {
+            ProtocolViolation::UnexpectedResponseType => &self.unexpected_response_types,
+            ProtocolViolation::DuplicateResponse => &self.duplicate_responses,
+            ProtocolViolation::ResponseAfterEndOfStream => &self.responses_after_end_of_stream,
+        });
+    }
+
+    pub(super) fn record_backpressure(&self, blocked: Duration) {
+        StreamStats::increment(&self.backpressure_events);
+        self.backpressure_micros
+            .fetch_add(blocked.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_dynamic_metadata(&self, bytes: usize) {
+        self.dynamic_metadata_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Total time requests waited for room in the request channel
+    pub fn backpressure_time(&self) -> Duration {
+        Duration::from_micros(self.backpressure_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn clear(&self) {
+        self.message_timeouts.store(0, Ordering::Relaxed);
+        self.unexpected_response_types.store(0, Ordering::Relaxed);
+        self.duplicate_responses.store(0, Ordering::Relaxed);
+        self.responses_after_end_of_stream.store(0, Ordering::Relaxed);
+        self.orphaned_responses.store(0, Ordering::Relaxed);
+        self.backpressure_events.store(0, Ordering::Relaxed);
+        self.backpressure_micros.store(0, Ordering::Relaxed);
+        self.dynamic_metadata_bytes.store(0, Ordering::Relaxed);
+        self.message_sizes.requests.clear();
+        self.message_sizes.responses.clear();
+        self.server_times.0.clear();
+        self.phase_latencies.clear();
+        self.mode_overrides.clear();
+        self.stream_kills.killed.store(0, Ordering::Relaxed);
+        self.stream_kills.recovery_micros.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::envoy::extensions::filters::http::ext_proc::v3::processing_mode::BodySendMode;
+
+    #[test]
+    fn mode_overrides_are_broken_down() {
+        let stats = ModeOverrideStats::default();
+        let before = ProcessingMode::default();
+        let mut after = before.clone();
+        after.set_response_body_mode(BodySendMode::Streamed);
+        stats.record(Some(Phase::RequestHeaders), &before, &after);
+        stats.record(Some(Phase::ResponseHeaders), &after, &after);
+        assert_eq!(stats.after_phase.request_headers.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.after_phase.response_headers.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.changed_fields.response_body_mode.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.changed_fields.request_body_mode.load(Ordering::Relaxed), 0);
+        stats.clear();
+        assert_eq!(stats.after_phase.request_headers.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn phase_latencies_are_recorded_per_phase() {
+        let latencies = PhaseLatencies::default();
+        let timing = |phase, sent_at_us, answered_at_us| PhaseTiming {
+            phase,
+            sent_at_us,
+            answered_at_us,
+            request_bytes: 0,
+            response_bytes: 0,
+        };
+        latencies.record(&[
+            timing(Phase::RequestHeaders, 100, Some(150)),
+            timing(Phase::RequestBody, 150, Some(400)),
+            timing(Phase::RequestBody, 160, Some(410)),
+            // Never answered, e.g. after an ImmediateResponse
+            timing(Phase::ResponseHeaders, 500, None),
+        ]);
+        assert_eq!(latencies.request_headers.histogram().max(), 50);
+        assert_eq!(latencies.request_body.histogram().len(), 2);
+        assert_eq!(latencies.response_headers.histogram().len(), 0);
+        let recorded: Vec<_> = latencies
+            .iter()
+            .filter(|(_, histogram)| histogram.histogram().len() > 0)
+            .map(|(phase, _)| phase)
+            .collect();
+        assert_eq!(recorded, [Phase::RequestHeaders, Phase::RequestBody]);
+        latencies.clear();
+        assert_eq!(latencies.request_body.histogram().len(), 0);
+    }
+}
@@ -0,0 +1,57 @@
+//! Bandwidth cap of a stream's body sends, emulating slow clients and origins
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Token bucket of body bytes, refilled continuously and holding up to one second of them
+pub(super) struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(super) fn new(bytes_per_sec: u64) -> TokenBucket {
+        TokenBucket {
+            rate: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes `bytes` from the bucket, returning how long to wait before sending them. Bodies
+    /// larger than the bucket borrow from later refills, delaying the next ones
+    pub(super) fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_are_spread_at_the_rate() {
+        let mut bucket = TokenBucket::new(1000);
+        let start = bucket.refilled_at;
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, start), Duration::from_millis(500));
+        // The debt is paid off before the next bytes go out
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.reserve(250, later), Duration::from_millis(250));
+        // Idle time refills at most one second of bytes
+        let idle = later + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(1000, idle), Duration::ZERO);
+        assert_eq!(bucket.reserve(1, idle), Duration::from_millis(1));
+    }
+}
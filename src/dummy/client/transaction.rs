@@ -0,0 +1,459 @@
+use std::{
+    fmt::{Display, Formatter},
+    ops::Range,
+    time::Duration,
+};
+
+use prost::Message;
+use serde::Serialize;
+use tonic::Code;
+
+use super::error::StreamHandleError;
+use super::mutation::{apply_body_mutation, apply_header_mutation, removed_headers_present};
+use super::Phase;
+use crate::dummy::{
+    attribute_echo_header, protobuf::value_to_string, DummyData, PROCESSING_TIME_HEADER,
+};
+use crate::proto::google::protobuf::Struct;
+use crate::proto::envoy::service::ext_proc::v3::{
+    common_response::ResponseStatus, processing_response::Response, CommonResponse,
+    HeaderMutation, ImmediateResponse, ProcessingRequest,
+};
+
+/// The last handled transaction, after applying the server's mutations
+#[derive(Default, Debug, Clone)]
+pub struct Transaction {
+    pub request_headers: Vec<(String, String)>,
+    /// Request body after the server's mutations, None if it wasn't mutated
+    pub request_body: Option<Vec<u8>>,
+    pub request_trailers: Vec<(String, String)>,
+    pub response_headers: Vec<(String, String)>,
+    /// Response body after the server's mutations, None if it wasn't mutated
+    pub response_body: Option<Vec<u8>>,
+    pub response_trailers: Vec<(String, String)>,
+    /// Set if the server answered with CONTINUE_AND_REPLACE, ending processing of the request
+    pub request_replaced: bool,
+    /// Set if the server answered with CONTINUE_AND_REPLACE, ending processing of the response
+    pub response_replaced: bool,
+    /// Set if the server ended the transaction with an ImmediateResponse
+    pub immediate_response: Option<ImmediateResponseReport>,
+    /// dynamic_metadata of all responses, merged like Envoy merges it into the filter's namespace
+    pub dynamic_metadata: Struct,
+    /// Encoded size of the dynamic_metadata received, summed over the responses
+    pub dynamic_metadata_bytes: usize,
+    /// Requests sent during the transaction, if captured (see [`super::transcript`])
+    pub transcript: Vec<ProcessingRequest>,
+    /// Time the server reported processing the transaction's messages, zero if it doesn't report
+    /// it (see [`PROCESSING_TIME_HEADER`])
+    pub server_time: Duration,
+    /// mode_overrides received during the transaction
+    pub mode_overrides: u32,
+    /// The messages sent, in order, with their timings and sizes
+    pub phases: Vec<PhaseTiming>,
+    /// Byte range of its body the last body message carried
+    pub(super) body_chunk: Option<Range<usize>>,
+}
+
+/// When a message of a transaction was sent and answered, relative to the start of the
+/// transaction, and the encoded sizes of the message and of its responses
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    pub sent_at_us: u64,
+    /// None if the message wasn't answered, e.g. in observability mode or after a failure
+    pub answered_at_us: Option<u64>,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+}
+
+impl Transaction {
+    pub(super) fn new(data: &DummyData) -> Transaction {
+        Transaction {
+            request_headers: data.req_headers.clone(),
+            request_body: None,
+            request_trailers: data.req_trailers.clone(),
+            response_headers: data.resp_headers.clone(),
+            response_body: None,
+            response_trailers: data.resp_trailers.clone(),
+            request_replaced: false,
+            response_replaced: false,
+            immediate_response: None,
+            dynamic_metadata: Struct::default(),
+            dynamic_metadata_bytes: 0,
+            transcript: Vec::new(),
+            server_time: Duration::ZERO,
+            mode_overrides: 0,
+            phases: Vec::new(),
+            body_chunk: None,
+        }
+    }
+
+    pub(super) fn merge_dynamic_metadata(&mut self, metadata: Struct) {
+        self.dynamic_metadata_bytes += metadata.encoded_len();
+        self.dynamic_metadata.fields.extend(metadata.fields);
+    }
+
+    /// Checks the transaction against the data's expectations
+    pub(super) fn verify(&self, data: &DummyData) -> Result<(), StreamHandleError> {
+        for key in &data.expect.dynamic_metadata_keys {
+            if !self.dynamic_metadata.fields.contains_key(key) {
+                return Err(StreamHandleError::MissingDynamicMetadata(key.clone()));
+            }
+        }
+        for name in &data.expect.echoed_attributes {
+            if !self.attribute_echoed(data, name) {
+                return Err(StreamHandleError::AttributeNotEchoed(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the sent value of attribute `name` came back as an echo header or dynamic metadata
+    fn attribute_echoed(&self, data: &DummyData, name: &str) -> bool {
+        let Some(sent) = data
+            .req_attributes
+            .values()
+            .find_map(|attributes| attributes.fields.get(name))
+        else {
+            return false;
+        };
+        let header = attribute_echo_header(name);
+        let echoed_header = self
+            .request_headers
+            .iter()
+            .any(|(key, value)| key == &header && value == &value_to_string(sent));
+        echoed_header || self.dynamic_metadata.fields.get(name) == Some(sent)
+    }
+
+    /// Applies the mutations of a server response. Body responses respond to the chunk of the
+    /// body the last body message carried, the whole body unless it was streamed.
+    ///
+    /// Fails if headers the server removed are still present afterwards.
+    pub(super) fn apply_response(
+        &mut self,
+        response: &Response,
+        data: &DummyData,
+    ) -> Result<(), StreamHandleError> {
+        fn verified_mutation(
+            headers: &mut Vec<(String, String)>,
+            mutation: &HeaderMutation,
+        ) -> Result<(), StreamHandleError> {
+            apply_header_mutation(headers, mutation);
+            match removed_headers_present(headers, mutation).first() {
+                Some(name) => Err(StreamHandleError::HeaderNotRemoved(name.to_string())),
+                None => Ok(()),
+            }
+        }
+        fn header_mutation(
+            headers: &mut Vec<(String, String)>,
+            common: &Option<CommonResponse>,
+        ) -> Result<(), StreamHandleError> {
+            match common
+                .as_ref()
+                .and_then(|common| common.header_mutation.as_ref())
+            {
+                Some(mutation) => verified_mutation(headers, mutation),
+                None => Ok(()),
+            }
+        }
+        fn body_mutation(
+            effective: &mut Option<Vec<u8>>,
+            body: &[u8],
+            chunk: Range<usize>,
+            common: &Option<CommonResponse>,
+        ) {
+            let mutation = common
+                .as_ref()
+                .and_then(|common| common.body_mutation.as_ref());
+            apply_body_mutation(effective, body, chunk, mutation);
+        }
+        /// Adds the response's trailers, returns whether processing of the message was replaced
+        fn continue_and_replace(
+            trailers: &mut Vec<(String, String)>,
+            common: &Option<CommonResponse>,
+        ) -> bool {
+            let Some(common) = common.as_ref() else {
+                return false;
+            };
+            if let Some(ref added) = common.trailers {
+                trailers.extend(
+                    added
+                        .headers
+                        .iter()
+                        .map(|trailer| (trailer.key.to_lowercase(), trailer.value.clone())),
+                );
+            }
+            common.status == ResponseStatus::ContinueAndReplace as i32
+        }
+
+        self.server_time += reported_processing_time(response).unwrap_or_default();
+        match response {
+            Response::RequestHeaders(headers) => {
+                header_mutation(&mut self.request_headers, &headers.response)?;
+                let whole = 0..data.req_body.len();
+                body_mutation(&mut self.request_body, &data.req_body, whole, &headers.response);
+                self.request_replaced =
+                    continue_and_replace(&mut self.request_trailers, &headers.response);
+            }
+            Response::ResponseHeaders(headers) => {
+                header_mutation(&mut self.response_headers, &headers.response)?;
+                let whole = 0..data.resp_body.len();
+                body_mutation(&mut self.response_body, &data.resp_body, whole, &headers.response);
+                self.response_replaced =
+                    continue_and_replace(&mut self.response_trailers, &headers.response);
+            }
+            Response::RequestBody(body) => {
+                header_mutation(&mut self.request_headers, &body.response)?;
+                let chunk = self.body_chunk.clone().unwrap_or(0..data.req_body.len());
+                body_mutation(&mut self.request_body, &data.req_body, chunk, &body.response);
+            }
+            Response::ResponseBody(body) => {
+                header_mutation(&mut self.response_headers, &body.response)?;
+                let chunk = self.body_chunk.clone().unwrap_or(0..data.resp_body.len());
+                body_mutation(&mut self.response_body, &data.resp_body, chunk, &body.response);
+            }
+            Response::RequestTrailers(trailers) => {
+                if let Some(ref mutation) = trailers.header_mutation {
+                    verified_mutation(&mut self.request_trailers, mutation)?;
+                }
+            }
+            Response::ResponseTrailers(trailers) => {
+                if let Some(ref mutation) = trailers.header_mutation {
+                    verified_mutation(&mut self.response_trailers, mutation)?;
+                }
+            }
+            Response::ImmediateResponse(immediate_response) => {
+                self.immediate_response = Some(ImmediateResponseReport::from(immediate_response));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The processing time the server reported in the response's header mutation, if any
+fn reported_processing_time(response: &Response) -> Option<Duration> {
+    let mutation = match response {
+        Response::RequestHeaders(headers) | Response::ResponseHeaders(headers) => {
+            headers.response.as_ref()?.header_mutation.as_ref()
+        }
+        Response::RequestBody(body) | Response::ResponseBody(body) => {
+            body.response.as_ref()?.header_mutation.as_ref()
+        }
+        Response::RequestTrailers(trailers) | Response::ResponseTrailers(trailers) => {
+            trailers.header_mutation.as_ref()
+        }
+        Response::ImmediateResponse(immediate_response) => immediate_response.headers.as_ref(),
+    }?;
+    mutation
+        .set_headers
+        .iter()
+        .filter_map(|option| option.header.as_ref())
+        .find(|header| header.key.eq_ignore_ascii_case(PROCESSING_TIME_HEADER))
+        .and_then(|header| header.value.parse().ok())
+        .map(Duration::from_micros)
+}
+
+/// A decoded ImmediateResponse
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImmediateResponseReport {
+    /// HTTP status of the local reply, 0 if the server didn't set one
+    pub status: u32,
+    pub grpc_status: Option<Code>,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub details: String,
+}
+
+impl From<&ImmediateResponse> for ImmediateResponseReport {
+    fn from(response: &ImmediateResponse) -> Self {
+        let mut headers = Vec::new();
+        if let Some(ref mutation) = response.headers {
+            apply_header_mutation(&mut headers, mutation);
+        }
+        ImmediateResponseReport {
+            status: response
+                .status
+                .as_ref()
+                .map_or(0, |status| status.code as u32),
+            grpc_status: response
+                .grpc_status
+                .as_ref()
+                .map(|status| Code::from_i32(status.status as i32)),
+            headers,
+            body: response.body.clone(),
+            details: response.details.clone(),
+        }
+    }
+}
+
+impl Display for ImmediateResponseReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "immediate response {}", self.status)?;
+        if let Some(code) = self.grpc_status {
+            write!(f, " (gRPC {:?})", code)?;
+        }
+        if !self.details.is_empty() {
+            write!(f, ", details '{}'", self.details)?;
+        }
+        for (key, value) in &self.headers {
+            write!(f, ", {}: {}", key, value)?;
+        }
+        if !self.body.is_empty() {
+            write!(f, ", body '{}'", self.body)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dummy::ExpectationConfig;
+    use crate::proto::envoy::{
+        config::core::v3::{HeaderMap, HeaderValue, HeaderValueOption},
+        r#type::v3::HttpStatus,
+        service::ext_proc::v3::{GrpcStatus, HeadersResponse},
+    };
+
+    #[test]
+    fn verifies_dynamic_metadata_keys() {
+        use crate::proto::google::protobuf::{value::Kind, Value};
+
+        let data = DummyData {
+            expect: ExpectationConfig {
+                dynamic_metadata_keys: vec!["decision".to_string(), "score".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut transaction = Transaction::new(&data);
+        let metadata = |key: &str| Struct {
+            fields: [(
+                key.to_string(),
+                Value {
+                    kind: Some(Kind::BoolValue(true)),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        };
+
+        transaction.merge_dynamic_metadata(metadata("decision"));
+        assert!(matches!(
+            transaction.verify(&data),
+            Err(StreamHandleError::MissingDynamicMetadata(ref key)) if key == "score"
+        ));
+        transaction.merge_dynamic_metadata(metadata("score"));
+        assert!(transaction.verify(&data).is_ok());
+        assert_eq!(
+            transaction.dynamic_metadata_bytes,
+            metadata("decision").encoded_len() + metadata("score").encoded_len()
+        );
+    }
+
+    #[test]
+    fn verifies_echoed_attributes() {
+        use crate::dummy::{protobuf::struct_from_json, EXT_PROC_ATTRIBUTE_NAMESPACE};
+
+        let serde_json::Value::Object(attributes) =
+            serde_json::json!({"request.path": "/login", "source.port": 8443})
+        else {
+            unreachable!()
+        };
+        let data = DummyData {
+            req_attributes: [(
+                EXT_PROC_ATTRIBUTE_NAMESPACE.to_string(),
+                struct_from_json(&attributes),
+            )]
+            .into_iter()
+            .collect(),
+            expect: ExpectationConfig {
+                echoed_attributes: vec!["request.path".to_string(), "source.port".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut transaction = Transaction::new(&data);
+        transaction
+            .request_headers
+            .push((attribute_echo_header("request.path"), "/login".to_string()));
+        assert!(matches!(
+            transaction.verify(&data),
+            Err(StreamHandleError::AttributeNotEchoed(ref name)) if name == "source.port"
+        ));
+
+        let port = data.req_attributes[EXT_PROC_ATTRIBUTE_NAMESPACE].fields["source.port"].clone();
+        transaction.merge_dynamic_metadata(Struct {
+            fields: [("source.port".to_string(), port)].into_iter().collect(),
+        });
+        assert!(transaction.verify(&data).is_ok());
+    }
+
+    #[test]
+    fn continue_and_replace_adds_trailers() {
+        let data = DummyData {
+            resp_status: 200,
+            resp_body: b"body".to_vec(),
+            resp_trailers: vec![("grpc-status".to_string(), "0".to_string())],
+            ..Default::default()
+        };
+        let mut transaction = Transaction::new(&data);
+        let response = Response::ResponseHeaders(HeadersResponse {
+            response: Some(CommonResponse {
+                status: ResponseStatus::ContinueAndReplace as i32,
+                trailers: Some(HeaderMap {
+                    headers: vec![HeaderValue {
+                        key: "X-Trailer".to_string(),
+                        value: "added".to_string(),
+                    }],
+                }),
+                ..Default::default()
+            }),
+        });
+
+        transaction.apply_response(&response, &data).unwrap();
+        assert!(transaction.response_replaced);
+        assert!(!transaction.request_replaced);
+        assert_eq!(transaction.response_body, None);
+        assert_eq!(
+            transaction.response_trailers,
+            vec![
+                ("grpc-status".to_string(), "0".to_string()),
+                ("x-trailer".to_string(), "added".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_immediate_response() {
+        let report = ImmediateResponseReport::from(&ImmediateResponse {
+            status: Some(HttpStatus { code: 403 }),
+            headers: Some(HeaderMutation {
+                set_headers: vec![HeaderValueOption {
+                    header: Some(HeaderValue {
+                        key: "x-denied-by".to_string(),
+                        value: "ext_proc".to_string(),
+                    }),
+                    ..Default::default()
+                }],
+                remove_headers: vec![],
+            }),
+            body: "denied".to_string(),
+            grpc_status: Some(GrpcStatus {
+                status: Code::PermissionDenied as u32,
+            }),
+            details: "no access".to_string(),
+        });
+
+        assert_eq!(report.status, 403);
+        assert_eq!(report.grpc_status, Some(Code::PermissionDenied));
+        assert_eq!(
+            report.headers,
+            vec![("x-denied-by".to_string(), "ext_proc".to_string())]
+        );
+        assert_eq!(
+            report.to_string(),
+            "immediate response 403 (gRPC PermissionDenied), details 'no access', x-denied-by: ext_proc, body 'denied'"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Transcripts of the requests sent during a transaction, exported in the proto3 JSON mapping as
+//! newline-delimited messages, so an exchange can be replayed against any server with grpcurl:
+//!
+//! ```text
+//! grpcurl -plaintext -d @ -import-path third_party/envoy/api -import-path third_party/xds \
+//!     -import-path third_party/protoc-gen-validate -import-path third_party/googleapis \
+//!     -proto envoy/service/ext_proc/v3/external_processor.proto \
+//!     [::1]:50051 envoy.service.ext_proc.v3.ExternalProcessor/Process < transcript.json
+//! ```
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Map, Value};
+
+use crate::dummy::protobuf::struct_to_json;
+use crate::proto::envoy::{
+    config::core::v3::HeaderMap,
+    service::ext_proc::v3::{
+        processing_request::Request, HttpBody, HttpHeaders, HttpTrailers, ProcessingRequest,
+    },
+};
+
+/// One JSON message per line
+pub fn to_grpcurl_json(transcript: &[ProcessingRequest]) -> String {
+    transcript
+        .iter()
+        .map(|request| request_to_json(request).to_string() + "\n")
+        .collect()
+}
+
+/// Default values are omitted, like protobuf JSON printers do
+pub fn request_to_json(request: &ProcessingRequest) -> Value {
+    let mut message = Map::new();
+    if request.async_mode {
+        message.insert("asyncMode".to_string(), json!(true));
+    }
+    let (name, value) = match request.request {
+        Some(Request::RequestHeaders(ref headers)) => ("requestHeaders", headers_to_json(headers)),
+        Some(Request::ResponseHeaders(ref headers)) => {
+            ("responseHeaders", headers_to_json(headers))
+        }
+        Some(Request::RequestBody(ref body)) => ("requestBody", body_to_json(body)),
+        Some(Request::ResponseBody(ref body)) => ("responseBody", body_to_json(body)),
+        Some(Request::RequestTrailers(ref trailers)) => {
+            ("requestTrailers", trailers_to_json(trailers))
+        }
+        Some(Request::ResponseTrailers(ref trailers)) => {
+            ("responseTrailers", trailers_to_json(trailers))
+        }
+        None => return Value::Object(message),
+    };
+    message.insert(name.to_string(), value);
+    Value::Object(message)
+}
+
+fn header_map_to_json(headers: &HeaderMap) -> Value {
+    let headers: Vec<Value> = headers
+        .headers
+        .iter()
+        .map(|header| json!({"key": header.key, "value": header.value}))
+        .collect();
+    json!({ "headers": headers })
+}
+
+fn headers_to_json(headers: &HttpHeaders) -> Value {
+    let mut message = Map::new();
+    if let Some(ref header_map) = headers.headers {
+        message.insert("headers".to_string(), header_map_to_json(header_map));
+    }
+    if !headers.attributes.is_empty() {
+        let attributes: Map<String, Value> = headers
+            .attributes
+            .iter()
+            .map(|(namespace, attributes)| {
+                (namespace.clone(), Value::Object(struct_to_json(attributes)))
+            })
+            .collect();
+        message.insert("attributes".to_string(), Value::Object(attributes));
+    }
+    if headers.end_of_stream {
+        message.insert("endOfStream".to_string(), json!(true));
+    }
+    Value::Object(message)
+}
+
+fn body_to_json(body: &HttpBody) -> Value {
+    let mut message = Map::new();
+    if !body.body.is_empty() {
+        message.insert("body".to_string(), json!(STANDARD.encode(&body.body)));
+    }
+    if body.end_of_stream {
+        message.insert("endOfStream".to_string(), json!(true));
+    }
+    Value::Object(message)
+}
+
+fn trailers_to_json(trailers: &HttpTrailers) -> Value {
+    match trailers.trailers {
+        Some(ref trailers) => json!({ "trailers": header_map_to_json(trailers) }),
+        None => json!({}),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::envoy::config::core::v3::HeaderValue;
+
+    #[test]
+    fn requests_are_proto3_json_lines() {
+        let transcript = vec![
+            ProcessingRequest {
+                request: Some(Request::RequestHeaders(HttpHeaders {
+                    headers: Some(HeaderMap {
+                        headers: vec![HeaderValue {
+                            key: "host".to_string(),
+                            value: "www.example.com".to_string(),
+                        }],
+                    }),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+            ProcessingRequest {
+                request: Some(Request::RequestBody(HttpBody {
+                    body: b"hello".to_vec(),
+                    end_of_stream: true,
+                })),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            to_grpcurl_json(&transcript),
+            "{\"requestHeaders\":{\"headers\":{\"headers\":[{\"key\":\"host\",\"value\":\"www.example.com\"}]}}}\n\
+             {\"requestBody\":{\"body\":\"aGVsbG8=\",\"endOfStream\":true}}\n"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! How [`super::ClientStream`]s reach the External Processor
+
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    body::BoxBody,
+    client::GrpcService,
+    codegen::{Body, Bytes, StdError},
+    Status, Streaming,
+};
+
+use crate::proto::envoy::service::ext_proc::v3::{
+    external_processor_client::ExternalProcessorClient, ProcessingRequest, ProcessingResponse,
+};
+
+/// Opens ext_proc streams. Implemented by [`ExternalProcessorClient`]s over any gRPC service:
+/// a custom-configured [`tonic::transport::Channel`], an in-memory `ExternalProcessorServer`
+/// in tests, or a mock implementing this trait directly
+#[tonic::async_trait]
+pub trait Transport: Send {
+    /// Starts a `Process` call sending `requests`, returning the server's responses
+    async fn open(
+        &mut self,
+        requests: ReceiverStream<ProcessingRequest>,
+    ) -> Result<Streaming<ProcessingResponse>, Status>;
+}
+
+#[tonic::async_trait]
+impl<T> Transport for ExternalProcessorClient<T>
+where
+    T: GrpcService<BoxBody> + Send,
+    T::Future: Send,
+    T::Error: Into<StdError>,
+    T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    async fn open(
+        &mut self,
+        requests: ReceiverStream<ProcessingRequest>,
+    ) -> Result<Streaming<ProcessingResponse>, Status> {
+        Ok(self.process(requests).await?.into_inner())
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        dummy::{
+            client::{ClientStream, Config, Phase},
+            server::{behavior::BehaviorConfig, server_processing_mode, ExtProcService},
+            DummyData,
+        },
+        proto::envoy::{
+            extensions::filters::http::ext_proc::v3::processing_mode::BodySendMode,
+            service::ext_proc::v3::external_processor_server::ExternalProcessorServer,
+        },
+    };
+
+    #[test]
+    fn streams_run_over_an_in_memory_server() {
+        let service = ExtProcService::new(server_processing_mode(false), BehaviorConfig::default());
+        let mut client = ExternalProcessorClient::new(ExternalProcessorServer::new(service));
+        let data = DummyData {
+            req_headers: vec![(":method".to_string(), "GET".to_string())],
+            resp_status: 200,
+            resp_headers: vec![(":status".to_string(), "200".to_string())],
+            ..Default::default()
+        };
+        let mut stream = ClientStream::new(Arc::new(data), Config::default());
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            stream.start_stream(&mut client).await.unwrap();
+            stream.handle_stream().await.unwrap();
+        });
+        stream.finish_stream();
+        assert!(stream.transaction().immediate_response.is_none());
+    }
+
+    #[test]
+    fn pipelined_bodies_are_answered_once_whole() {
+        let mut processing_mode = server_processing_mode(false);
+        processing_mode.set_request_body_mode(BodySendMode::Streamed);
+        processing_mode.set_response_body_mode(BodySendMode::Streamed);
+        let service = ExtProcService::new(processing_mode, BehaviorConfig::default())
+            .with_held_chunk_responses(true);
+        let mut client = ExternalProcessorClient::new(ExternalProcessorServer::new(service));
+        let data = DummyData {
+            req_headers: vec![(":method".to_string(), "POST".to_string())],
+            req_body: b"0123456789".to_vec(),
+            resp_status: 200,
+            resp_headers: vec![(":status".to_string(), "200".to_string())],
+            ..Default::default()
+        };
+        let config = Config {
+            streamed_bodies: true,
+            body_chunk_size: Some(4),
+            pipelined_bodies: true,
+            ..Default::default()
+        };
+        let mut stream = ClientStream::new(Arc::new(data), config);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            stream.start_stream(&mut client).await.unwrap();
+            stream.handle_stream().await.unwrap();
+        });
+        let chunks: Vec<_> = stream
+            .transaction()
+            .phases
+            .iter()
+            .filter(|timing| timing.phase == Phase::RequestBody)
+            .collect();
+        assert_eq!(chunks.len(), 3);
+        // Every chunk went out before the server answered the first
+        let last_sent = chunks.iter().map(|timing| timing.sent_at_us).max().unwrap();
+        assert!(chunks.iter().all(|timing| timing.answered_at_us >= Some(last_sent)));
+    }
+}
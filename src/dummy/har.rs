@@ -0,0 +1,212 @@
+//! Captured traffic from HAR files (as exported by browsers or Envoy's tap filter), turned into
+//! transactions to replay against an ext_proc server
+
+use std::{fs::File, io::Read, path::Path};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+
+use super::DummyData;
+
+use error::HarError;
+
+pub mod error {
+    use quick_error::quick_error;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum HarError {
+            OpenError(err: std::io::Error) {
+                display("Could not read HAR file: {}", err)
+                from()
+            }
+            ParseError(err: serde_json::Error) {
+                display("Could not parse HAR file: {}", err)
+                from()
+            }
+            InvalidEntry(index: usize, reason: String) {
+                display("Invalid HAR entry {}: {}", index, reason)
+            }
+            Empty {
+                display("HAR file has no entries.")
+            }
+        }
+    );
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Har {
+    pub(crate) log: HarLog,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HarLog {
+    pub(crate) entries: Vec<HarEntry>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HarEntry {
+    pub(crate) request: HarRequest,
+    #[serde(default)]
+    pub(crate) response: Option<HarResponse>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HarRequest {
+    #[serde(default)]
+    pub(crate) method: String,
+    #[serde(default)]
+    pub(crate) url: String,
+    pub(crate) headers: Vec<HarHeader>,
+    #[serde(default, rename = "postData")]
+    pub(crate) post_data: Option<HarContent>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HarResponse {
+    pub(crate) status: u32,
+    pub(crate) headers: Vec<HarHeader>,
+    #[serde(default)]
+    pub(crate) content: Option<HarContent>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct HarHeader {
+    pub(crate) name: String,
+    pub(crate) value: String,
+}
+
+/// A request's postData or a response's content
+#[derive(Deserialize)]
+pub(crate) struct HarContent {
+    #[serde(default)]
+    text: Option<String>,
+    /// `base64` for binary content
+    #[serde(default)]
+    encoding: Option<String>,
+}
+
+impl HarContent {
+    fn body(&self) -> Result<Vec<u8>, String> {
+        let Some(ref text) = self.text else {
+            return Ok(Vec::new());
+        };
+        match self.encoding.as_deref() {
+            Some("base64") => STANDARD.decode(text).map_err(|e| e.to_string()),
+            _ => Ok(text.as_bytes().to_vec()),
+        }
+    }
+}
+
+/// Headers of a HAR message, without its pseudo headers, which are derived from the entry
+fn regular_headers(headers: Vec<HarHeader>) -> impl Iterator<Item = (String, String)> {
+    headers
+        .into_iter()
+        .filter(|header| !header.name.starts_with(':'))
+        .map(|header| (header.name, header.value))
+}
+
+impl HarEntry {
+    fn into_data(self) -> Result<DummyData, String> {
+        let uri: hyper::Uri = self.request.url.parse().map_err(|e| format!("URL: {}", e))?;
+        let mut req_headers = vec![(":method".to_string(), self.request.method)];
+        if let Some(scheme) = uri.scheme_str() {
+            req_headers.push((":scheme".to_string(), scheme.to_string()));
+        }
+        if let Some(authority) = uri.authority() {
+            req_headers.push((":authority".to_string(), authority.to_string()));
+        }
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        req_headers.push((":path".to_string(), path.to_string()));
+        req_headers.extend(regular_headers(self.request.headers));
+        let req_body = match self.request.post_data {
+            Some(ref post_data) => post_data.body()?,
+            None => Vec::new(),
+        };
+        let mut data = DummyData {
+            req_headers,
+            req_body,
+            ..Default::default()
+        };
+        // Entries of aborted requests have no response, the server then answers a bare 200
+        let response = self.response.unwrap_or(HarResponse {
+            status: 200,
+            headers: Vec::new(),
+            content: None,
+        });
+        data.resp_status = response.status;
+        data.resp_headers = vec![(":status".to_string(), response.status.to_string())];
+        data.resp_headers.extend(regular_headers(response.headers));
+        if let Some(ref content) = response.content {
+            data.resp_body = content.body()?;
+        }
+        Ok(data)
+    }
+}
+
+impl DummyData {
+    /// The transactions of a HAR file's entries, in order, with pseudo headers derived from each
+    /// request's method and URL and each response's status
+    pub fn from_har<P: AsRef<Path>>(path: P) -> Result<Vec<DummyData>, HarError> {
+        DummyData::read_har(File::open(path)?)
+    }
+
+    pub fn read_har<R: Read>(reader: R) -> Result<Vec<DummyData>, HarError> {
+        let har: Har = serde_json::from_reader(reader)?;
+        if har.log.entries.is_empty() {
+            return Err(HarError::Empty);
+        }
+        har.log
+            .entries
+            .into_iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                entry
+                    .into_data()
+                    .map_err(|reason| HarError::InvalidEntry(index, reason))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_become_transactions() {
+        let har = serde_json::json!({"log": {"entries": [
+            {
+                "request": {
+                    "method": "POST",
+                    "url": "https://www.example.com/search?q=1",
+                    "headers": [
+                        {"name": ":authority", "value": "www.example.com"},
+                        {"name": "content-type", "value": "text/plain"}
+                    ],
+                    "postData": {"mimeType": "text/plain", "text": "query"}
+                },
+                "response": {
+                    "status": 404,
+                    "headers": [{"name": "server", "value": "envoy"}],
+                    "content": {"size": 3, "text": "AAH/", "encoding": "base64"}
+                }
+            },
+            {"request": {"method": "GET", "url": "not a url", "headers": []}}
+        ]}});
+        let error = DummyData::read_har(har.to_string().as_bytes()).err().unwrap();
+        assert!(matches!(error, HarError::InvalidEntry(1, _)));
+
+        let mut har = har;
+        har["log"]["entries"].as_array_mut().unwrap().pop();
+        let data = DummyData::read_har(har.to_string().as_bytes()).unwrap();
+        let values: Vec<&str> = data[0].req_headers.iter().map(|(_, v)| v.as_str()).collect();
+        assert_eq!(
+            values,
+            ["POST", "https", "www.example.com", "/search?q=1", "text/plain"]
+        );
+        assert_eq!(data[0].req_body, b"query");
+        assert_eq!(data[0].resp_status, 404);
+        assert_eq!(data[0].resp_headers[0], (":status".to_string(), "404".to_string()));
+        assert_eq!(data[0].resp_body, [0, 1, 255]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Clients connected to an in-process [`ExtProcService`] over in-memory duplex connections, to
+//! unit-test client logic without binding sockets
+
+use std::io;
+
+use tonic::{
+    codegen::{ok, Context, Poll, Ready, Service},
+    transport::{Channel, Endpoint, Server, Uri},
+};
+
+use super::server::ExtProcService;
+use crate::proto::envoy::service::ext_proc::v3::{
+    external_processor_client::ExternalProcessorClient,
+    external_processor_server::ExternalProcessorServer,
+};
+
+/// Size of the in-memory pipes, in bytes
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// A client of `service`, as if it listened on a socket. Every connection (including the
+/// reconnections of the channel) is served by `service` on its own task
+pub async fn in_memory_client(
+    service: ExtProcService,
+) -> Result<ExternalProcessorClient<Channel>, tonic::transport::Error> {
+    // Never resolved, the connector is used instead
+    let channel = Endpoint::from_static("http://in-memory.invalid")
+        .connect_with_connector(DuplexConnector {
+            server: ExternalProcessorServer::new(service),
+        })
+        .await?;
+    Ok(ExternalProcessorClient::new(channel))
+}
+
+/// Connects by serving the server end of a new duplex pipe
+struct DuplexConnector {
+    server: ExternalProcessorServer<ExtProcService>,
+}
+
+impl Service<Uri> for DuplexConnector {
+    type Response = tokio::io::DuplexStream;
+    type Error = io::Error;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let (client, server) = tokio::io::duplex(PIPE_CAPACITY);
+        tokio::spawn(
+            Server::builder()
+                .add_service(self.server.clone())
+                .serve_with_incoming(tokio_stream::once(Ok::<_, io::Error>(server))),
+        );
+        ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use super::*;
+    use crate::{
+        dummy::{
+            client::{ClientStream, Config},
+            server::{behavior::BehaviorConfig, server_processing_mode},
+            DummyData,
+        },
+        proto::envoy::service::ext_proc::v3::{
+            processing_request::Request, HttpHeaders, ProcessingRequest,
+        },
+    };
+
+    #[test]
+    fn clients_reach_the_in_process_service() {
+        let service = ExtProcService::new(server_processing_mode(false), BehaviorConfig::default());
+        let data = DummyData {
+            req_headers: vec![(":method".to_string(), "GET".to_string())],
+            resp_status: 200,
+            resp_headers: vec![(":status".to_string(), "200".to_string())],
+            ..Default::default()
+        };
+        let config = Config {
+            reuse_stream: true,
+            ..Default::default()
+        };
+        let mut stream = ClientStream::new(Arc::new(data), config);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut client = in_memory_client(service).await.unwrap();
+            for _ in 0..3 {
+                stream.start_stream(&mut client).await.unwrap();
+                stream.handle_stream().await.unwrap();
+            }
+        });
+        stream.finish_stream();
+        assert!(stream.transaction().immediate_response.is_none());
+    }
+
+    #[test]
+    fn streams_end_between_transactions_on_shutdown() {
+        let (shutdown, shutdown_receiver) = tokio::sync::watch::channel(false);
+        let service = ExtProcService::new(server_processing_mode(false), BehaviorConfig::default())
+            .with_shutdown(shutdown_receiver);
+        let headers = |request: fn(HttpHeaders) -> Request| ProcessingRequest {
+            async_mode: false,
+            request: Some(request(HttpHeaders {
+                end_of_stream: true,
+                ..Default::default()
+            })),
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut client = in_memory_client(service).await.unwrap();
+            let (requests, receiver) = tokio::sync::mpsc::channel(1);
+            let mut responses = client
+                .process(ReceiverStream::new(receiver))
+                .await
+                .unwrap()
+                .into_inner();
+            requests.send(headers(Request::RequestHeaders)).await.unwrap();
+            assert!(responses.message().await.unwrap().is_some());
+
+            // The transaction in progress is finished
+            shutdown.send(true).unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            requests.send(headers(Request::ResponseHeaders)).await.unwrap();
+            assert!(responses.message().await.unwrap().is_some());
+
+            let end = tokio::time::timeout(Duration::from_secs(1), responses.message()).await;
+            assert!(matches!(end, Ok(Ok(None))));
+        });
+    }
+}
@@ -1,37 +1,273 @@
+use std::collections::HashMap;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use serde::Deserialize;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-use self::error::TryFromError;
+use self::error::{DataConfigError, TryFromError};
+use crate::proto::google::protobuf::Struct;
 
+#[cfg(feature = "client")]
 pub mod client;
+pub mod har;
+#[cfg(all(feature = "client", feature = "server"))]
+pub mod in_memory;
+pub mod protobuf;
+pub mod recording;
+#[cfg(feature = "server")]
 pub mod server;
 
-#[derive(Deserialize, Debug)]
+/// Namespace Envoy's ext_proc filter sends request attributes in
+pub const EXT_PROC_ATTRIBUTE_NAMESPACE: &str = "envoy.filters.http.ext_proc";
+
+/// Header mutation the server reports how long it took to answer a message in, in microseconds
+/// (see `server::ExtProcService::with_processing_time`)
+pub const PROCESSING_TIME_HEADER: &str = "x-ext-proc-processing-time-us";
+
+/// Name of the header an echoed attribute is reflected in
+pub fn attribute_echo_header(name: &str) -> String {
+    format!("x-ext-proc-attribute-{}", name.to_lowercase())
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 pub struct DummyDataConfig {
     pub request_headers: Vec<(String, String)>,
+    /// Attributes sent with the request headers, e.g. `{"request.path": "/", "source.port": 443}`
+    #[serde(default)]
+    pub request_attributes: serde_json::Map<String, serde_json::Value>,
     #[serde(default)]
     pub request_body_filename: String,
+    /// Request body inline, instead of from `request_body_filename`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<InlineBody>,
+    /// Generate a request body of this many bytes instead, the same every run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body_size: Option<usize>,
+    #[serde(default)]
+    pub request_trailers: Vec<(String, String)>,
 
     pub response_status: u32,
     pub response_headers: Vec<(String, String)>,
+    /// Attributes sent with the response headers, e.g.
+    /// `{"response.code": 200, "xds.route_metadata": {"filter_metadata": {...}}}`
+    #[serde(default)]
+    pub response_attributes: serde_json::Map<String, serde_json::Value>,
     #[serde(default)]
     pub response_body_filename: String,
+    /// Response body inline, instead of from `response_body_filename`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_body: Option<InlineBody>,
+    /// Generate a response body of this many bytes instead, the same every run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_body_size: Option<usize>,
+    /// Content of the bodies generated by `request_body_size` and `response_body_size`
+    #[serde(default)]
+    pub synthetic_body_content: SyntheticContent,
+    #[serde(default)]
+    pub response_trailers: Vec<(String, String)>,
+
+    /// Checks applied to every transaction
+    #[serde(default)]
+    pub expect: ExpectationConfig,
+
+    /// Traffic mix replacing the data above in benchmarks, each transaction drawing one of these
+    /// shapes by weight, e.g. mostly small GETs with a few large uploads
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mix: Vec<MixShapeConfig>,
+}
+
+/// Format of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// The format of a file by its extension: `.yaml` or `.yml`, `.toml`, JSON otherwise
+    pub fn of_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+impl DummyDataConfig {
+    /// Reads a data config in `format`, or the format of the file's extension
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        format: Option<ConfigFormat>,
+    ) -> Result<DummyDataConfig, DataConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        DummyDataConfig::parse(&text, format.unwrap_or_else(|| ConfigFormat::of_path(path)))
+    }
+
+    pub fn parse(text: &str, format: ConfigFormat) -> Result<DummyDataConfig, DataConfigError> {
+        Ok(match format {
+            ConfigFormat::Json => serde_json::from_str(text)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(text)?,
+            ConfigFormat::Toml => toml::from_str(text)?,
+        })
+    }
+}
+
+/// Content of generated bodies
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyntheticContent {
+    /// Random bytes, which compression can't shrink
+    #[default]
+    Incompressible,
+    /// Text of words drawn from a small vocabulary, which compresses like typical HTML or JSON
+    Compressible,
+}
+
+const SYNTHETIC_WORDS: [&str; 16] = [
+    "lorem", "ipsum", "dolor", "sit", "amet", "consectetur", "adipiscing", "elit", "sed", "do",
+    "eiusmod", "tempor", "incididunt", "ut", "labore", "magna",
+];
+
+/// A pseudo-random body of `size` bytes, the same for a given size and content
+pub fn synthetic_body(size: usize, content: SyntheticContent) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(size as u64);
+    let mut body = Vec::with_capacity(size + 16);
+    match content {
+        SyntheticContent::Incompressible => {
+            body.resize(size, 0);
+            rng.fill_bytes(&mut body);
+        }
+        SyntheticContent::Compressible => {
+            while body.len() < size {
+                let word = SYNTHETIC_WORDS[rng.gen_range(0..SYNTHETIC_WORDS.len())];
+                body.extend_from_slice(word.as_bytes());
+                body.push(b' ');
+            }
+            body.truncate(size);
+        }
+    }
+    body
+}
+
+/// A body given in a data config, for small payloads
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum InlineBody {
+    /// The UTF-8 text of the body
+    Text(String),
+    /// Binary body, base64 encoded, e.g. `{"base64": "H4sIAAAAAAAA/w=="}`
+    Base64 { base64: String },
+}
+
+impl InlineBody {
+    pub fn decode(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        match self {
+            InlineBody::Text(text) => Ok(text.as_bytes().to_vec()),
+            InlineBody::Base64 { base64 } => STANDARD.decode(base64),
+        }
+    }
+}
+
+/// A request/response shape of a traffic mix, with the fields of a data config (its own `mix`
+/// is ignored)
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct MixShapeConfig {
+    /// Names the shape in the per-scenario breakdown of benchmark reports
+    pub name: String,
+    /// Share of the transactions sent with this shape, relative to the other shapes
+    #[serde(default = "MixShapeConfig::default_weight")]
+    pub weight: u32,
+    #[serde(flatten)]
+    pub data: DummyDataConfig,
+}
+
+impl MixShapeConfig {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+/// A shape of a traffic mix, with its data loaded
+pub struct MixShape {
+    pub name: String,
+    pub weight: u32,
+    pub data: Arc<DummyData>,
+}
+
+impl TryFrom<MixShapeConfig> for MixShape {
+    type Error = TryFromError;
+
+    fn try_from(value: MixShapeConfig) -> Result<Self, Self::Error> {
+        Ok(MixShape {
+            name: value.name,
+            weight: value.weight,
+            data: Arc::new(DummyData::try_from(value.data)?),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct ExpectationConfig {
+    /// Top-level keys the server must emit in dynamic_metadata during each transaction
+    #[serde(default)]
+    pub dynamic_metadata_keys: Vec<String>,
+    /// Request attributes the server must reflect back unchanged, either as
+    /// request headers or as dynamic metadata (see [`server::behavior::AttributeEchoConfig`])
+    #[serde(default)]
+    pub echoed_attributes: Vec<String>,
 }
+
+#[derive(Default)]
 pub struct DummyData {
     pub req_headers: Vec<(String, String)>,
+    /// Request attributes by namespace
+    pub req_attributes: HashMap<String, Struct>,
     pub req_body: Vec<u8>,
+    pub req_trailers: Vec<(String, String)>,
 
     pub resp_status: u32,
     pub resp_headers: Vec<(String, String)>,
+    /// Response attributes by namespace
+    pub resp_attributes: HashMap<String, Struct>,
     pub resp_body: Vec<u8>,
+    pub resp_trailers: Vec<(String, String)>,
+
+    pub expect: ExpectationConfig,
 }
 
-mod error {
+pub mod error {
     use std::path::PathBuf;
     use quick_error::quick_error;
 
+    quick_error!(
+        #[derive(Debug)]
+        pub enum DataConfigError {
+            OpenError(err: std::io::Error) {
+                display("Could not open config file: {}", err)
+                from()
+            }
+            JsonError(err: serde_json::Error) {
+                display("Could not parse config file: {}", err)
+                from()
+            }
+            YamlError(err: serde_yaml::Error) {
+                display("Could not parse YAML config file: {}", err)
+                from()
+            }
+            TomlError(err: toml::de::Error) {
+                display("Could not parse TOML config file: {}", err)
+                from()
+            }
+        }
+    );
+
     quick_error!(
         #[derive(Debug)]
         pub enum TryFromError {
@@ -41,6 +277,12 @@ mod error {
             ReadFile(file: &'static str, path: PathBuf, err: std::io::Error) {
                 display("could not read {} file at '{}': {}", file, path.to_string_lossy(), err)
             }
+            ConflictingBody(body: &'static str) {
+                display("{} can only be given one way: from a file, inline or by size", body)
+            }
+            InvalidBase64(body: &'static str, err: base64::DecodeError) {
+                display("invalid base64 {}: {}", body, err)
+            }
         }
     );
 }
@@ -60,8 +302,24 @@ impl TryFrom<DummyDataConfig> for DummyData {
         fn maybe_read_body(
             name: &'static str,
             path: &str,
+            inline: Option<&InlineBody>,
+            synthetic: Option<(usize, SyntheticContent)>,
             buf: &mut Vec<u8>,
         ) -> Result<(), TryFromError> {
+            let sources = [!path.is_empty(), inline.is_some(), synthetic.is_some()];
+            if sources.iter().filter(|given| **given).count() > 1 {
+                return Err(TryFromError::ConflictingBody(name));
+            }
+            if let Some((size, content)) = synthetic {
+                *buf = synthetic_body(size, content);
+                return Ok(());
+            }
+            if let Some(inline) = inline {
+                *buf = inline
+                    .decode()
+                    .map_err(|e| TryFromError::InvalidBase64(name, e))?;
+                return Ok(());
+            }
             if path.is_empty() {
                 return Ok(());
             }
@@ -74,25 +332,148 @@ impl TryFrom<DummyDataConfig> for DummyData {
         }
 
         let mut req_body = Vec::new();
-        maybe_read_body("request body", &value.request_body_filename, &mut req_body)?;
+        maybe_read_body(
+            "request body",
+            &value.request_body_filename,
+            value.request_body.as_ref(),
+            value
+                .request_body_size
+                .map(|size| (size, value.synthetic_body_content)),
+            &mut req_body,
+        )?;
 
         let mut resp_body = Vec::new();
         maybe_read_body(
             "response body",
             &value.response_body_filename,
+            value.response_body.as_ref(),
+            value
+                .response_body_size
+                .map(|size| (size, value.synthetic_body_content)),
             &mut resp_body,
         )?;
 
         Ok(DummyData {
             req_headers: value.request_headers,
+            req_attributes: attributes(&value.request_attributes),
             req_body,
+            req_trailers: value.request_trailers,
             resp_status: value.response_status,
             resp_headers: value.response_headers,
+            resp_attributes: attributes(&value.response_attributes),
             resp_body,
+            resp_trailers: value.response_trailers,
+            expect: value.expect,
         })
     }
 }
 
+/// Attributes in the namespace Envoy's ext_proc filter sends them in, none if `attributes` is empty
+fn attributes(
+    attributes: &serde_json::Map<String, serde_json::Value>,
+) -> HashMap<String, Struct> {
+    let mut by_namespace = HashMap::new();
+    if !attributes.is_empty() {
+        by_namespace.insert(
+            EXT_PROC_ATTRIBUTE_NAMESPACE.to_string(),
+            protobuf::struct_from_json(attributes),
+        );
+    }
+    by_namespace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_can_be_inline() {
+        let config = |bodies: serde_json::Value| {
+            let mut config = serde_json::json!({
+                "request_headers": [],
+                "response_status": 200,
+                "response_headers": [],
+            });
+            config.as_object_mut().unwrap().extend(bodies.as_object().unwrap().clone());
+            serde_json::from_value::<DummyDataConfig>(config).unwrap()
+        };
+        let data = DummyData::try_from(config(serde_json::json!({
+            "request_body": "{\"q\": 1}",
+            "response_body": {"base64": "AAH/"},
+        })))
+        .unwrap();
+        assert_eq!(data.req_body, b"{\"q\": 1}");
+        assert_eq!(data.resp_body, [0, 1, 255]);
+        let conflicting = config(serde_json::json!({
+            "request_body": "a",
+            "request_body_filename": "bench/fixtures/simple_response.html",
+        }));
+        assert!(matches!(
+            DummyData::try_from(conflicting),
+            Err(TryFromError::ConflictingBody("request body"))
+        ));
+        let invalid = config(serde_json::json!({"response_body": {"base64": "not base64!"}}));
+        assert!(matches!(
+            DummyData::try_from(invalid),
+            Err(TryFromError::InvalidBase64("response body", _))
+        ));
+    }
+
+    #[test]
+    fn response_attributes_are_namespaced_like_envoy() {
+        let config: DummyDataConfig = serde_json::from_value(serde_json::json!({
+            "request_headers": [],
+            "response_status": 200,
+            "response_headers": [],
+            "response_attributes": {
+                "response.code": 200,
+                "xds.route_metadata": {"filter_metadata": {"tenant": "a"}},
+            },
+        }))
+        .unwrap();
+        let data = DummyData::try_from(config).unwrap();
+        assert!(data.req_attributes.is_empty());
+        let attributes = &data.resp_attributes[EXT_PROC_ATTRIBUTE_NAMESPACE];
+        assert_eq!(attributes.fields.len(), 2);
+        assert!(attributes.fields.contains_key("xds.route_metadata"));
+    }
+
+    #[test]
+    fn bodies_are_generated_by_size() {
+        let incompressible = synthetic_body(4096, SyntheticContent::Incompressible);
+        assert_eq!(incompressible.len(), 4096);
+        assert_eq!(incompressible, synthetic_body(4096, SyntheticContent::Incompressible));
+        let compressible = synthetic_body(4096, SyntheticContent::Compressible);
+        assert_eq!(compressible.len(), 4096);
+        let compressed_len = |body: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, body).unwrap();
+            encoder.finish().unwrap().len()
+        };
+        assert!(compressed_len(&compressible) < 2048);
+        assert!(compressed_len(&incompressible) > 4000);
+    }
+
+    #[test]
+    fn configs_are_read_in_yaml_and_toml() {
+        assert_eq!(ConfigFormat::of_path(Path::new("data.yml")), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::of_path(Path::new("data.json")), ConfigFormat::Json);
+        let yaml = "request_headers:\n  - [\":method\", GET]\nresponse_status: 200\n\
+                    response_headers: []\nrequest_body: hello\n";
+        let config = DummyDataConfig::parse(yaml, ConfigFormat::Yaml).unwrap();
+        assert_eq!(config.request_headers[0], (":method".to_string(), "GET".to_string()));
+        let toml = "request_headers = [[\":method\", \"GET\"]]\nresponse_status = 200\n\
+                    response_headers = []\n";
+        let config = DummyDataConfig::parse(toml, ConfigFormat::Toml).unwrap();
+        assert_eq!(config.response_status, 200);
+        assert!(matches!(
+            DummyDataConfig::parse(toml, ConfigFormat::Yaml),
+            Err(DataConfigError::YamlError(_))
+        ));
+    }
+}
+
 #[cfg(test)]
 mod fixture_gen {
     use build_html::{Html, HtmlContainer};
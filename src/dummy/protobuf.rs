@@ -0,0 +1,95 @@
+//! Conversions between json values and the protobuf Struct type used for attributes and metadata
+
+use serde_json::{Map, Value as Json};
+
+use crate::proto::google::protobuf::{value::Kind, ListValue, NullValue, Struct, Value};
+
+pub fn struct_from_json(map: &Map<String, Json>) -> Struct {
+    Struct {
+        fields: map
+            .iter()
+            .map(|(key, value)| (key.clone(), value_from_json(value)))
+            .collect(),
+    }
+}
+
+pub fn value_from_json(json: &Json) -> Value {
+    let kind = match json {
+        Json::Null => Kind::NullValue(NullValue::NullValue as i32),
+        Json::Bool(value) => Kind::BoolValue(*value),
+        Json::Number(number) => Kind::NumberValue(number.as_f64().unwrap_or_default()),
+        Json::String(value) => Kind::StringValue(value.clone()),
+        Json::Array(values) => Kind::ListValue(ListValue {
+            values: values.iter().map(value_from_json).collect(),
+        }),
+        Json::Object(map) => Kind::StructValue(struct_from_json(map)),
+    };
+    Value { kind: Some(kind) }
+}
+
+pub fn struct_to_json(value: &Struct) -> Map<String, Json> {
+    value
+        .fields
+        .iter()
+        .map(|(key, value)| (key.clone(), value_to_json(value)))
+        .collect()
+}
+
+/// Integral numbers are converted back to json integers
+pub fn value_to_json(value: &Value) -> Json {
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => Json::Null,
+        Some(Kind::BoolValue(value)) => Json::Bool(value),
+        Some(Kind::NumberValue(number)) => {
+            if number.fract() == 0.0 && number.abs() < i64::MAX as f64 {
+                Json::from(number as i64)
+            } else {
+                Json::from(number)
+            }
+        }
+        Some(Kind::StringValue(ref value)) => Json::String(value.clone()),
+        Some(Kind::ListValue(ref list)) => {
+            Json::Array(list.values.iter().map(value_to_json).collect())
+        }
+        Some(Kind::StructValue(ref value)) => Json::Object(struct_to_json(value)),
+    }
+}
+
+/// Text form of a value, e.g. for a header: strings as they are, anything else as json
+pub fn value_to_string(value: &Value) -> String {
+    match value.kind {
+        Some(Kind::StringValue(ref value)) => value.clone(),
+        _ => value_to_json(value).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_round_trip() {
+        let json = serde_json::json!({
+            "path": "/login",
+            "port": 8443,
+            "ratio": 0.5,
+            "tls": true,
+            "tags": ["a", null],
+            "peer": {"principal": "spiffe://cluster/ns/default"}
+        });
+        let Json::Object(map) = json.clone() else {
+            unreachable!()
+        };
+        assert_eq!(Json::Object(struct_to_json(&struct_from_json(&map))), json);
+    }
+
+    #[test]
+    fn value_text() {
+        assert_eq!(value_to_string(&value_from_json(&Json::from("/login"))), "/login");
+        assert_eq!(value_to_string(&value_from_json(&Json::from(8443))), "8443");
+        assert_eq!(
+            value_to_string(&value_from_json(&serde_json::json!(["a", 1]))),
+            r#"["a",1]"#
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Recorded ext_proc exchanges, as written by run_server's `--record` and replayed by
+//! bench_client's `replay` command. A recording holds the messages of a stream in the order they
+//! were received and sent, as length-delimited [`RecordedMessage`]s readable with
+//! [`read_recording`] or any protobuf library
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use prost::Message;
+
+use crate::proto::envoy::service::ext_proc::v3::{ProcessingRequest, ProcessingResponse};
+
+/// A request received on the stream, or a response sent on it
+#[derive(Clone, PartialEq, Message)]
+pub struct RecordedMessage {
+    /// Time since the stream's first request, in microseconds
+    #[prost(uint64, tag = "1")]
+    pub elapsed_us: u64,
+    #[prost(message, optional, tag = "2")]
+    pub request: Option<ProcessingRequest>,
+    #[prost(message, optional, tag = "3")]
+    pub response: Option<ProcessingResponse>,
+}
+
+/// The messages of a stream's recording, in order
+pub fn read_recording<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedMessage>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let mut buf = bytes.as_slice();
+    let mut messages = Vec::new();
+    while !buf.is_empty() {
+        let message = RecordedMessage::decode_length_delimited(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
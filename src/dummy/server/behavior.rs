@@ -0,0 +1,804 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tonic::{Code, Status};
+
+use crate::proto::envoy::{
+    config::core::v3::{HeaderMap, HeaderValue, HeaderValueOption},
+    r#type::v3::HttpStatus,
+    service::ext_proc::v3::{
+        processing_request::Request, GrpcStatus, HeaderMutation, ImmediateResponse,
+    },
+};
+use crate::proto::google::protobuf::{BoolValue, Struct, Value};
+
+use crate::dummy::protobuf::struct_from_json;
+
+use super::faults::{FaultInjectionConfig, LatencyConfig, StreamKillConfig};
+use super::script::ScriptConfig;
+
+use error::BehaviorConfigError;
+
+pub mod error {
+    use quick_error::quick_error;
+    use std::path::PathBuf;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum BehaviorConfigError {
+            OpenError(err: std::io::Error) {
+                display("Could not open behavior config file: {}", err)
+                from()
+            }
+            ParseError(err: serde_json::Error) {
+                display("Could not parse behavior config file: {}", err)
+                from()
+            }
+            InvalidScript(message: String) {
+                display("Invalid behavior script: {}", message)
+            }
+            BodyFileError(path: PathBuf, err: std::io::Error) {
+                display("Could not read replacement body {}: {}", path.display(), err)
+            }
+        }
+    );
+}
+
+/// Configurable behavior of [`super::ExtProcService`], loaded from a json file by run_server
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BehaviorConfig {
+    /// Mutation returned in the HeadersResponse to request headers
+    #[serde(default)]
+    pub request_headers: HeaderMutationConfig,
+    /// Mutation returned in the HeadersResponse to response headers
+    #[serde(default)]
+    pub response_headers: HeaderMutationConfig,
+    /// Mutation returned in the TrailersResponse to request trailers
+    #[serde(default)]
+    pub request_trailers: HeaderMutationConfig,
+    /// Mutation returned in the TrailersResponse to response trailers
+    #[serde(default)]
+    pub response_trailers: HeaderMutationConfig,
+    /// Trailers added to requests through the CommonResponse of the request headers response.
+    /// Trailers can only be added with CONTINUE_AND_REPLACE, which ends processing of the request.
+    #[serde(default)]
+    pub add_request_trailers: Vec<(String, String)>,
+    /// Trailers added to responses through the CommonResponse of the response headers response
+    #[serde(default)]
+    pub add_response_trailers: Vec<(String, String)>,
+    /// Deny transactions with an ImmediateResponse
+    #[serde(default)]
+    pub immediate_response: Option<ImmediateResponseConfig>,
+    /// Reflect attributes of headers messages back to the client
+    #[serde(default)]
+    pub echo_attributes: Option<AttributeEchoConfig>,
+    /// dynamic_metadata attached to the responses to a kind of message
+    #[serde(default)]
+    pub dynamic_metadata: Option<DynamicMetadataConfig>,
+    /// Cap on the body size of each request and response, summed over its chunks
+    #[serde(default)]
+    pub max_body_size: Option<BodySizeLimitConfig>,
+    /// Replacement of request bodies, in the BodyResponse to request bodies
+    #[serde(default)]
+    pub request_body: Option<BodyReplacementConfig>,
+    /// Replacement of response bodies, in the BodyResponse to response bodies
+    #[serde(default)]
+    pub response_body: Option<BodyReplacementConfig>,
+    /// Random delays and failures, seeded by the service (see [`super::ExtProcService::with_seed`])
+    #[serde(default)]
+    pub faults: Option<FaultInjectionConfig>,
+    /// Delays before responding, by message type (see [`LatencyConfig`])
+    #[serde(default)]
+    pub latency: Option<LatencyConfig>,
+    /// Termination of streams after a number of transactions or a time
+    #[serde(default)]
+    pub kill_streams: Option<StreamKillConfig>,
+    /// State machine switching the behavior of each stream as it progresses (see
+    /// [`super::script::ScriptConfig`])
+    #[serde(default)]
+    pub script: Option<ScriptConfig>,
+}
+
+impl BehaviorConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<BehaviorConfig, BehaviorConfigError> {
+        let file = File::open(path)?;
+        let mut behavior: BehaviorConfig = serde_json::from_reader(file)?;
+        if let Some(ref script) = behavior.script {
+            script.validate().map_err(BehaviorConfigError::InvalidScript)?;
+        }
+        behavior.load_body_files()?;
+        Ok(behavior)
+    }
+
+    /// Reads the `body_file`s of body replacements, including those of the script's states
+    pub(super) fn load_body_files(&mut self) -> Result<(), BehaviorConfigError> {
+        for replacement in [&mut self.request_body, &mut self.response_body]
+            .into_iter()
+            .flatten()
+        {
+            replacement.load_body_file()?;
+        }
+        if let Some(ref mut script) = self.script {
+            for state in script.states.values_mut() {
+                if let Some(ref mut behavior) = state.behavior {
+                    behavior.load_body_files()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Behavior shared by every stream of a server, which can be replaced while streams are open.
+/// Each message is handled with the behavior current when it is received.
+#[derive(Clone, Default)]
+pub struct SharedBehavior(Arc<RwLock<Arc<BehaviorConfig>>>);
+
+impl SharedBehavior {
+    pub fn new(behavior: BehaviorConfig) -> SharedBehavior {
+        SharedBehavior(Arc::new(RwLock::new(Arc::new(behavior))))
+    }
+
+    pub fn load(&self) -> Arc<BehaviorConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, behavior: BehaviorConfig) {
+        *self.0.write().unwrap() = Arc::new(behavior);
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct AttributeEchoConfig {
+    /// Names of attributes to echo, e.g. "request.path". Every attribute is echoed when empty.
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// How the attributes are reflected
+    #[serde(default)]
+    pub target: EchoTarget,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EchoTarget {
+    /// Header mutations of the headers response, see [`crate::dummy::attribute_echo_header`]
+    #[default]
+    Headers,
+    /// dynamic_metadata fields named after the attribute
+    DynamicMetadata,
+}
+
+impl AttributeEchoConfig {
+    /// The selected attributes, across all namespaces
+    pub fn select(&self, attributes: &HashMap<String, Struct>) -> Vec<(String, Value)> {
+        attributes
+            .values()
+            .flat_map(|attributes| attributes.fields.iter())
+            .filter(|(name, _)| self.names.is_empty() || self.names.contains(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct DynamicMetadataConfig {
+    /// Message whose responses carry the metadata
+    #[serde(default = "DynamicMetadataConfig::default_on")]
+    pub on: MessageKind,
+    /// Fields of the metadata, e.g. `{"decision": "allow", "policy": {"id": 7}}`. Envoy merges
+    /// them into the ext_proc filter's namespace
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl DynamicMetadataConfig {
+    fn default_on() -> MessageKind {
+        MessageKind::RequestHeaders
+    }
+
+    /// The metadata of the response to a `kind` message, if it carries any
+    pub fn for_message(&self, kind: MessageKind) -> Option<Struct> {
+        (self.on == kind).then(|| struct_from_json(&self.fields))
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct BodySizeLimitConfig {
+    /// Largest body in bytes
+    pub max_bytes: usize,
+    /// How bodies exceeding the cap are rejected
+    #[serde(default)]
+    pub action: BodySizeAction,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodySizeAction {
+    /// Deny the transaction with a 413 ImmediateResponse
+    #[default]
+    ImmediateResponse,
+    /// End the stream with a RESOURCE_EXHAUSTED status
+    GrpcError,
+}
+
+impl BodySizeLimitConfig {
+    /// Checks a body that reached `size` bytes so far. Bodies within the cap are Ok(None), others
+    /// are answered with the immediate response, or end the stream with the status.
+    pub fn check(&self, size: usize) -> Result<Option<ImmediateResponse>, Status> {
+        if size <= self.max_bytes {
+            return Ok(None);
+        }
+        let details = format!("body of {} bytes exceeds {} bytes", size, self.max_bytes);
+        match self.action {
+            BodySizeAction::ImmediateResponse => Ok(Some(ImmediateResponse {
+                status: Some(HttpStatus { code: 413 }),
+                headers: None,
+                body: "Payload Too Large".to_string(),
+                grpc_status: Some(GrpcStatus {
+                    status: grpc_code_for_http_status(413) as u32,
+                }),
+                details,
+            })),
+            BodySizeAction::GrpcError => Err(Status::resource_exhausted(details)),
+        }
+    }
+}
+
+/// A body replacement, which also sets content-length to the new body's length. Bodies streamed in
+/// several chunks are replaced chunk by chunk, the response to each chunk carrying the part of the
+/// replacement at the chunk's offset
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct BodyReplacementConfig {
+    /// Body the original one is replaced with, uncompressed
+    #[serde(default)]
+    pub body: String,
+    /// File the replacement body is read from instead, when the behavior config is loaded, for
+    /// large or binary replacements. Relative paths are relative to the working directory
+    #[serde(default)]
+    pub body_file: Option<PathBuf>,
+    /// Clear the body with a clear_body mutation instead of replacing it
+    #[serde(default)]
+    pub clear: bool,
+    /// Gzip the replacement of gzip encoded bodies, keeping their content-encoding. Otherwise
+    /// replacements are sent uncompressed and content-encoding is removed
+    #[serde(default)]
+    pub recompress: bool,
+    /// Content of `body_file`
+    #[serde(skip)]
+    pub(super) file_body: Option<Arc<[u8]>>,
+}
+
+impl BodyReplacementConfig {
+    fn load_body_file(&mut self) -> Result<(), BehaviorConfigError> {
+        if let Some(ref path) = self.body_file {
+            let body = std::fs::read(path)
+                .map_err(|err| BehaviorConfigError::BodyFileError(path.clone(), err))?;
+            self.file_body = Some(body.into());
+        }
+        Ok(())
+    }
+
+    /// The replacement body, uncompressed
+    pub fn body(&self) -> &[u8] {
+        self.file_body.as_deref().unwrap_or(self.body.as_bytes())
+    }
+}
+
+/// The kind of message a ProcessingRequest carries
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    RequestHeaders,
+    RequestBody,
+    RequestTrailers,
+    ResponseHeaders,
+    ResponseBody,
+    ResponseTrailers,
+}
+
+impl MessageKind {
+    pub fn of(request: &Request) -> MessageKind {
+        match request {
+            Request::RequestHeaders(_) => MessageKind::RequestHeaders,
+            Request::RequestBody(_) => MessageKind::RequestBody,
+            Request::RequestTrailers(_) => MessageKind::RequestTrailers,
+            Request::ResponseHeaders(_) => MessageKind::ResponseHeaders,
+            Request::ResponseBody(_) => MessageKind::ResponseBody,
+            Request::ResponseTrailers(_) => MessageKind::ResponseTrailers,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::RequestHeaders => "request_headers",
+            MessageKind::RequestBody => "request_body",
+            MessageKind::RequestTrailers => "request_trailers",
+            MessageKind::ResponseHeaders => "response_headers",
+            MessageKind::ResponseBody => "response_body",
+            MessageKind::ResponseTrailers => "response_trailers",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct ImmediateResponseConfig {
+    /// Message answered with the immediate response
+    #[serde(default = "ImmediateResponseConfig::default_on")]
+    pub on: MessageKind,
+    /// HTTP status sent to the downstream client
+    #[serde(default = "ImmediateResponseConfig::default_status")]
+    pub status: u32,
+    /// Headers of the local reply
+    #[serde(default)]
+    pub headers: HeaderMutationConfig,
+    /// Body of the local reply. The placeholders `{status}`, `{details}`, `{message}` and
+    /// `{header:<name>}` (a header of the answered message) are substituted.
+    #[serde(default)]
+    pub body: String,
+    /// Details logged by Envoy and reported by the client
+    #[serde(default)]
+    pub details: String,
+    /// gRPC status name (e.g. "PERMISSION_DENIED") for gRPC downstreams.
+    /// Mapped from the HTTP status when unset.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_grpc_code",
+        serialize_with = "serialize_grpc_code"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub grpc_status: Option<Code>,
+}
+
+impl ImmediateResponseConfig {
+    fn default_on() -> MessageKind {
+        MessageKind::RequestHeaders
+    }
+
+    fn default_status() -> u32 {
+        403
+    }
+
+    pub fn to_immediate_response(&self, request: &Request) -> ImmediateResponse {
+        let headers = match request {
+            Request::RequestHeaders(headers) | Request::ResponseHeaders(headers) => {
+                headers.headers.as_ref()
+            }
+            Request::RequestTrailers(trailers) | Request::ResponseTrailers(trailers) => {
+                trailers.trailers.as_ref()
+            }
+            _ => None,
+        };
+        let body = render_template(&self.body, |placeholder| match placeholder {
+            "status" => Some(self.status.to_string()),
+            "details" => Some(self.details.clone()),
+            "message" => Some(MessageKind::of(request).as_str().to_string()),
+            _ => placeholder
+                .strip_prefix("header:")
+                .and_then(|name| header_value(headers, name)),
+        });
+        let grpc_status = self
+            .grpc_status
+            .unwrap_or_else(|| grpc_code_for_http_status(self.status));
+
+        ImmediateResponse {
+            status: Some(HttpStatus {
+                code: self.status as i32,
+            }),
+            headers: self.headers.to_mutation(headers),
+            body,
+            grpc_status: Some(GrpcStatus {
+                status: grpc_status as u32,
+            }),
+            details: self.details.clone(),
+        }
+    }
+}
+
+pub(super) fn header_value(headers: Option<&HeaderMap>, name: &str) -> Option<String> {
+    headers?
+        .headers
+        .iter()
+        .find(|header| header.key.eq_ignore_ascii_case(name))
+        .map(|header| header.value.clone())
+}
+
+/// Replaces `{placeholder}`s in `template` using `lookup`, leaving unknown placeholders as they are
+fn render_template<F: Fn(&str) -> Option<String>>(template: &str, lookup: F) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        let placeholder = &rest[start + 1..end];
+        match lookup(placeholder) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// The gRPC status Envoy uses for a local reply with the given HTTP status
+pub fn grpc_code_for_http_status(status: u32) -> Code {
+    match status {
+        200..=299 => Code::Ok,
+        400 => Code::Internal,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::Unimplemented,
+        429 | 502 | 503 | 504 => Code::Unavailable,
+        _ => Code::Unknown,
+    }
+}
+
+/// gRPC status names as used in the gRPC specification
+const GRPC_CODE_NAMES: [(&str, Code); 17] = [
+    ("OK", Code::Ok),
+    ("CANCELLED", Code::Cancelled),
+    ("UNKNOWN", Code::Unknown),
+    ("INVALID_ARGUMENT", Code::InvalidArgument),
+    ("DEADLINE_EXCEEDED", Code::DeadlineExceeded),
+    ("NOT_FOUND", Code::NotFound),
+    ("ALREADY_EXISTS", Code::AlreadyExists),
+    ("PERMISSION_DENIED", Code::PermissionDenied),
+    ("RESOURCE_EXHAUSTED", Code::ResourceExhausted),
+    ("FAILED_PRECONDITION", Code::FailedPrecondition),
+    ("ABORTED", Code::Aborted),
+    ("OUT_OF_RANGE", Code::OutOfRange),
+    ("UNIMPLEMENTED", Code::Unimplemented),
+    ("INTERNAL", Code::Internal),
+    ("UNAVAILABLE", Code::Unavailable),
+    ("DATA_LOSS", Code::DataLoss),
+    ("UNAUTHENTICATED", Code::Unauthenticated),
+];
+
+/// Parses a gRPC status name as used in the gRPC specification, e.g. "RESOURCE_EXHAUSTED"
+pub fn grpc_code_from_name(name: &str) -> Option<Code> {
+    GRPC_CODE_NAMES
+        .iter()
+        .find(|(code_name, _)| *code_name == name)
+        .map(|(_, code)| *code)
+}
+
+/// The gRPC specification name of a status, e.g. "RESOURCE_EXHAUSTED"
+pub fn grpc_code_name(code: Code) -> &'static str {
+    GRPC_CODE_NAMES
+        .iter()
+        .find(|(_, named)| *named == code)
+        .map_or("UNKNOWN", |(name, _)| name)
+}
+
+pub(super) fn serialize_grpc_code<S: Serializer>(
+    code: &Option<Code>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    code.map(grpc_code_name).serialize(serializer)
+}
+
+pub(super) fn deserialize_grpc_code<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Code>, D::Error> {
+    let name = Option::<String>::deserialize(deserializer)?;
+    name.map(|name| {
+        grpc_code_from_name(&name).ok_or_else(|| {
+            serde::de::Error::custom(format!("unknown gRPC status '{}'", name))
+        })
+    })
+    .transpose()
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct HeaderMutationConfig {
+    /// Headers to set, either `["name", "value"]` pairs (appended to existing values) or
+    /// `{"key": "name", "value": "value", "append_action": "..."}` objects
+    #[serde(default)]
+    pub set_headers: Vec<HeaderOptionConfig>,
+    /// Names of headers to remove
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "HeaderOptionRepr")]
+pub struct HeaderOptionConfig {
+    pub key: String,
+    pub value: String,
+    pub append_action: AppendAction,
+}
+
+impl HeaderOptionConfig {
+    pub fn new(key: &str, value: &str) -> HeaderOptionConfig {
+        HeaderOptionConfig {
+            key: key.to_string(),
+            value: value.to_string(),
+            append_action: AppendAction::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum HeaderOptionRepr {
+    Pair(String, String),
+    Option {
+        key: String,
+        value: String,
+        #[serde(default)]
+        append_action: AppendAction,
+    },
+}
+
+impl JsonSchema for HeaderOptionConfig {
+    fn schema_name() -> String {
+        "HeaderOptionConfig".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        HeaderOptionRepr::json_schema(gen)
+    }
+}
+
+impl From<HeaderOptionRepr> for HeaderOptionConfig {
+    fn from(repr: HeaderOptionRepr) -> Self {
+        match repr {
+            HeaderOptionRepr::Pair(key, value) => HeaderOptionConfig {
+                key,
+                value,
+                append_action: AppendAction::default(),
+            },
+            HeaderOptionRepr::Option {
+                key,
+                value,
+                append_action,
+            } => HeaderOptionConfig {
+                key,
+                value,
+                append_action,
+            },
+        }
+    }
+}
+
+/// Mirrors Envoy's HeaderValueOption.HeaderAppendAction. The vendored ext_proc API only has the
+/// `append` flag, so the server resolves each action against the headers of the message it
+/// answers and sends `append` or a replacement, or nothing at all.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AppendAction {
+    #[default]
+    AppendIfExistsOrAdd,
+    AddIfAbsent,
+    OverwriteIfExistsOrAdd,
+    OverwriteIfExists,
+}
+
+impl AppendAction {
+    /// The `append` flag carrying out the action given whether the header exists, or None if the
+    /// header is to be left alone
+    fn append(self, exists: bool) -> Option<bool> {
+        match self {
+            AppendAction::AppendIfExistsOrAdd => Some(true),
+            AppendAction::AddIfAbsent => (!exists).then_some(false),
+            AppendAction::OverwriteIfExistsOrAdd => Some(false),
+            AppendAction::OverwriteIfExists => exists.then_some(false),
+        }
+    }
+}
+
+impl HeaderMutationConfig {
+    pub fn is_empty(&self) -> bool {
+        self.set_headers.is_empty() && self.remove_headers.is_empty()
+    }
+
+    /// The configured mutation of `headers`, the headers of the message being answered if it has
+    /// any, or None if it doesn't change anything
+    pub fn to_mutation(&self, headers: Option<&HeaderMap>) -> Option<HeaderMutation> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(HeaderMutation {
+            set_headers: self
+                .set_headers
+                .iter()
+                .filter_map(|option| {
+                    let exists = header_value(headers, &option.key).is_some();
+                    let append = option.append_action.append(exists)?;
+                    Some(HeaderValueOption {
+                        header: Some(HeaderValue {
+                            key: option.key.to_lowercase(),
+                            value: option.value.clone(),
+                        }),
+                        append: Some(BoolValue { value: append }),
+                    })
+                })
+                .collect(),
+            remove_headers: self
+                .remove_headers
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::envoy::service::ext_proc::v3::HttpHeaders;
+
+    #[test]
+    fn shared_behavior_is_replaced_for_all_handles() {
+        let behavior = SharedBehavior::default();
+        let stream_handle = behavior.clone();
+        let before = stream_handle.load();
+        behavior.store(BehaviorConfig {
+            add_request_trailers: vec![("x-reloaded".to_string(), "1".to_string())],
+            ..Default::default()
+        });
+        assert!(before.add_request_trailers.is_empty());
+        assert_eq!(stream_handle.load().add_request_trailers.len(), 1);
+    }
+
+    #[test]
+    fn body_size_limit_actions() {
+        let mut limit: BodySizeLimitConfig = serde_json::from_str(r#"{"max_bytes": 10}"#).unwrap();
+        assert!(matches!(limit.check(10), Ok(None)));
+        let Ok(Some(immediate)) = limit.check(11) else {
+            panic!("expected an immediate response");
+        };
+        assert_eq!(immediate.status.unwrap().code, 413);
+        assert_eq!(immediate.details, "body of 11 bytes exceeds 10 bytes");
+
+        limit.action = BodySizeAction::GrpcError;
+        let Err(status) = limit.check(11) else {
+            panic!("expected an error status");
+        };
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn renders_body_template() {
+        let config: ImmediateResponseConfig = serde_json::from_str(
+            r#"{
+                "status": 429,
+                "body": "{status} for {header:Host} on {message}: {details} {unknown}",
+                "details": "rate limited"
+            }"#,
+        )
+        .unwrap();
+        let request = Request::RequestHeaders(HttpHeaders {
+            headers: Some(HeaderMap {
+                headers: vec![HeaderValue {
+                    key: "host".to_string(),
+                    value: "example.com".to_string(),
+                }],
+            }),
+            ..Default::default()
+        });
+
+        let response = config.to_immediate_response(&request);
+        assert_eq!(
+            response.body,
+            "429 for example.com on request_headers: rate limited {unknown}"
+        );
+        assert_eq!(response.details, "rate limited");
+        assert_eq!(response.status.unwrap().code, 429);
+        assert_eq!(
+            response.grpc_status.unwrap().status,
+            Code::Unavailable as u32
+        );
+    }
+
+    #[test]
+    fn explicit_grpc_status_wins() {
+        let config: ImmediateResponseConfig =
+            serde_json::from_str(r#"{"grpc_status": "RESOURCE_EXHAUSTED"}"#).unwrap();
+        assert_eq!(config.on, MessageKind::RequestHeaders);
+        assert_eq!(config.status, 403);
+        let response = config.to_immediate_response(&Request::RequestHeaders(Default::default()));
+        assert_eq!(
+            response.grpc_status.unwrap().status,
+            Code::ResourceExhausted as u32
+        );
+
+        assert!(serde_json::from_str::<ImmediateResponseConfig>(r#"{"grpc_status": "NOPE"}"#).is_err());
+    }
+
+    #[test]
+    fn parses_set_headers_with_append_action() {
+        let config: HeaderMutationConfig = serde_json::from_str(
+            r#"{"set_headers": [
+                ["x-pair", "1"],
+                {"key": "x-default", "value": "2"},
+                {"key": "X-Absent", "value": "3", "append_action": "add_if_absent"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.set_headers,
+            vec![
+                HeaderOptionConfig::new("x-pair", "1"),
+                HeaderOptionConfig::new("x-default", "2"),
+                HeaderOptionConfig {
+                    append_action: AppendAction::AddIfAbsent,
+                    ..HeaderOptionConfig::new("X-Absent", "3")
+                },
+            ]
+        );
+
+        let mutation = config.to_mutation(None).unwrap();
+        assert_eq!(mutation.set_headers.len(), 3);
+        let absent = mutation.set_headers[2].header.as_ref().unwrap();
+        assert_eq!(absent.key, "x-absent");
+    }
+
+    /// The `append` flags `action` resolves to against a message with the `existing` headers
+    fn resolve(action: AppendAction, existing: &[&str]) -> Vec<Option<bool>> {
+        let config = HeaderMutationConfig {
+            set_headers: vec![HeaderOptionConfig {
+                append_action: action,
+                ..HeaderOptionConfig::new("X-Tag", "b")
+            }],
+            remove_headers: vec![],
+        };
+        let headers = HeaderMap {
+            headers: existing
+                .iter()
+                .map(|key| HeaderValue {
+                    key: key.to_string(),
+                    value: "a".to_string(),
+                })
+                .collect(),
+        };
+        config
+            .to_mutation(Some(&headers))
+            .unwrap()
+            .set_headers
+            .iter()
+            .map(|option| option.append.as_ref().map(|append| append.value))
+            .collect()
+    }
+
+    #[test]
+    fn append_if_exists_or_add() {
+        let action = AppendAction::AppendIfExistsOrAdd;
+        assert_eq!(resolve(action, &["x-tag"]), vec![Some(true)]);
+        assert_eq!(resolve(action, &[]), vec![Some(true)]);
+    }
+
+    #[test]
+    fn add_if_absent() {
+        let action = AppendAction::AddIfAbsent;
+        assert_eq!(resolve(action, &["x-tag"]), vec![]);
+        assert_eq!(resolve(action, &["host"]), vec![Some(false)]);
+    }
+
+    #[test]
+    fn overwrite_if_exists_or_add() {
+        let action = AppendAction::OverwriteIfExistsOrAdd;
+        assert_eq!(resolve(action, &["X-Tag"]), vec![Some(false)]);
+        assert_eq!(resolve(action, &[]), vec![Some(false)]);
+    }
+
+    #[test]
+    fn overwrite_if_exists() {
+        let action = AppendAction::OverwriteIfExists;
+        assert_eq!(resolve(action, &["x-tag"]), vec![Some(false)]);
+        assert_eq!(resolve(action, &["host"]), vec![]);
+    }
+
+    #[test]
+    fn remove_only_mutation() {
+        let config: HeaderMutationConfig =
+            serde_json::from_str(r#"{"remove_headers": ["X-Debug"]}"#).unwrap();
+        let mutation = config.to_mutation(None).expect("removals are a mutation");
+        assert!(mutation.set_headers.is_empty());
+        assert_eq!(mutation.remove_headers, vec!["x-debug".to_string()]);
+    }
+}
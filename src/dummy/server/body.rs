@@ -0,0 +1,351 @@
+//! Body replacements, split over the responses to streamed body chunks, with content-encoding
+//! and content-length kept coherent with the new body
+
+use std::{io::Write, ops::Range};
+
+use flate2::{write::GzEncoder, Compression};
+
+use super::{
+    behavior::{
+        AppendAction, BehaviorConfig, BodyReplacementConfig, HeaderMutationConfig,
+        HeaderOptionConfig,
+    },
+    empty_response,
+};
+use crate::proto::envoy::extensions::filters::http::ext_proc::v3::{
+    processing_mode::BodySendMode, ProcessingMode,
+};
+use crate::proto::envoy::service::ext_proc::v3::{
+    body_mutation::Mutation, processing_request::Request, processing_response::Response,
+    BodyMutation, HttpBody, HttpHeaders, ProcessingResponse,
+};
+
+/// content-encoding of the current request and response of a stream
+#[derive(Default)]
+pub(super) struct ContentEncodings {
+    request: Option<String>,
+    response: Option<String>,
+}
+
+impl ContentEncodings {
+    /// Records the encoding of headers messages, which start a new request or response
+    pub(super) fn record(&mut self, request: &Request) {
+        match request {
+            // Request headers start a new transaction on reused streams
+            Request::RequestHeaders(headers) => {
+                *self = ContentEncodings {
+                    request: content_encoding(headers),
+                    response: None,
+                }
+            }
+            Request::ResponseHeaders(headers) => self.response = content_encoding(headers),
+            _ => {}
+        }
+    }
+}
+
+fn content_encoding(headers: &HttpHeaders) -> Option<String> {
+    headers
+        .headers
+        .as_ref()?
+        .headers
+        .iter()
+        .find(|header| header.key.eq_ignore_ascii_case("content-encoding"))
+        .map(|header| header.value.trim().to_lowercase())
+}
+
+/// The last body chunk received for the current request and response of a stream
+#[derive(Default)]
+pub(super) struct BodyChunks {
+    request: BodyChunk,
+    response: BodyChunk,
+}
+
+/// Range of a body chunk within its body, and whether it is the last chunk of the body
+#[derive(Default)]
+struct BodyChunk {
+    range: Range<usize>,
+    last: bool,
+}
+
+impl BodyChunk {
+    fn next(&self, body: &HttpBody, body_mode: BodySendMode) -> BodyChunk {
+        let start = if self.last { 0 } else { self.range.end };
+        BodyChunk {
+            range: start..start + body.body.len(),
+            // Chunks without end_of_stream may still end the body when trailers follow it, which
+            // is only known for buffered bodies
+            last: body.end_of_stream || body_mode == BodySendMode::Buffered,
+        }
+    }
+
+    /// The part of `replacement` answering this chunk: the bytes at the chunk's range, and
+    /// everything after them too on the last chunk
+    fn part<'a>(&self, replacement: &'a [u8]) -> &'a [u8] {
+        let start = self.range.start.min(replacement.len());
+        let end = if self.last {
+            replacement.len()
+        } else {
+            self.range.end.min(replacement.len())
+        };
+        &replacement[start..end]
+    }
+}
+
+impl BodyChunks {
+    /// Records the position of body chunks within their body. `mode` is the processing mode the
+    /// client was asked to use, telling whether bodies are sent in several chunks.
+    pub(super) fn record(&mut self, request: &Request, mode: &ProcessingMode) {
+        match request {
+            // Request headers start a new transaction on reused streams
+            Request::RequestHeaders(_) => *self = BodyChunks::default(),
+            Request::ResponseHeaders(_) => self.response = BodyChunk::default(),
+            Request::RequestBody(body) => {
+                self.request = self.request.next(body, mode.request_body_mode())
+            }
+            Request::ResponseBody(body) => {
+                self.response = self.response.next(body, mode.response_body_mode())
+            }
+            Request::RequestTrailers(_) | Request::ResponseTrailers(_) => {}
+        }
+    }
+}
+
+/// Replaces the body of body responses, if configured.
+///
+/// Bodies sent in several chunks are replaced chunk by chunk: the response to each chunk carries
+/// the part of the replacement at the same offset and of the same length, and the response to the
+/// last chunk the rest of it, so that the chunks rebuild the replacement. When trailers follow a
+/// streamed body, its last chunk can't be told apart and longer replacements are cut to the length
+/// of the original body. Header changes are only sent with the first chunk.
+pub(super) fn replace_body(
+    behavior: &BehaviorConfig,
+    encodings: &ContentEncodings,
+    chunks: &BodyChunks,
+    response: &mut ProcessingResponse,
+) {
+    let (body_response, config, encoding, chunk) = match response.response {
+        Some(Response::RequestBody(ref mut body)) => (
+            body,
+            &behavior.request_body,
+            &encodings.request,
+            &chunks.request,
+        ),
+        Some(Response::ResponseBody(ref mut body)) => (
+            body,
+            &behavior.response_body,
+            &encodings.response,
+            &chunks.response,
+        ),
+        _ => return,
+    };
+    let Some(config) = config else {
+        return;
+    };
+    let (body, headers) = encode_replacement(config, encoding.as_deref());
+    let mutation = if config.clear {
+        Mutation::ClearBody(true)
+    } else {
+        Mutation::Body(chunk.part(&body).to_vec())
+    };
+    let common = body_response.response.get_or_insert_with(empty_response);
+    common.body_mutation = Some(BodyMutation {
+        mutation: Some(mutation),
+    });
+    if chunk.range.start == 0 {
+        common.header_mutation = headers.to_mutation(None);
+    }
+}
+
+/// The replacement body, and the header changes describing it. Replacements of gzip bodies are
+/// gzipped when recompressing, other replacements are sent uncompressed. Cleared bodies are empty
+fn encode_replacement(
+    config: &BodyReplacementConfig,
+    encoding: Option<&str>,
+) -> (Vec<u8>, HeaderMutationConfig) {
+    let gzip = matches!(encoding, Some("gzip" | "x-gzip"));
+    let recompress = gzip && config.recompress && !config.clear;
+    let body = if config.clear {
+        Vec::new()
+    } else if recompress {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(config.body())
+            .and_then(|_| encoder.finish())
+            .expect("writing to memory can't fail")
+    } else {
+        config.body().to_vec()
+    };
+    let mut headers = HeaderMutationConfig {
+        set_headers: vec![HeaderOptionConfig {
+            key: "content-length".to_string(),
+            value: body.len().to_string(),
+            append_action: AppendAction::OverwriteIfExistsOrAdd,
+        }],
+        remove_headers: Vec::new(),
+    };
+    if encoding.is_some() && !recompress {
+        headers.remove_headers.push("content-encoding".to_string());
+    }
+    (body, headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::proto::envoy::service::ext_proc::v3::HeaderMutation;
+
+    #[test]
+    fn replacements_match_the_original_encoding() {
+        let mut config = BodyReplacementConfig {
+            body: "replaced".to_string(),
+            recompress: true,
+            ..Default::default()
+        };
+        let (body, headers) = encode_replacement(&config, Some("gzip"));
+        let mut decoded = String::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "replaced");
+        assert_eq!(headers.set_headers[0].value, body.len().to_string());
+        assert!(headers.remove_headers.is_empty());
+
+        // Encodings that can't be reproduced are dropped with the original body
+        for encoding in [Some("br"), Some("gzip")] {
+            config.recompress = encoding == Some("br");
+            let (body, headers) = encode_replacement(&config, encoding);
+            assert_eq!(body, b"replaced");
+            assert_eq!(headers.set_headers[0].value, "8");
+            assert_eq!(headers.remove_headers, ["content-encoding"]);
+        }
+
+        let (_, headers) = encode_replacement(&config, None);
+        assert!(headers.remove_headers.is_empty());
+    }
+
+    #[test]
+    fn bodies_are_cleared_or_replaced_from_a_file() {
+        let mut response = ProcessingResponse {
+            response: Some(Response::ResponseBody(Default::default())),
+            ..Default::default()
+        };
+        let mut behavior = BehaviorConfig {
+            response_body: Some(BodyReplacementConfig {
+                clear: true,
+                recompress: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut encodings = ContentEncodings::default();
+        encodings.response = Some("gzip".to_string());
+        let mut chunks = BodyChunks::default();
+        chunks.record(&body(b"body", true), &ProcessingMode::default());
+        replace_body(&behavior, &encodings, &chunks, &mut response);
+        let Some(Response::ResponseBody(ref body)) = response.response else {
+            panic!("not a body response");
+        };
+        let common = body.response.as_ref().unwrap();
+        assert_eq!(
+            common.body_mutation.as_ref().unwrap().mutation,
+            Some(Mutation::ClearBody(true))
+        );
+        let headers = common.header_mutation.as_ref().unwrap();
+        assert_eq!(headers.set_headers[0].header.as_ref().unwrap().value, "0");
+        assert_eq!(headers.remove_headers, ["content-encoding"]);
+
+        let path = std::env::temp_dir().join("ext_proc_replacement_body.bin");
+        std::fs::write(&path, [0u8, 159, 146, 150]).unwrap();
+        let replacement = behavior.response_body.as_mut().unwrap();
+        replacement.clear = false;
+        replacement.recompress = false;
+        replacement.body_file = Some(path.clone());
+        behavior.load_body_files().unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(behavior.response_body.unwrap().body(), [0, 159, 146, 150]);
+    }
+
+    fn body(body: &[u8], end_of_stream: bool) -> Request {
+        Request::ResponseBody(HttpBody {
+            body: body.to_vec(),
+            end_of_stream,
+        })
+    }
+
+    fn replaced_chunk(
+        behavior: &BehaviorConfig,
+        chunks: &BodyChunks,
+    ) -> (Vec<u8>, Option<HeaderMutation>) {
+        let mut response = ProcessingResponse {
+            response: Some(Response::ResponseBody(Default::default())),
+            ..Default::default()
+        };
+        replace_body(
+            behavior,
+            &ContentEncodings::default(),
+            chunks,
+            &mut response,
+        );
+        let Some(Response::ResponseBody(body)) = response.response else {
+            panic!("not a body response");
+        };
+        let common = body.response.unwrap();
+        match common.body_mutation.and_then(|mutation| mutation.mutation) {
+            Some(Mutation::Body(replacement)) => (replacement, common.header_mutation),
+            other => panic!("expected a replacement, got {:?}", other),
+        }
+    }
+
+    fn replacement(body: &str) -> BehaviorConfig {
+        BehaviorConfig {
+            response_body: Some(BodyReplacementConfig {
+                body: body.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn streamed_bodies_are_replaced_chunk_by_chunk() {
+        let behavior = replacement("a longer replacement");
+        let streamed = ProcessingMode {
+            response_body_mode: BodySendMode::Streamed.into(),
+            ..Default::default()
+        };
+        let mut chunks = BodyChunks::default();
+        let mut replaced = Vec::new();
+        for (chunk, end_of_stream) in [(&b"orig"[..], false), (b"inal", false), (b" body", true)] {
+            chunks.record(&body(chunk, end_of_stream), &streamed);
+            let (replacement, headers) = replaced_chunk(&behavior, &chunks);
+            // content-length is only set with the first chunk
+            assert_eq!(headers.is_some(), replaced.is_empty());
+            replaced.push(replacement);
+        }
+        assert_eq!(replaced, [&b"a lo"[..], b"nger", b" replacement"]);
+
+        // A new response starts a new body, and shorter replacements leave later chunks empty
+        let behavior = replacement("short");
+        chunks.record(&Request::ResponseHeaders(Default::default()), &streamed);
+        let mut replaced = Vec::new();
+        for (chunk, end_of_stream) in [(&b"original"[..], false), (b" body", true)] {
+            chunks.record(&body(chunk, end_of_stream), &streamed);
+            replaced.push(replaced_chunk(&behavior, &chunks).0);
+        }
+        assert_eq!(replaced, [&b"short"[..], b""]);
+
+        // Buffered bodies are replaced whole even when trailers follow them
+        chunks.record(&Request::ResponseHeaders(Default::default()), &streamed);
+        let buffered = ProcessingMode {
+            response_body_mode: BodySendMode::Buffered.into(),
+            ..Default::default()
+        };
+        chunks.record(&body(b"original", false), &buffered);
+        assert_eq!(replaced_chunk(&behavior, &chunks).0, b"short");
+    }
+}
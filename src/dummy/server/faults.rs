@@ -0,0 +1,245 @@
+use std::{collections::HashMap, f64::consts::PI, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tonic::{Code, Status};
+
+use super::behavior::{deserialize_grpc_code, serialize_grpc_code, MessageKind};
+
+/// Random delays and failures injected into a stream, to simulate a flaky server
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct FaultInjectionConfig {
+    /// Delay before handling each message, drawn uniformly from `[min, max]` milliseconds
+    #[serde(default)]
+    pub latency_ms: Option<(u64, u64)>,
+    /// Probability of ending the stream with `error_status` instead of handling a message
+    #[serde(default)]
+    pub error_rate: f64,
+    /// End streams with `error_status` once they handled this many messages, instead of handling
+    /// the next one
+    #[serde(default)]
+    pub error_after_messages: Option<u64>,
+    /// gRPC status name (e.g. "RESOURCE_EXHAUSTED") injected errors end streams with.
+    /// UNAVAILABLE when unset.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_grpc_code",
+        serialize_with = "serialize_grpc_code"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub error_status: Option<Code>,
+}
+
+/// Delays before responding to messages, to simulate a slow processor and measure the queueing
+/// it causes. Unlike the latency of [`FaultInjectionConfig`], they count as processing time
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct LatencyConfig {
+    /// Delay of the messages without one of their own in `messages`
+    #[serde(default)]
+    pub default: Option<DelayDistribution>,
+    /// Delays by message type
+    #[serde(default)]
+    pub messages: HashMap<MessageKind, DelayDistribution>,
+}
+
+impl LatencyConfig {
+    pub fn delay(&self, kind: MessageKind) -> Option<&DelayDistribution> {
+        self.messages.get(&kind).or(self.default.as_ref())
+    }
+}
+
+/// How delays are drawn, in milliseconds
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum DelayDistribution {
+    /// Always `ms`
+    Fixed { ms: f64 },
+    /// Uniformly from `[min_ms, max_ms]`
+    Uniform { min_ms: f64, max_ms: f64 },
+    /// Log-normally around `median_ms`, `sigma` being the standard deviation of the delay's
+    /// logarithm: the long tail of real services, e.g. a sigma of 1 puts p99 at 10x the median
+    LogNormal { median_ms: f64, sigma: f64 },
+}
+
+/// Deliberate termination of streams, to exercise the stream recovery of clients. Streams are
+/// killed when the request headers of their next transaction arrive, leaving it unanswered.
+/// Resets are left to clients (see bench_client's --kill-closure), the server always ends streams
+/// with a status
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct StreamKillConfig {
+    /// Kill streams that handled this many transactions
+    #[serde(default)]
+    pub after_transactions: Option<u64>,
+    /// Kill streams open for this many milliseconds
+    #[serde(default)]
+    pub after_ms: Option<u64>,
+    /// gRPC status name (e.g. "UNAVAILABLE") killed streams end with.
+    /// They end cleanly, with an OK status, when unset.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_grpc_code",
+        serialize_with = "serialize_grpc_code"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub status: Option<Code>,
+}
+
+impl StreamKillConfig {
+    /// Whether a stream that handled `transactions` transactions and was opened `age` ago is
+    /// killed
+    pub fn is_due(&self, transactions: u64, age: Duration) -> bool {
+        matches!(self.after_transactions, Some(max) if transactions >= max)
+            || matches!(self.after_ms, Some(ms) if age >= Duration::from_millis(ms))
+    }
+
+    /// The status killed streams end with, None to end them cleanly
+    pub fn status(&self) -> Option<Status> {
+        self.status.map(|code| Status::new(code, "stream killed by dummy ext_proc"))
+    }
+}
+
+/// What to inject before handling a message
+#[derive(Debug, Default)]
+pub struct Fault {
+    pub delay: Option<Duration>,
+    pub error: Option<Status>,
+}
+
+/// Draws the faults of a single stream. With a seed, each stream's faults only depend on the seed
+/// and on the order the stream was opened in, so runs with the same seed see the same schedule.
+pub struct FaultInjector {
+    rng: StdRng,
+}
+
+impl FaultInjector {
+    /// Injector of the `stream`th stream opened by the server
+    pub fn new(seed: Option<u64>, stream: u64) -> FaultInjector {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(stream)),
+            None => StdRng::from_entropy(),
+        };
+        FaultInjector { rng }
+    }
+
+    /// The faults of the message following the `handled` first ones of the stream
+    pub fn draw(&mut self, config: &FaultInjectionConfig, handled: u64) -> Fault {
+        let delay = config
+            .latency_ms
+            .map(|(min, max)| Duration::from_millis(self.rng.gen_range(min..=max.max(min))));
+        let drawn = config.error_rate > 0.0 && self.rng.gen_bool(config.error_rate.min(1.0));
+        let exhausted = matches!(config.error_after_messages, Some(max) if handled >= max);
+        let error = (drawn || exhausted).then(|| {
+            Status::new(
+                config.error_status.unwrap_or(Code::Unavailable),
+                "fault injected by dummy ext_proc",
+            )
+        });
+        Fault { delay, error }
+    }
+
+    /// Draws a delay from `distribution`
+    pub fn draw_delay(&mut self, distribution: &DelayDistribution) -> Duration {
+        let ms = match *distribution {
+            DelayDistribution::Fixed { ms } => ms,
+            DelayDistribution::Uniform { min_ms, max_ms } => {
+                min_ms + self.rng.gen::<f64>() * (max_ms - min_ms).max(0.0)
+            }
+            DelayDistribution::LogNormal { median_ms, sigma } => {
+                // Box-Muller transform of two uniform draws into a standard normal one
+                let u1 = 1.0 - self.rng.gen::<f64>();
+                let u2 = self.rng.gen::<f64>();
+                let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+                median_ms * (sigma * normal).exp()
+            }
+        };
+        Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flaky() -> FaultInjectionConfig {
+        FaultInjectionConfig {
+            latency_ms: Some((1, 100)),
+            error_rate: 0.3,
+            error_after_messages: None,
+            error_status: None,
+        }
+    }
+
+    fn schedule(injector: &mut FaultInjector) -> Vec<(Option<Duration>, bool)> {
+        (0..50)
+            .map(|_| {
+                let fault = injector.draw(&flaky(), 0);
+                (fault.delay, fault.error.is_some())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seeded_schedules_repeat() {
+        let first = schedule(&mut FaultInjector::new(Some(7), 3));
+        assert_eq!(first, schedule(&mut FaultInjector::new(Some(7), 3)));
+        assert_ne!(first, schedule(&mut FaultInjector::new(Some(7), 4)));
+        assert!(first
+            .iter()
+            .all(|(delay, _)| (1..=100).contains(&delay.unwrap().as_millis())));
+    }
+
+    #[test]
+    fn streams_are_killed_after_transactions_or_age() {
+        let kill = StreamKillConfig {
+            after_transactions: Some(10),
+            after_ms: Some(1000),
+            status: None,
+        };
+        assert!(!kill.is_due(9, Duration::from_millis(999)));
+        assert!(kill.is_due(10, Duration::ZERO));
+        assert!(kill.is_due(0, Duration::from_secs(1)));
+        assert!(kill.status().is_none());
+        assert!(!StreamKillConfig::default().is_due(u64::MAX, Duration::MAX));
+    }
+
+    #[test]
+    fn streams_fail_after_messages() {
+        let config = FaultInjectionConfig {
+            error_after_messages: Some(3),
+            error_status: Some(Code::ResourceExhausted),
+            ..Default::default()
+        };
+        let mut injector = FaultInjector::new(None, 0);
+        assert!(injector.draw(&config, 2).error.is_none());
+        let error = injector.draw(&config, 3).error.unwrap();
+        assert_eq!(error.code(), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn delays_follow_their_distribution() {
+        let latency: LatencyConfig = serde_json::from_value(serde_json::json!({
+            "default": {"fixed": {"ms": 5}},
+            "messages": {
+                "request_body": {"uniform": {"min_ms": 10, "max_ms": 20}},
+                "response_body": {"log_normal": {"median_ms": 10, "sigma": 1}}
+            }
+        }))
+        .unwrap();
+        let mut injector = FaultInjector::new(Some(1), 0);
+        let mut draw = |kind| injector.draw_delay(latency.delay(kind).unwrap());
+        assert_eq!(draw(MessageKind::RequestHeaders), Duration::from_millis(5));
+        let uniform = draw(MessageKind::RequestBody);
+        assert!((Duration::from_millis(10)..=Duration::from_millis(20)).contains(&uniform));
+        let mut log_normal: Vec<_> = (0..1001).map(|_| draw(MessageKind::ResponseBody)).collect();
+        log_normal.sort();
+        let median = log_normal[500].as_secs_f64() * 1000.0;
+        assert!((7.0..14.0).contains(&median), "median {}ms", median);
+    }
+
+    #[test]
+    fn no_faults_by_default() {
+        let fault = FaultInjector::new(None, 0).draw(&FaultInjectionConfig::default(), u64::MAX);
+        assert!(fault.delay.is_none() && fault.error.is_none());
+    }
+}
@@ -0,0 +1,146 @@
+//! Hooks around the handling of each stream's messages, so concerns like logging, metrics and
+//! validation compose without changing how responses are built
+
+use std::sync::Arc;
+
+use tonic::{metadata::MetadataMap, Extensions, Status};
+
+use crate::proto::envoy::service::ext_proc::v3::{ProcessingRequest, ProcessingResponse};
+
+/// A stream, as seen by its interceptors
+pub struct StreamContext {
+    /// Number of the stream, in the order streams were opened
+    pub stream_id: u64,
+    /// Requests received so far, including the current one
+    pub requests: u64,
+    /// gRPC metadata the stream was opened with
+    pub metadata: MetadataMap,
+    /// Extensions of the stream's request (e.g. connection info), where interceptors can also
+    /// keep their own state between messages
+    pub extensions: Extensions,
+}
+
+/// Pre and post hooks of request handling. An error ends the stream with that status
+pub trait Interceptor: Send + Sync {
+    /// Called with each request before it is handled
+    fn on_request(
+        &self,
+        _context: &mut StreamContext,
+        _request: &ProcessingRequest,
+    ) -> Result<(), Status> {
+        Ok(())
+    }
+
+    /// Called with each response before it is sent, which may be changed
+    fn on_response(
+        &self,
+        _context: &mut StreamContext,
+        _response: &mut ProcessingResponse,
+    ) -> Result<(), Status> {
+        Ok(())
+    }
+}
+
+/// Interceptors in the order they were added. Like layers, the first one sees requests first and
+/// responses last
+#[derive(Clone, Default)]
+pub struct InterceptorChain {
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl InterceptorChain {
+    pub fn push(&mut self, interceptor: Arc<dyn Interceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    pub fn on_request(
+        &self,
+        context: &mut StreamContext,
+        request: &ProcessingRequest,
+    ) -> Result<(), Status> {
+        context.requests += 1;
+        self.interceptors
+            .iter()
+            .try_for_each(|interceptor| interceptor.on_request(context, request))
+    }
+
+    pub fn on_response(
+        &self,
+        context: &mut StreamContext,
+        response: &mut ProcessingResponse,
+    ) -> Result<(), Status> {
+        self.interceptors
+            .iter()
+            .rev()
+            .try_for_each(|interceptor| interceptor.on_response(context, response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::proto::envoy::service::ext_proc::v3::processing_request::Request;
+
+    /// Records the order hooks are called in, and denies a stream's third request
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Interceptor for Recorder {
+        fn on_request(
+            &self,
+            context: &mut StreamContext,
+            _request: &ProcessingRequest,
+        ) -> Result<(), Status> {
+            self.calls.lock().unwrap().push(format!("{} request", self.name));
+            if context.requests > 2 {
+                return Err(Status::permission_denied("too many requests"));
+            }
+            Ok(())
+        }
+
+        fn on_response(
+            &self,
+            _context: &mut StreamContext,
+            _response: &mut ProcessingResponse,
+        ) -> Result<(), Status> {
+            self.calls.lock().unwrap().push(format!("{} response", self.name));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hooks_run_like_layers() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = InterceptorChain::default();
+        for name in ["outer", "inner"] {
+            chain.push(Arc::new(Recorder {
+                name,
+                calls: calls.clone(),
+            }));
+        }
+        let mut context = StreamContext {
+            stream_id: 0,
+            requests: 0,
+            metadata: MetadataMap::new(),
+            extensions: Extensions::default(),
+        };
+        let request = ProcessingRequest {
+            async_mode: false,
+            request: Some(Request::RequestHeaders(Default::default())),
+        };
+        chain.on_request(&mut context, &request).unwrap();
+        chain
+            .on_response(&mut context, &mut ProcessingResponse::default())
+            .unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["outer request", "inner request", "inner response", "outer response"]
+        );
+        chain.on_request(&mut context, &request).unwrap();
+        assert!(chain.on_request(&mut context, &request).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Server side metrics, to correlate the server's behavior with what clients measure: requests by
+//! message type, open streams, how long messages take to answer and how long streams last.
+//! Gathered by [`MetricsInterceptor`] and served to Prometheus by run_server's --metrics-port
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use metered::{hdr_histogram::AtomicHdrHistogram, metric::Histogram};
+use tonic::Status;
+
+use super::{
+    behavior::MessageKind,
+    interceptor::{Interceptor, StreamContext},
+};
+use crate::{
+    proto::envoy::service::ext_proc::v3::{ProcessingRequest, ProcessingResponse},
+    report::prometheus::{escape_label, TextWriter},
+};
+
+const PREFIX: &str = "ext_proc_server";
+const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+/// Largest recorded duration, an hour in microseconds
+const MAX_RECORDED_MICROS: u64 = 3_600_000_000;
+
+const MESSAGE_KINDS: [MessageKind; 6] = [
+    MessageKind::RequestHeaders,
+    MessageKind::RequestBody,
+    MessageKind::RequestTrailers,
+    MessageKind::ResponseHeaders,
+    MessageKind::ResponseBody,
+    MessageKind::ResponseTrailers,
+];
+
+fn kind_index(kind: MessageKind) -> usize {
+    MESSAGE_KINDS.iter().position(|k| *k == kind).unwrap()
+}
+
+/// Metrics of an ExtProcService, since it started
+pub struct ServerMetrics {
+    /// Requests received, by message type in the order of [`MESSAGE_KINDS`]
+    requests: [AtomicU64; 6],
+    streams: AtomicU64,
+    active_streams: AtomicU64,
+    /// From the receipt of a message to its response being sent, in microseconds
+    processing_time_us: AtomicHdrHistogram,
+    /// From the first message of a stream to its end, in microseconds
+    stream_duration_us: AtomicHdrHistogram,
+}
+
+impl Default for ServerMetrics {
+    fn default() -> ServerMetrics {
+        ServerMetrics {
+            requests: Default::default(),
+            streams: AtomicU64::new(0),
+            active_streams: AtomicU64::new(0),
+            processing_time_us: AtomicHdrHistogram::with_bound(MAX_RECORDED_MICROS),
+            stream_duration_us: AtomicHdrHistogram::with_bound(MAX_RECORDED_MICROS),
+        }
+    }
+}
+
+impl ServerMetrics {
+    pub fn requests(&self, kind: MessageKind) -> u64 {
+        self.requests[kind_index(kind)].load(Ordering::Relaxed)
+    }
+
+    /// Streams open now
+    pub fn active_streams(&self) -> u64 {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+}
+
+/// The metrics of several services in the Prometheus text format, each labeled with its
+/// `instance` name
+pub fn render(instances: &[(String, Arc<ServerMetrics>)]) -> String {
+    let labeled: Vec<(String, &ServerMetrics)> = instances
+        .iter()
+        .map(|(name, metrics)| (format!("instance=\"{}\"", escape_label(name)), &**metrics))
+        .collect();
+    let mut out = TextWriter::new(PREFIX);
+    out.header("requests_total", "counter", "Requests received, by message type");
+    for (labels, metrics) in &labeled {
+        for (kind, count) in MESSAGE_KINDS.iter().zip(&metrics.requests) {
+            let labels = format!("{},message=\"{}\"", labels, kind.as_str());
+            out.sample("requests_total", &labels, count.load(Ordering::Relaxed) as f64);
+        }
+    }
+    out.header("streams_total", "counter", "Streams opened");
+    for (labels, metrics) in &labeled {
+        out.sample("streams_total", labels, metrics.streams.load(Ordering::Relaxed) as f64);
+    }
+    out.header("active_streams", "gauge", "Streams open now");
+    for (labels, metrics) in &labeled {
+        out.sample("active_streams", labels, metrics.active_streams() as f64);
+    }
+    write_summary(
+        &mut out,
+        &labeled,
+        "processing_time_ms",
+        "Time from the receipt of a message to its response being sent, in milliseconds",
+        |metrics| &metrics.processing_time_us,
+    );
+    write_summary(
+        &mut out,
+        &labeled,
+        "stream_duration_ms",
+        "Time from the first message of a stream to its end, in milliseconds",
+        |metrics| &metrics.stream_duration_us,
+    );
+    out.finish()
+}
+
+/// Writes a histogram of microseconds as a summary in milliseconds
+fn write_summary<F>(
+    out: &mut TextWriter,
+    labeled: &[(String, &ServerMetrics)],
+    name: &str,
+    help: &str,
+    histogram: F,
+) where
+    F: Fn(&ServerMetrics) -> &AtomicHdrHistogram,
+{
+    out.header(name, "summary", help);
+    for &(ref labels, metrics) in labeled {
+        let histogram = histogram(metrics).histogram();
+        for quantile in QUANTILES {
+            let quantile_labels = format!("{},quantile=\"{}\"", labels, quantile);
+            out.sample(name, &quantile_labels, histogram.quantile(quantile) as f64 / 1000.0);
+        }
+        let count = histogram.len() as f64;
+        out.sample(&format!("{}_sum", name), labels, histogram.mean() * count / 1000.0);
+        out.sample(&format!("{}_count", name), labels, count);
+    }
+}
+
+/// Gathers [`ServerMetrics`]. Streams are counted from their first message
+pub struct MetricsInterceptor {
+    metrics: Arc<ServerMetrics>,
+}
+
+impl MetricsInterceptor {
+    pub fn new(metrics: Arc<ServerMetrics>) -> MetricsInterceptor {
+        MetricsInterceptor { metrics }
+    }
+}
+
+/// Kept in the extensions of open streams, recording their end when they are dropped
+struct OpenStream {
+    metrics: Arc<ServerMetrics>,
+    opened_at: Instant,
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.metrics.active_streams.fetch_sub(1, Ordering::Relaxed);
+        let duration = self.opened_at.elapsed().as_micros() as u64;
+        self.metrics.stream_duration_us.record(duration);
+    }
+}
+
+/// Receipt of the message being handled
+struct Received(Instant);
+
+impl Interceptor for MetricsInterceptor {
+    fn on_request(
+        &self,
+        context: &mut StreamContext,
+        request: &ProcessingRequest,
+    ) -> Result<(), Status> {
+        let now = Instant::now();
+        if context.extensions.get::<OpenStream>().is_none() {
+            self.metrics.streams.fetch_add(1, Ordering::Relaxed);
+            self.metrics.active_streams.fetch_add(1, Ordering::Relaxed);
+            context.extensions.insert(OpenStream {
+                metrics: self.metrics.clone(),
+                opened_at: now,
+            });
+        }
+        if let Some(ref message) = request.request {
+            let index = kind_index(MessageKind::of(message));
+            self.metrics.requests[index].fetch_add(1, Ordering::Relaxed);
+        }
+        context.extensions.insert(Received(now));
+        Ok(())
+    }
+
+    fn on_response(
+        &self,
+        context: &mut StreamContext,
+        _response: &mut ProcessingResponse,
+    ) -> Result<(), Status> {
+        if let Some(Received(received_at)) = context.extensions.get::<Received>() {
+            let processing_time = received_at.elapsed().as_micros() as u64;
+            self.metrics.processing_time_us.record(processing_time);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{metadata::MetadataMap, Extensions};
+
+    use super::*;
+    use crate::proto::envoy::service::ext_proc::v3::processing_request::Request;
+
+    #[test]
+    fn streams_and_requests_are_counted() {
+        let metrics = Arc::new(ServerMetrics::default());
+        let interceptor = MetricsInterceptor::new(metrics.clone());
+        let mut context = StreamContext {
+            stream_id: 0,
+            requests: 0,
+            metadata: MetadataMap::new(),
+            extensions: Extensions::default(),
+        };
+        let request = ProcessingRequest {
+            async_mode: false,
+            request: Some(Request::RequestHeaders(Default::default())),
+        };
+        for _ in 0..2 {
+            interceptor.on_request(&mut context, &request).unwrap();
+            interceptor
+                .on_response(&mut context, &mut ProcessingResponse::default())
+                .unwrap();
+        }
+        assert_eq!(metrics.requests(MessageKind::RequestHeaders), 2);
+        assert_eq!(metrics.active_streams(), 1);
+        assert_eq!(metrics.processing_time_us.histogram().len(), 2);
+        drop(context);
+        assert_eq!(metrics.active_streams(), 0);
+        assert_eq!(metrics.stream_duration_us.histogram().len(), 1);
+        let text = render(&[("50051".to_string(), metrics)]);
+        assert!(text.contains(
+            "ext_proc_server_requests_total{instance=\"50051\",message=\"request_headers\"} 2\n"
+        ));
+        assert!(text.contains("ext_proc_server_streams_total{instance=\"50051\"} 1\n"));
+    }
+}
@@ -1,27 +1,166 @@
-use std::{sync::Arc, pin::Pin};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use futures::Stream;
+use futures::{
+    future::{self, Either},
+    Stream,
+};
+use log::debug;
+use prost::Message;
+use tokio::sync::watch;
 use tonic::{async_trait, Code, Request as TRequest, Response as TResponse, Status, Streaming};
 
 use crate::proto::envoy::{
-    extensions::filters::http::ext_proc::v3::ProcessingMode,
+    config::core::v3::{HeaderMap, HeaderValue, HeaderValueOption},
+    extensions::filters::http::ext_proc::v3::{
+        processing_mode::{BodySendMode, HeaderSendMode},
+        ProcessingMode,
+    },
     service::ext_proc::v3::{
         common_response::ResponseStatus, external_processor_server::ExternalProcessor,
         processing_request::Request, processing_response::Response, BodyResponse, CommonResponse,
-        GrpcStatus, HeadersResponse, ImmediateResponse, ProcessingRequest, ProcessingResponse,
+        GrpcStatus, HeaderMutation, HeadersResponse, HttpHeaders, ImmediateResponse,
+        ProcessingRequest, ProcessingResponse, TrailersResponse,
     },
 };
 
+use super::{attribute_echo_header, protobuf::value_to_string, PROCESSING_TIME_HEADER};
+use behavior::{BehaviorConfig, EchoTarget, MessageKind, SharedBehavior};
+use body::{BodyChunks, ContentEncodings};
+use faults::FaultInjector;
+use interceptor::{Interceptor, InterceptorChain, StreamContext};
+use script::ScriptRunner;
+use validation::OrderValidator;
+
+pub mod behavior;
+mod body;
+pub mod faults;
+pub mod interceptor;
+pub mod metrics;
+pub mod mode;
+pub mod recording;
+pub mod reflection;
+pub mod script;
+pub mod validation;
+
 pub struct ExtProcService {
     processing_mode: Arc<ProcessingMode>,
+    behavior: SharedBehavior,
+    strict_ordering: bool,
+    idle_timeout: Option<Duration>,
+    seed: Option<u64>,
+    max_message_size: Option<usize>,
+    report_processing_time: bool,
+    hold_chunk_responses: bool,
+    shutdown: Option<watch::Receiver<bool>>,
+    /// Streams opened so far, numbering each stream's fault schedule
+    stream_count: Arc<AtomicU64>,
+    interceptors: InterceptorChain,
+}
+
+/// The processing mode run_server starts streams with: headers are sent, bodies are buffered and
+/// trailers are sent only if asked for
+pub fn server_processing_mode(send_trailers: bool) -> ProcessingMode {
+    let trailer_mode = if send_trailers {
+        HeaderSendMode::Send
+    } else {
+        HeaderSendMode::Skip
+    };
+    let mut processing_mode = ProcessingMode::default();
+    processing_mode.set_request_header_mode(HeaderSendMode::Send);
+    processing_mode.set_response_header_mode(HeaderSendMode::Send);
+    processing_mode.set_request_body_mode(BodySendMode::Buffered);
+    processing_mode.set_response_body_mode(BodySendMode::Buffered);
+    processing_mode.set_request_trailer_mode(trailer_mode);
+    processing_mode.set_response_trailer_mode(trailer_mode);
+    processing_mode
 }
 
 impl ExtProcService {
-    pub fn new(processing_mode: ProcessingMode) -> ExtProcService {
+    pub fn new(processing_mode: ProcessingMode, behavior: BehaviorConfig) -> ExtProcService {
         ExtProcService {
             processing_mode: Arc::new(processing_mode),
+            behavior: SharedBehavior::new(behavior),
+            strict_ordering: false,
+            idle_timeout: None,
+            seed: None,
+            max_message_size: None,
+            report_processing_time: false,
+            hold_chunk_responses: false,
+            shutdown: None,
+            stream_count: Arc::new(AtomicU64::new(0)),
+            interceptors: InterceptorChain::default(),
         }
     }
+
+    /// Validate the order of each stream's messages against the processing mode, ending streams
+    /// that violate it with a FAILED_PRECONDITION status, or INVALID_ARGUMENT for requests
+    /// carrying no message (see [`validation::OrderValidator`])
+    pub fn with_strict_ordering(mut self, strict_ordering: bool) -> ExtProcService {
+        self.strict_ordering = strict_ordering;
+        self
+    }
+
+    /// Close streams that receive no message for this long, ending them with an OK status
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> ExtProcService {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Seed of injected faults (see [`faults::FaultInjector`]), drawn from entropy when unset
+    pub fn with_seed(mut self, seed: Option<u64>) -> ExtProcService {
+        self.seed = seed;
+        self
+    }
+
+    /// End streams receiving or about to send a message larger than this many bytes, encoded,
+    /// with a RESOURCE_EXHAUSTED status like gRPC's max receive and send message sizes
+    pub fn with_max_message_size(mut self, max_message_size: Option<usize>) -> ExtProcService {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Report how long each message took to answer, from its receipt to its response being sent,
+    /// in a [`PROCESSING_TIME_HEADER`] header mutation of the response
+    pub fn with_processing_time(mut self, report_processing_time: bool) -> ExtProcService {
+        self.report_processing_time = report_processing_time;
+        self
+    }
+
+    /// Hold the responses to the chunks of STREAMED bodies until the chunk ending the body (or
+    /// the trailers) is received, then send them all, like a processor that needs the whole body
+    /// before answering. Only clients sending chunks without awaiting their responses (see
+    /// [`super::client::Config::pipelined_bodies`]) get past the first chunk
+    pub fn with_held_chunk_responses(mut self, hold_chunk_responses: bool) -> ExtProcService {
+        self.hold_chunk_responses = hold_chunk_responses;
+        self
+    }
+
+    /// End streams with an OK status between transactions once `shutdown` turns true, so a
+    /// graceful shutdown doesn't wait for clients to close the streams they reuse. Transactions
+    /// in progress are finished first
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> ExtProcService {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Run `interceptor`'s hooks around the handling of every message, after those of the
+    /// interceptors added before it (see [`interceptor::InterceptorChain`])
+    pub fn with_interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> ExtProcService {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Handle to replace the behavior of the service, including its open streams
+    pub fn behavior(&self) -> SharedBehavior {
+        self.behavior.clone()
+    }
 }
 
 #[async_trait]
@@ -33,13 +172,149 @@ impl ExternalProcessor for ExtProcService {
         &self,
         requests: TRequest<Streaming<ProcessingRequest>>,
     ) -> Result<TResponse<Self::ProcessStream>, Status> {
-        let mut stream = requests.into_inner();
+        let (metadata, extensions, mut stream) = requests.into_parts();
         let processing_mode = self.processing_mode.clone();
+        let behavior = self.behavior.clone();
+        let mut validator = self
+            .strict_ordering
+            .then(|| OrderValidator::new(processing_mode.as_ref().clone()));
+        let mut body_sizes = BodySizes::default();
+        let mut body_chunks = BodyChunks::default();
+        let mut encodings = ContentEncodings::default();
+        let idle_timeout = self.idle_timeout;
+        let max_message_size = self.max_message_size;
+        let report_processing_time = self.report_processing_time;
+        let hold_chunk_responses = self.hold_chunk_responses;
+        let mut shutdown = self.shutdown.clone();
+        // Between the request headers and the end of the response
+        let mut in_transaction = false;
+        let mut held_responses = Vec::new();
+        let stream_id = self.stream_count.fetch_add(1, Ordering::Relaxed);
+        let mut faults = FaultInjector::new(self.seed, stream_id);
+        let mut script = ScriptRunner::default();
+        let opened_at = Instant::now();
+        let mut transactions = 0u64;
+        let interceptors = self.interceptors.clone();
+        let mut context = StreamContext {
+            stream_id,
+            requests: 0,
+            metadata,
+            extensions,
+        };
         let output = async_stream::try_stream! {
-            while let Some(request) = stream.message().await? {
-                let mut response = ExtProcService::init_response(&processing_mode);
-                handle_request(request, &mut response);
-                yield response;
+            loop {
+                // None if the stream idled for idle_timeout
+                let next_message = async {
+                    match idle_timeout {
+                        Some(idle_timeout) => tokio::time::timeout(idle_timeout, stream.message()).await.ok(),
+                        None => Some(stream.message().await),
+                    }
+                };
+                let message = match shutdown.as_mut().filter(|_| !in_transaction) {
+                    Some(shutdown) => {
+                        let shutdown = Box::pin(shutdown_requested(shutdown));
+                        match future::select(Box::pin(next_message), shutdown).await {
+                            Either::Left((message, _)) => message,
+                            Either::Right(_) => {
+                                debug!("Closing stream for shutdown");
+                                break;
+                            }
+                        }
+                    }
+                    None => next_message.await,
+                };
+                let Some(message) = message else {
+                    debug!("Closing stream idle for {:?}", idle_timeout);
+                    break;
+                };
+                let Some(request) = message? else {
+                    break;
+                };
+                match request.request {
+                    Some(Request::RequestHeaders(_)) => in_transaction = true,
+                    Some(ref message) if ends_transaction(message) => in_transaction = false,
+                    _ => {}
+                }
+                let received_at = Instant::now();
+                let holds_response = hold_chunk_responses && is_partial_body(&request);
+                let kind = request.request.as_ref().map(MessageKind::of);
+                check_message_size(max_message_size, &request)?;
+                interceptors.on_request(&mut context, &request)?;
+                if let Some(validator) = validator.as_mut() {
+                    validator.check_request(&request)?;
+                }
+                let body_size = request.request.as_ref().and_then(|message| body_sizes.record(message));
+                if let Some(ref message) = request.request {
+                    body_chunks.record(message, &processing_mode);
+                    encodings.record(message);
+                }
+                let loaded = behavior.load();
+                let behavior = script.step(&loaded, &request)?;
+                if let Some(Request::RequestHeaders(_)) = request.request {
+                    if let Some(ref kill) = behavior.kill_streams {
+                        if kill.is_due(transactions, opened_at.elapsed()) {
+                            debug!("Killing stream after {} transactions", transactions);
+                            match kill.status() {
+                                Some(status) => Err::<(), Status>(status)?,
+                                None => break,
+                            }
+                        }
+                    }
+                    transactions += 1;
+                }
+                if let Some(ref config) = behavior.faults {
+                    // The interceptor chain counted the current message
+                    let fault = faults.draw(config, context.requests - 1);
+                    if let Some(delay) = fault.delay {
+                        tokio::time::sleep(delay).await;
+                    }
+                    if let Some(error) = fault.error {
+                        Err::<(), Status>(error)?;
+                    }
+                }
+                if let (Some(limit), Some(size)) = (&behavior.max_body_size, body_size) {
+                    if let Some(immediate_response) = limit.check(size)? {
+                        in_transaction = false;
+                        if !request.async_mode {
+                            let mut response = ExtProcService::init_response(&processing_mode);
+                            response.response = Some(Response::ImmediateResponse(immediate_response));
+                            interceptors.on_response(&mut context, &mut response)?;
+                            if report_processing_time {
+                                add_processing_time(&mut response, received_at.elapsed());
+                            }
+                            check_message_size(max_message_size, &response)?;
+                            for held in held_responses.drain(..) {
+                                yield held;
+                            }
+                            yield response;
+                        }
+                        continue;
+                    }
+                }
+                if let Some(mut response) = ExtProcService::process_request(&processing_mode, &behavior, request) {
+                    if let (Some(latency), Some(kind)) = (&behavior.latency, kind) {
+                        if let Some(distribution) = latency.delay(kind) {
+                            tokio::time::sleep(faults.draw_delay(distribution)).await;
+                        }
+                    }
+                    if let Some(Response::ImmediateResponse(_)) = response.response {
+                        in_transaction = false;
+                    }
+                    body::replace_body(&behavior, &encodings, &body_chunks, &mut response);
+                    interceptors.on_response(&mut context, &mut response)?;
+                    if report_processing_time {
+                        add_processing_time(&mut response, received_at.elapsed());
+                    }
+                    check_message_size(max_message_size, &response)?;
+                    if holds_response {
+                        held_responses.push(response);
+                        continue;
+                    }
+                    for held in held_responses.drain(..) {
+                        yield held;
+                    }
+                    yield response;
+                }
             }
         };
         Ok(TResponse::new(Box::pin(output)))
@@ -47,6 +322,23 @@ impl ExternalProcessor for ExtProcService {
 }
 
 impl ExtProcService {
+    /// Builds the response for a single request, or None if the request was sent in
+    /// observability mode (`async_mode`) and must not be responded to
+    fn process_request(
+        processing_mode: &ProcessingMode,
+        behavior: &BehaviorConfig,
+        request: ProcessingRequest,
+    ) -> Option<ProcessingResponse> {
+        if request.async_mode {
+            return None;
+        }
+        let mut response = ExtProcService::init_response(processing_mode);
+        let kind = request.request.as_ref().map(MessageKind::of);
+        handle_request(request, behavior, &mut response);
+        add_dynamic_metadata(behavior, kind, &mut response);
+        Some(response)
+    }
+
     fn init_response(processing_mode: &ProcessingMode) -> ProcessingResponse {
         ProcessingResponse{
             dynamic_metadata: None,
@@ -56,48 +348,580 @@ impl ExtProcService {
     }
 }
 
-fn handle_request(request: ProcessingRequest, response: &mut ProcessingResponse) {
+/// Resolves once `shutdown` is true, never if its sender is dropped before
+pub async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            future::pending::<()>().await;
+        }
+    }
+}
+
+/// Whether `message` is the last Envoy sends during a transaction: the end of the response
+fn ends_transaction(message: &Request) -> bool {
+    match message {
+        Request::ResponseHeaders(headers) => headers.end_of_stream,
+        Request::ResponseBody(body) => body.end_of_stream,
+        Request::ResponseTrailers(_) => true,
+        _ => false,
+    }
+}
+
+/// Fails messages larger than `max` bytes, encoded
+fn check_message_size<M: Message>(max: Option<usize>, message: &M) -> Result<(), Status> {
+    match max {
+        Some(max) if message.encoded_len() > max => Err(Status::resource_exhausted(format!(
+            "message of {} bytes exceeds the {} bytes limit",
+            message.encoded_len(),
+            max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `request` is a body chunk that doesn't end its body
+fn is_partial_body(request: &ProcessingRequest) -> bool {
     match request.request {
-        Some(Request::RequestHeaders(_)) => {
+        Some(Request::RequestBody(ref body) | Request::ResponseBody(ref body)) => {
+            !body.end_of_stream
+        }
+        _ => false,
+    }
+}
+
+/// Body bytes received for the current request and response of a stream
+#[derive(Default)]
+struct BodySizes {
+    request: usize,
+    response: usize,
+}
+
+impl BodySizes {
+    /// Adds a body chunk to its message's size and returns that size. Headers start a new message.
+    fn record(&mut self, request: &Request) -> Option<usize> {
+        match request {
+            // Request headers start a new transaction on reused streams
+            Request::RequestHeaders(_) => {
+                *self = BodySizes::default();
+                None
+            }
+            Request::ResponseHeaders(_) => {
+                self.response = 0;
+                None
+            }
+            Request::RequestBody(body) => {
+                self.request += body.body.len();
+                Some(self.request)
+            }
+            Request::ResponseBody(body) => {
+                self.response += body.body.len();
+                Some(self.response)
+            }
+            Request::RequestTrailers(_) | Request::ResponseTrailers(_) => None,
+        }
+    }
+}
+
+fn handle_request(
+    request: ProcessingRequest,
+    behavior: &BehaviorConfig,
+    response: &mut ProcessingResponse,
+) {
+    let Some(request) = request.request else {
+        response.response = Some(Response::ImmediateResponse(ImmediateResponse {
+            status: None,
+            headers: None,
+            body: String::default(),
+            grpc_status: Some(GrpcStatus {
+                status: Code::InvalidArgument as u32,
+            }),
+            details: "ProcessingRequest without a request message".to_string(),
+        }));
+        return;
+    };
+
+    if let Some(ref immediate_response) = behavior.immediate_response {
+        if immediate_response.on == MessageKind::of(&request) {
+            response.response = Some(Response::ImmediateResponse(
+                immediate_response.to_immediate_response(&request),
+            ));
+            return;
+        }
+    }
+
+    match request {
+        Request::RequestHeaders(headers) => {
+            let mutation = behavior
+                .request_headers
+                .to_mutation(headers.headers.as_ref());
+            let mut common = mutation_response(mutation);
+            add_trailers(&mut common, &behavior.add_request_trailers);
+            echo_attributes(behavior, &headers, &mut common, response);
             response.response = Some(Response::RequestHeaders(HeadersResponse {
-                response: Some(empty_response()),
+                response: Some(common),
             }));
         }
-        Some(Request::ResponseHeaders(_)) => {
+        Request::ResponseHeaders(headers) => {
+            let mutation = behavior
+                .response_headers
+                .to_mutation(headers.headers.as_ref());
+            let mut common = mutation_response(mutation);
+            add_trailers(&mut common, &behavior.add_response_trailers);
+            echo_attributes(behavior, &headers, &mut common, response);
             response.response = Some(Response::ResponseHeaders(HeadersResponse {
-                response: Some(empty_response()),
+                response: Some(common),
             }));
         }
-        Some(Request::RequestBody(_)) => {
+        Request::RequestBody(_) => {
             response.response = Some(Response::RequestBody(BodyResponse {
                 response: Some(empty_response()),
             }));
         }
-        Some(Request::ResponseBody(_)) => {
+        Request::ResponseBody(_) => {
             response.response = Some(Response::ResponseBody(BodyResponse {
                 response: Some(empty_response()),
             }));
         }
-        _ => {
-            response.response = Some(Response::ImmediateResponse(ImmediateResponse {
-                status: None,
-                headers: None,
-                body: String::default(),
-                grpc_status: Some(GrpcStatus {
-                    status: Code::InvalidArgument as u32,
-                }),
-                details: String::default(),
-            }))
+        Request::RequestTrailers(trailers) => {
+            response.response = Some(Response::RequestTrailers(TrailersResponse {
+                header_mutation: behavior
+                    .request_trailers
+                    .to_mutation(trailers.trailers.as_ref()),
+            }));
+        }
+        Request::ResponseTrailers(trailers) => {
+            response.response = Some(Response::ResponseTrailers(TrailersResponse {
+                header_mutation: behavior
+                    .response_trailers
+                    .to_mutation(trailers.trailers.as_ref()),
+            }));
         }
     }
 }
 
 fn empty_response() -> CommonResponse {
+    mutation_response(None)
+}
+
+/// Reflects the message's attributes as headers or dynamic metadata, if configured
+fn echo_attributes(
+    behavior: &BehaviorConfig,
+    headers: &HttpHeaders,
+    common: &mut CommonResponse,
+    response: &mut ProcessingResponse,
+) {
+    let Some(ref echo) = behavior.echo_attributes else {
+        return;
+    };
+    let attributes = echo.select(&headers.attributes);
+    if attributes.is_empty() {
+        return;
+    }
+    match echo.target {
+        EchoTarget::Headers => {
+            let mutation = common.header_mutation.get_or_insert_with(Default::default);
+            mutation
+                .set_headers
+                .extend(attributes.iter().map(|(name, value)| HeaderValueOption {
+                    header: Some(HeaderValue {
+                        key: attribute_echo_header(name),
+                        value: value_to_string(value),
+                    }),
+                    ..Default::default()
+                }));
+        }
+        EchoTarget::DynamicMetadata => {
+            let metadata = response.dynamic_metadata.get_or_insert_with(Default::default);
+            metadata.fields.extend(attributes);
+        }
+    }
+}
+
+/// Attaches the configured dynamic_metadata to the response to a `kind` message, next to echoed
+/// attributes
+fn add_dynamic_metadata(
+    behavior: &BehaviorConfig,
+    kind: Option<MessageKind>,
+    response: &mut ProcessingResponse,
+) {
+    let metadata = match (kind, &behavior.dynamic_metadata) {
+        (Some(kind), Some(config)) => config.for_message(kind),
+        _ => None,
+    };
+    if let Some(metadata) = metadata {
+        let merged = response.dynamic_metadata.get_or_insert_with(Default::default);
+        merged.fields.extend(metadata.fields);
+    }
+}
+
+/// Reports `elapsed` in a header mutation of the response
+fn add_processing_time(response: &mut ProcessingResponse, elapsed: Duration) {
+    let mutation = match response.response {
+        Some(Response::RequestHeaders(ref mut headers))
+        | Some(Response::ResponseHeaders(ref mut headers)) => {
+            let common = headers.response.get_or_insert_with(empty_response);
+            common.header_mutation.get_or_insert_with(Default::default)
+        }
+        Some(Response::RequestBody(ref mut body)) | Some(Response::ResponseBody(ref mut body)) => {
+            let common = body.response.get_or_insert_with(empty_response);
+            common.header_mutation.get_or_insert_with(Default::default)
+        }
+        Some(Response::RequestTrailers(ref mut trailers))
+        | Some(Response::ResponseTrailers(ref mut trailers)) => {
+            trailers.header_mutation.get_or_insert_with(Default::default)
+        }
+        Some(Response::ImmediateResponse(ref mut immediate_response)) => {
+            immediate_response.headers.get_or_insert_with(Default::default)
+        }
+        None => return,
+    };
+    mutation.set_headers.push(HeaderValueOption {
+        header: Some(HeaderValue {
+            key: PROCESSING_TIME_HEADER.to_string(),
+            value: elapsed.as_micros().to_string(),
+        }),
+        ..Default::default()
+    });
+}
+
+/// Adds trailers to a headers response, which requires CONTINUE_AND_REPLACE
+fn add_trailers(common: &mut CommonResponse, trailers: &[(String, String)]) {
+    if trailers.is_empty() {
+        return;
+    }
+    common.status = ResponseStatus::ContinueAndReplace as i32;
+    common.trailers = Some(HeaderMap {
+        headers: trailers
+            .iter()
+            .map(|(key, value)| HeaderValue {
+                key: key.to_lowercase(),
+                value: value.clone(),
+            })
+            .collect(),
+    });
+}
+
+fn mutation_response(header_mutation: Option<HeaderMutation>) -> CommonResponse {
     CommonResponse {
         status: ResponseStatus::Continue as i32,
-        header_mutation: None,
+        header_mutation,
         body_mutation: None,
         trailers: None,
         clear_route_cache: false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::envoy::service::ext_proc::v3::{HttpBody, HttpTrailers};
+    use behavior::{
+        AttributeEchoConfig, DynamicMetadataConfig, HeaderMutationConfig, HeaderOptionConfig,
+        ImmediateResponseConfig,
+    };
+    use crate::dummy::{protobuf::struct_from_json, EXT_PROC_ATTRIBUTE_NAMESPACE};
+
+    fn streamed_mode() -> ProcessingMode {
+        let mut processing_mode = ProcessingMode::default();
+        processing_mode.set_request_header_mode(HeaderSendMode::Send);
+        processing_mode.set_request_body_mode(BodySendMode::Streamed);
+        processing_mode.set_response_header_mode(HeaderSendMode::Skip);
+        processing_mode
+    }
+
+    fn request(async_mode: bool, request: Request) -> ProcessingRequest {
+        ProcessingRequest {
+            async_mode,
+            request: Some(request),
+        }
+    }
+
+    #[test]
+    fn responds_with_mode_override() {
+        let processing_mode = streamed_mode();
+        let response = ExtProcService::process_request(
+            &processing_mode,
+            &BehaviorConfig::default(),
+            request(false, Request::RequestHeaders(HttpHeaders::default())),
+        )
+        .expect("synchronous requests must be responded to");
+
+        assert_eq!(response.mode_override, Some(processing_mode));
+        assert!(matches!(response.response, Some(Response::RequestHeaders(_))));
+    }
+
+    #[test]
+    fn observability_mode_is_not_responded_to() {
+        let processing_mode = streamed_mode();
+        let requests = [
+            Request::RequestHeaders(HttpHeaders::default()),
+            Request::RequestBody(HttpBody::default()),
+            Request::ResponseHeaders(HttpHeaders::default()),
+            Request::ResponseBody(HttpBody::default()),
+        ];
+        for observed in requests {
+            assert_eq!(
+                ExtProcService::process_request(
+                    &processing_mode,
+                    &BehaviorConfig::default(),
+                    request(true, observed)
+                ),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn observability_mode_does_not_depend_on_processing_mode() {
+        // Even phases the advertised mode skips are left unanswered rather than rejected
+        let response = ExtProcService::process_request(
+            &streamed_mode(),
+            &BehaviorConfig::default(),
+            request(true, Request::ResponseHeaders(HttpHeaders::default())),
+        );
+        assert_eq!(response, None);
+    }
+
+    #[test]
+    fn trailers_are_mutated() {
+        let behavior = BehaviorConfig {
+            response_trailers: HeaderMutationConfig {
+                set_headers: vec![HeaderOptionConfig::new("X-Checksum", "abc")],
+                remove_headers: vec![],
+            },
+            ..Default::default()
+        };
+
+        let response = ExtProcService::process_request(
+            &streamed_mode(),
+            &behavior,
+            request(false, Request::ResponseTrailers(HttpTrailers::default())),
+        );
+        let Some(Response::ResponseTrailers(trailers)) = response.and_then(|r| r.response) else {
+            panic!("expected a response trailers response");
+        };
+        let set_headers = trailers.header_mutation.unwrap().set_headers;
+        assert_eq!(set_headers.len(), 1);
+        assert_eq!(set_headers[0].header.as_ref().unwrap().key, "x-checksum");
+
+        let response = ExtProcService::process_request(
+            &streamed_mode(),
+            &behavior,
+            request(false, Request::RequestTrailers(HttpTrailers::default())),
+        );
+        assert!(matches!(
+            response.and_then(|r| r.response),
+            Some(Response::RequestTrailers(TrailersResponse {
+                header_mutation: None
+            }))
+        ));
+    }
+
+    #[test]
+    fn configured_message_is_denied() {
+        let behavior = BehaviorConfig {
+            immediate_response: Some(ImmediateResponseConfig {
+                on: MessageKind::RequestBody,
+                status: 413,
+                headers: Default::default(),
+                body: "too large".to_string(),
+                details: "denied by test".to_string(),
+                grpc_status: None,
+            }),
+            ..Default::default()
+        };
+
+        let headers_response = ExtProcService::process_request(
+            &streamed_mode(),
+            &behavior,
+            request(false, Request::RequestHeaders(HttpHeaders::default())),
+        );
+        assert!(matches!(
+            headers_response.and_then(|r| r.response),
+            Some(Response::RequestHeaders(_))
+        ));
+
+        let body_response = ExtProcService::process_request(
+            &streamed_mode(),
+            &behavior,
+            request(false, Request::RequestBody(HttpBody::default())),
+        );
+        let Some(Response::ImmediateResponse(immediate)) = body_response.and_then(|r| r.response) else {
+            panic!("expected an immediate response");
+        };
+        assert_eq!(immediate.status.unwrap().code, 413);
+        assert_eq!(immediate.details, "denied by test");
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let body = HttpBody {
+            body: vec![0; 64],
+            end_of_stream: true,
+        };
+        let message = request(false, Request::RequestBody(body));
+        assert!(check_message_size(None, &message).is_ok());
+        assert!(check_message_size(Some(message.encoded_len()), &message).is_ok());
+        let status = check_message_size(Some(32), &message).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn processing_time_is_reported_in_a_header() {
+        let mut response = ExtProcService::process_request(
+            &streamed_mode(),
+            &BehaviorConfig::default(),
+            request(false, Request::RequestBody(HttpBody::default())),
+        )
+        .unwrap();
+        add_processing_time(&mut response, Duration::from_micros(1500));
+        let Some(Response::RequestBody(body)) = response.response else {
+            panic!("expected a body response");
+        };
+        let mutation = body.response.unwrap().header_mutation.unwrap();
+        let header = mutation.set_headers[0].header.as_ref().unwrap();
+        assert_eq!(
+            (header.key.as_str(), header.value.as_str()),
+            (PROCESSING_TIME_HEADER, "1500")
+        );
+    }
+
+    #[test]
+    fn body_sizes_add_up_until_next_headers() {
+        let chunk = |size| HttpBody {
+            body: vec![0; size],
+            end_of_stream: false,
+        };
+        let mut sizes = BodySizes::default();
+        assert_eq!(sizes.record(&Request::RequestBody(chunk(3))), Some(3));
+        assert_eq!(sizes.record(&Request::RequestBody(chunk(4))), Some(7));
+        assert_eq!(sizes.record(&Request::ResponseBody(chunk(5))), Some(5));
+        assert_eq!(sizes.record(&Request::RequestHeaders(HttpHeaders::default())), None);
+        assert_eq!(sizes.record(&Request::RequestBody(chunk(1))), Some(1));
+        assert_eq!(sizes.record(&Request::ResponseBody(chunk(1))), Some(1));
+    }
+
+    #[test]
+    fn headers_are_removed() {
+        let behavior = BehaviorConfig {
+            request_headers: HeaderMutationConfig {
+                set_headers: vec![],
+                remove_headers: vec!["Cache-Control".to_string()],
+            },
+            ..Default::default()
+        };
+        let response = ExtProcService::process_request(
+            &streamed_mode(),
+            &behavior,
+            request(false, Request::RequestHeaders(HttpHeaders::default())),
+        );
+        let Some(Response::RequestHeaders(headers)) = response.and_then(|r| r.response) else {
+            panic!("expected a request headers response");
+        };
+        let mutation = headers.response.unwrap().header_mutation.unwrap();
+        assert_eq!(mutation.remove_headers, vec!["cache-control".to_string()]);
+    }
+
+    #[test]
+    fn trailers_are_added_with_continue_and_replace() {
+        let behavior = BehaviorConfig {
+            add_response_trailers: vec![("X-Trailer".to_string(), "added".to_string())],
+            ..Default::default()
+        };
+        let response = ExtProcService::process_request(
+            &streamed_mode(),
+            &behavior,
+            request(false, Request::ResponseHeaders(HttpHeaders::default())),
+        );
+        let Some(Response::ResponseHeaders(headers)) = response.and_then(|r| r.response) else {
+            panic!("expected a response headers response");
+        };
+        let common = headers.response.unwrap();
+        assert_eq!(common.status, ResponseStatus::ContinueAndReplace as i32);
+        assert_eq!(common.trailers.unwrap().headers[0].key, "x-trailer");
+    }
+
+    fn headers_with_attributes() -> HttpHeaders {
+        let serde_json::Value::Object(attributes) =
+            serde_json::json!({"request.path": "/login", "source.port": 8443})
+        else {
+            unreachable!()
+        };
+        HttpHeaders {
+            attributes: [(
+                EXT_PROC_ATTRIBUTE_NAMESPACE.to_string(),
+                struct_from_json(&attributes),
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn attributes_are_echoed_as_headers() {
+        let behavior = BehaviorConfig {
+            echo_attributes: Some(AttributeEchoConfig {
+                names: vec!["source.port".to_string()],
+                target: EchoTarget::Headers,
+            }),
+            ..Default::default()
+        };
+        let response = ExtProcService::process_request(
+            &streamed_mode(),
+            &behavior,
+            request(false, Request::RequestHeaders(headers_with_attributes())),
+        );
+        let Some(Response::RequestHeaders(headers)) = response.and_then(|r| r.response) else {
+            panic!("expected a request headers response");
+        };
+        let set_headers = headers.response.unwrap().header_mutation.unwrap().set_headers;
+        assert_eq!(set_headers.len(), 1);
+        let echoed = set_headers[0].header.as_ref().unwrap();
+        assert_eq!(echoed.key, "x-ext-proc-attribute-source.port");
+        assert_eq!(echoed.value, "8443");
+    }
+
+    #[test]
+    fn attributes_are_echoed_as_dynamic_metadata() {
+        let behavior = BehaviorConfig {
+            echo_attributes: Some(AttributeEchoConfig {
+                names: vec![],
+                target: EchoTarget::DynamicMetadata,
+            }),
+            ..Default::default()
+        };
+        let response = ExtProcService::process_request(
+            &streamed_mode(),
+            &behavior,
+            request(false, Request::RequestHeaders(headers_with_attributes())),
+        )
+        .unwrap();
+        let metadata = response.dynamic_metadata.unwrap();
+        assert_eq!(metadata.fields.len(), 2);
+        assert!(metadata.fields.contains_key("request.path"));
+    }
+
+    #[test]
+    fn dynamic_metadata_is_attached_to_the_configured_message() {
+        let behavior = BehaviorConfig {
+            dynamic_metadata: Some(DynamicMetadataConfig {
+                on: MessageKind::ResponseHeaders,
+                fields: serde_json::json!({"decision": "allow", "policy": {"id": 7}})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            }),
+            ..Default::default()
+        };
+        let respond = |request| {
+            ExtProcService::process_request(&streamed_mode(), &behavior, request).unwrap()
+        };
+        let response = respond(request(false, Request::RequestHeaders(HttpHeaders::default())));
+        assert_eq!(response.dynamic_metadata, None);
+        let response = respond(request(false, Request::ResponseHeaders(HttpHeaders::default())));
+        let metadata = response.dynamic_metadata.unwrap();
+        assert_eq!(metadata.fields.len(), 2);
+        assert!(metadata.fields.contains_key("policy"));
+    }
+}
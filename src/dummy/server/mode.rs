@@ -0,0 +1,124 @@
+use clap::ValueEnum;
+
+use crate::proto::envoy::extensions::filters::http::ext_proc::v3::{
+    processing_mode::{BodySendMode, HeaderSendMode},
+    ProcessingMode,
+};
+
+use super::server_processing_mode;
+
+/// Flags overriding parts of the processing mode run_server pushes to clients as mode_override
+/// (see [`server_processing_mode`])
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ProcessingModeArgs {
+    /// Ask clients to send request and response trailers
+    #[arg(long)]
+    pub send_trailers: bool,
+
+    #[arg(long, value_enum)]
+    pub request_header_mode: Option<HeaderMode>,
+
+    #[arg(long, value_enum)]
+    pub response_header_mode: Option<HeaderMode>,
+
+    #[arg(long, value_enum)]
+    pub request_body_mode: Option<BodyMode>,
+
+    #[arg(long, value_enum)]
+    pub response_body_mode: Option<BodyMode>,
+
+    /// Overrides --send-trailers for request trailers
+    #[arg(long, value_enum)]
+    pub request_trailer_mode: Option<HeaderMode>,
+
+    /// Overrides --send-trailers for response trailers
+    #[arg(long, value_enum)]
+    pub response_trailer_mode: Option<HeaderMode>,
+}
+
+impl ProcessingModeArgs {
+    pub fn processing_mode(&self) -> ProcessingMode {
+        let mut mode = server_processing_mode(self.send_trailers);
+        if let Some(header_mode) = self.request_header_mode {
+            mode.set_request_header_mode(header_mode.into());
+        }
+        if let Some(header_mode) = self.response_header_mode {
+            mode.set_response_header_mode(header_mode.into());
+        }
+        if let Some(body_mode) = self.request_body_mode {
+            mode.set_request_body_mode(body_mode.into());
+        }
+        if let Some(body_mode) = self.response_body_mode {
+            mode.set_response_body_mode(body_mode.into());
+        }
+        if let Some(trailer_mode) = self.request_trailer_mode {
+            mode.set_request_trailer_mode(trailer_mode.into());
+        }
+        if let Some(trailer_mode) = self.response_trailer_mode {
+            mode.set_response_trailer_mode(trailer_mode.into());
+        }
+        mode
+    }
+}
+
+/// [`HeaderSendMode`] as a command line value
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderMode {
+    Default,
+    Send,
+    Skip,
+}
+
+impl From<HeaderMode> for HeaderSendMode {
+    fn from(mode: HeaderMode) -> Self {
+        match mode {
+            HeaderMode::Default => HeaderSendMode::Default,
+            HeaderMode::Send => HeaderSendMode::Send,
+            HeaderMode::Skip => HeaderSendMode::Skip,
+        }
+    }
+}
+
+/// [`BodySendMode`] as a command line value
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyMode {
+    None,
+    Streamed,
+    Buffered,
+    BufferedPartial,
+}
+
+impl From<BodyMode> for BodySendMode {
+    fn from(mode: BodyMode) -> Self {
+        match mode {
+            BodyMode::None => BodySendMode::None,
+            BodyMode::Streamed => BodySendMode::Streamed,
+            BodyMode::Buffered => BodySendMode::Buffered,
+            BodyMode::BufferedPartial => BodySendMode::BufferedPartial,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_server_defaults() {
+        let args = ProcessingModeArgs {
+            send_trailers: true,
+            request_body_mode: Some(BodyMode::Streamed),
+            response_trailer_mode: Some(HeaderMode::Skip),
+            ..Default::default()
+        };
+        let mode = args.processing_mode();
+        assert_eq!(mode.request_body_mode(), BodySendMode::Streamed);
+        assert_eq!(mode.response_body_mode(), BodySendMode::Buffered);
+        assert_eq!(mode.request_trailer_mode(), HeaderSendMode::Send);
+        assert_eq!(mode.response_trailer_mode(), HeaderSendMode::Skip);
+        assert_eq!(
+            ProcessingModeArgs::default().processing_mode(),
+            server_processing_mode(false)
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Recordings of the messages of every stream, to compare what a real Envoy sends with what the
+//! dummy client does (see [`crate::dummy::recording`] for the format)
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use log::warn;
+use prost::Message;
+use tonic::Status;
+
+use super::interceptor::{Interceptor, StreamContext};
+use crate::{
+    dummy::recording::RecordedMessage,
+    proto::envoy::service::ext_proc::v3::{ProcessingRequest, ProcessingResponse},
+};
+
+/// Records every stream in `stream-<id>.pb` files of a directory. Streams whose file can't be
+/// written are served anyway, unrecorded
+pub struct RecordingInterceptor {
+    dir: PathBuf,
+}
+
+impl RecordingInterceptor {
+    /// Records in `dir`, created if missing
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<RecordingInterceptor> {
+        fs::create_dir_all(&dir)?;
+        Ok(RecordingInterceptor {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn record(&self, context: &mut StreamContext, message: RecordedMessage) {
+        if context.extensions.get::<Recording>().is_none() {
+            let path = self.dir.join(format!("stream-{}.pb", context.stream_id));
+            let file = File::create(&path).map_err(|e| {
+                warn!("Could not record stream in {}: {}", path.display(), e);
+            });
+            context.extensions.insert(Recording {
+                file: file.ok(),
+                opened_at: Instant::now(),
+            });
+        }
+        let recording = context.extensions.get_mut::<Recording>().unwrap();
+        let Some(ref mut file) = recording.file else {
+            return;
+        };
+        let message = RecordedMessage {
+            elapsed_us: recording.opened_at.elapsed().as_micros() as u64,
+            ..message
+        };
+        if let Err(e) = file.write_all(&message.encode_length_delimited_to_vec()) {
+            warn!("Stopped recording stream {}: {}", context.stream_id, e);
+            recording.file = None;
+        }
+    }
+}
+
+/// Kept in the extensions of recorded streams
+struct Recording {
+    /// None once the recording failed
+    file: Option<File>,
+    opened_at: Instant,
+}
+
+impl Interceptor for RecordingInterceptor {
+    fn on_request(
+        &self,
+        context: &mut StreamContext,
+        request: &ProcessingRequest,
+    ) -> Result<(), Status> {
+        let message = RecordedMessage {
+            request: Some(request.clone()),
+            ..Default::default()
+        };
+        self.record(context, message);
+        Ok(())
+    }
+
+    fn on_response(
+        &self,
+        context: &mut StreamContext,
+        response: &mut ProcessingResponse,
+    ) -> Result<(), Status> {
+        let message = RecordedMessage {
+            response: Some(response.clone()),
+            ..Default::default()
+        };
+        self.record(context, message);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{metadata::MetadataMap, Extensions};
+
+    use super::*;
+    use crate::{
+        dummy::recording::read_recording,
+        proto::envoy::service::ext_proc::v3::processing_request::Request,
+    };
+
+    #[test]
+    fn streams_are_recorded_in_order() {
+        let dir = std::env::temp_dir().join(format!("ext_proc_recording_{}", std::process::id()));
+        let interceptor = RecordingInterceptor::new(&dir).unwrap();
+        let mut context = StreamContext {
+            stream_id: 7,
+            requests: 0,
+            metadata: MetadataMap::new(),
+            extensions: Extensions::default(),
+        };
+        let request = ProcessingRequest {
+            async_mode: false,
+            request: Some(Request::RequestHeaders(Default::default())),
+        };
+        interceptor.on_request(&mut context, &request).unwrap();
+        interceptor
+            .on_response(&mut context, &mut ProcessingResponse::default())
+            .unwrap();
+        drop(context);
+        let messages = read_recording(dir.join("stream-7.pb")).unwrap();
+        _ = fs::remove_dir_all(&dir);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].request, Some(request));
+        assert!(messages[0].response.is_none());
+        assert_eq!(messages[1].response, Some(ProcessingResponse::default()));
+        assert!(messages[1].elapsed_us >= messages[0].elapsed_us);
+    }
+}
@@ -0,0 +1,38 @@
+//! gRPC server reflection of the ext_proc service, so grpcurl and grpcui can call the dummy
+//! server without being given the Envoy protos
+
+use tonic_reflection::server::{Builder, Error, ServerReflection, ServerReflectionServer};
+
+/// The encoded `FileDescriptorSet` of external_processor.proto and its imports, emitted by
+/// build.rs
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/ext_proc_descriptor.bin"));
+
+/// A reflection service describing ExternalProcessor, to add next to it
+pub fn reflection_service() -> Result<ServerReflectionServer<impl ServerReflection>, Error> {
+    Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+
+    #[test]
+    fn the_descriptor_set_describes_ext_proc() {
+        let descriptors = prost_types::FileDescriptorSet::decode(FILE_DESCRIPTOR_SET).unwrap();
+        let file = descriptors
+            .file
+            .iter()
+            .find(|file| file.package() == "envoy.service.ext_proc.v3")
+            .unwrap();
+        assert!(file
+            .service
+            .iter()
+            .any(|service| service.name() == "ExternalProcessor"));
+        assert!(reflection_service().is_ok());
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tonic::{Code, Status};
+
+use super::behavior::{deserialize_grpc_code, header_value, serialize_grpc_code, BehaviorConfig};
+use crate::proto::envoy::service::ext_proc::v3::{processing_request::Request, ProcessingRequest};
+
+/// A state machine switching the behavior of each stream as it progresses, for reproducible
+/// interaction patterns like "accept 10 transactions then start failing". Every stream runs its
+/// own copy, starting in the `initial` state
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct ScriptConfig {
+    /// State streams start in
+    pub initial: String,
+    /// States by name
+    pub states: BTreeMap<String, ScriptState>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct ScriptState {
+    /// Behavior of the stream in this state, instead of the rest of the behavior config. Its own
+    /// script is ignored
+    #[serde(default)]
+    pub behavior: Option<BehaviorConfig>,
+    /// End the stream with this error on the first message handled in this state
+    #[serde(default)]
+    pub error: Option<ScriptErrorConfig>,
+    /// Transitions out of this state, the first one whose conditions all hold is taken
+    #[serde(default)]
+    pub transitions: Vec<TransitionConfig>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default, Clone)]
+pub struct ScriptErrorConfig {
+    /// gRPC status name (e.g. "INTERNAL") the stream ends with. UNAVAILABLE when unset.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_grpc_code",
+        serialize_with = "serialize_grpc_code"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub status: Option<Code>,
+    /// Message of the status, naming the state when empty
+    #[serde(default)]
+    pub message: String,
+}
+
+/// A transition, checked as each message arrives so the message is handled in the state it leads
+/// to. Transitions without conditions are taken right away
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct TransitionConfig {
+    /// State to switch to
+    pub to: String,
+    /// Once this many messages were handled in the current state
+    #[serde(default)]
+    pub after_messages: Option<u64>,
+    /// On the request headers following this many transactions in the current state
+    #[serde(default)]
+    pub after_transactions: Option<u64>,
+    /// On a headers or trailers message carrying this header
+    #[serde(default)]
+    pub on_header: Option<HeaderMatchConfig>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+pub struct HeaderMatchConfig {
+    /// Header name, case insensitive
+    pub name: String,
+    /// Value the header must have, any value matches when unset
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+impl ScriptConfig {
+    /// Checks that the initial state and the targets of transitions exist
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.states.contains_key(&self.initial) {
+            return Err(format!("unknown initial state '{}'", self.initial));
+        }
+        for (name, state) in &self.states {
+            if let Some(transition) = state
+                .transitions
+                .iter()
+                .find(|transition| !self.states.contains_key(&transition.to))
+            {
+                return Err(format!(
+                    "state '{}' transitions to unknown state '{}'",
+                    name, transition.to
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TransitionConfig {
+    fn holds(&self, runner: &ScriptRunner, message: Option<&Request>) -> bool {
+        let is_request_headers = matches!(message, Some(Request::RequestHeaders(_)));
+        let after_messages = self.after_messages.map_or(true, |n| runner.messages >= n);
+        let after_transactions = self
+            .after_transactions
+            .map_or(true, |n| is_request_headers && runner.transactions >= n);
+        let on_header = self.on_header.as_ref().map_or(true, |header| {
+            let headers = match message {
+                Some(Request::RequestHeaders(headers))
+                | Some(Request::ResponseHeaders(headers)) => headers.headers.as_ref(),
+                Some(Request::RequestTrailers(trailers))
+                | Some(Request::ResponseTrailers(trailers)) => trailers.trailers.as_ref(),
+                _ => None,
+            };
+            match header_value(headers, &header.name) {
+                Some(value) => header.value.as_ref().map_or(true, |expected| *expected == value),
+                None => false,
+            }
+        });
+        after_messages && after_transactions && on_header
+    }
+}
+
+/// Where a stream is in the script of its behavior
+#[derive(Debug, Default)]
+pub struct ScriptRunner {
+    /// Current state, None before the first message
+    state: Option<String>,
+    /// Messages handled in the current state
+    messages: u64,
+    /// Transactions started in the current state
+    transactions: u64,
+}
+
+impl ScriptRunner {
+    /// Advances the script with `request`, returning the behavior to handle it with, or the
+    /// error to end the stream with. Without a script, that's `base`. Streams whose state was
+    /// removed by a reload of the behavior start over from the initial state
+    pub fn step<'a>(
+        &mut self,
+        base: &'a BehaviorConfig,
+        request: &ProcessingRequest,
+    ) -> Result<&'a BehaviorConfig, Status> {
+        let Some(ref script) = base.script else {
+            return Ok(base);
+        };
+        let current = self
+            .state
+            .as_ref()
+            .filter(|state| script.states.contains_key(*state))
+            .cloned();
+        let mut name = match current {
+            Some(name) => name,
+            None => self.enter(&script.initial),
+        };
+        let Some(mut state) = script.states.get(&name) else {
+            return Ok(base);
+        };
+        let message = request.request.as_ref();
+        if let Some(transition) = state
+            .transitions
+            .iter()
+            .find(|transition| transition.holds(self, message))
+        {
+            if let Some(next) = script.states.get(&transition.to) {
+                name = self.enter(&transition.to);
+                state = next;
+            }
+        }
+        self.messages += 1;
+        if matches!(message, Some(Request::RequestHeaders(_))) {
+            self.transactions += 1;
+        }
+        if let Some(ref error) = state.error {
+            let message = match error.message.as_str() {
+                "" => format!("scripted error in state '{}'", name),
+                message => message.to_string(),
+            };
+            return Err(Status::new(error.status.unwrap_or(Code::Unavailable), message));
+        }
+        Ok(state.behavior.as_ref().unwrap_or(base))
+    }
+
+    fn enter(&mut self, state: &str) -> String {
+        self.state = Some(state.to_string());
+        self.messages = 0;
+        self.transactions = 0;
+        state.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::envoy::{
+        config::core::v3::{HeaderMap, HeaderValue},
+        service::ext_proc::v3::{HttpBody, HttpHeaders},
+    };
+
+    fn request_headers(headers: &[(&str, &str)]) -> ProcessingRequest {
+        ProcessingRequest {
+            async_mode: false,
+            request: Some(Request::RequestHeaders(HttpHeaders {
+                headers: Some(HeaderMap {
+                    headers: headers
+                        .iter()
+                        .map(|(key, value)| HeaderValue {
+                            key: key.to_string(),
+                            value: value.to_string(),
+                        })
+                        .collect(),
+                }),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn request_body() -> ProcessingRequest {
+        ProcessingRequest {
+            async_mode: false,
+            request: Some(Request::RequestBody(HttpBody::default())),
+        }
+    }
+
+    fn script(json: serde_json::Value) -> BehaviorConfig {
+        let behavior: BehaviorConfig = serde_json::from_value(json).unwrap();
+        behavior.script.as_ref().unwrap().validate().unwrap();
+        behavior
+    }
+
+    #[test]
+    fn fails_after_a_number_of_transactions() {
+        let behavior = script(serde_json::json!({
+            "script": {
+                "initial": "healthy",
+                "states": {
+                    "healthy": {"transitions": [{"to": "failing", "after_transactions": 2}]},
+                    "failing": {"error": {"status": "INTERNAL"}}
+                }
+            }
+        }));
+        let mut runner = ScriptRunner::default();
+        for _ in 0..2 {
+            assert!(runner.step(&behavior, &request_headers(&[])).is_ok());
+            assert!(runner.step(&behavior, &request_body()).is_ok());
+        }
+        let status = runner.step(&behavior, &request_headers(&[])).unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        assert_eq!(status.message(), "scripted error in state 'failing'");
+    }
+
+    #[test]
+    fn headers_switch_behavior() {
+        let behavior = script(serde_json::json!({
+            "script": {
+                "initial": "open",
+                "states": {
+                    "open": {"transitions": [
+                        {"to": "denying", "on_header": {"name": "X-Mode", "value": "deny"}}
+                    ]},
+                    "denying": {
+                        "behavior": {"immediate_response": {"status": 429}},
+                        "transitions": [{"to": "open", "after_messages": 1}]
+                    }
+                }
+            }
+        }));
+        let mut runner = ScriptRunner::default();
+        let step = |runner: &mut ScriptRunner, headers: &[(&str, &str)]| {
+            let behavior = runner.step(&behavior, &request_headers(headers)).unwrap();
+            behavior.immediate_response.as_ref().map(|deny| deny.status)
+        };
+        assert_eq!(step(&mut runner, &[("x-mode", "allow")]), None);
+        assert_eq!(step(&mut runner, &[("x-mode", "deny")]), Some(429));
+        assert_eq!(step(&mut runner, &[]), None);
+    }
+
+    #[test]
+    fn unknown_states_are_rejected() {
+        let config: ScriptConfig = serde_json::from_value(serde_json::json!({
+            "initial": "start",
+            "states": {"start": {"transitions": [{"to": "missing"}]}}
+        }))
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+}
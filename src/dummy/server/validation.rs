@@ -0,0 +1,338 @@
+//! Validation of the order of incoming messages against the processing mode the server
+//! advertises, to vet client implementations
+
+use std::fmt::{Display, Formatter};
+
+use tonic::Status;
+
+use super::behavior::MessageKind;
+use crate::proto::envoy::{
+    extensions::filters::http::ext_proc::v3::{
+        processing_mode::{BodySendMode, HeaderSendMode},
+        ProcessingMode,
+    },
+    service::ext_proc::v3::{processing_request::Request, ProcessingRequest},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderViolation {
+    /// The processing mode doesn't send this kind of message
+    NotRequested(MessageKind),
+    /// A message of this kind was already received in the transaction
+    Duplicate(MessageKind),
+    /// A body or trailers message before the headers of its direction
+    BeforeHeaders(MessageKind),
+    /// A body after the trailers of its direction
+    BodyAfterTrailers(MessageKind),
+    /// A message after its direction ended with end_of_stream
+    AfterEndOfStream(MessageKind),
+    /// A response message before the request headers
+    ResponseBeforeRequest(MessageKind),
+    /// A request body or trailers message after the response started
+    RequestAfterResponse(MessageKind),
+}
+
+impl Display for OrderViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderViolation::NotRequested(kind) => {
+                write!(f, "{} sent although the processing mode skips it", kind.as_str())
+            }
+            OrderViolation::Duplicate(kind) => {
+                write!(f, "{} sent more than once in a transaction", kind.as_str())
+            }
+            OrderViolation::BeforeHeaders(kind) => {
+                write!(f, "{} sent before the headers", kind.as_str())
+            }
+            OrderViolation::BodyAfterTrailers(kind) => {
+                write!(f, "{} sent after the trailers", kind.as_str())
+            }
+            OrderViolation::AfterEndOfStream(kind) => {
+                write!(f, "{} sent after end_of_stream", kind.as_str())
+            }
+            OrderViolation::ResponseBeforeRequest(kind) => {
+                write!(f, "{} sent before the request headers", kind.as_str())
+            }
+            OrderViolation::RequestAfterResponse(kind) => {
+                write!(f, "{} sent after the response started", kind.as_str())
+            }
+        }
+    }
+}
+
+impl From<OrderViolation> for Status {
+    fn from(violation: OrderViolation) -> Self {
+        Status::failed_precondition(format!("Message order violation: {}", violation))
+    }
+}
+
+/// What was received in one direction (request or response) of the current transaction
+#[derive(Default)]
+struct Direction {
+    headers: bool,
+    body_chunks: usize,
+    trailers: bool,
+    ended: bool,
+}
+
+impl Direction {
+    fn started(&self) -> bool {
+        self.headers || self.body_chunks > 0 || self.trailers
+    }
+
+    fn check_headers(
+        &mut self,
+        mode: HeaderSendMode,
+        kind: MessageKind,
+        end_of_stream: bool,
+    ) -> Result<(), OrderViolation> {
+        if mode == HeaderSendMode::Skip {
+            return Err(OrderViolation::NotRequested(kind));
+        }
+        if self.started() {
+            return Err(OrderViolation::Duplicate(kind));
+        }
+        self.headers = true;
+        self.ended = end_of_stream;
+        Ok(())
+    }
+
+    fn check_body(
+        &mut self,
+        header_mode: HeaderSendMode,
+        mode: BodySendMode,
+        kind: MessageKind,
+        end_of_stream: bool,
+    ) -> Result<(), OrderViolation> {
+        if mode == BodySendMode::None {
+            return Err(OrderViolation::NotRequested(kind));
+        }
+        if header_mode != HeaderSendMode::Skip && !self.headers {
+            return Err(OrderViolation::BeforeHeaders(kind));
+        }
+        if self.trailers {
+            return Err(OrderViolation::BodyAfterTrailers(kind));
+        }
+        if self.ended {
+            return Err(OrderViolation::AfterEndOfStream(kind));
+        }
+        // Only streamed bodies, and partially buffered ones past the buffer limit, are sent in
+        // chunks
+        let chunked = matches!(mode, BodySendMode::Streamed | BodySendMode::BufferedPartial);
+        if !chunked && self.body_chunks > 0 {
+            return Err(OrderViolation::Duplicate(kind));
+        }
+        self.body_chunks += 1;
+        self.ended = end_of_stream;
+        Ok(())
+    }
+
+    fn check_trailers(
+        &mut self,
+        header_mode: HeaderSendMode,
+        mode: HeaderSendMode,
+        kind: MessageKind,
+    ) -> Result<(), OrderViolation> {
+        if mode != HeaderSendMode::Send {
+            return Err(OrderViolation::NotRequested(kind));
+        }
+        if self.trailers {
+            return Err(OrderViolation::Duplicate(kind));
+        }
+        if header_mode != HeaderSendMode::Skip && !self.headers {
+            return Err(OrderViolation::BeforeHeaders(kind));
+        }
+        if self.ended {
+            return Err(OrderViolation::AfterEndOfStream(kind));
+        }
+        self.trailers = true;
+        self.ended = true;
+        Ok(())
+    }
+}
+
+/// Tracks the messages of a stream's current transaction. Request headers start a new
+/// transaction once the previous one ended its request or started its response.
+pub struct OrderValidator {
+    mode: ProcessingMode,
+    request: Direction,
+    response: Direction,
+}
+
+impl OrderValidator {
+    pub fn new(mode: ProcessingMode) -> OrderValidator {
+        OrderValidator {
+            mode,
+            request: Direction::default(),
+            response: Direction::default(),
+        }
+    }
+
+    /// Checks a ProcessingRequest, which must carry a message, ending the stream with a
+    /// descriptive status if it is out of order
+    pub fn check_request(&mut self, request: &ProcessingRequest) -> Result<(), Status> {
+        match request.request {
+            Some(ref message) => self.check(message).map_err(Status::from),
+            None => Err(Status::invalid_argument("ProcessingRequest without a request message")),
+        }
+    }
+
+    pub fn check(&mut self, request: &Request) -> Result<(), OrderViolation> {
+        let kind = MessageKind::of(request);
+        let mode = &self.mode;
+        let is_response = !matches!(
+            request,
+            Request::RequestHeaders(_) | Request::RequestBody(_) | Request::RequestTrailers(_)
+        );
+        if is_response
+            && mode.request_header_mode() != HeaderSendMode::Skip
+            && !self.request.headers
+        {
+            return Err(OrderViolation::ResponseBeforeRequest(kind));
+        }
+        // Request headers after the response started begin the next transaction
+        let continues_request = !is_response && !matches!(request, Request::RequestHeaders(_));
+        if continues_request && self.response.started() {
+            return Err(OrderViolation::RequestAfterResponse(kind));
+        }
+
+        match request {
+            Request::RequestHeaders(headers) => {
+                if self.request.ended || self.response.started() {
+                    self.request = Direction::default();
+                    self.response = Direction::default();
+                }
+                self.request
+                    .check_headers(mode.request_header_mode(), kind, headers.end_of_stream)
+            }
+            Request::ResponseHeaders(headers) => self.response.check_headers(
+                mode.response_header_mode(),
+                kind,
+                headers.end_of_stream,
+            ),
+            Request::RequestBody(body) => self.request.check_body(
+                mode.request_header_mode(),
+                mode.request_body_mode(),
+                kind,
+                body.end_of_stream,
+            ),
+            Request::ResponseBody(body) => self.response.check_body(
+                mode.response_header_mode(),
+                mode.response_body_mode(),
+                kind,
+                body.end_of_stream,
+            ),
+            Request::RequestTrailers(_) => self.request.check_trailers(
+                mode.request_header_mode(),
+                mode.request_trailer_mode(),
+                kind,
+            ),
+            Request::ResponseTrailers(_) => self.response.check_trailers(
+                mode.response_header_mode(),
+                mode.response_trailer_mode(),
+                kind,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dummy::server::server_processing_mode;
+    use crate::proto::envoy::service::ext_proc::v3::{HttpBody, HttpHeaders, HttpTrailers};
+
+    fn headers(request: bool, end_of_stream: bool) -> Request {
+        let headers = HttpHeaders {
+            end_of_stream,
+            ..Default::default()
+        };
+        if request {
+            Request::RequestHeaders(headers)
+        } else {
+            Request::ResponseHeaders(headers)
+        }
+    }
+
+    fn body(end_of_stream: bool) -> Request {
+        Request::RequestBody(HttpBody {
+            body: b"body".to_vec(),
+            end_of_stream,
+        })
+    }
+
+    #[test]
+    fn accepts_transactions_in_order() {
+        let mut validator = OrderValidator::new(server_processing_mode(true));
+        for _ in 0..2 {
+            validator.check(&headers(true, false)).unwrap();
+            validator.check(&body(false)).unwrap();
+            validator
+                .check(&Request::RequestTrailers(HttpTrailers::default()))
+                .unwrap();
+            validator.check(&headers(false, true)).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_body_before_headers() {
+        let mut validator = OrderValidator::new(server_processing_mode(false));
+        assert_eq!(
+            validator.check(&body(true)),
+            Err(OrderViolation::BeforeHeaders(MessageKind::RequestBody))
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_buffered_body() {
+        let mut validator = OrderValidator::new(server_processing_mode(false));
+        validator.check(&headers(true, false)).unwrap();
+        validator.check(&body(false)).unwrap();
+        assert_eq!(
+            validator.check(&body(true)),
+            Err(OrderViolation::Duplicate(MessageKind::RequestBody))
+        );
+    }
+
+    #[test]
+    fn rejects_body_after_end_of_stream() {
+        let mut validator = OrderValidator::new(server_processing_mode(false));
+        validator.check(&headers(true, true)).unwrap();
+        assert_eq!(
+            validator.check(&body(false)),
+            Err(OrderViolation::AfterEndOfStream(MessageKind::RequestBody))
+        );
+    }
+
+    #[test]
+    fn rejects_skipped_trailers_and_early_responses() {
+        let mut validator = OrderValidator::new(server_processing_mode(false));
+        assert_eq!(
+            validator.check(&headers(false, false)),
+            Err(OrderViolation::ResponseBeforeRequest(MessageKind::ResponseHeaders))
+        );
+        validator.check(&headers(true, false)).unwrap();
+        assert_eq!(
+            validator.check(&Request::RequestTrailers(HttpTrailers::default())),
+            Err(OrderViolation::NotRequested(MessageKind::RequestTrailers))
+        );
+        assert_eq!(
+            validator.check(&headers(true, false)),
+            Err(OrderViolation::Duplicate(MessageKind::RequestHeaders))
+        );
+    }
+
+    #[test]
+    fn rejects_request_messages_after_the_response_started() {
+        let mut validator = OrderValidator::new(server_processing_mode(false));
+        validator.check(&headers(true, false)).unwrap();
+        validator.check(&headers(false, false)).unwrap();
+        assert_eq!(
+            validator.check(&body(true)),
+            Err(OrderViolation::RequestAfterResponse(MessageKind::RequestBody))
+        );
+        let empty = ProcessingRequest::default();
+        let status = validator.check_request(&empty).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
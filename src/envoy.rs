@@ -0,0 +1,181 @@
+//! Envoy sidecar for integration tests, started in a container with an ext_proc filter pointing
+//! at a server running on the host, so tests can send HTTP requests through the real proxy path.
+//!
+//! Requires a running Docker daemon. The container uses host networking, so the External
+//! Processor server must listen on a host address reachable from it (e.g. `127.0.0.1`).
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use hyper::{client::HttpConnector, Body, Client, Request, Response};
+use testcontainers::{clients::Cli, core::WaitFor, Container, GenericImage, RunnableImage};
+
+use crate::filter_config::{indent, FilterConfig};
+
+use error::SidecarError;
+
+pub mod error {
+    use quick_error::quick_error;
+
+    quick_error! {
+        #[derive(Debug)]
+        pub enum SidecarError {
+            ConfigWriteError(err: std::io::Error) {
+                display("Could not write envoy config: {}", err)
+                from()
+            }
+            HttpError(err: hyper::Error) {
+                display("HTTP request through envoy failed: {}", err)
+                from()
+            }
+        }
+    }
+}
+
+const ENVOY_IMAGE: &str = "envoyproxy/envoy";
+const ENVOY_CONFIG_PATH: &str = "/etc/envoy/envoy.yaml";
+
+#[derive(Debug, Clone)]
+pub struct SidecarConfig {
+    /// Tag of the envoyproxy/envoy image
+    pub image_tag: String,
+    /// Port envoy listens to for HTTP requests
+    pub listener_port: u16,
+    /// The ext_proc filter and its server
+    pub filter: FilterConfig,
+    /// Status and body of the direct response returned for every request, in place of an upstream
+    pub response_status: u32,
+    pub response_body: String,
+}
+
+impl Default for SidecarConfig {
+    fn default() -> Self {
+        SidecarConfig {
+            image_tag: "v1.26-latest".to_string(),
+            listener_port: 10000,
+            filter: FilterConfig::default(),
+            response_status: 200,
+            response_body: "dummy".to_string(),
+        }
+    }
+}
+
+impl SidecarConfig {
+    /// Envoy bootstrap config with a single listener, routed to a direct response through the
+    /// ext_proc filter
+    pub fn to_yaml(&self) -> String {
+        format!(
+            r#"static_resources:
+  listeners:
+  - name: ingress
+    address:
+      socket_address: {{ address: 0.0.0.0, port_value: {listener_port} }}
+    filter_chains:
+    - filters:
+      - name: envoy.filters.network.http_connection_manager
+        typed_config:
+          "@type": type.googleapis.com/envoy.extensions.filters.network.http_connection_manager.v3.HttpConnectionManager
+          stat_prefix: ingress
+          route_config:
+            virtual_hosts:
+            - name: direct
+              domains: ["*"]
+              routes:
+              - match: {{ prefix: "/" }}
+                direct_response:
+                  status: {response_status}
+                  body: {{ inline_string: {response_body:?} }}
+          http_filters:
+{ext_proc_filter}          - name: envoy.filters.http.router
+            typed_config:
+              "@type": type.googleapis.com/envoy.extensions.filters.http.router.v3.Router
+  clusters:
+{ext_proc_cluster}"#,
+            listener_port = self.listener_port,
+            response_status = self.response_status,
+            response_body = self.response_body,
+            ext_proc_filter = indent(&self.filter.filter_yaml(), 10),
+            ext_proc_cluster = indent(&self.filter.cluster_yaml(), 2),
+        )
+    }
+}
+
+/// A running envoy container, stopped when dropped
+pub struct EnvoySidecar<'d> {
+    _container: Container<'d, GenericImage>,
+    config_path: PathBuf,
+    listener_port: u16,
+    client: Client<HttpConnector>,
+}
+
+impl<'d> EnvoySidecar<'d> {
+    /// Starts envoy and waits until it is ready to accept requests
+    pub fn start(docker: &'d Cli, config: &SidecarConfig) -> Result<EnvoySidecar<'d>, SidecarError> {
+        static STARTED: AtomicUsize = AtomicUsize::new(0);
+        let config_path = std::env::temp_dir().join(format!(
+            "ext_proc_envoy_{}_{}.yaml",
+            std::process::id(),
+            STARTED.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&config_path, config.to_yaml())?;
+
+        let image = GenericImage::new(ENVOY_IMAGE, &config.image_tag)
+            .with_volume(config_path.to_string_lossy(), ENVOY_CONFIG_PATH)
+            .with_wait_for(WaitFor::message_on_stderr("starting main dispatch loop"));
+        let container = docker.run(RunnableImage::from(image).with_network("host"));
+
+        Ok(EnvoySidecar {
+            _container: container,
+            config_path,
+            listener_port: config.listener_port,
+            client: Client::new(),
+        })
+    }
+
+    /// URL of a path on envoy's listener
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.listener_port, path)
+    }
+
+    /// Sends a request through envoy. Relative URIs are sent to envoy's listener
+    pub async fn request(&self, mut request: Request<Body>) -> Result<Response<Body>, SidecarError> {
+        if request.uri().authority().is_none() {
+            *request.uri_mut() = self.url(&request.uri().to_string()).parse().unwrap();
+        }
+        Ok(self.client.request(request).await?)
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Response<Body>, SidecarError> {
+        Ok(self.client.get(self.url(path).parse().unwrap()).await?)
+    }
+}
+
+impl Drop for EnvoySidecar<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.config_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_points_filter_at_server() {
+        let config = SidecarConfig {
+            filter: FilterConfig {
+                server_port: 50052,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let yaml = config.to_yaml();
+        assert!(yaml.contains("socket_address: { address: 127.0.0.1, port_value: 50052 }"));
+        assert!(yaml.contains("          - name: envoy.filters.http.ext_proc\n"));
+        assert!(yaml.contains("  - name: ext_proc\n"));
+        assert!(yaml.contains(r#"body: { inline_string: "dummy" }"#));
+    }
+}
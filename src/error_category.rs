@@ -0,0 +1,147 @@
+//! Classification of the errors of clients, servers and benchmarks, telling lost connections and
+//! timeouts, worth retrying, from protocol and application failures
+
+use std::{
+    fmt::{Display, Formatter},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::Serialize;
+use tonic::{Code, Status};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The connection or stream was lost
+    Transport,
+    /// A peer broke the ext_proc protocol (unexpected messages, limits, ordering)
+    Protocol,
+    /// A response didn't arrive in time
+    Timeout,
+    /// The transaction was processed, but not as expected (e.g. mutations that didn't apply)
+    Application,
+}
+
+impl ErrorCategory {
+    /// All categories, in the order they are reported
+    pub const ALL: [ErrorCategory; 4] = [
+        ErrorCategory::Transport,
+        ErrorCategory::Protocol,
+        ErrorCategory::Timeout,
+        ErrorCategory::Application,
+    ];
+
+    /// Whether the same transaction may succeed when retried
+    pub fn is_retryable(self) -> bool {
+        matches!(self, ErrorCategory::Transport | ErrorCategory::Timeout)
+    }
+
+    pub fn of_status(status: &Status) -> ErrorCategory {
+        match status.code() {
+            Code::Unavailable | Code::Cancelled | Code::Unknown => ErrorCategory::Transport,
+            Code::DeadlineExceeded => ErrorCategory::Timeout,
+            Code::FailedPrecondition
+            | Code::InvalidArgument
+            | Code::Unimplemented
+            | Code::OutOfRange
+            | Code::ResourceExhausted
+            | Code::DataLoss => ErrorCategory::Protocol,
+            _ => ErrorCategory::Application,
+        }
+    }
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ErrorCategory::Transport => "transport",
+            ErrorCategory::Protocol => "protocol",
+            ErrorCategory::Timeout => "timeout",
+            ErrorCategory::Application => "application",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Errors that can be told apart by [`ErrorCategory`]
+pub trait Categorized {
+    fn category(&self) -> ErrorCategory;
+
+    /// The gRPC status the error originates from, if any
+    fn status(&self) -> Option<&Status> {
+        None
+    }
+
+    fn is_retryable(&self) -> bool {
+        self.category().is_retryable()
+    }
+}
+
+impl Categorized for Status {
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::of_status(self)
+    }
+
+    fn status(&self) -> Option<&Status> {
+        Some(self)
+    }
+}
+
+/// Error counts by category
+#[derive(Debug, Default, Serialize)]
+pub struct ErrorBreakdown {
+    pub transport: AtomicU64,
+    pub protocol: AtomicU64,
+    pub timeout: AtomicU64,
+    pub application: AtomicU64,
+}
+
+impl ErrorBreakdown {
+    fn counter(&self, category: ErrorCategory) -> &AtomicU64 {
+        match category {
+            ErrorCategory::Transport => &self.transport,
+            ErrorCategory::Protocol => &self.protocol,
+            ErrorCategory::Timeout => &self.timeout,
+            ErrorCategory::Application => &self.application,
+        }
+    }
+
+    pub fn record<E: Categorized>(&self, error: &E) {
+        self.counter(error.category()).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, category: ErrorCategory) -> u64 {
+        self.counter(category).load(Ordering::Relaxed)
+    }
+
+    pub fn clear(&self) {
+        for category in ErrorCategory::ALL {
+            self.counter(category).store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_are_categorized() {
+        let unavailable = Status::unavailable("connection reset");
+        assert_eq!(unavailable.category(), ErrorCategory::Transport);
+        assert!(unavailable.is_retryable());
+        assert_eq!(
+            Status::failed_precondition("body before headers").category(),
+            ErrorCategory::Protocol
+        );
+        assert!(!Status::permission_denied("denied").is_retryable());
+
+        let breakdown = ErrorBreakdown::default();
+        breakdown.record(&unavailable);
+        breakdown.record(&Status::deadline_exceeded("late"));
+        assert_eq!(breakdown.get(ErrorCategory::Transport), 1);
+        assert_eq!(breakdown.get(ErrorCategory::Timeout), 1);
+        breakdown.clear();
+        assert_eq!(breakdown.get(ErrorCategory::Transport), 0);
+    }
+}
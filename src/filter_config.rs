@@ -0,0 +1,150 @@
+//! Envoy configuration of the ext_proc filter, matching the playground's client and server
+//! settings, so a scenario can be moved to a real Envoy
+
+use std::time::Duration;
+
+use crate::proto::envoy::extensions::filters::http::ext_proc::v3::ProcessingMode;
+
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    /// Name of the cluster of the External Processor gRPC server
+    pub cluster_name: String,
+    pub server_address: String,
+    pub server_port: u16,
+    /// Processing mode the filter starts every stream with
+    pub processing_mode: ProcessingMode,
+    pub message_timeout: Duration,
+    pub max_message_timeout: Option<Duration>,
+    /// Let requests through when the server fails or times out
+    pub failure_mode_allow: bool,
+    /// Don't wait for responses, like the client's observability mode
+    pub async_mode: bool,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            cluster_name: "ext_proc".to_string(),
+            server_address: "127.0.0.1".to_string(),
+            server_port: 50051,
+            processing_mode: ProcessingMode::default(),
+            message_timeout: Duration::from_millis(200),
+            max_message_timeout: None,
+            failure_mode_allow: false,
+            async_mode: false,
+        }
+    }
+}
+
+/// Envoy's Duration format, e.g. `0.2s`
+fn duration(duration: Duration) -> String {
+    format!("{}s", duration.as_secs_f64())
+}
+
+/// Indents every non-empty line of `yaml` by `spaces`
+pub fn indent(yaml: &str, spaces: usize) -> String {
+    yaml.lines()
+        .map(|line| {
+            if line.is_empty() {
+                "\n".to_string()
+            } else {
+                format!("{:spaces$}{}\n", "", line, spaces = spaces)
+            }
+        })
+        .collect()
+}
+
+impl FilterConfig {
+    /// An entry of a HttpConnectionManager's http_filters, to place before the router
+    pub fn filter_yaml(&self) -> String {
+        let mode = &self.processing_mode;
+        let mut yaml = format!(
+            r#"- name: envoy.filters.http.ext_proc
+  typed_config:
+    "@type": type.googleapis.com/envoy.extensions.filters.http.ext_proc.v3.ExternalProcessor
+    grpc_service:
+      envoy_grpc: {{ cluster_name: {cluster_name} }}
+    failure_mode_allow: {failure_mode_allow}
+    async_mode: {async_mode}
+    message_timeout: {message_timeout}
+"#,
+            cluster_name = self.cluster_name,
+            failure_mode_allow = self.failure_mode_allow,
+            async_mode = self.async_mode,
+            message_timeout = duration(self.message_timeout),
+        );
+        if let Some(max) = self.max_message_timeout {
+            yaml.push_str(&format!("    max_message_timeout: {}\n", duration(max)));
+        }
+        yaml.push_str(&format!(
+            r#"    processing_mode:
+      request_header_mode: {}
+      response_header_mode: {}
+      request_body_mode: {}
+      response_body_mode: {}
+      request_trailer_mode: {}
+      response_trailer_mode: {}
+"#,
+            mode.request_header_mode().as_str_name(),
+            mode.response_header_mode().as_str_name(),
+            mode.request_body_mode().as_str_name(),
+            mode.response_body_mode().as_str_name(),
+            mode.request_trailer_mode().as_str_name(),
+            mode.response_trailer_mode().as_str_name(),
+        ));
+        yaml
+    }
+
+    /// An entry of static_resources' clusters, for the gRPC server
+    pub fn cluster_yaml(&self) -> String {
+        format!(
+            r#"- name: {cluster_name}
+  type: STRICT_DNS
+  typed_extension_protocol_options:
+    envoy.extensions.upstreams.http.v3.HttpProtocolOptions:
+      "@type": type.googleapis.com/envoy.extensions.upstreams.http.v3.HttpProtocolOptions
+      explicit_http_config:
+        http2_protocol_options: {{}}
+  load_assignment:
+    cluster_name: {cluster_name}
+    endpoints:
+    - lb_endpoints:
+      - endpoint:
+          address:
+            socket_address: {{ address: {server_address}, port_value: {server_port} }}
+"#,
+            cluster_name = self.cluster_name,
+            server_address = self.server_address,
+            server_port = self.server_port,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::envoy::extensions::filters::http::ext_proc::v3::processing_mode::BodySendMode;
+
+    #[test]
+    fn filter_yaml_matches_settings() {
+        let mut processing_mode = ProcessingMode::default();
+        processing_mode.set_request_body_mode(BodySendMode::Buffered);
+        let config = FilterConfig {
+            processing_mode,
+            max_message_timeout: Some(Duration::from_secs(2)),
+            failure_mode_allow: true,
+            ..Default::default()
+        };
+        let yaml = config.filter_yaml();
+        assert!(yaml.contains("envoy_grpc: { cluster_name: ext_proc }"));
+        assert!(yaml.contains("failure_mode_allow: true"));
+        assert!(yaml.contains("message_timeout: 0.2s"));
+        assert!(yaml.contains("max_message_timeout: 2s"));
+        assert!(yaml.contains("request_body_mode: BUFFERED"));
+    }
+
+    #[test]
+    fn indents_lines() {
+        assert_eq!(indent("- a\n  b\n", 4), "    - a\n      b\n");
+    }
+}
@@ -1,2 +1,17 @@
+pub mod affinity;
+#[cfg(feature = "client")]
+pub mod bench;
+pub mod cli;
+#[cfg(feature = "client")]
+pub mod conformance;
 pub mod dummy;
-pub mod proto;
\ No newline at end of file
+pub mod error_category;
+#[cfg(feature = "envoy-sidecar")]
+pub mod envoy;
+pub mod filter_config;
+pub mod proto;
+pub mod report;
+#[cfg(feature = "client")]
+pub mod schema;
+pub mod tls;
+pub mod uds;
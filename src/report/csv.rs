@@ -0,0 +1,76 @@
+//! CSV results: a header and a row with the summary of a run, and the monitor's samples in a
+//! separate file, latencies in milliseconds
+
+use std::time::SystemTime;
+
+use super::{sink::Sample, RunSummary};
+
+pub fn summary_to_csv(summary: &RunSummary) -> String {
+    let latency = &summary.latency;
+    format!(
+        "name,started_at,duration_secs,concurrency,count,errors,rate,\
+         mean_ms,min_ms,max_ms,p50_ms,p90_ms,p95_ms,p99_ms,p999_ms\n\
+         {},{},{},{},{},{},{:.2},{:.3},{},{},{},{},{},{},{}\n",
+        quote(&summary.name),
+        timestamp(summary.started_at),
+        summary.duration.as_secs_f64(),
+        summary.concurrency,
+        summary.count,
+        summary.errors,
+        summary.rate(),
+        latency.mean,
+        latency.min,
+        latency.max,
+        latency.p50,
+        latency.p90,
+        latency.p95,
+        latency.p99,
+        latency.p999
+    )
+}
+
+pub fn samples_to_csv(samples: &[Sample]) -> String {
+    let mut csv = String::from("timestamp,rate,mean_latency_ms,errors\n");
+    for sample in samples {
+        csv.push_str(&format!(
+            "{},{:.2},{:.3},{}\n",
+            timestamp(sample.timestamp),
+            sample.rate,
+            sample.mean_latency,
+            sample.errors
+        ));
+    }
+    csv
+}
+
+fn timestamp(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+/// Quotes fields holding separators, quotes or line breaks
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::test_summary;
+
+    #[test]
+    fn csv_summary() {
+        let mut summary = test_summary();
+        summary.name = "Reuse, max 10".to_string();
+        let csv = summary_to_csv(&summary);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            "\"Reuse, max 10\",1970-01-01T00:00:00.000Z,10,100,1000,10,100.00,2.500,1,20,2,4,5,9,15"
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! ghz JSON report, durations in nanoseconds
+
+use serde_json::{json, Value};
+
+use super::RunSummary;
+
+const NANOS_PER_MILLI: f64 = 1_000_000.0;
+
+pub fn to_json(summary: &RunSummary) -> Value {
+    let nanos = |millis: u64| millis * NANOS_PER_MILLI as u64;
+    let latency = &summary.latency;
+    let latency_distribution: Vec<Value> = [
+        (50, latency.p50),
+        (90, latency.p90),
+        (95, latency.p95),
+        (99, latency.p99),
+    ]
+    .iter()
+    .map(|(percentage, millis)| json!({"percentage": percentage, "latency": nanos(*millis)}))
+    .collect();
+
+    let mut error_distribution = serde_json::Map::new();
+    let mut status_code_distribution = serde_json::Map::new();
+    status_code_distribution.insert("OK".to_string(), json!(summary.count - summary.errors));
+    if summary.errors > 0 {
+        error_distribution.insert("stream handling failed".to_string(), json!(summary.errors));
+        status_code_distribution.insert("Unknown".to_string(), json!(summary.errors));
+    }
+
+    json!({
+        "name": summary.name,
+        "date": humantime::format_rfc3339_seconds(summary.started_at).to_string(),
+        "options": {
+            "call": "envoy.service.ext_proc.v3.ExternalProcessor.Process",
+            "concurrency": summary.concurrency,
+        },
+        "count": summary.count,
+        "total": summary.duration.as_nanos() as u64,
+        "average": (latency.mean * NANOS_PER_MILLI) as u64,
+        "fastest": nanos(latency.min),
+        "slowest": nanos(latency.max),
+        "rps": summary.rate(),
+        "errorDistribution": error_distribution,
+        "statusCodeDistribution": status_code_distribution,
+        "latencyDistribution": latency_distribution,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::test_summary;
+
+    #[test]
+    fn ghz_report() {
+        let report = to_json(&test_summary());
+        assert_eq!(report["count"], 1000);
+        assert_eq!(report["total"], 10_000_000_000u64);
+        assert_eq!(report["average"], 2_500_000);
+        assert_eq!(report["rps"], 100.0);
+        assert_eq!(report["date"], "1970-01-01T00:00:00Z");
+        assert_eq!(report["statusCodeDistribution"]["OK"], 990);
+        assert_eq!(report["latencyDistribution"][3]["latency"], 9_000_000);
+    }
+}
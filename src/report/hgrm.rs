@@ -0,0 +1,90 @@
+//! HdrHistogram's percentile distribution format (`.hgrm`), as printed by Java's
+//! `outputPercentileDistribution` and read by the HdrHistogram plotter
+//! (<https://hdrhistogram.github.io/HdrHistogram/plotFiles.html>)
+
+use std::io::{self, Write};
+
+use hdrhistogram::Histogram;
+
+/// Percentile steps per halving of the distance to 100%, as in HdrHistogram's own output
+const TICKS_PER_HALF_DISTANCE: u32 = 5;
+
+/// Writes the distribution of `histogram`, its values divided by `scale` (e.g. 1000.0 to write
+/// microseconds as milliseconds)
+pub fn write_percentiles<W: Write>(
+    histogram: &Histogram<u64>,
+    scale: f64,
+    mut out: W,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:>12} {:>14} {:>10} {:>14}\n",
+        "Value", "Percentile", "TotalCount", "1/(1-Percentile)"
+    )?;
+    let mut total = 0;
+    for step in histogram.iter_quantiles(TICKS_PER_HALF_DISTANCE) {
+        total += step.count_since_last_iteration();
+        let value = step.value_iterated_to() as f64 / scale;
+        let quantile = step.quantile_iterated_to();
+        if quantile < 1.0 {
+            writeln!(
+                out,
+                "{:12.3} {:2.12} {:10} {:14.2}",
+                value,
+                quantile,
+                total,
+                1.0 / (1.0 - quantile)
+            )?;
+        } else {
+            writeln!(out, "{:12.3} {:2.12} {:10}", value, quantile, total)?;
+        }
+    }
+    let sub_buckets = (2 * 10u64.pow(histogram.sigfig() as u32)).next_power_of_two();
+    writeln!(
+        out,
+        "#[Mean    = {:12.3}, StdDeviation   = {:12.3}]",
+        histogram.mean() / scale,
+        histogram.stdev() / scale
+    )?;
+    writeln!(
+        out,
+        "#[Max     = {:12.3}, Total count    = {:12}]",
+        histogram.max() as f64 / scale,
+        histogram.len()
+    )?;
+    writeln!(
+        out,
+        "#[Buckets = {:12}, SubBuckets     = {:12}]",
+        histogram.buckets(),
+        sub_buckets
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distribution_is_written_in_the_plotter_format() {
+        let mut histogram = Histogram::<u64>::new_with_max(3_600_000_000, 3).unwrap();
+        for latency_us in 1..=1000 {
+            histogram.record(latency_us * 10).unwrap();
+        }
+        let mut out = Vec::new();
+        write_percentiles(&histogram, 1000.0, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(
+            lines.next().unwrap().split_whitespace().collect::<Vec<_>>(),
+            ["Value", "Percentile", "TotalCount", "1/(1-Percentile)"]
+        );
+        let rows: Vec<Vec<&str>> = lines
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(rows.first().unwrap()[..3], ["0.010", "0.000000000000", "1"]);
+        assert_eq!(rows.last().unwrap()[..], ["10.007", "1.000000000000", "1000"]);
+        assert!(text.contains("#[Max     =       10.007, Total count    =         1000]"));
+        assert!(text.ends_with("SubBuckets     =         2048]\n"));
+    }
+}
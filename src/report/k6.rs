@@ -0,0 +1,78 @@
+//! k6 summary export, trends in milliseconds
+
+use serde_json::{json, Value};
+
+use super::RunSummary;
+
+pub fn to_json(summary: &RunSummary) -> Value {
+    let latency = &summary.latency;
+    let passes = summary.count - summary.errors;
+    let pass_rate = if summary.count > 0 {
+        passes as f64 / summary.count as f64
+    } else {
+        0.0
+    };
+    let checks = json!({
+        "passes": passes,
+        "fails": summary.errors,
+        "value": pass_rate,
+    });
+
+    json!({
+        "root_group": {
+            "name": "",
+            "path": "",
+            "id": "d41d8cd98f00b204e9800998ecf8427e",
+            "groups": {},
+            "checks": {
+                summary.name.clone(): {
+                    "name": summary.name,
+                    "path": format!("::{}", summary.name),
+                    "id": "",
+                    "passes": passes,
+                    "fails": summary.errors,
+                },
+            },
+        },
+        "metrics": {
+            "grpc_req_duration": {
+                "avg": latency.mean,
+                "min": latency.min,
+                "med": latency.p50,
+                "max": latency.max,
+                "p(90)": latency.p90,
+                "p(95)": latency.p95,
+                "p(99)": latency.p99,
+                "p(99.9)": latency.p999,
+            },
+            "iterations": {
+                "count": summary.count,
+                "rate": summary.rate(),
+            },
+            "vus": {
+                "value": summary.concurrency,
+                "min": summary.concurrency,
+                "max": summary.concurrency,
+            },
+            "checks": checks,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::test_summary;
+
+    #[test]
+    fn k6_summary() {
+        let report = to_json(&test_summary());
+        let metrics = &report["metrics"];
+        assert_eq!(metrics["grpc_req_duration"]["avg"], 2.5);
+        assert_eq!(metrics["grpc_req_duration"]["p(95)"], 5);
+        assert_eq!(metrics["grpc_req_duration"]["p(99.9)"], 15);
+        assert_eq!(metrics["iterations"]["rate"], 100.0);
+        assert_eq!(metrics["checks"]["fails"], 10);
+        assert_eq!(report["root_group"]["checks"]["No Reuse"]["passes"], 990);
+    }
+}
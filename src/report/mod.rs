@@ -0,0 +1,122 @@
+//! Benchmark results in the formats of other load testing and telemetry tools, so they can be
+//! graphed and compared by existing tooling
+
+use std::time::{Duration, SystemTime};
+
+pub mod csv;
+pub mod ghz;
+pub mod hgrm;
+pub mod k6;
+pub mod prometheus;
+pub mod sink;
+
+/// Results of a single benchmark run
+#[derive(Debug, Clone)]
+pub struct RunSummary {
+    /// Scenario name, e.g. "Infinite Reuse"
+    pub name: String,
+    pub started_at: SystemTime,
+    pub duration: Duration,
+    /// Concurrently benchmarked streams
+    pub concurrency: usize,
+    /// Handled streams, including failed ones
+    pub count: u64,
+    pub errors: u64,
+    pub latency: LatencySummary,
+}
+
+/// Stream latency, in milliseconds
+#[derive(Debug, Default, Clone)]
+pub struct LatencySummary {
+    pub mean: f64,
+    pub min: u64,
+    pub max: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub p999: u64,
+}
+
+impl RunSummary {
+    /// Streams handled per second
+    pub fn rate(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.count as f64 / self.duration.as_secs_f64()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// `ghz --format json`
+    Ghz,
+    /// `k6 run --summary-export`
+    K6,
+    /// Every result of the run, as `bench_client --json-only` prints them, with the samples of
+    /// the run's progress
+    Json,
+    /// A row with the summary of the run, and the samples of its progress in a `-samples.csv`
+    /// file next to it
+    Csv,
+}
+
+impl OutputFormat {
+    /// The summary of a run in this format, None for CSV (see [`csv`]). The full results of the
+    /// `Json` format are left to the benchmark engine, only the summary is rendered here
+    pub fn to_json(self, summary: &RunSummary) -> Option<serde_json::Value> {
+        match self {
+            OutputFormat::Ghz => Some(ghz::to_json(summary)),
+            OutputFormat::K6 => Some(k6::to_json(summary)),
+            OutputFormat::Json => Some(summary_to_json(summary)),
+            OutputFormat::Csv => None,
+        }
+    }
+}
+
+/// The summary of a run, with the fields of the full `--json-only` results it has
+pub fn summary_to_json(summary: &RunSummary) -> serde_json::Value {
+    let latency = &summary.latency;
+    serde_json::json!({
+        "name": summary.name,
+        "started_at": humantime::format_rfc3339_seconds(summary.started_at).to_string(),
+        "duration_secs": summary.duration.as_secs_f64(),
+        "concurrency": summary.concurrency,
+        "count": summary.count,
+        "errors": summary.errors,
+        "rate": summary.rate(),
+        "latency_ms": {
+            "mean": latency.mean,
+            "min": latency.min,
+            "max": latency.max,
+            "p50": latency.p50,
+            "p90": latency.p90,
+            "p95": latency.p95,
+            "p99": latency.p99,
+            "p999": latency.p999,
+        },
+    })
+}
+
+#[cfg(test)]
+pub(crate) fn test_summary() -> RunSummary {
+    RunSummary {
+        name: "No Reuse".to_string(),
+        started_at: SystemTime::UNIX_EPOCH,
+        duration: Duration::from_secs(10),
+        concurrency: 100,
+        count: 1000,
+        errors: 10,
+        latency: LatencySummary {
+            mean: 2.5,
+            min: 1,
+            max: 20,
+            p50: 2,
+            p90: 4,
+            p95: 5,
+            p99: 9,
+            p999: 15,
+        },
+    }
+}
@@ -0,0 +1,106 @@
+//! Prometheus scrape endpoints, serving metrics in the Prometheus text exposition format
+
+use std::{convert::Infallible, fmt::Write, future::Future, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4";
+
+/// Builds a scrape response, prefixing the names of metrics with `prefix`
+pub struct TextWriter {
+    prefix: &'static str,
+    out: String,
+}
+
+impl TextWriter {
+    pub fn new(prefix: &'static str) -> TextWriter {
+        TextWriter {
+            prefix,
+            out: String::new(),
+        }
+    }
+
+    /// Starts a metric family of this kind (counter, gauge, summary, histogram)
+    pub fn header(&mut self, name: &str, kind: &str, help: &str) {
+        _ = writeln!(self.out, "# HELP {}_{} {}", self.prefix, name, help);
+        _ = writeln!(self.out, "# TYPE {}_{} {}", self.prefix, name, kind);
+    }
+
+    /// Adds a sample, `labels` being comma separated `name="value"` pairs
+    pub fn sample(&mut self, name: &str, labels: &str, value: f64) {
+        _ = writeln!(self.out, "{}_{}{{{}}} {}", self.prefix, name, labels, value);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// Escapes a label value
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Binds an endpoint serving what `render` returns at `/metrics`. Must be called within a Tokio
+/// runtime, which the returned server is then spawned on
+pub fn serve<F>(address: SocketAddr, render: F) -> Result<impl Future<Output = ()>, hyper::Error>
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let render = Arc::new(render);
+    let make_service = make_service_fn(move |_| {
+        let render = render.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let response = respond(&request, render.as_ref());
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&address)?.serve(make_service);
+    Ok(async move {
+        if let Err(e) = server.await {
+            log::error!("Prometheus endpoint failed: {}", e);
+        }
+    })
+}
+
+fn respond<F: Fn() -> String>(request: &Request<Body>, render: &F) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, CONTENT_TYPE_TEXT.parse().unwrap());
+            *response.body_mut() = Body::from(render());
+        }
+        _ => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_written_in_the_text_format() {
+        let mut writer = TextWriter::new("test");
+        writer.header("requests_total", "counter", "Requests");
+        let labels = format!("name=\"{}\"", escape_label("a \"b\"\n"));
+        writer.sample("requests_total", &labels, 3.0);
+        assert_eq!(
+            writer.finish(),
+            "# HELP test_requests_total Requests\n\
+             # TYPE test_requests_total counter\n\
+             test_requests_total{name=\"a \\\"b\\\"\\n\"} 3\n"
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Live metrics, pushed periodically during a run over UDP
+
+use std::{
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MEASUREMENT: &str = "ext_proc_bench";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SinkFormat {
+    /// InfluxDB line protocol
+    Influx,
+    /// StatsD gauges, with DogStatsD tags
+    Statsd,
+}
+
+/// Metrics of a run, sampled since it started
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub timestamp: SystemTime,
+    /// Streams handled per second
+    pub rate: f64,
+    /// Mean stream latency in milliseconds
+    pub mean_latency: f64,
+    pub errors: u64,
+}
+
+/// Pushes [`Sample`]s to an InfluxDB or StatsD endpoint
+pub struct UdpSink {
+    socket: UdpSocket,
+    format: SinkFormat,
+    tags: Vec<(String, String)>,
+}
+
+impl UdpSink {
+    pub fn connect<A: ToSocketAddrs>(format: SinkFormat, address: A) -> io::Result<UdpSink> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        Ok(UdpSink {
+            socket,
+            format,
+            tags: Vec::new(),
+        })
+    }
+
+    /// A sink to the same endpoint, tagging every sample with `key=value`
+    pub fn with_tag(&self, key: &str, value: &str) -> io::Result<UdpSink> {
+        let mut tags = self.tags.clone();
+        tags.push((key.to_string(), value.to_string()));
+        Ok(UdpSink {
+            socket: self.socket.try_clone()?,
+            format: self.format,
+            tags,
+        })
+    }
+
+    pub fn send(&self, sample: &Sample) -> io::Result<()> {
+        let payload = match self.format {
+            SinkFormat::Influx => influx_line(sample, &self.tags),
+            SinkFormat::Statsd => statsd_lines(sample, &self.tags),
+        };
+        self.socket.send(payload.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Escapes spaces, commas and equal signs in tag keys and values
+fn escape_influx(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn influx_line(sample: &Sample, tags: &[(String, String)]) -> String {
+    let tags: String = tags
+        .iter()
+        .map(|(key, value)| format!(",{}={}", escape_influx(key), escape_influx(value)))
+        .collect();
+    let timestamp = sample
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{}{} rate={},mean_latency_ms={},errors={}i {}\n",
+        MEASUREMENT, tags, sample.rate, sample.mean_latency, sample.errors, timestamp
+    )
+}
+
+fn statsd_lines(sample: &Sample, tags: &[(String, String)]) -> String {
+    let tags = if tags.is_empty() {
+        String::new()
+    } else {
+        let tags: Vec<String> = tags
+            .iter()
+            .map(|(key, value)| format!("{}:{}", key, value.replace([',', '|'], "_")))
+            .collect();
+        format!("|#{}", tags.join(","))
+    };
+    [
+        ("rate", sample.rate),
+        ("mean_latency_ms", sample.mean_latency),
+        ("errors", sample.errors as f64),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{}.{}:{}|g{}\n", MEASUREMENT, name, value, tags))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn sample() -> Sample {
+        Sample {
+            timestamp: UNIX_EPOCH + Duration::from_secs(1),
+            rate: 1500.5,
+            mean_latency: 2.25,
+            errors: 3,
+        }
+    }
+
+    fn tags() -> Vec<(String, String)> {
+        vec![("scenario".to_string(), "No Reuse".to_string())]
+    }
+
+    #[test]
+    fn influx_line_protocol() {
+        assert_eq!(
+            influx_line(&sample(), &tags()),
+            "ext_proc_bench,scenario=No\\ Reuse rate=1500.5,mean_latency_ms=2.25,errors=3i 1000000000\n"
+        );
+    }
+
+    #[test]
+    fn statsd_gauges() {
+        assert_eq!(
+            statsd_lines(&sample(), &tags()),
+            "ext_proc_bench.rate:1500.5|g|#scenario:No Reuse\n\
+             ext_proc_bench.mean_latency_ms:2.25|g|#scenario:No Reuse\n\
+             ext_proc_bench.errors:3|g|#scenario:No Reuse\n"
+        );
+    }
+
+    #[test]
+    fn samples_are_sent() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink = UdpSink::connect(SinkFormat::Influx, receiver.local_addr().unwrap())
+            .unwrap()
+            .with_tag("scenario", "No Reuse")
+            .unwrap();
+        sink.send(&sample()).unwrap();
+        let mut buffer = [0; 512];
+        let received = receiver.recv(&mut buffer).unwrap();
+        assert!(buffer[..received].starts_with(b"ext_proc_bench,scenario=No\\ Reuse "));
+    }
+}
@@ -0,0 +1,53 @@
+//! JSON Schemas of the config files, for editor autocompletion and validation of configs before
+//! a benchmark is scheduled
+
+use schemars::schema_for;
+
+#[cfg(feature = "server")]
+use crate::dummy::server::behavior::BehaviorConfig;
+use crate::{bench::profile::BenchmarkFile, dummy::DummyDataConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFile {
+    /// bench_client's data config (see [`DummyDataConfig`])
+    Data,
+    /// run_server's --behavior-config (see [`BehaviorConfig`])
+    #[cfg(feature = "server")]
+    Behavior,
+    /// bench_client's --bench-file (see [`BenchmarkFile`])
+    Benchmark,
+}
+
+impl ConfigFile {
+    pub fn schema(self) -> serde_json::Value {
+        let schema = match self {
+            ConfigFile::Data => schema_for!(DummyDataConfig),
+            #[cfg(feature = "server")]
+            ConfigFile::Behavior => schema_for!(BehaviorConfig),
+            ConfigFile::Benchmark => schema_for!(BenchmarkFile),
+        };
+        serde_json::to_value(schema).expect("schemas serialize to JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schemas_describe_config_fields() {
+        let data = ConfigFile::Data.schema();
+        assert_eq!(data["title"], "DummyDataConfig");
+        assert!(data["properties"]["request_headers"].is_object());
+        let required = data["required"].as_array().unwrap();
+        assert!(required.contains(&"response_status".into()));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn behavior_schema_describes_header_options() {
+        let behavior = ConfigFile::Behavior.schema();
+        assert!(behavior["properties"]["immediate_response"].is_object());
+        assert!(behavior["definitions"]["HeaderOptionConfig"].is_object());
+    }
+}
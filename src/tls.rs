@@ -0,0 +1,104 @@
+//! TLS of the gRPC connections, with tonic's rustls backend and the system's trust store, when
+//! built with the `tls` feature. Without it, connections are plaintext only
+
+use std::path::PathBuf;
+
+use tonic::transport::Endpoint;
+
+use error::TlsError;
+
+pub mod error {
+    use quick_error::quick_error;
+    use std::path::PathBuf;
+    quick_error!(
+        #[derive(Debug)]
+        pub enum TlsError {
+            ReadError(path: PathBuf, err: std::io::Error) {
+                display("Could not read {}: {}", path.display(), err)
+            }
+            ConfigError(err: tonic::transport::Error) {
+                display("Invalid TLS configuration: {}", err)
+                from()
+            }
+        }
+    );
+}
+
+/// Whether TLS support was compiled in
+pub const ENABLED: bool = cfg!(feature = "tls");
+
+/// Client side TLS settings of `https://` endpoints, on top of the system's trust store
+#[derive(Debug, Clone, Default)]
+pub struct ClientTls {
+    /// PEM CA certificate servers may also be verified against, e.g. a self-signed test CA
+    pub ca: Option<PathBuf>,
+    /// PEM certificate chain and private key presented to servers requiring client
+    /// certificates (mutual TLS)
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// Name server certificates are verified against instead of the URL's host, e.g. when
+    /// connecting by IP address
+    pub domain: Option<String>,
+}
+
+#[cfg(feature = "tls")]
+fn read_pem(path: &std::path::Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|e| TlsError::ReadError(path.to_path_buf(), e))
+}
+
+/// Enables TLS on endpoints of `https://` URLs
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+pub fn secure_endpoint(endpoint: Endpoint, tls: &ClientTls) -> Result<Endpoint, TlsError> {
+    #[cfg(feature = "tls")]
+    if endpoint.uri().scheme_str() == Some("https") {
+        use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+        let mut config = ClientTlsConfig::new();
+        if let Some(ref ca) = tls.ca {
+            config = config.ca_certificate(Certificate::from_pem(read_pem(ca)?));
+        }
+        if let Some((ref cert, ref key)) = tls.identity {
+            config = config.identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?));
+        }
+        if let Some(ref domain) = tls.domain {
+            config = config.domain_name(domain.clone());
+        }
+        return Ok(endpoint.tls_config(config)?);
+    }
+    Ok(endpoint)
+}
+
+/// Server TLS with the PEM encoded certificate chain and private key read from these files.
+/// With a `client_ca`, clients must present a certificate it signed (mutual TLS)
+#[cfg(feature = "tls")]
+pub fn server_tls_config<P: AsRef<std::path::Path>>(
+    cert: P,
+    key: P,
+    client_ca: Option<P>,
+) -> Result<tonic::transport::ServerTlsConfig, TlsError> {
+    use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+    let identity = Identity::from_pem(read_pem(cert.as_ref())?, read_pem(key.as_ref())?);
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(ca) = client_ca {
+        config = config.client_ca_root(Certificate::from_pem(read_pem(ca.as_ref())?));
+    }
+    Ok(config)
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_certificates_are_reported_for_https_only() {
+        let tls = ClientTls {
+            ca: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..Default::default()
+        };
+        let plain = Endpoint::from_static("http://[::1]:50051");
+        assert!(secure_endpoint(plain, &tls).is_ok());
+        let secure = Endpoint::from_static("https://[::1]:50051");
+        assert!(matches!(
+            secure_endpoint(secure, &tls),
+            Err(TlsError::ReadError(path, _)) if path == PathBuf::from("/nonexistent/ca.pem")
+        ));
+    }
+}
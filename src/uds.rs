@@ -0,0 +1,119 @@
+//! gRPC connections over unix domain sockets, as ext_proc sidecars commonly use. Channels keep
+//! their endpoint's URL for the `:authority` and TLS, only the connection is made to the socket
+
+use std::path::Path;
+
+#[cfg(unix)]
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+/// Connects channels to a unix domain socket, whatever their URL
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UdsConnector {
+    path: Arc<Path>,
+}
+
+#[cfg(unix)]
+impl UdsConnector {
+    pub fn new<P: AsRef<Path>>(path: P) -> UdsConnector {
+        UdsConnector {
+            path: path.as_ref().into(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl hyper::service::Service<Uri> for UdsConnector {
+    type Response = UnixStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<UnixStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let path = self.path.clone();
+        Box::pin(async move { UnixStream::connect(&*path).await })
+    }
+}
+
+/// Connects to `endpoint`, over the unix domain socket at `uds` if there is one
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub async fn connect(
+    endpoint: &Endpoint,
+    uds: Option<&Path>,
+) -> Result<Channel, tonic::transport::Error> {
+    #[cfg(unix)]
+    if let Some(path) = uds {
+        return endpoint.connect_with_connector(UdsConnector::new(path)).await;
+    }
+    endpoint.connect().await
+}
+
+#[cfg(all(test, unix, feature = "client", feature = "server"))]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::UnixListener;
+    use tokio_stream::wrappers::UnixListenerStream;
+    use tonic::transport::Server;
+
+    use super::*;
+    use crate::{
+        dummy::{
+            client::{ClientStream, Config},
+            server::{behavior::BehaviorConfig, server_processing_mode, ExtProcService},
+            DummyData,
+        },
+        proto::envoy::service::ext_proc::v3::{
+            external_processor_client::ExternalProcessorClient,
+            external_processor_server::ExternalProcessorServer,
+        },
+    };
+
+    #[test]
+    fn streams_run_over_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("ext_proc_uds_{}.sock", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = UnixListener::bind(&path).unwrap();
+            let service =
+                ExtProcService::new(server_processing_mode(false), BehaviorConfig::default());
+            tokio::spawn(
+                Server::builder()
+                    .add_service(ExternalProcessorServer::new(service))
+                    .serve_with_incoming(UnixListenerStream::new(listener)),
+            );
+            // The URL's port is never dialed
+            let endpoint = Endpoint::from_static("http://[::1]:1");
+            let channel = connect(&endpoint, Some(&path)).await.unwrap();
+            let data = DummyData {
+                req_headers: vec![(":method".to_string(), "GET".to_string())],
+                resp_status: 200,
+                resp_headers: vec![(":status".to_string(), "200".to_string())],
+                ..Default::default()
+            };
+            let mut stream = ClientStream::new(Arc::new(data), Config::default());
+            stream
+                .start_stream(&mut ExternalProcessorClient::new(channel))
+                .await
+                .unwrap();
+            stream.handle_stream().await.unwrap();
+        });
+        _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,64 @@
+//! Requests sent through a real envoy, see [`ext_proc_playground::envoy`].
+//! Needs docker: `cargo test --features envoy-sidecar --test envoy_sidecar -- --ignored`
+#![cfg(feature = "envoy-sidecar")]
+
+use ext_proc_playground::{
+    dummy::server::{
+        behavior::{BehaviorConfig, HeaderMutationConfig, HeaderOptionConfig},
+        ExtProcService,
+    },
+    envoy::{EnvoySidecar, SidecarConfig},
+    filter_config::FilterConfig,
+    proto::envoy::{
+        extensions::filters::http::ext_proc::v3::{processing_mode::HeaderSendMode, ProcessingMode},
+        service::ext_proc::v3::external_processor_server::ExternalProcessorServer,
+    },
+};
+use testcontainers::clients::Cli;
+use tonic::transport::Server;
+
+const SERVER_PORT: u16 = 50061;
+
+#[test]
+#[ignore]
+fn response_headers_are_mutated_through_envoy() {
+    let mut processing_mode = ProcessingMode::default();
+    processing_mode.set_request_header_mode(HeaderSendMode::Send);
+    processing_mode.set_response_header_mode(HeaderSendMode::Send);
+    let behavior = BehaviorConfig {
+        response_headers: HeaderMutationConfig {
+            set_headers: vec![HeaderOptionConfig::new("x-ext-proc", "dummy")],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let service = ExtProcService::new(processing_mode.clone(), behavior);
+    runtime.spawn(
+        Server::builder()
+            .add_service(ExternalProcessorServer::new(service))
+            .serve(format!("127.0.0.1:{}", SERVER_PORT).parse().unwrap()),
+    );
+
+    let docker = Cli::default();
+    let envoy = EnvoySidecar::start(
+        &docker,
+        &SidecarConfig {
+            filter: FilterConfig {
+                server_port: SERVER_PORT,
+                processing_mode,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let response = runtime.block_on(envoy.get("/")).unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-ext-proc"], "dummy");
+}